@@ -0,0 +1,188 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use prometheus_license_exporter::{
+    parse_config, parse_dsls_line, parse_lmstat, parse_lmx_xml, parse_olicense_xml,
+};
+use std::fmt::Write;
+
+// Size of the generated inputs, roughly the size of a large site license
+const FEATURES: usize = 2000;
+const USERS_PER_FEATURE: usize = 25;
+
+fn lmstat_output() -> String {
+    let mut raw = String::from(
+        "lmutil - Copyright (c) 1989-2020 Flexera. All Rights Reserved.
+Flexible License Manager status on Mon 1/2/2023 10:00
+
+License server status: 27000@lic1,27000@lic2,27000@lic3
+    License file(s) on lic1: /opt/flexlm/license.dat:
+
+      lic1: license server UP (MASTER) v11.16.2
+      lic2: license server UP v11.16.2
+      lic3: license server UP v11.16.2
+
+Vendor daemon status (on lic1):
+
+     vendx: UP v11.16.2
+
+Feature usage info:
+
+",
+    );
+
+    for f in 0..FEATURES {
+        let _ = write!(
+            raw,
+            "Users of feature_{f}:  (Total of 100 licenses issued;  Total of {USERS_PER_FEATURE} licenses in use)
+
+  \"feature_{f}\" v1.0, vendor: vendx, expiry: 31-dec-2030
+  floating license
+
+"
+        );
+        for u in 0..USERS_PER_FEATURE {
+            let _ = writeln!(
+                raw,
+                "    user{u} host{u} /dev/tty (v1.0) (lic1/27000 {u}), start Mon 1/2 9:00"
+            );
+        }
+        raw.push('\n');
+    }
+
+    raw
+}
+
+fn lmx_output() -> String {
+    let mut raw = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<LM-X>
+  <LICENSE_PATH TYPE=\"NETWORK\" HOST=\"6200@lic1\" SERVER_VERSION=\"5.5\" UPTIME=\"8 hour(s)\" STATUS=\"SUCCESS\">
+",
+    );
+
+    for f in 0..FEATURES {
+        let _ = writeln!(
+            raw,
+            "    <FEATURE NAME=\"feature_{f}\" VERSION=\"22.0\" VENDOR=\"ALTAIR\" START=\"2020-04-25\" END=\"2030-12-31\" USED_LICENSES=\"{USERS_PER_FEATURE}\" TOTAL_LICENSES=\"100\" DENIED_LICENSES=\"0\" SHARE=\"CUSTOM ,VIRTUAL\">"
+        );
+        for u in 0..USERS_PER_FEATURE {
+            let _ = writeln!(
+                raw,
+                "      <USER NAME=\"user{u}\" HOST=\"host{u}\" IP=\"192.0.2.1\" USED_LICENSES=\"1\" LOGIN_TIME=\"2023-01-02 09:00\" CHECKOUT_TIME=\"2023-01-02 09:00\" SHARE_CUSTOM=\"user{u}:host{u}\"/>"
+            );
+        }
+        raw.push_str("    </FEATURE>\n");
+    }
+    raw.push_str("  </LICENSE_PATH>\n</LM-X>\n");
+
+    raw
+}
+
+fn olicense_output() -> String {
+    let mut raw = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<licenseStatus>
+  <serverVersion>4.6.1</serverVersion>
+",
+    );
+
+    for f in 0..FEATURES {
+        let checkouts: Vec<String> = (0..USERS_PER_FEATURE)
+            .map(|u| format!("user{u}@host{u} #1"))
+            .collect();
+        let _ = write!(
+            raw,
+            "  <license>
+    <name>feature_{f}</name>
+    <module>
+      <name>module_{f}</name>
+    </module>
+    <licenser>Optimal</licenser>
+    <versionRange>1.0-2.0</versionRange>
+    <floatCount>100</floatCount>
+    <floatsLocked>{USERS_PER_FEATURE}</floatsLocked>
+    <floatsLockedBy>{}</floatsLockedBy>
+    <expiration>2030-12-31</expiration>
+  </license>
+",
+            checkouts.join(", ")
+        );
+    }
+    raw.push_str("</licenseStatus>\n");
+
+    raw
+}
+
+fn dsls_output() -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for f in 0..FEATURES {
+        lines.push(format!(
+            "Dassault Systemes,5E756A80-0001,FEATURE{f},NamedUser,STD,426,2023-12-31 00:00:00,YLC,0,2030-12-31 23:59:00,200000000012345,100,{USERS_PER_FEATURE},0,0"
+        ));
+        for u in 0..USERS_PER_FEATURE {
+            lines.push(format!(
+                "Dassault Systemes,5E756A80-0001,FEATURE{f},NamedUser,STD,426,2023-12-31 00:00:00,YLC,0,2030-12-31 23:59:00,200000000012345,1,1,0,0,host{u},user{u},{u},CATIA.exe,R2023x,{u},2023-01-02 09:00:00,2023-01-02 09:30:00,2023-01-02 09:00:00,0"
+            ));
+        }
+    }
+
+    lines
+}
+
+fn bench_lmstat(c: &mut Criterion) {
+    let cfg =
+        parse_config("flexlm:\n  - name: bench\n    license: 27000@lic1,27000@lic2,27000@lic3\n")
+            .unwrap();
+    let lic = &cfg.flexlm.unwrap()[0];
+    let raw = lmstat_output();
+
+    let mut group = c.benchmark_group("flexlm");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("parse_lmstat", |b| {
+        b.iter(|| {
+            let mut servers = Vec::new();
+            parse_lmstat(lic, black_box(&raw), &mut servers)
+        })
+    });
+    group.finish();
+}
+
+fn bench_lmx(c: &mut Criterion) {
+    let raw = lmx_output();
+
+    let mut group = c.benchmark_group("lmx");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("parse_xml", |b| {
+        b.iter(|| parse_lmx_xml(black_box(raw.clone())).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_olicense(c: &mut Criterion) {
+    let raw = olicense_output();
+
+    let mut group = c.benchmark_group("olicense");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("parse_xml", |b| {
+        b.iter(|| parse_olicense_xml(black_box(raw.as_bytes())).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_dsls(c: &mut Criterion) {
+    let lines = dsls_output();
+
+    let mut group = c.benchmark_group("dsls");
+    group.throughput(Throughput::Elements(lines.len() as u64));
+    group.bench_function("extract_data", |b| {
+        b.iter(|| {
+            for line in lines.iter() {
+                black_box(parse_dsls_line(black_box(line)).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_lmstat, bench_lmx, bench_olicense, bench_dsls);
+criterion_main!(benches);
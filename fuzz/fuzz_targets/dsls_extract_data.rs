@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prometheus_license_exporter::parse_dsls_line;
+
+fuzz_target!(|data: &str| {
+    for line in data.lines() {
+        let _ = parse_dsls_line(line);
+    }
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prometheus_license_exporter::{parse_hasp_features, parse_hasp_sessions};
+
+fuzz_target!(|data: &str| {
+    let _ = parse_hasp_features(data.to_string());
+    let _ = parse_hasp_sessions(data.to_string());
+});
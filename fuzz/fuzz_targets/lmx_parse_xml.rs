@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prometheus_license_exporter::parse_lmx_xml;
+
+fuzz_target!(|data: &str| {
+    let _ = parse_lmx_xml(data.to_string());
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use prometheus_license_exporter::parse_olicense_xml;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_olicense_xml(data);
+});
@@ -0,0 +1,259 @@
+use prometheus::proto::MetricFamily;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+// Ad-hoc queries for a single feature, e.g. for chat bots without access to Prometheus. The
+// values are taken from the metrics of the last collection, the license servers aren't
+// queried.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Feature {
+    pub app: String,
+    pub backend: String,
+    pub feature: String,
+    pub issued: Option<f64>,
+    pub used: Option<f64>,
+    // user -> number of used licenses
+    pub users: BTreeMap<String, f64>,
+    pub expiration: Vec<Expiration>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Expiration {
+    pub licenses: Option<i64>,
+    pub expires: f64,
+}
+
+// Current usage of feature of the license app, None if the feature isn't exported
+pub fn feature(families: &[MetricFamily], app: &str, feature: &str) -> Option<Feature> {
+    let mut result: Option<Feature> = None;
+
+    for mf in families {
+        let name = mf.get_name();
+        let (backend, kind) = match [
+            "_feature_issued",
+            "_feature_used",
+            "_feature_used_users",
+            "_feature_expiration_seconds",
+        ]
+        .iter()
+        .find_map(|s| name.strip_suffix(s).map(|b| (b, *s)))
+        {
+            Some(v) => v,
+            None => continue,
+        };
+
+        for m in mf.get_metric() {
+            let labels: HashMap<&str, &str> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            if labels.get("app") != Some(&app) || labels.get("name") != Some(&feature) {
+                continue;
+            }
+
+            let entry = result.get_or_insert_with(|| Feature {
+                app: app.to_string(),
+                backend: backend.to_string(),
+                feature: feature.to_string(),
+                issued: None,
+                used: None,
+                users: BTreeMap::new(),
+                expiration: Vec::new(),
+            });
+            let value = m.get_gauge().get_value();
+
+            // Series with further labels, e.g. the versions of RLM features, are summed up
+            match kind {
+                "_feature_issued" => *entry.issued.get_or_insert(0.0) += value,
+                "_feature_used" => *entry.used.get_or_insert(0.0) += value,
+                "_feature_used_users" => {
+                    // Checkouts of a user with different versions are summed up
+                    if let Some(user) = labels.get("user") {
+                        *entry.users.entry(user.to_string()).or_insert(0.0) += value;
+                    }
+                }
+                _ => entry.expiration.push(Expiration {
+                    licenses: labels.get("licenses").and_then(|l| l.parse().ok()),
+                    expires: value,
+                }),
+            };
+        }
+    }
+
+    if let Some(f) = result.as_mut() {
+        f.expiration
+            .sort_by(|a, b| a.expires.partial_cmp(&b.expires).unwrap());
+    }
+    result
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Vendor {
+    pub app: String,
+    pub name: String,
+    pub version: String,
+    pub up: bool,
+}
+
+// Vendor daemons reported by the FlexLM license servers, of all licenses if app is None
+pub fn vendors(families: &[MetricFamily], app: Option<&str>) -> Vec<Vendor> {
+    let mut result: Vec<Vendor> = Vec::new();
+
+    for mf in families
+        .iter()
+        .filter(|mf| mf.get_name() == "flexlm_vendor_status")
+    {
+        for m in mf.get_metric() {
+            let labels: HashMap<&str, &str> = m
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            let vendor_app = labels.get("app").copied().unwrap_or("");
+            if app.is_some() && app != Some(vendor_app) {
+                continue;
+            }
+
+            result.push(Vendor {
+                app: vendor_app.to_string(),
+                name: labels.get("name").copied().unwrap_or("").to_string(),
+                version: labels.get("version").copied().unwrap_or("").to_string(),
+                up: m.get_gauge().get_value() == 1.0,
+            });
+        }
+    }
+
+    result.sort_by(|a, b| a.app.cmp(&b.app).then_with(|| a.name.cmp(&b.name)));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
+
+    #[test]
+    fn feature_usage() {
+        let registry = Registry::new();
+        let used =
+            IntGaugeVec::new(Opts::new("flexlm_feature_used", "test"), &["app", "name"]).unwrap();
+        let users = IntGaugeVec::new(
+            Opts::new("flexlm_feature_used_users", "test"),
+            &["app", "name", "user", "version"],
+        )
+        .unwrap();
+        let expiration = GaugeVec::new(
+            Opts::new("flexlm_feature_expiration_seconds", "test"),
+            &["app", "index", "licenses", "name"],
+        )
+        .unwrap();
+        registry.register(Box::new(used.clone())).unwrap();
+        registry.register(Box::new(users.clone())).unwrap();
+        registry.register(Box::new(expiration.clone())).unwrap();
+
+        used.with_label_values(&["flex1", "MATLAB"]).set(3);
+        used.with_label_values(&["flex2", "MATLAB"]).set(7);
+        users
+            .with_label_values(&["flex1", "MATLAB", "alice", "1.0"])
+            .set(1);
+        users
+            .with_label_values(&["flex1", "MATLAB", "alice", "2.0"])
+            .set(1);
+        users
+            .with_label_values(&["flex1", "MATLAB", "bob", "2.0"])
+            .set(1);
+        expiration
+            .with_label_values(&["flex1", "1", "5", "MATLAB"])
+            .set(1940544000.0);
+        expiration
+            .with_label_values(&["flex1", "2", "10", "MATLAB"])
+            .set(1924905600.0);
+
+        let families = registry.gather();
+        let result = feature(&families, "flex1", "MATLAB").unwrap();
+        assert_eq!(result.backend, "flexlm");
+        assert_eq!(result.used, Some(3.0));
+        assert_eq!(result.issued, None);
+        assert_eq!(result.users.get("alice"), Some(&2.0));
+        assert_eq!(result.users.get("bob"), Some(&1.0));
+        assert_eq!(
+            result.expiration,
+            vec![
+                Expiration {
+                    licenses: Some(10),
+                    expires: 1924905600.0
+                },
+                Expiration {
+                    licenses: Some(5),
+                    expires: 1940544000.0
+                }
+            ]
+        );
+
+        assert!(feature(&families, "flex1", "Simulink").is_none());
+    }
+
+    #[test]
+    fn feature_versions() {
+        let registry = Registry::new();
+        for name in ["rlm_feature_issued", "rlm_feature_used"] {
+            let gauge =
+                IntGaugeVec::new(Opts::new(name, "test"), &["app", "name", "version"]).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge
+                .with_label_values(&["rlm1", "hyperworks", "1.0"])
+                .set(5);
+            gauge
+                .with_label_values(&["rlm1", "hyperworks", "2.0"])
+                .set(2);
+        }
+
+        let result = feature(&registry.gather(), "rlm1", "hyperworks").unwrap();
+        assert_eq!(result.backend, "rlm");
+        assert_eq!(result.issued, Some(7.0));
+        assert_eq!(result.used, Some(7.0));
+    }
+
+    #[test]
+    fn vendor_daemons() {
+        let registry = Registry::new();
+        let status = IntGaugeVec::new(
+            Opts::new("flexlm_vendor_status", "test"),
+            &["app", "name", "version"],
+        )
+        .unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+
+        status
+            .with_label_values(&["flex2", "MLM", "v11.16.2"])
+            .set(1);
+        status
+            .with_label_values(&["flex1", "vendy", "v11.14.0"])
+            .set(0);
+        status
+            .with_label_values(&["flex1", "vendx", "v11.16.2"])
+            .set(1);
+
+        let families = registry.gather();
+        assert_eq!(
+            vendors(&families, Some("flex1")),
+            vec![
+                Vendor {
+                    app: "flex1".to_string(),
+                    name: "vendx".to_string(),
+                    version: "v11.16.2".to_string(),
+                    up: true,
+                },
+                Vendor {
+                    app: "flex1".to_string(),
+                    name: "vendy".to_string(),
+                    version: "v11.14.0".to_string(),
+                    up: false,
+                },
+            ]
+        );
+        assert_eq!(vendors(&families, None).len(), 3);
+        assert!(vendors(&families, Some("flex3")).is_empty());
+    }
+}
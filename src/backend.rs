@@ -0,0 +1,169 @@
+use crate::dsls;
+use crate::flexlm;
+use crate::hasp;
+use crate::licman20;
+use crate::lmx;
+use crate::olicense;
+use crate::rlm;
+use crate::series;
+
+use prometheus::core::Collector;
+use prometheus::Registry;
+
+// Metrics of a license backend. Tools embedding the parsers can register the metrics of the
+// backends they use into their own registry instead of the registry of the exporter.
+pub trait Backend: Sync {
+    // Name of the backend in the configuration file, e.g. flexlm
+    fn name(&self) -> &'static str;
+
+    fn register_into(&self, registry: &Registry);
+
+    fn collectors(&self) -> Vec<Box<dyn Collector>>;
+
+    // Metrics with series per license, see series.rs
+    fn vectors(&self) -> Vec<series::Vector>;
+}
+
+pub struct Dsls;
+pub struct FlexLM;
+pub struct Hasp;
+pub struct Licman20;
+pub struct Lmx;
+pub struct OLicense;
+pub struct Rlm;
+
+impl Backend for Dsls {
+    fn name(&self) -> &'static str {
+        "dsls"
+    }
+    fn register_into(&self, registry: &Registry) {
+        dsls::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        dsls::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        dsls::vectors()
+    }
+}
+
+impl Backend for FlexLM {
+    fn name(&self) -> &'static str {
+        "flexlm"
+    }
+    fn register_into(&self, registry: &Registry) {
+        flexlm::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        flexlm::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        flexlm::vectors()
+    }
+}
+
+impl Backend for Hasp {
+    fn name(&self) -> &'static str {
+        "hasp"
+    }
+    fn register_into(&self, registry: &Registry) {
+        hasp::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        hasp::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        hasp::vectors()
+    }
+}
+
+impl Backend for Licman20 {
+    fn name(&self) -> &'static str {
+        "licman20"
+    }
+    fn register_into(&self, registry: &Registry) {
+        licman20::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        licman20::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        licman20::vectors()
+    }
+}
+
+impl Backend for Lmx {
+    fn name(&self) -> &'static str {
+        "lmx"
+    }
+    fn register_into(&self, registry: &Registry) {
+        lmx::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        lmx::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        lmx::vectors()
+    }
+}
+
+impl Backend for OLicense {
+    fn name(&self) -> &'static str {
+        "olicense"
+    }
+    fn register_into(&self, registry: &Registry) {
+        olicense::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        olicense::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        olicense::vectors()
+    }
+}
+
+impl Backend for Rlm {
+    fn name(&self) -> &'static str {
+        "rlm"
+    }
+    fn register_into(&self, registry: &Registry) {
+        rlm::register_into(registry);
+    }
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        rlm::collectors()
+    }
+    fn vectors(&self) -> Vec<series::Vector> {
+        rlm::vectors()
+    }
+}
+
+// All backends, in the order of config::BACKENDS
+pub const BACKENDS: [&dyn Backend; 7] = [&Dsls, &FlexLM, &Hasp, &Licman20, &Lmx, &OLicense, &Rlm];
+
+pub fn by_name(name: &str) -> Option<&'static dyn Backend> {
+    BACKENDS.iter().find(|b| b.name() == name).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    #[test]
+    fn backend_names() {
+        let names: Vec<&str> = BACKENDS.iter().map(|b| b.name()).collect();
+        assert_eq!(names, config::BACKENDS);
+        assert_eq!(by_name("flexlm").unwrap().name(), "flexlm");
+        assert!(by_name("lmgrd").is_none());
+    }
+
+    #[test]
+    fn register_into_own_registry() {
+        let registry = Registry::new();
+        let rlm = by_name("rlm").unwrap();
+        rlm.register_into(&registry);
+        for collector in rlm.collectors() {
+            assert!(registry.unregister(collector).is_ok());
+        }
+    }
+}
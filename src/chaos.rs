@@ -0,0 +1,140 @@
+use lazy_static::lazy_static;
+use log::warn;
+use simple_error::bail;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
+
+// Simulated failures and slow scrapes of licenses, set by the hidden command line options
+// --fail-app and --delay-app to test alerting rules and scrape timeouts
+#[derive(Debug, Default)]
+struct Chaos {
+    fail: HashSet<String>,
+    delay: HashMap<String, Duration>,
+}
+
+lazy_static! {
+    static ref CHAOS: RwLock<Chaos> = RwLock::new(Chaos::default());
+}
+
+// fail - names of the licenses to fail, delay - entries of name=duration
+pub fn configure(fail: &[String], delay: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut chaos = Chaos::default();
+
+    for name in fail {
+        if name.is_empty() {
+            bail!("Empty license name for --fail-app");
+        }
+        chaos.fail.insert(name.to_string());
+    }
+
+    for entry in delay {
+        let (name, duration) = parse_delay(entry)?;
+        chaos.delay.insert(name, duration);
+    }
+
+    match CHAOS.write() {
+        Ok(mut v) => *v = chaos,
+        Err(e) => bail!("Can't set failure injection settings: {}", e),
+    };
+    Ok(())
+}
+
+// Delay and fail the scrape of a license as requested
+pub fn inject(app: &str) -> Result<(), Box<dyn Error>> {
+    let (fail, delay) = match CHAOS.read() {
+        Ok(v) => (v.fail.contains(app), v.delay.get(app).copied()),
+        Err(_) => return Ok(()),
+    };
+
+    if let Some(d) = delay {
+        warn!(
+            "chaos.rs:inject: Delaying scrape of {} by {} ms as requested by --delay-app",
+            app,
+            d.as_millis()
+        );
+        thread::sleep(d);
+    }
+
+    if fail {
+        bail!("Simulated failure as requested by --fail-app");
+    }
+    Ok(())
+}
+
+// name=duration, the duration is a number with an optional unit ms, s (default) or m
+fn parse_delay(entry: &str) -> Result<(String, Duration), Box<dyn Error>> {
+    let (name, raw) = match entry.split_once('=') {
+        Some(v) => v,
+        None => bail!(
+            "Invalid --delay-app value {}, expecting name=duration",
+            entry
+        ),
+    };
+    if name.is_empty() {
+        bail!("Empty license name in --delay-app value {}", entry);
+    }
+
+    let (number, factor) = if let Some(v) = raw.strip_suffix("ms") {
+        (v, 1)
+    } else if let Some(v) = raw.strip_suffix('s') {
+        (v, 1000)
+    } else if let Some(v) = raw.strip_suffix('m') {
+        (v, 60_000)
+    } else {
+        (raw, 1000)
+    };
+
+    let value: u64 = match number.parse() {
+        Ok(v) => v,
+        Err(e) => bail!(
+            "Invalid duration {} in --delay-app value {}: {}",
+            raw,
+            entry,
+            e
+        ),
+    };
+
+    Ok((name.to_string(), Duration::from_millis(value * factor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_values() {
+        assert_eq!(
+            parse_delay("flex=30s").unwrap(),
+            ("flex".to_string(), Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_delay("flex=2m").unwrap(),
+            ("flex".to_string(), Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_delay("flex=250ms").unwrap(),
+            ("flex".to_string(), Duration::from_millis(250))
+        );
+        assert_eq!(
+            parse_delay("flex=5").unwrap(),
+            ("flex".to_string(), Duration::from_secs(5))
+        );
+        for invalid in ["flex", "=5s", "flex=", "flex=5h", "flex=-1s"] {
+            assert!(parse_delay(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn inject_failures() {
+        configure(&["chaos_fail".to_string()], &["chaos_slow=1ms".to_string()]).unwrap();
+        assert!(inject("chaos_fail").is_err());
+        assert!(inject("chaos_slow").is_ok());
+        assert!(inject("chaos_other").is_ok());
+        assert!(configure(&[], &["invalid".to_string()]).is_err());
+        configure(&[], &[]).unwrap();
+        assert!(inject("chaos_fail").is_ok());
+    }
+}
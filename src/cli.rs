@@ -0,0 +1,767 @@
+use crate::{chaos, config, constants, exporter, http, logging, migrate, signals, usage};
+
+use log::error;
+use simple_error::bail;
+use std::error::Error;
+use std::{env, process};
+
+// Command line option, used for parsing and to generate shell completions and the man page
+pub struct CliOption {
+    pub short: &'static str,
+    pub long: &'static str,
+    // Name of the argument, empty for flags
+    pub hint: &'static str,
+    pub description: &'static str,
+}
+
+pub struct CliCommand {
+    pub name: &'static str,
+    // Name of the argument, empty for commands without argument
+    pub argument: &'static str,
+    pub description: &'static str,
+}
+
+pub const OPTIONS: [CliOption; 11] = [
+    CliOption {
+        short: "D",
+        long: "debug",
+        hint: "",
+        description: "Enable debug log",
+    },
+    CliOption {
+        short: "E",
+        long: "config-from-env",
+        hint: "",
+        description: "Read the configuration from the CONFIG_YAML environment variable",
+    },
+    CliOption {
+        short: "F",
+        long: "log-format",
+        hint: "format",
+        description: "Log format, plain (default) or color",
+    },
+    CliOption {
+        short: "V",
+        long: "version",
+        hint: "",
+        description: "Show version information",
+    },
+    CliOption {
+        short: "a",
+        long: "app",
+        hint: "name",
+        description: "Name of the license to scrape (scrape command only)",
+    },
+    CliOption {
+        short: "c",
+        long: "config",
+        hint: "config_file",
+        description: "Configuration file",
+    },
+    CliOption {
+        short: "f",
+        long: "config-format",
+        hint: "format",
+        description: "Format of the configuration file, json, toml or yaml (default: derived from the file name)",
+    },
+    CliOption {
+        short: "h",
+        long: "help",
+        hint: "",
+        description: "Show help text",
+    },
+    CliOption {
+        short: "l",
+        long: "listen",
+        hint: "address",
+        description: "Listen address for scrape requests",
+    },
+    CliOption {
+        short: "m",
+        long: "metrics-path",
+        hint: "path",
+        description: "Path of the metrics endpoint, replaces metrics_path of the configuration",
+    },
+    CliOption {
+        short: "q",
+        long: "quiet",
+        hint: "",
+        description: "Quiet operation, only log warning and error messages",
+    },
+];
+
+pub const COMMANDS: [CliCommand; 8] = [
+    CliCommand {
+        name: constants::COMMAND_RUN,
+        argument: "",
+        description: "Start the exporter (default)",
+    },
+    CliCommand {
+        name: constants::COMMAND_CHECK_CONFIG,
+        argument: "",
+        description: "Check the configuration file and exit",
+    },
+    CliCommand {
+        name: constants::COMMAND_MIGRATE_CONFIG,
+        argument: "",
+        description: "Print the configuration migrated to the current schema version",
+    },
+    CliCommand {
+        name: constants::COMMAND_SCRAPE,
+        argument: "",
+        description: "Fetch the metrics of a single license and print them to stdout",
+    },
+    CliCommand {
+        name: constants::COMMAND_LIST_BACKENDS,
+        argument: "",
+        description: "List the supported license backends and the configured licenses",
+    },
+    CliCommand {
+        name: constants::COMMAND_GENERATE_COMPLETIONS,
+        argument: "shell",
+        description: "Print shell completions for bash, fish or zsh",
+    },
+    CliCommand {
+        name: constants::COMMAND_GENERATE_MAN,
+        argument: "",
+        description: "Print the man page",
+    },
+    CliCommand {
+        name: constants::COMMAND_VERSION,
+        argument: "",
+        description: "Show version information",
+    },
+];
+
+pub const SHELLS: [&str; 3] = ["bash", "fish", "zsh"];
+
+pub fn options() -> getopts::Options {
+    let mut result = getopts::Options::new();
+
+    for opt in OPTIONS.iter() {
+        if opt.hint.is_empty() {
+            result.optflag(opt.short, opt.long, opt.description);
+        } else {
+            result.optopt(
+                opt.short,
+                opt.long,
+                opt.description,
+                &format!("<{}>", opt.hint),
+            );
+        }
+    }
+
+    // Hidden options for failure injection, not shown in the usage, completions and the man page
+    result.optmulti(
+        "",
+        "fail-app",
+        "Simulate a failed scrape of a license",
+        "<name>",
+    );
+    result.optmulti(
+        "",
+        "delay-app",
+        "Delay the scrape of a license",
+        "<name>=<duration>",
+    );
+
+    result
+}
+
+pub fn completions(shell: &str) -> Result<String, Box<dyn Error>> {
+    match shell {
+        "bash" => Ok(bash_completions()),
+        "fish" => Ok(fish_completions()),
+        "zsh" => Ok(zsh_completions()),
+        _ => bail!(
+            "unsupported shell {}, supported shells are {}",
+            shell,
+            SHELLS.join(", ")
+        ),
+    }
+}
+
+fn command_names() -> String {
+    COMMANDS
+        .iter()
+        .map(|c| c.name)
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn bash_completions() -> String {
+    let function = format!("_{}", constants::NAME.replace('-', "_"));
+    let words: Vec<String> = OPTIONS
+        .iter()
+        .flat_map(|o| vec![format!("-{}", o.short), format!("--{}", o.long)])
+        .collect();
+
+    format!(
+        "# bash completion for {name}
+{function}() {{
+    local cur prev
+    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+
+    case \"${{prev}}\" in
+        -c|--config)
+            COMPREPLY=( $(compgen -f -- \"${{cur}}\") )
+            return 0
+            ;;
+        -F|--log-format)
+            COMPREPLY=( $(compgen -W \"{log_formats}\" -- \"${{cur}}\") )
+            return 0
+            ;;
+        -f|--config-format)
+            COMPREPLY=( $(compgen -W \"{config_formats}\" -- \"${{cur}}\") )
+            return 0
+            ;;
+        -a|--app|-l|--listen|-m|--metrics-path)
+            return 0
+            ;;
+        {generate_completions})
+            COMPREPLY=( $(compgen -W \"{shells}\" -- \"${{cur}}\") )
+            return 0
+            ;;
+    esac
+
+    if [[ \"${{cur}}\" == -* ]]; then
+        COMPREPLY=( $(compgen -W \"{options}\" -- \"${{cur}}\") )
+    else
+        COMPREPLY=( $(compgen -W \"{commands}\" -- \"${{cur}}\") )
+    fi
+}}
+complete -F {function} {name}
+",
+        name = constants::NAME,
+        function = function,
+        generate_completions = constants::COMMAND_GENERATE_COMPLETIONS,
+        shells = SHELLS.join(" "),
+        log_formats = constants::LOG_FORMATS.join(" "),
+        config_formats = constants::CONFIG_FORMATS.join(" "),
+        options = words.join(" "),
+        commands = command_names(),
+    )
+}
+
+fn fish_completions() -> String {
+    let mut result = format!("# fish completion for {}\n", constants::NAME);
+
+    for opt in OPTIONS.iter() {
+        let argument = match opt.long {
+            "config" => " -r -F".to_string(),
+            "log-format" => format!(" -r -f -a '{}'", constants::LOG_FORMATS.join(" ")),
+            "config-format" => format!(" -r -f -a '{}'", constants::CONFIG_FORMATS.join(" ")),
+            _ if !opt.hint.is_empty() => " -r -f".to_string(),
+            _ => String::new(),
+        };
+        result.push_str(&format!(
+            "complete -c {} -s {} -l {}{} -d '{}'\n",
+            constants::NAME,
+            opt.short,
+            opt.long,
+            argument,
+            opt.description.replace('\'', "\\'")
+        ));
+    }
+
+    for cmd in COMMANDS.iter() {
+        result.push_str(&format!(
+            "complete -c {} -f -n 'not __fish_seen_subcommand_from {}' -a {} -d '{}'\n",
+            constants::NAME,
+            command_names(),
+            cmd.name,
+            cmd.description.replace('\'', "\\'")
+        ));
+    }
+
+    result.push_str(&format!(
+        "complete -c {} -f -n '__fish_seen_subcommand_from {}' -a '{}'\n",
+        constants::NAME,
+        constants::COMMAND_GENERATE_COMPLETIONS,
+        SHELLS.join(" ")
+    ));
+
+    result
+}
+
+fn zsh_completions() -> String {
+    let mut arguments: Vec<String> = Vec::new();
+
+    for opt in OPTIONS.iter() {
+        let description = opt.description.replace('\'', "'\\''").replace(':', "\\:");
+        let action = match opt.long {
+            "config" => ":config file:_files".to_string(),
+            "log-format" => format!(":{}:({})", opt.hint, constants::LOG_FORMATS.join(" ")),
+            "config-format" => format!(":{}:({})", opt.hint, constants::CONFIG_FORMATS.join(" ")),
+            _ if !opt.hint.is_empty() => format!(":{}: ", opt.hint),
+            _ => String::new(),
+        };
+        arguments.push(format!(
+            "    '(-{short} --{long})'{{-{short},--{long}{eq}}}'[{description}]{action}' \\\n",
+            short = opt.short,
+            long = opt.long,
+            eq = if opt.hint.is_empty() { "" } else { "=" },
+            description = description,
+            action = action,
+        ));
+    }
+
+    let commands: Vec<String> = COMMANDS
+        .iter()
+        .map(|c| {
+            format!(
+                "        '{}:{}'\n",
+                c.name,
+                c.description.replace(':', "\\:")
+            )
+        })
+        .collect();
+
+    format!(
+        "#compdef {name}
+# zsh completion for {name}
+
+_{function}() {{
+    local -a commands
+    commands=(
+{commands}    )
+
+    _arguments -s \\
+{arguments}    '1: :->command' \\
+    '2: :->argument'
+
+    case $state in
+        command)
+            _describe 'command' commands
+            ;;
+        argument)
+            if [[ ${{words[CURRENT-1]}} == {generate_completions} ]]; then
+                _values 'shell' {shells}
+            fi
+            ;;
+    esac
+}}
+
+_{function} \"$@\"
+",
+        name = constants::NAME,
+        function = constants::NAME.replace('-', "_"),
+        commands = commands.join(""),
+        arguments = arguments.join(""),
+        generate_completions = constants::COMMAND_GENERATE_COMPLETIONS,
+        shells = SHELLS.join(" "),
+    )
+}
+
+// Escape text for roff, leading dots and quotes start a request
+fn roff(text: &str) -> String {
+    let escaped = text.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with('.') || escaped.starts_with('\'') {
+        format!("\\&{}", escaped)
+    } else {
+        escaped
+    }
+}
+
+pub fn man_page() -> String {
+    let mut result = format!(
+        ".TH {} 8 \"\" \"{} {}\" \"System Administration\"\n",
+        roff(&constants::NAME.to_uppercase()),
+        roff(constants::NAME),
+        constants::VERSION
+    );
+
+    result.push_str(".SH NAME\n");
+    result.push_str(&format!(
+        "{} \\- export license usage to Prometheus\n",
+        roff(constants::NAME)
+    ));
+
+    result.push_str(".SH SYNOPSIS\n");
+    result.push_str(&format!(".B {}\n", roff(constants::NAME)));
+    for opt in OPTIONS.iter() {
+        if opt.hint.is_empty() {
+            result.push_str(&format!("[\\fB\\-{}\\fR]\n", opt.short));
+        } else {
+            result.push_str(&format!(
+                "[\\fB\\-{}\\fR \\fI{}\\fR]\n",
+                opt.short,
+                roff(opt.hint)
+            ));
+        }
+    }
+    result.push_str("[\\fIcommand\\fR]\n");
+
+    result.push_str(".SH DESCRIPTION\n");
+    result.push_str(&format!(
+        "{} exports the license usage of DSLS, FlexLM, HASP, Licman 2.0, LM\\-X, OLicense and RLM license servers as Prometheus metrics.\n",
+        roff(constants::NAME)
+    ));
+
+    result.push_str(".SH OPTIONS\n");
+    for opt in OPTIONS.iter() {
+        if opt.hint.is_empty() {
+            result.push_str(&format!(
+                ".TP\n\\fB\\-{}\\fR, \\fB\\-\\-{}\\fR\n",
+                opt.short,
+                roff(opt.long)
+            ));
+        } else {
+            result.push_str(&format!(
+                ".TP\n\\fB\\-{short}\\fR \\fI{hint}\\fR, \\fB\\-\\-{long}\\fR=\\fI{hint}\\fR\n",
+                short = opt.short,
+                long = roff(opt.long),
+                hint = roff(opt.hint)
+            ));
+        }
+        result.push_str(&format!("{}\n", roff(opt.description)));
+    }
+
+    result.push_str(".SH COMMANDS\n");
+    for cmd in COMMANDS.iter() {
+        if cmd.argument.is_empty() {
+            result.push_str(&format!(".TP\n\\fB{}\\fR\n", roff(cmd.name)));
+        } else {
+            result.push_str(&format!(
+                ".TP\n\\fB{}\\fR \\fI{}\\fR\n",
+                roff(cmd.name),
+                roff(cmd.argument)
+            ));
+        }
+        result.push_str(&format!("{}\n", roff(cmd.description)));
+    }
+
+    result.push_str(".SH SEE ALSO\n");
+    result.push_str(&format!("{}\n", roff(constants::SOURCE)));
+
+    result
+}
+
+// Parse the command line and run the command, exits the process on errors
+pub fn run() {
+    let argv: Vec<String> = env::args().collect();
+    let options = options();
+    let mut log_level = log::LevelFilter::Info;
+
+    let opts = match options.parse(&argv[1..]) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error: Can't parse command line arguments: {}", e);
+            println!();
+            usage::show_usage();
+            process::exit(1);
+        }
+    };
+
+    if opts.opt_present("h") {
+        usage::show_usage();
+        process::exit(0);
+    }
+
+    if opts.opt_present("V") {
+        usage::show_version();
+        process::exit(0);
+    }
+
+    if opts.opt_present("D") {
+        log_level = log::LevelFilter::Debug;
+    }
+
+    if opts.opt_present("q") {
+        log_level = log::LevelFilter::Warn;
+    }
+
+    let log_format = opts
+        .opt_str("F")
+        .unwrap_or_else(|| constants::LOG_FORMAT_PLAIN.to_string());
+    if !constants::LOG_FORMATS.contains(&log_format.as_str()) {
+        eprintln!(
+            "Error: Invalid log format {}, supported formats are {}",
+            log_format,
+            constants::LOG_FORMATS.join(", ")
+        );
+        println!();
+        usage::show_usage();
+        process::exit(1);
+    }
+
+    if let Some(format) = opts.opt_str("f") {
+        if let Err(e) =
+            config::ConfigFormat::from_name(&format).and_then(config::override_config_format)
+        {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = opts.opt_str("m") {
+        if let Err(e) = config::override_metrics_path(&path) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let fail_apps = opts.opt_strs("fail-app");
+    let delay_apps = opts.opt_strs("delay-app");
+    if let Err(e) = chaos::configure(&fail_apps, &delay_apps) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    // Without a command the exporter is started, as in previous versions
+    let command = opts
+        .free
+        .first()
+        .map_or(constants::COMMAND_RUN, |c| c.as_str());
+    let arguments = if command == constants::COMMAND_GENERATE_COMPLETIONS {
+        1
+    } else {
+        0
+    };
+    if opts.free.len() > 1 + arguments {
+        eprintln!("Error: Only one command can be used");
+        println!();
+        usage::show_usage();
+        process::exit(1);
+    }
+
+    match command {
+        constants::COMMAND_VERSION => {
+            usage::show_version();
+        }
+        constants::COMMAND_GENERATE_COMPLETIONS => {
+            let shell = match opts.free.get(1) {
+                Some(v) => v,
+                None => {
+                    eprintln!(
+                        "Error: The shell is mandatory for the {} command",
+                        constants::COMMAND_GENERATE_COMPLETIONS
+                    );
+                    process::exit(1);
+                }
+            };
+            match completions(shell) {
+                Ok(v) => print!("{}", v),
+                Err(e) => {
+                    eprintln!("Error: Can't generate completions: {}", e);
+                    process::exit(1);
+                }
+            };
+        }
+        constants::COMMAND_GENERATE_MAN => {
+            print!("{}", man_page());
+        }
+        constants::COMMAND_LIST_BACKENDS => {
+            let config = opts
+                .opt_str("c")
+                .map(|f| load_config(&config::ConfigSource::File(f)));
+            list_backends(&config);
+        }
+        constants::COMMAND_CHECK_CONFIG => {
+            let source = config_source(&opts);
+            let config = load_config(&source);
+            let problems = config::check_configuration(&config);
+            if !problems.is_empty() {
+                for problem in problems.iter() {
+                    eprintln!("Error: {}", problem);
+                }
+                process::exit(1);
+            }
+            println!("Configuration from {} is valid", source);
+        }
+        constants::COMMAND_MIGRATE_CONFIG => {
+            let source = config_source(&opts);
+            let migration = source
+                .read()
+                .and_then(|(raw, format)| migrate::migrate(&raw, format));
+            match migration {
+                Ok(v) => {
+                    print!("{}", v.config);
+                    if let Some(version) = v.from_version {
+                        eprintln!(
+                            "Note: Configuration from {} already uses schema version {}",
+                            source, version
+                        );
+                    }
+                    for hint in v.hints.iter() {
+                        eprintln!("Note: {}", hint);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: Can't migrate configuration from {}: {}", source, e);
+                    process::exit(1);
+                }
+            };
+        }
+        constants::COMMAND_SCRAPE => {
+            let app = match opts.opt_str("a") {
+                Some(v) => v,
+                None => {
+                    eprintln!("Error: The name of the license to scrape is mandatory for the scrape command");
+                    println!();
+                    usage::show_usage();
+                    process::exit(1);
+                }
+            };
+            let config = load_config(&config_source(&opts));
+            init_logging(&config, log_level, &log_format, true);
+            scrape(&config, &app);
+        }
+        constants::COMMAND_RUN => {
+            let listen_address = opts
+                .opt_str("l")
+                .unwrap_or_else(|| constants::DEFAULT_PROMETHEUS_ADDRESS.to_string());
+            let source = config_source(&opts);
+            let config = load_config(&source);
+            init_logging(&config, log_level, &log_format, false);
+            serve(config, &source, &listen_address);
+        }
+        _ => {
+            eprintln!("Error: Unknown command {}", command);
+            println!();
+            usage::show_usage();
+            process::exit(1);
+        }
+    };
+}
+
+// The configuration file given by -c or, e.g. in a container, the configuration passed in the
+// CONFIG_YAML environment variable
+fn config_source(opts: &getopts::Matches) -> config::ConfigSource {
+    if opts.opt_present("E") {
+        return config::ConfigSource::Environment;
+    }
+    if let Some(v) = opts.opt_str("c") {
+        return config::ConfigSource::File(v);
+    }
+    if env::var_os(constants::CONFIG_YAML_ENV).is_some() {
+        return config::ConfigSource::Environment;
+    }
+
+    eprintln!(
+        "Error: Configuration file is mandatory if {} is not set",
+        constants::CONFIG_YAML_ENV
+    );
+    println!();
+    usage::show_usage();
+    process::exit(1);
+}
+
+fn load_config(source: &config::ConfigSource) -> config::Configuration {
+    match source.load() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Error: Configuration parsing failed: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn init_logging(
+    config: &config::Configuration,
+    log_level: log::LevelFilter,
+    log_format: &str,
+    to_stderr: bool,
+) {
+    logging::register_secrets(config);
+    let result = if to_stderr {
+        logging::init_stderr(log_level, log_format)
+    } else {
+        logging::init(log_level, log_format)
+    };
+    if let Err(e) = result {
+        eprintln!("Error: Can't initialise logging: {}", e);
+        process::exit(1);
+    }
+
+    if let Some(glob) = &config.global {
+        if let Some(keytab) = &glob.kerberos_keytab {
+            // Used by the Kerberos library to obtain initial credentials for SPNEGO authentication
+            env::set_var("KRB5_CLIENT_KTNAME", keytab);
+        }
+    }
+}
+
+// Supported backends and, if a configuration file was given, the configured licenses
+fn list_backends(config: &Option<config::Configuration>) {
+    for backend in config::BACKENDS {
+        match config {
+            Some(cfg) => {
+                let names = cfg.license_names(backend);
+                if names.is_empty() {
+                    println!("{}: -", backend);
+                } else {
+                    println!("{}: {}", backend, names.join(", "));
+                }
+            }
+            None => println!("{}", backend),
+        };
+    }
+}
+
+// Fetch the metrics of a single license and print them to stdout
+fn scrape(config: &config::Configuration, app: &str) {
+    let selected = config.select(app);
+    if config::BACKENDS
+        .iter()
+        .all(|b| selected.license_names(b).is_empty())
+    {
+        eprintln!("Error: No license named {} found in the configuration", app);
+        process::exit(1);
+    }
+
+    exporter::configure(&selected);
+    exporter::register(&selected);
+    print!("{}", exporter::metrics(&selected));
+}
+
+fn serve(config: config::Configuration, source: &config::ConfigSource, listen_address: &str) {
+    signals::init();
+    exporter::configure(&config);
+    exporter::register(&config);
+    if let Err(e) = http::server(config, source, listen_address) {
+        error!("cli.rs:serve: Can't start HTTP server: {}", e);
+        process::exit(1);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completions_contain_options_and_commands() {
+        for shell in SHELLS {
+            let result = completions(shell).unwrap();
+            for opt in OPTIONS.iter() {
+                assert!(result.contains(opt.long), "{}: {}", shell, opt.long);
+            }
+            for cmd in COMMANDS.iter() {
+                assert!(result.contains(cmd.name), "{}: {}", shell, cmd.name);
+            }
+        }
+        assert!(completions("tcsh").is_err());
+    }
+
+    #[test]
+    fn man_page_escapes_options() {
+        let result = man_page();
+        assert!(result.starts_with(".TH PROMETHEUS\\-LICENSE\\-EXPORTER 8"));
+        assert!(result.contains(
+            "\\fB\\-c\\fR \\fIconfig_file\\fR, \\fB\\-\\-config\\fR=\\fIconfig_file\\fR"
+        ));
+        assert!(result.contains(".TP\n\\fBcheck\\-config\\fR\n"));
+        assert!(!result.lines().any(|l| l.starts_with('\'')));
+    }
+
+    #[test]
+    fn options_parse() {
+        let opts = options()
+            .parse(["--config=c.yaml", "-a", "flex", "scrape"])
+            .unwrap();
+        assert_eq!(opts.opt_str("c").unwrap(), "c.yaml");
+        assert_eq!(opts.opt_str("app").unwrap(), "flex");
+        assert_eq!(opts.free, vec!["scrape"]);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::config;
+use crate::exporter;
+use crate::peersync;
+use crate::runtime;
+
+use lazy_static::lazy_static;
+use log::{debug, error, info};
+use prometheus::core::Collector as _;
+use prometheus::proto::MetricFamily;
+use prometheus::{GaugeVec, Opts};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Not part of the sample set, it is rendered on every scrape, so a stuck collection thread shows
+// an old timestamp while its last sample set is still served
+lazy_static! {
+    static ref HEARTBEAT: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "license_exporter_collector_heartbeat_timestamp",
+            "Time of the last finished background collection of the backend"
+        ),
+        &["backend"],
+    )
+    .unwrap();
+}
+
+// Background collection, enabled by global.scrape_interval. A thread queries the license
+// servers on its own interval and scrapes are answered with the last sample set, so the
+// number of Prometheus servers and their scrape intervals don't affect the license servers.
+// Licenses of a scrape group are queried on the interval of the group instead.
+pub struct Collector {
+    cfg: Mutex<Arc<config::Configuration>>,
+    sample: RwLock<Option<String>>,
+    running: AtomicBool,
+}
+
+impl Collector {
+    // Starts the collection thread if scrape_interval is set
+    pub fn new(cfg: Arc<config::Configuration>) -> Arc<Self> {
+        let collector = Arc::new(Collector {
+            cfg: Mutex::new(cfg),
+            sample: RwLock::new(None),
+            running: AtomicBool::new(false),
+        });
+        collector.start();
+        collector
+    }
+
+    // Use a reloaded configuration for the next collection. The collection thread is started
+    // if scrape_interval was added and stops if it was removed.
+    pub fn set_config(self: &Arc<Self>, cfg: Arc<config::Configuration>) {
+        match self.cfg.lock() {
+            Ok(mut v) => *v = cfg,
+            Err(e) => {
+                error!("collector.rs:set_config: Can't update configuration: {}", e);
+                return;
+            }
+        };
+        self.start();
+    }
+
+    // Last sample set, None until the first collection has finished
+    pub fn sample(&self) -> Option<String> {
+        match self.sample.read() {
+            Ok(v) => v.clone(),
+            Err(e) => {
+                error!("collector.rs:sample: Can't read sample set: {}", e);
+                None
+            }
+        }
+    }
+
+    // Heartbeat of the collection thread, appended to the sample set
+    pub fn heartbeat(&self) -> Vec<MetricFamily> {
+        HEARTBEAT.collect()
+    }
+
+    fn config(&self) -> Option<Arc<config::Configuration>> {
+        match self.cfg.lock() {
+            Ok(v) => Some(Arc::clone(&v)),
+            Err(e) => {
+                error!("collector.rs:config: Can't read configuration: {}", e);
+                None
+            }
+        }
+    }
+
+    fn start(self: &Arc<Self>) {
+        let enabled = self
+            .config()
+            .map_or(false, |c| c.scrape_interval().is_some());
+        if !enabled || self.running.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let collector = Arc::clone(self);
+        thread::spawn(move || collector.run());
+    }
+
+    fn run(self: Arc<Self>) {
+        info!("collector.rs:run: Starting background collection");
+        // scrape group -> time of its next collection
+        let mut next_collection: HashMap<Option<String>, Instant> = HashMap::new();
+
+        loop {
+            let cfg = match self.config() {
+                Some(v) => v,
+                None => break,
+            };
+            let groups = cfg.scrape_groups();
+            if groups.is_empty() {
+                break;
+            }
+
+            // The series of the other groups keep the values of their last collection, so the
+            // sample set contains the latest data of every group
+            let start = Instant::now();
+            let due: Vec<Option<String>> = groups
+                .iter()
+                .filter(|(g, _)| next_collection.get(g).map_or(true, |t| *t <= start))
+                .map(|(g, _)| g.clone())
+                .collect();
+            debug!("collector.rs:run: Collecting scrape groups {:?}", due);
+
+            let selected = cfg.select_groups(&due);
+            let sample = exporter::metrics(&selected);
+            beat(&cfg, &selected);
+            peersync::push(&selected, &sample);
+            match self.sample.write() {
+                Ok(mut v) => *v = Some(sample),
+                Err(e) => error!("collector.rs:run: Can't store sample set: {}", e),
+            };
+
+            next_collection.retain(|g, _| groups.iter().any(|(name, _)| name == g));
+            for (group, interval) in groups.iter() {
+                if due.contains(group) {
+                    next_collection.insert(group.clone(), start + *interval);
+                    runtime::dropped_cycles(dropped_cycles(start.elapsed(), *interval));
+                }
+            }
+            let next = next_collection.values().min().copied().unwrap_or(start);
+            let wait = next.saturating_duration_since(Instant::now());
+            debug!(
+                "collector.rs:run: Collection finished after {:?}, next collection in {:?}",
+                start.elapsed(),
+                wait
+            );
+
+            thread::sleep(wait);
+        }
+
+        info!("collector.rs:run: Background collection stopped");
+        HEARTBEAT.reset();
+        match self.sample.write() {
+            Ok(mut v) => *v = None,
+            Err(e) => error!("collector.rs:run: Can't clear sample set: {}", e),
+        };
+        self.running.store(false, Ordering::SeqCst);
+
+        // scrape_interval could have been added again while stopping
+        self.start();
+    }
+}
+
+// Intervals passed during a collection, the next collection starts late instead of
+// catching up on them
+fn dropped_cycles(elapsed: Duration, interval: Duration) -> u64 {
+    if interval.is_zero() {
+        return 0;
+    }
+    (elapsed.as_secs_f64() / interval.as_secs_f64()) as u64
+}
+
+// Update the heartbeat of the collected backends, backends without licenses are removed
+fn beat(cfg: &config::Configuration, collected: &config::Configuration) {
+    let now = chrono::Local::now().timestamp() as f64;
+
+    for backend in config::BACKENDS {
+        if cfg.license_names(backend).is_empty() {
+            let _ = HEARTBEAT.remove_label_values(&[backend]);
+        } else if !collected.license_names(backend).is_empty() {
+            debug!(
+                "collector.rs:beat: Setting license_exporter_collector_heartbeat_timestamp {} -> {}",
+                backend, now
+            );
+            HEARTBEAT.with_label_values(&[backend]).set(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use prometheus::TextEncoder;
+
+    #[test]
+    fn dropped_collection_cycles() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(dropped_cycles(Duration::from_secs(59), minute), 0);
+        assert_eq!(dropped_cycles(Duration::from_secs(60), minute), 1);
+        assert_eq!(dropped_cycles(Duration::from_secs(150), minute), 2);
+        assert_eq!(dropped_cycles(minute, Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn heartbeat_of_collected_backends() {
+        let cfg = testutil::parse_config(
+            "global:\n  scrape_interval: 60\n  scrape_groups:\n    hourly: 3600\nflexlm:\n  - name: flex1\n    license: 27000@lic1\n  - name: flex2\n    license: 27000@lic2\n    scrape_group: hourly\nrlm:\n  - name: rlm\n    license: 5053@lic3\n    isv: vendor\n    scrape_group: hourly\n",
+        );
+        HEARTBEAT.with_label_values(&["lmx"]).set(1.0);
+
+        beat(&cfg, &cfg.select_groups(&[None]));
+        let collector = Collector {
+            cfg: Mutex::new(Arc::new(cfg)),
+            sample: RwLock::new(None),
+            running: AtomicBool::new(false),
+        };
+        let heartbeat = TextEncoder::new()
+            .encode_to_string(&collector.heartbeat())
+            .unwrap();
+        assert!(heartbeat.contains("# TYPE license_exporter_collector_heartbeat_timestamp gauge\n"));
+        assert!(heartbeat.contains("{backend=\"flexlm\"}"));
+        // rlm wasn't collected yet, lmx has no licenses anymore
+        assert!(!heartbeat.contains("rlm"));
+        assert!(!heartbeat.contains("lmx"));
+    }
+}
@@ -0,0 +1,564 @@
+use crate::config;
+use crate::constants;
+
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use prometheus::{IntCounterVec, Opts, Registry};
+use regex::Regex;
+use simple_error::bail;
+use std::error::Error;
+use std::io::{Read, Write};
+#[cfg(all(test, unix))]
+use std::os::unix::process::ExitStatusExt;
+#[cfg(all(test, windows))]
+use std::os::windows::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::{mpsc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// External license tools (lmutil, rlmutil, ...) can hang forever if a license server doesn't
+// answer. They are killed if they don't finish within global.command_timeout.
+lazy_static! {
+    static ref TIMEOUT: RwLock<Duration> =
+        RwLock::new(Duration::from_secs(constants::DEFAULT_COMMAND_TIMEOUT));
+    pub static ref COMMAND_TIMEOUTS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "license_exporter_command_timeouts_total",
+            "Number of external commands killed because they didn't finish within command_timeout"
+        ),
+        &["command"],
+    )
+    .unwrap();
+}
+
+pub fn configure(cfg: &config::Configuration) {
+    match TIMEOUT.write() {
+        Ok(mut v) => *v = cfg.command_timeout(),
+        Err(e) => error!("command.rs:configure: Can't set command timeout: {}", e),
+    };
+}
+
+fn timeout() -> Duration {
+    match TIMEOUT.read() {
+        Ok(v) => *v,
+        Err(_) => Duration::from_secs(constants::DEFAULT_COMMAND_TIMEOUT),
+    }
+}
+
+// Runs the license tools of a backend. The fetch functions of the backends get the runner
+// passed, so the parsers can be tested with canned outputs instead of the license tools.
+pub trait CommandRunner: Sync {
+    // Run cmd with optional input passed to stdin, e.g. the menu selection of licman20_appl
+    fn run(&self, cmd: &mut Command, input: Option<&[u8]>) -> Result<Output, Box<dyn Error>>;
+
+    // Run an interactive cmd, the input of every step is sent after the output matches the
+    // prompt of the step. Runners without a real process pass the input of all steps at once.
+    fn interact(
+        &self,
+        cmd: &mut Command,
+        steps: &[Step],
+        _prompt_timeout: Duration,
+    ) -> Result<Output, Box<dyn Error>> {
+        let input: Vec<u8> = steps.iter().flat_map(|s| s.send.iter().copied()).collect();
+        self.run(cmd, Some(&input))
+    }
+}
+
+// Step of an interactive command like licman20_appl
+pub struct Step {
+    // Prompt to wait for, matched against the output since the previous step
+    pub expect: Regex,
+    pub send: Vec<u8>,
+}
+
+// Runs the license tools like Command::output, but kills them after command_timeout
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, cmd: &mut Command, input: Option<&[u8]>) -> Result<Output, Box<dyn Error>> {
+        output(cmd, input.map(|i| i.to_vec()), timeout())
+    }
+
+    fn interact(
+        &self,
+        cmd: &mut Command,
+        steps: &[Step],
+        prompt_timeout: Duration,
+    ) -> Result<Output, Box<dyn Error>> {
+        interact(cmd, steps, prompt_timeout, timeout())
+    }
+}
+
+// Replies with canned outputs instead of running the license tools. The output is selected by
+// the arguments and the input of the command, the program is ignored.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FixtureRunner {
+    fixtures: Vec<Fixture>,
+}
+
+#[cfg(test)]
+struct Fixture {
+    args: Vec<String>,
+    input: Option<Vec<u8>>,
+    // exit code, None if the command times out
+    exit_code: Option<i32>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FixtureRunner {
+    pub fn new() -> Self {
+        FixtureRunner::default()
+    }
+
+    // Successful command with args printing stdout
+    pub fn reply(self, args: &[&str], stdout: &[u8]) -> Self {
+        self.add(args, None, Some(0), stdout, b"")
+    }
+
+    // Successful command with args and input printing stdout and stderr, e.g. licman20_appl
+    // printing the menu to stdout and the result to stderr
+    pub fn reply_to_input(self, args: &[&str], input: &[u8], stdout: &[u8], stderr: &[u8]) -> Self {
+        self.add(args, Some(input), Some(0), stdout, stderr)
+    }
+
+    // Command with args failing with an error message
+    pub fn fail(self, args: &[&str], stderr: &[u8]) -> Self {
+        self.add(args, None, Some(1), b"", stderr)
+    }
+
+    // Command with args exiting with exit_code, e.g. tools reporting an unreachable server by
+    // a special exit code but printing the usual output
+    pub fn exit(self, args: &[&str], exit_code: i32, stdout: &[u8], stderr: &[u8]) -> Self {
+        self.add(args, None, Some(exit_code), stdout, stderr)
+    }
+
+    // Command with args killed because it didn't finish within command_timeout
+    pub fn time_out(self, args: &[&str]) -> Self {
+        self.add(args, None, None, b"", b"")
+    }
+
+    fn add(
+        mut self,
+        args: &[&str],
+        input: Option<&[u8]>,
+        exit_code: Option<i32>,
+        stdout: &[u8],
+        stderr: &[u8],
+    ) -> Self {
+        self.fixtures.push(Fixture {
+            args: args.iter().map(|a| a.to_string()).collect(),
+            input: input.map(|i| i.to_vec()),
+            exit_code,
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FixtureRunner {
+    fn run(&self, cmd: &mut Command, input: Option<&[u8]>) -> Result<Output, Box<dyn Error>> {
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().to_string())
+            .collect();
+
+        let fixture = match self
+            .fixtures
+            .iter()
+            .find(|f| f.args == args && f.input.as_deref() == input)
+        {
+            Some(v) => v,
+            None => bail!("no fixture for {:?} {}", cmd.get_program(), args.join(" ")),
+        };
+
+        let exit_code = match fixture.exit_code {
+            Some(v) => v,
+            None => bail!(
+                "{} timed out after {} seconds",
+                cmd.get_program().to_string_lossy(),
+                timeout().as_secs()
+            ),
+        };
+
+        Ok(Output {
+            status: exit_status(exit_code),
+            stdout: fixture.stdout.clone(),
+            stderr: fixture.stderr.clone(),
+        })
+    }
+}
+
+#[cfg(all(test, unix))]
+fn exit_status(exit_code: i32) -> ExitStatus {
+    ExitStatus::from_raw(exit_code << 8)
+}
+
+#[cfg(all(test, windows))]
+fn exit_status(exit_code: i32) -> ExitStatus {
+    ExitStatus::from_raw(exit_code as u32)
+}
+
+pub fn output(
+    cmd: &mut Command,
+    input: Option<Vec<u8>>,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error>> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+
+    let mut child = cmd
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Pass data and collect output in separate threads, a command reading or writing a lot
+    // of data would block otherwise
+    let stdin = child.stdin.take();
+    let writer = thread::spawn(move || {
+        if let (Some(mut stdin), Some(data)) = (stdin, input) {
+            let _ = stdin.write_all(&data);
+        }
+    });
+    let stdout = child.stdout.take();
+    let stdout_reader = thread::spawn(move || read_all(stdout));
+    let stderr = child.stderr.take();
+    let stderr_reader = thread::spawn(move || read_all(stderr));
+
+    let status = wait(&mut child, &program, timeout)?;
+
+    let _ = writer.join();
+    let stdout = match stdout_reader.join() {
+        Ok(v) => v,
+        Err(_) => bail!("can't read output of {}", program),
+    };
+    let stderr = match stderr_reader.join() {
+        Ok(v) => v,
+        Err(_) => bail!("can't read error output of {}", program),
+    };
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+// Run cmd like output, but send the input of every step only after its prompt was printed.
+// The command is killed if a prompt doesn't appear within prompt_timeout, e.g. because the
+// menu of the tool changed and it waits for other input.
+pub fn interact(
+    cmd: &mut Command,
+    steps: &[Step],
+    prompt_timeout: Duration,
+    timeout: Duration,
+) -> Result<Output, Box<dyn Error>> {
+    let program = cmd.get_program().to_string_lossy().to_string();
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The output is passed on in chunks while it is read, so the prompts can be awaited with a
+    // timeout
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take();
+    let stdout_reader = thread::spawn(move || {
+        let mut raw = Vec::new();
+        if let Some(mut s) = stdout {
+            let mut buffer = [0; 4096];
+            while let Ok(n) = s.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                raw.extend_from_slice(&buffer[..n]);
+                let _ = sender.send(buffer[..n].to_vec());
+            }
+        }
+        raw
+    });
+    let stderr = child.stderr.take();
+    let stderr_reader = thread::spawn(move || read_all(stderr));
+
+    let mut stdin = child.stdin.take();
+    for step in steps {
+        let mut seen = String::new();
+        let deadline = Instant::now() + prompt_timeout;
+
+        while !step.expect.is_match(&seen) {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(v) => seen.push_str(&String::from_utf8_lossy(&v)),
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    warn!(
+                        "command.rs:interact: Killing {}, prompt {} didn't appear within {} seconds",
+                        program,
+                        step.expect,
+                        prompt_timeout.as_secs()
+                    );
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    COMMAND_TIMEOUTS
+                        .with_label_values(&[&command_name(&program)])
+                        .inc();
+                    bail!(
+                        "{} didn't print prompt {} within {} seconds",
+                        program,
+                        step.expect,
+                        prompt_timeout.as_secs()
+                    );
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    let _ = child.wait();
+                    bail!("{} exited before printing prompt {}", program, step.expect);
+                }
+            };
+        }
+
+        debug!(
+            "command.rs:interact: Prompt {} of {} matched",
+            step.expect, program
+        );
+        if let Some(v) = stdin.as_mut() {
+            if let Err(e) = v.write_all(&step.send) {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("Can't send input to {}: {}", program, e);
+            }
+        }
+    }
+    drop(stdin);
+
+    let status = wait(&mut child, &program, timeout)?;
+
+    let stdout = match stdout_reader.join() {
+        Ok(v) => v,
+        Err(_) => bail!("can't read output of {}", program),
+    };
+    let stderr = match stderr_reader.join() {
+        Ok(v) => v,
+        Err(_) => bail!("can't read error output of {}", program),
+    };
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+// Wait for child to exit, it's killed after timeout
+fn wait(child: &mut Child, program: &str, timeout: Duration) -> Result<ExitStatus, Box<dyn Error>> {
+    let start = Instant::now();
+    let status = loop {
+        if let Some(v) = child.try_wait()? {
+            break v;
+        }
+        if start.elapsed() > timeout {
+            warn!(
+                "command.rs:wait: Killing {} after {} seconds",
+                program,
+                timeout.as_secs()
+            );
+            let _ = child.kill();
+            let _ = child.wait();
+            COMMAND_TIMEOUTS
+                .with_label_values(&[&command_name(program)])
+                .inc();
+            bail!("{} timed out after {} seconds", program, timeout.as_secs());
+        }
+        thread::sleep(Duration::from_millis(constants::COMMAND_POLL_INTERVAL_MS));
+    };
+    debug!(
+        "command.rs:wait: {} finished after {:?}",
+        program,
+        start.elapsed()
+    );
+
+    Ok(status)
+}
+
+fn read_all<R: Read>(source: Option<R>) -> Vec<u8> {
+    let mut raw = Vec::new();
+    if let Some(mut s) = source {
+        let _ = s.read_to_end(&mut raw);
+    }
+    raw
+}
+
+// Label value of the command, without the path of the configured tool
+fn command_name(program: &str) -> String {
+    Path::new(program)
+        .file_name()
+        .map_or_else(|| program.to_string(), |n| n.to_string_lossy().to_string())
+}
+
+pub fn register_into(registry: &Registry) {
+    registry
+        .register(Box::new(COMMAND_TIMEOUTS.clone()))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kill_on_timeout() {
+        let result = output(
+            Command::new("sleep").arg("10"),
+            None,
+            Duration::from_millis(200),
+        );
+        assert!(result.is_err());
+        assert_eq!(COMMAND_TIMEOUTS.with_label_values(&["sleep"]).get(), 1);
+    }
+
+    #[test]
+    fn fixture_runner() {
+        let runner = FixtureRunner::new()
+            .reply(&["lmstat", "-a"], b"lmstat output")
+            .reply_to_input(&[], b"4\n", b"menu", b"licenses")
+            .fail(&["lmstat", "-i"], b"lmgrd is not running");
+
+        let result = runner
+            .run(Command::new("lmutil").arg("lmstat").arg("-a"), None)
+            .unwrap();
+        assert!(result.status.success());
+        assert_eq!(result.stdout, b"lmstat output");
+
+        let result = runner
+            .run(&mut Command::new("licman20_appl"), Some(b"4\n"))
+            .unwrap();
+        assert_eq!(result.stderr, b"licenses");
+
+        let result = runner
+            .run(Command::new("lmutil").arg("lmstat").arg("-i"), None)
+            .unwrap();
+        assert!(!result.status.success());
+        assert_eq!(result.status.code(), Some(1));
+
+        let runner = FixtureRunner::new()
+            .exit(&["rlmstat", "-a"], 3, b"partial output", b"")
+            .time_out(&["lmstat", "-a"]);
+        let result = runner
+            .run(Command::new("rlmutil").arg("rlmstat").arg("-a"), None)
+            .unwrap();
+        assert_eq!(result.status.code(), Some(3));
+        assert_eq!(result.stdout, b"partial output");
+        assert!(runner
+            .run(Command::new("lmutil").arg("lmstat").arg("-a"), None)
+            .unwrap_err()
+            .to_string()
+            .contains("timed out"));
+
+        assert!(runner
+            .run(&mut Command::new("licman20_appl"), Some(b"2\n"))
+            .is_err());
+    }
+
+    #[test]
+    fn interactive_prompts() {
+        let steps = [
+            Step {
+                expect: Regex::new(r"choice:\s*$").unwrap(),
+                send: b"4\n".to_vec(),
+            },
+            Step {
+                expect: Regex::new(r"choice:\s*$").unwrap(),
+                send: b"X\n".to_vec(),
+            },
+        ];
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("printf 'Menu, choice: '; read a; echo $a >&2; printf 'choice: '; read b");
+        let result = interact(
+            &mut cmd,
+            &steps,
+            Duration::from_secs(10),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert!(result.status.success());
+        assert_eq!(result.stderr, b"4\n");
+
+        // The menu changed and the prompt doesn't appear
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("printf 'Select: '; read a");
+        let start = Instant::now();
+        assert!(interact(
+            &mut cmd,
+            &steps,
+            Duration::from_millis(300),
+            Duration::from_secs(10)
+        )
+        .is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let runner = FixtureRunner::new().reply_to_input(&[], b"4\nX\n", b"menu", b"licenses");
+        let result = runner
+            .interact(
+                &mut Command::new("licman20_appl"),
+                &steps,
+                Duration::from_secs(1),
+            )
+            .unwrap();
+        assert_eq!(result.stderr, b"licenses");
+    }
+
+    #[test]
+    fn interactive_input_closed() {
+        let steps = [Step {
+            expect: Regex::new(r"choice:\s*$").unwrap(),
+            send: b"4\n".to_vec(),
+        }];
+        // The command doesn't read its input, it is killed instead of left behind
+        let pid_file =
+            std::env::temp_dir().join(format!("license-exporter-interact-{}", std::process::id()));
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!(
+            "exec 0<&-; echo $$ > '{}'; printf 'choice: '; exec sleep 30",
+            pid_file.display()
+        ));
+        assert!(interact(
+            &mut cmd,
+            &steps,
+            Duration::from_secs(10),
+            Duration::from_secs(60)
+        )
+        .is_err());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+        let alive = Command::new("kill")
+            .arg("-0")
+            .arg(pid.trim())
+            .status()
+            .unwrap();
+        assert!(!alive.success());
+    }
+
+    #[test]
+    fn pass_input() {
+        let result = output(
+            &mut Command::new("cat"),
+            Some(b"4\nX\n".to_vec()),
+            Duration::from_secs(10),
+        )
+        .unwrap();
+        assert!(result.status.success());
+        assert_eq!(result.stdout, b"4\nX\n");
+        assert_eq!(command_name("/opt/flexlm/bin/lmutil"), "lmutil");
+    }
+}
@@ -1,7 +1,21 @@
+use crate::constants;
+use crate::license;
+use crate::logging;
+use crate::resolver;
+
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use regex::Regex;
 use serde::Deserialize;
 use simple_error::bail;
+use std::collections::HashMap;
+use std::env;
 use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Configuration {
@@ -13,218 +27,2120 @@ pub struct Configuration {
     pub olicense: Option<Vec<Olicense>>,
     pub lmx: Option<Vec<Lmx>>,
     pub rlm: Option<Vec<Rlm>>,
+    // Version of the configuration schema, see check_version
+    pub version: Option<u64>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+// Supported license backends, named like their section in the configuration file
+pub const BACKENDS: [&str; 7] = [
+    "dsls", "flexlm", "hasp", "licman20", "lmx", "olicense", "rlm",
+];
+
+impl Configuration {
+    // Names of the configured licenses of a backend
+    pub fn license_names(&self, backend: &str) -> Vec<String> {
+        fn names<T>(entries: &Option<Vec<T>>, name: fn(&T) -> &String) -> Vec<String> {
+            entries
+                .iter()
+                .flatten()
+                .map(|e| name(e).to_string())
+                .collect()
+        }
+
+        match backend {
+            "dsls" => names(&self.dsls, |e| &e.name),
+            "flexlm" => names(&self.flexlm, |e| &e.name),
+            "hasp" => names(&self.hasp, |e| &e.name),
+            "licman20" => names(&self.licman20, |e| &e.name),
+            "lmx" => names(&self.lmx, |e| &e.name),
+            "olicense" => names(&self.olicense, |e| &e.name),
+            "rlm" => names(&self.rlm, |e| &e.name),
+            _ => Vec::new(),
+        }
+    }
+
+    // Copy of the configuration with the licenses named name only
+    pub fn select(&self, name: &str) -> Configuration {
+        let mut result = self.clone();
+
+        if let Some(v) = result.dsls.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.flexlm.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.hasp.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.licman20.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.lmx.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.olicense.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+        if let Some(v) = result.rlm.as_mut() {
+            v.retain(|e| e.name == name);
+        }
+
+        result
+    }
+
+    // Path of the metrics endpoint
+    pub fn metrics_path(&self) -> &str {
+        match &self.global {
+            Some(glob) => glob
+                .metrics_path
+                .as_deref()
+                .unwrap_or(constants::DEFAULT_METRICS_PATH),
+            None => constants::DEFAULT_METRICS_PATH,
+        }
+    }
+
+    // Token for the admin endpoints, the admin endpoints are disabled without a token
+    pub fn admin_token(&self) -> Option<&str> {
+        self.global.as_ref().and_then(|g| g.admin_token.as_deref())
+    }
+
+    // Separate listen address of the admin endpoints, None if they are served on the
+    // listen address of the metrics
+    pub fn admin_listen(&self) -> Option<&str> {
+        self.global.as_ref().and_then(|g| g.admin_listen.as_deref())
+    }
+
+    // Token for the metrics endpoint, the metrics are public without token or basic_auth_users
+    pub fn scrape_token(&self) -> Option<&str> {
+        self.global.as_ref().and_then(|g| g.scrape_token.as_deref())
+    }
+
+    // Networks allowed to connect to the listen address of the metrics, all if None
+    pub fn allowed_networks(&self) -> Option<&[IpNet]> {
+        self.global
+            .as_ref()
+            .filter(|g| g.allowed_networks.is_some())
+            .map(|g| g.allowed_nets.as_slice())
+    }
+
+    pub fn admin_users(&self) -> Option<&Vec<String>> {
+        self.global.as_ref().and_then(|g| g.admin_users.as_ref())
+    }
+
+    // Users allowed to fetch the metrics, the metrics are public without users
+    pub fn basic_auth_users(&self) -> Option<&HashMap<String, String>> {
+        self.global
+            .as_ref()
+            .and_then(|g| g.basic_auth_users.as_ref())
+    }
+
+    pub fn peer_sync(&self) -> Option<&PeerSync> {
+        self.global.as_ref().and_then(|g| g.peer_sync.as_ref())
+    }
+
+    // Maximal number of licenses queried at the same time
+    pub fn parallelism(&self) -> usize {
+        self.global
+            .as_ref()
+            .and_then(|g| g.parallelism)
+            .unwrap_or(constants::DEFAULT_PARALLELISM)
+    }
+
+    // Maximal number of concurrent scrapes
+    pub fn max_requests(&self) -> usize {
+        self.global
+            .as_ref()
+            .and_then(|g| g.max_requests)
+            .unwrap_or(constants::DEFAULT_MAX_REQUESTS)
+    }
+
+    // Time to wait for the metrics of a scrape, None waits until all licenses are collected
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.global
+            .as_ref()
+            .and_then(|g| g.request_timeout)
+            .map(Duration::from_secs)
+    }
+
+    // Time after which external license tools are killed
+    pub fn command_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.global
+                .as_ref()
+                .and_then(|g| g.command_timeout)
+                .unwrap_or(constants::DEFAULT_COMMAND_TIMEOUT),
+        )
+    }
+
+    // Export the distribution of seats per user as license_feature_seats_per_user
+    pub fn seats_per_user_histogram(&self) -> bool {
+        self.global
+            .as_ref()
+            .and_then(|g| g.seats_per_user_histogram)
+            .unwrap_or(false)
+    }
+
+    // Number of users exported in license_top_user_seats, 0 if disabled
+    pub fn top_users(&self) -> usize {
+        self.global.as_ref().and_then(|g| g.top_users).unwrap_or(0)
+    }
+
+    // Interval of the background collection, None collects the metrics on every scrape
+    pub fn scrape_interval(&self) -> Option<Duration> {
+        self.global
+            .as_ref()
+            .and_then(|g| g.scrape_interval)
+            .map(Duration::from_secs)
+    }
+
+    // Groups of the background collection with their interval, None is the group of the
+    // licenses without scrape_group collected every scrape_interval. Empty without background
+    // collection.
+    pub fn scrape_groups(&self) -> Vec<(Option<String>, Duration)> {
+        let interval = match self.scrape_interval() {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+
+        let mut groups: Vec<(Option<String>, Duration)> = self
+            .global
+            .iter()
+            .filter_map(|g| g.scrape_groups.as_ref())
+            .flatten()
+            .map(|(name, secs)| (Some(name.to_string()), Duration::from_secs(*secs)))
+            .collect();
+        groups.sort();
+        groups.insert(0, (None, interval));
+        groups
+    }
+
+    // Copy of the configuration with the licenses of the scrape groups only
+    pub fn select_groups(&self, groups: &[Option<String>]) -> Configuration {
+        let mut result = self.clone();
+
+        if let Some(v) = result.dsls.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.flexlm.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.hasp.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.licman20.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.lmx.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.olicense.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+        if let Some(v) = result.rlm.as_mut() {
+            v.retain(|e| groups.contains(&e.scrape_group));
+        }
+
+        result
+    }
+
+    // Names and scrape groups of all licenses
+    fn license_groups(&self) -> Vec<(&str, Option<&str>)> {
+        fn groups<T>(
+            entries: &Option<Vec<T>>,
+            group: fn(&T) -> (&str, Option<&str>),
+        ) -> Vec<(&str, Option<&str>)> {
+            entries.iter().flatten().map(group).collect()
+        }
+
+        let mut result = groups(&self.dsls, |e| (&e.name, e.scrape_group.as_deref()));
+        result.extend(groups(&self.flexlm, |e| {
+            (&e.name, e.scrape_group.as_deref())
+        }));
+        result.extend(groups(&self.hasp, |e| (&e.name, e.scrape_group.as_deref())));
+        result.extend(groups(&self.licman20, |e| {
+            (&e.name, e.scrape_group.as_deref())
+        }));
+        result.extend(groups(&self.lmx, |e| (&e.name, e.scrape_group.as_deref())));
+        result.extend(groups(&self.olicense, |e| {
+            (&e.name, e.scrape_group.as_deref())
+        }));
+        result.extend(groups(&self.rlm, |e| (&e.name, e.scrape_group.as_deref())));
+        result
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct GlobalConfiguration {
+    pub admin_listen: Option<String>,
+    pub admin_token: Option<String>,
+    pub admin_token_file: Option<String>,
+    // basic_auth_users allowed to see user names, all users if not set
+    pub admin_users: Option<Vec<String>>,
+    // Networks (CIDR) or addresses allowed to connect to the listen address of the metrics
+    pub allowed_networks: Option<Vec<String>>,
+    #[serde(skip)]
+    pub allowed_nets: Vec<IpNet>,
+    pub anomaly_detection: Option<bool>,
+    // user name -> bcrypt hash of the password for the metrics endpoint
+    pub basic_auth_users: Option<HashMap<String, String>>,
+    pub command_timeout: Option<u64>,
+    pub constant_labels: Option<HashMap<String, String>>,
     pub dslicsrv: Option<String>,
+    pub excluded_features: Option<Vec<String>>,
+    pub export_expiration_info: Option<bool>,
+    pub export_totals: Option<bool>,
+    pub hooks: Option<Vec<Hook>>,
+    pub hosts: Option<HashMap<String, String>>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub kerberos_keytab: Option<String>,
     pub licman20_appl: Option<String>,
     pub lmutil: Option<String>,
     pub lmxendutil: Option<String>,
+    pub max_requests: Option<usize>,
+    pub metric_help: Option<HashMap<String, String>>,
+    // Prepended to the names of all exported metrics, e.g. licexp_
+    pub metric_prefix: Option<String>,
+    pub metrics_path: Option<String>,
+    pub no_proxy: Option<String>,
+    pub parallelism: Option<usize>,
+    pub peer_sync: Option<PeerSync>,
+    pub projects: Option<HashMap<String, Vec<String>>>,
+    #[serde(skip)]
+    pub project_filters: Vec<(String, license::FeatureFilter)>,
+    pub request_timeout: Option<u64>,
     pub rlmutil: Option<String>,
+    pub scrape_interval: Option<u64>,
+    // group name -> interval in seconds of the background collection of its licenses
+    pub scrape_groups: Option<HashMap<String, u64>>,
+    // Bearer token for the metrics endpoint
+    pub scrape_token: Option<String>,
+    pub scrape_token_file: Option<String>,
+    pub seats_per_user_histogram: Option<bool>,
+    pub top_users: Option<usize>,
+}
+
+// Replication of the collected metrics to a hot standby instance
+#[derive(Clone, Debug, Deserialize)]
+pub struct PeerSync {
+    pub ca_file: Option<String>,
+    // standby: serve pushed metrics not older than max_age seconds instead of collecting them
+    pub max_age: Option<u64>,
+    // primary: URL of the standby instance, the metrics are pushed after every collection
+    pub push_to: Option<String>,
+    pub timeout: Option<u64>,
+    pub token: Option<String>,
+    pub token_file: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Hook {
+    pub args: Option<Vec<String>>,
+    pub command: String,
+    pub name: String,
+    pub timeout: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Dsls {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
+    // Path of dslicsrv for this license, overrides global.dslicsrv
+    pub dslicsrv: Option<String>,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
+    // Query expiration dates only every expiration_interval seconds
+    pub expiration_interval: Option<u64>,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
     pub license: String,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub scrape_group: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct FlexLM {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
+    pub exclude_uncounted: Option<bool>,
+    pub export_borrow: Option<bool>,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
+    // Query expiration dates only every expiration_interval seconds
+    pub expiration_interval: Option<u64>,
     pub export_user: Option<bool>,
+    // Vendor daemons of the features as feature -> vendor, learned from lmstat for licenses
+    // with vendors. Starts empty on every reload.
+    #[serde(skip)]
+    pub feature_vendors: Arc<Mutex<HashMap<String, String>>>,
+    pub include_features: Option<Vec<String>>,
     pub license: String,
+    pub license_file: Option<String>,
+    // Path of lmutil for this license, overrides global.lmutil
+    pub lmutil: Option<String>,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub native: Option<bool>,
+    pub options_file: Option<String>,
+    pub scrape_group: Option<String>,
+    // Append the vendor daemon to the feature names of the usage metrics
+    pub vendor_suffix: Option<bool>,
+    // Only export the features and the status of these vendor daemons
+    pub vendors: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Rlm {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
+    pub failover: Option<String>,
     pub isv: String,
     pub license: String,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    // Path of rlmutil for this license, overrides global.rlmutil
+    pub rlmutil: Option<String>,
+    pub scrape_group: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Lmx {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
     pub license: String,
+    pub license_file: Option<String>,
+    // Path of lmxendutil for this license, overrides global.lmxendutil
+    pub lmxendutil: Option<String>,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub scrape_group: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Licman20 {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
+    // Path of licman20_appl for this license, overrides global.licman20_appl
+    pub licman20_appl: Option<String>,
+    pub menu: Option<Licman20Menu>,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub scrape_group: Option<String>,
+}
+
+// Menu of licman20_appl, the options are sent after the prompt appeared
+#[derive(Clone, Debug, Deserialize)]
+pub struct Licman20Menu {
+    pub checkouts: Option<String>,
+    pub licenses: Option<String>,
+    pub prompt: Option<String>,
+    pub quit: Option<String>,
+    pub timeout: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Hasp {
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
     pub authentication: Option<HaspAuth>,
+    pub ca_file: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
     pub hasp_key: String,
+    pub insecure_ssl: Option<bool>,
     pub license: String,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub proxy: Option<String>,
+    pub scrape_group: Option<String>,
+    pub spnego: Option<bool>,
+    pub use_https: Option<bool>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct HaspAuth {
+    #[serde(default)]
     pub username: String,
+    pub username_file: Option<String>,
+    #[serde(default)]
     pub password: String,
+    pub password_file: Option<String>,
+}
+
+impl HaspAuth {
+    // User name and password for the license server. Unlike other secret files, the files are
+    // read on every scrape, so rotated secrets are used without a configuration reload. Their
+    // content is redacted from the log like the other secrets.
+    pub fn credentials(&self) -> Result<(String, String), Box<dyn Error>> {
+        let username = match &self.username_file {
+            Some(f) => {
+                let v = read_secret(f, "username")?;
+                logging::add_secret(&v);
+                v
+            }
+            None => self.username.clone(),
+        };
+        let password = match &self.password_file {
+            Some(f) => {
+                let v = read_secret(f, "password")?;
+                logging::add_secret(&v);
+                v
+            }
+            None => self.password.clone(),
+        };
+        Ok((username, password))
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Olicense {
+    pub aggregate_modules: Option<bool>,
+    pub anonymize_salt: Option<String>,
+    pub anonymize_users: Option<bool>,
+    #[serde(skip)]
+    pub anonymizer: license::Anonymizer,
+    pub ca_file: Option<String>,
+    pub client_certificate: Option<ClientCertificate>,
     pub excluded_features: Option<Vec<String>>,
+    #[serde(skip)]
+    pub feature_filter: license::FeatureFilter,
     pub export_user: Option<bool>,
+    pub include_features: Option<Vec<String>>,
+    pub insecure_ssl: Option<bool>,
     pub license: String,
+    pub max_response_size: Option<u64>,
+    pub modules: Option<Vec<String>>,
     pub name: String,
+    pub on_failure: Option<OnFailure>,
+    pub proxy: Option<String>,
+    pub scrape_group: Option<String>,
+    pub spnego: Option<bool>,
+    pub use_https: Option<bool>,
+}
+
+// What happens to the series of a license if fetching the license data fails
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    // Remove the series, so the license disappears until the next successful fetch
+    Drop,
+    // Export the values of the last successful fetch
+    #[default]
+    Keep,
+    // Export the series of the last successful fetch with the value 0
+    Zero,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ClientCertificate {
+    // PEM encoded certificate if key is set, PKCS#12 file otherwise
+    pub certificate: String,
+    pub key: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+// Source of the configuration, a file or the complete YAML configuration in the CONFIG_YAML
+// environment variable, e.g. for a Kubernetes ConfigMap
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+    File(String),
+    Environment,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> Result<Configuration, Box<dyn Error>> {
+        let (raw, format) = self.read()?;
+        parse_config_as(&raw, format)
+    }
+
+    // Unparsed configuration and its format
+    pub fn read(&self) -> Result<(String, ConfigFormat), Box<dyn Error>> {
+        match self {
+            ConfigSource::File(f) => Ok((fs::read_to_string(f)?, file_format(f))),
+            ConfigSource::Environment => match env::var(constants::CONFIG_YAML_ENV) {
+                Ok(v) => Ok((v, ConfigFormat::Yaml)),
+                Err(e) => bail!("Can't read {}: {}", constants::CONFIG_YAML_ENV, e),
+            },
+        }
+    }
+}
+
+// Format of the configuration file, by default the format is derived from the file name
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    pub fn from_name(name: &str) -> Result<Self, Box<dyn Error>> {
+        match name.to_lowercase().as_str() {
+            constants::CONFIG_FORMAT_JSON => Ok(ConfigFormat::Json),
+            constants::CONFIG_FORMAT_TOML => Ok(ConfigFormat::Toml),
+            constants::CONFIG_FORMAT_YAML => Ok(ConfigFormat::Yaml),
+            _ => bail!(
+                "Invalid configuration format {}, supported formats are {}",
+                name,
+                constants::CONFIG_FORMATS.join(", ")
+            ),
+        }
+    }
+
+    // Files ending with .json are JSON, files ending with .toml are TOML, everything else is
+    // YAML
+    pub fn from_file(file: &str) -> Self {
+        let extension = std::path::Path::new(file)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase());
+        match extension.as_deref() {
+            Some(constants::CONFIG_FORMAT_JSON) => ConfigFormat::Json,
+            Some(constants::CONFIG_FORMAT_TOML) => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::File(v) => write!(f, "file {}", v),
+            ConfigSource::Environment => {
+                write!(f, "environment variable {}", constants::CONFIG_YAML_ENV)
+            }
+        }
+    }
+}
+
+lazy_static! {
+    // Path of the metrics endpoint given on the command line, kept on configuration reloads
+    static ref METRICS_PATH_OVERRIDE: RwLock<Option<String>> = RwLock::new(None);
+    // Format of configuration files given on the command line, derived from the file name if None
+    static ref CONFIG_FORMAT_OVERRIDE: RwLock<Option<ConfigFormat>> = RwLock::new(None);
+    static ref RE_METRICS_PATH: Regex = Regex::new(r"^(/[a-zA-Z0-9_.~\-]+)+$").unwrap();
+}
+
+// Serve the metrics on path instead of the metrics_path of the configuration
+pub fn override_metrics_path(path: &str) -> Result<(), Box<dyn Error>> {
+    validate_metrics_path(path)?;
+    match METRICS_PATH_OVERRIDE.write() {
+        Ok(mut v) => *v = Some(path.to_string()),
+        Err(e) => bail!("Can't set metrics path: {}", e),
+    };
+    Ok(())
+}
+
+// Parse configuration files as format regardless of their name
+pub fn override_config_format(format: ConfigFormat) -> Result<(), Box<dyn Error>> {
+    match CONFIG_FORMAT_OVERRIDE.write() {
+        Ok(mut v) => *v = Some(format),
+        Err(e) => bail!("Can't set configuration format: {}", e),
+    };
+    Ok(())
+}
+
+fn apply_overrides(cfg: &mut Configuration) {
+    let metrics_path = match METRICS_PATH_OVERRIDE.read() {
+        Ok(v) => v.clone(),
+        Err(_) => None,
+    };
+    if let Some(path) = metrics_path {
+        set_metrics_path(cfg, path);
+    }
+}
+
+fn set_metrics_path(cfg: &mut Configuration, path: String) {
+    cfg.global
+        .get_or_insert_with(GlobalConfiguration::default)
+        .metrics_path = Some(path);
+}
+
+fn validate_metrics_path(path: &str) -> Result<(), Box<dyn Error>> {
+    if !RE_METRICS_PATH.is_match(path) {
+        bail!(
+            "Invalid metrics_path {}, the path must start with / and can't be /",
+            path
+        );
+    }
+    if path == constants::HEALTHZ_PATH || path == constants::READYZ_PATH {
+        bail!(
+            "Invalid metrics_path {}, the path is used by the health probes",
+            path
+        );
+    }
+    Ok(())
 }
 
 pub fn parse_config_file(f: &str) -> Result<Configuration, Box<dyn Error>> {
     let unparsed = fs::read_to_string(f)?;
-    let config: Configuration = serde_yaml::from_str(unparsed.as_str())?;
+    parse_config_as(&unparsed, file_format(f))
+}
+
+// Format given on the command line or derived from the file name
+fn file_format(f: &str) -> ConfigFormat {
+    match CONFIG_FORMAT_OVERRIDE.read() {
+        Ok(v) => v.unwrap_or_else(|| ConfigFormat::from_file(f)),
+        Err(_) => ConfigFormat::from_file(f),
+    }
+}
+
+pub fn parse_config(raw: &str) -> Result<Configuration, Box<dyn Error>> {
+    parse_config_as(raw, ConfigFormat::Yaml)
+}
+
+pub fn parse_config_as(raw: &str, format: ConfigFormat) -> Result<Configuration, Box<dyn Error>> {
+    let value = parse_value(raw, format)?;
+    schema_version(&value)?;
 
+    // Deserializing from a Value loses the position of errors, so only YAML configurations
+    // using defaults or referencing environment variables take the detour. Other formats are
+    // always converted, so defaults and environment variables work the same.
+    let mut config: Configuration =
+        if format == ConfigFormat::Yaml && value.get("defaults").is_none() && !raw.contains("${") {
+            serde_yaml::from_str(raw)?
+        } else {
+            from_value(value)?
+        };
+
+    apply_overrides(&mut config);
+    read_secret_files(&mut config)?;
     validate_configuration(&config)?;
+    build_feature_filters(&mut config)?;
+    build_project_filters(&mut config)?;
+    build_allowed_networks(&mut config)?;
 
     Ok(config)
 }
 
-fn validate_configuration(cfg: &Configuration) -> Result<(), Box<dyn Error>> {
-    if let Some(flexlm) = &cfg.flexlm {
-        for flex in flexlm {
-            if flex.name.is_empty() {
-                bail!("Empty name for FlexLM license");
+fn from_value(mut value: serde_yaml::Value) -> Result<Configuration, Box<dyn Error>> {
+    apply_defaults(&mut value)?;
+    expand_env(&mut value)?;
+    Ok(serde_yaml::from_value(value)?)
+}
+
+// Unprocessed configuration in any format as YAML value
+pub fn parse_value(raw: &str, format: ConfigFormat) -> Result<serde_yaml::Value, Box<dyn Error>> {
+    Ok(match format {
+        ConfigFormat::Yaml => serde_yaml::from_str(raw)?,
+        ConfigFormat::Json => {
+            serde_yaml::to_value(serde_json::from_str::<serde_json::Value>(raw)?)?
+        }
+        ConfigFormat::Toml => serde_yaml::to_value(toml::from_str::<toml::Value>(raw)?)?,
+    })
+}
+
+// Version of the configuration schema, None if the configuration doesn't set it. The version is
+// checked before the configuration is deserialized, so configurations written for a newer
+// exporter are rejected instead of being misread.
+pub fn schema_version(value: &serde_yaml::Value) -> Result<Option<u64>, Box<dyn Error>> {
+    let version = match value.get("version") {
+        None | Some(serde_yaml::Value::Null) => return Ok(None),
+        Some(v) => v,
+    };
+
+    match version.as_u64() {
+        Some(v) if v > constants::CONFIG_VERSION => bail!(
+            "Configuration version {} is not supported, {} {} reads configuration versions up to {}. Update the exporter to use this configuration",
+            v,
+            constants::NAME,
+            constants::VERSION,
+            constants::CONFIG_VERSION
+        ),
+        Some(v) if v > 0 => Ok(Some(v)),
+        _ => bail!(
+            "Invalid configuration version {}, the version must be an integer from 1 to {}",
+            serde_yaml::to_string(version)
+                .unwrap_or_default()
+                .trim_start_matches("---")
+                .trim(),
+            constants::CONFIG_VERSION
+        ),
+    }
+}
+
+// Settings of the defaults section are inherited by every license of the backend which
+// doesn't set them itself, e.g.
+//
+// defaults:
+//   flexlm:
+//     export_user: true
+//
+// Values aren't merged, a license setting e.g. excluded_features replaces the default list.
+fn apply_defaults(value: &mut serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+    let map = match value.as_mapping_mut() {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    let defaults = match map.remove("defaults") {
+        Some(serde_yaml::Value::Mapping(v)) => v,
+        Some(serde_yaml::Value::Null) | None => return Ok(()),
+        Some(_) => bail!("The defaults section must map backends to their default settings"),
+    };
+
+    for (backend, settings) in defaults {
+        let backend = match backend.as_str() {
+            Some(v) if BACKENDS.contains(&v) => v.to_string(),
+            _ => bail!("Invalid backend {:?} in the defaults section", backend),
+        };
+        let settings = match settings {
+            serde_yaml::Value::Mapping(v) => v,
+            serde_yaml::Value::Null => continue,
+            _ => bail!("Defaults of {} must be a map of settings", backend),
+        };
+        if settings.contains_key("name") {
+            bail!("Defaults of {} can't set the name of the licenses", backend);
+        }
+
+        let licenses = match map.get_mut(backend.as_str()) {
+            Some(serde_yaml::Value::Sequence(v)) => v,
+            _ => continue,
+        };
+        for lic in licenses.iter_mut() {
+            if let Some(lic) = lic.as_mapping_mut() {
+                for (key, default) in settings.iter() {
+                    if !lic.contains_key(key) {
+                        lic.insert(key.clone(), default.clone());
+                    }
+                }
             }
+        }
+    }
 
-            if flex.license.is_empty() {
-                bail!(
-                    "Missing license information for FlexLM license {}",
-                    flex.name
-                );
+    Ok(())
+}
+
+// Replace ${NAME} in string values by the value of the environment variable NAME, e.g. to
+// inject credentials instead of storing them in the configuration. $${NAME} is kept as
+// ${NAME}.
+fn expand_env(value: &mut serde_yaml::Value) -> Result<(), Box<dyn Error>> {
+    match value {
+        serde_yaml::Value::String(s) => *s = expand_env_str(s)?,
+        serde_yaml::Value::Sequence(seq) => {
+            for v in seq.iter_mut() {
+                expand_env(v)?;
             }
         }
+        serde_yaml::Value::Mapping(map) => {
+            for (_, v) in map.iter_mut() {
+                expand_env(v)?;
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => expand_env(&mut tagged.value)?,
+        _ => {}
+    };
+    Ok(())
+}
+
+fn expand_env_str(raw: &str) -> Result<String, Box<dyn Error>> {
+    lazy_static! {
+        static ref RE_ENV_REFERENCE: Regex =
+            Regex::new(r"\$(\$)?\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
     }
 
-    if let Some(rlm) = &cfg.rlm {
-        for _rlm in rlm {
-            if _rlm.name.is_empty() {
-                bail!("Empty name for RLM license");
+    let mut result = String::with_capacity(raw.len());
+    let mut last = 0;
+    for capt in RE_ENV_REFERENCE.captures_iter(raw) {
+        let whole = capt.get(0).unwrap();
+        let name = capt.get(2).map_or("", |m| m.as_str());
+        result.push_str(&raw[last..whole.start()]);
+        if capt.get(1).is_some() {
+            result.push_str(&whole.as_str()[1..]);
+        } else {
+            match env::var(name) {
+                Ok(v) => result.push_str(&v),
+                Err(e) => bail!(
+                    "Can't expand environment variable {} in configuration: {}",
+                    name,
+                    e
+                ),
+            };
+        }
+        last = whole.end();
+    }
+    result.push_str(&raw[last..]);
+
+    Ok(result)
+}
+
+// Secrets can be read from files instead, e.g. from mounted Kubernetes secrets
+fn read_secret_files(cfg: &mut Configuration) -> Result<(), Box<dyn Error>> {
+    if let Some(glob) = cfg.global.as_mut() {
+        read_secret_file(&mut glob.admin_token, &glob.admin_token_file, "admin_token")?;
+        read_secret_file(
+            &mut glob.scrape_token,
+            &glob.scrape_token_file,
+            "scrape_token",
+        )?;
+        if let Some(peer_sync) = glob.peer_sync.as_mut() {
+            read_secret_file(&mut peer_sync.token, &peer_sync.token_file, "token")?;
+        }
+    }
+
+    let certificates = cfg
+        .hasp
+        .iter_mut()
+        .flatten()
+        .filter_map(|h| h.client_certificate.as_mut())
+        .chain(
+            cfg.olicense
+                .iter_mut()
+                .flatten()
+                .filter_map(|o| o.client_certificate.as_mut()),
+        );
+    for ccert in certificates {
+        read_secret_file(&mut ccert.password, &ccert.password_file, "password")?;
+    }
+
+    Ok(())
+}
+
+fn read_secret_file(
+    value: &mut Option<String>,
+    file: &Option<String>,
+    name: &str,
+) -> Result<(), Box<dyn Error>> {
+    let file = match file {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    if value.is_some() {
+        bail!("Only one of {} and {}_file can be set", name, name);
+    }
+
+    *value = Some(read_secret(file, name)?);
+    Ok(())
+}
+
+fn read_secret(file: &str, name: &str) -> Result<String, Box<dyn Error>> {
+    let secret = match fs::read_to_string(file) {
+        Ok(v) => v,
+        Err(e) => bail!("Can't read {}_file {}: {}", name, file, e),
+    };
+    // Files created by editors or echo usually end with a newline
+    Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+}
+
+fn merge_excluded_features(excludes: &mut Option<Vec<String>>, global: &[String]) {
+    if global.is_empty() {
+        return;
+    }
+
+    let excl = excludes.get_or_insert_with(Vec::new);
+    for f in global {
+        if !excl.contains(f) {
+            excl.push(f.to_string());
+        }
+    }
+}
+
+fn build_feature_filters(cfg: &mut Configuration) -> Result<(), Box<dyn Error>> {
+    let global = match &cfg.global {
+        Some(glob) => glob.excluded_features.clone().unwrap_or_default(),
+        None => Vec::new(),
+    };
+
+    for flex in cfg.flexlm.iter_mut().flatten() {
+        merge_excluded_features(&mut flex.excluded_features, &global);
+        flex.feature_filter =
+            license::FeatureFilter::new(&flex.excluded_features, &flex.include_features)?;
+        if flex.vendor_suffix.unwrap_or(false) {
+            flex.feature_filter = flex
+                .feature_filter
+                .with_suffix(constants::VENDOR_SUFFIX_SEPARATOR);
+        }
+        flex.anonymizer = license::Anonymizer::new(flex.anonymize_users, &flex.anonymize_salt);
+    }
+    for _rlm in cfg.rlm.iter_mut().flatten() {
+        merge_excluded_features(&mut _rlm.excluded_features, &global);
+        _rlm.feature_filter =
+            license::FeatureFilter::new(&_rlm.excluded_features, &_rlm.include_features)?;
+        _rlm.anonymizer = license::Anonymizer::new(_rlm.anonymize_users, &_rlm.anonymize_salt);
+    }
+    for _lmx in cfg.lmx.iter_mut().flatten() {
+        merge_excluded_features(&mut _lmx.excluded_features, &global);
+        _lmx.feature_filter =
+            license::FeatureFilter::new(&_lmx.excluded_features, &_lmx.include_features)?;
+        _lmx.anonymizer = license::Anonymizer::new(_lmx.anonymize_users, &_lmx.anonymize_salt);
+    }
+    for _dsls in cfg.dsls.iter_mut().flatten() {
+        merge_excluded_features(&mut _dsls.excluded_features, &global);
+        _dsls.feature_filter =
+            license::FeatureFilter::new(&_dsls.excluded_features, &_dsls.include_features)?;
+        _dsls.anonymizer = license::Anonymizer::new(_dsls.anonymize_users, &_dsls.anonymize_salt);
+    }
+    for _licman20 in cfg.licman20.iter_mut().flatten() {
+        merge_excluded_features(&mut _licman20.excluded_features, &global);
+        _licman20.feature_filter =
+            license::FeatureFilter::new(&_licman20.excluded_features, &_licman20.include_features)?;
+        _licman20.anonymizer =
+            license::Anonymizer::new(_licman20.anonymize_users, &_licman20.anonymize_salt);
+    }
+    for _hasp in cfg.hasp.iter_mut().flatten() {
+        merge_excluded_features(&mut _hasp.excluded_features, &global);
+        _hasp.feature_filter =
+            license::FeatureFilter::new(&_hasp.excluded_features, &_hasp.include_features)?;
+        _hasp.anonymizer = license::Anonymizer::new(_hasp.anonymize_users, &_hasp.anonymize_salt);
+    }
+    for _olic in cfg.olicense.iter_mut().flatten() {
+        merge_excluded_features(&mut _olic.excluded_features, &global);
+        _olic.feature_filter =
+            license::FeatureFilter::new(&_olic.excluded_features, &_olic.include_features)?;
+        _olic.anonymizer = license::Anonymizer::new(_olic.anonymize_users, &_olic.anonymize_salt);
+    }
+
+    Ok(())
+}
+
+fn build_allowed_networks(cfg: &mut Configuration) -> Result<(), Box<dyn Error>> {
+    if let Some(glob) = cfg.global.as_mut() {
+        if let Some(networks) = &glob.allowed_networks {
+            let mut nets = Vec::new();
+            for network in networks {
+                nets.push(parse_network(network)?);
             }
+            glob.allowed_nets = nets;
+        }
+    }
+    Ok(())
+}
 
-            if _rlm.license.is_empty() {
-                bail!("Missing license information for RLM license {}", _rlm.name);
+// Network in CIDR notation or a single address
+fn parse_network(network: &str) -> Result<IpNet, Box<dyn Error>> {
+    if let Ok(v) = network.parse::<IpNet>() {
+        return Ok(v);
+    }
+    match network.parse::<IpAddr>() {
+        Ok(v) => Ok(IpNet::from(v)),
+        Err(_) => bail!("Invalid network {} in allowed_networks", network),
+    }
+}
+
+fn build_project_filters(cfg: &mut Configuration) -> Result<(), Box<dyn Error>> {
+    if let Some(glob) = cfg.global.as_mut() {
+        if let Some(projects) = &glob.projects {
+            let mut filters = Vec::new();
+            for (project, features) in projects {
+                match license::FeatureFilter::new(&Some(features.clone()), &None) {
+                    Ok(v) => filters.push((project.to_string(), v)),
+                    Err(e) => bail!("Invalid feature list for project {}: {}", project, e),
+                };
             }
-            if _rlm.isv.is_empty() {
-                bail!("Missing ISV for RLM license {}", _rlm.name);
+            // Keep output stable across scrapes
+            filters.sort_by(|a, b| a.0.cmp(&b.0));
+            glob.project_filters = filters;
+        }
+    }
+    Ok(())
+}
+
+fn validate_feature_list(
+    problems: &mut Vec<String>,
+    location: &str,
+    field: &str,
+    features: &Option<Vec<String>>,
+) {
+    if let Some(list) = features {
+        for (i, f) in list.iter().enumerate() {
+            if let Some(re) = license::pattern(f) {
+                if let Err(e) = Regex::new(re) {
+                    problems.push(format!(
+                        "{}: {}[{}]: Invalid regular expression {}: {}",
+                        location, field, i, f, e
+                    ));
+                }
             }
         }
     }
+}
 
-    if let Some(lmx) = &cfg.lmx {
-        for _lmx in lmx {
-            if _lmx.name.is_empty() {
-                bail!("Empty name for LM-X license");
+// Like excluded_features, but an empty list would skip all features of the license
+fn validate_include_features(
+    problems: &mut Vec<String>,
+    location: &str,
+    includes: &Option<Vec<String>>,
+) {
+    if let Some(incl) = includes {
+        if incl.is_empty() {
+            problems.push(format!(
+                "{}: include_features: The list of features must not be empty",
+                location
+            ));
+        }
+    }
+    validate_feature_list(problems, location, "include_features", includes);
+}
+
+// Location of the license with index i of a backend in problem reports, e.g. "flexlm[2] (matlab)"
+fn license_location(backend: &str, index: usize, name: &str) -> String {
+    if name.is_empty() {
+        format!("{}[{}]", backend, index)
+    } else {
+        format!("{}[{}] ({})", backend, index, name)
+    }
+}
+
+// Validate a parsed configuration, all problems are reported at once as
+// "<location>: <field>: <problem>"
+fn validate_configuration(cfg: &Configuration) -> Result<(), Box<dyn Error>> {
+    let problems = validation_problems(cfg);
+    if !problems.is_empty() {
+        bail!(
+            "Invalid configuration ({} problems):\n  {}",
+            problems.len(),
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+fn validation_problems(cfg: &Configuration) -> Vec<String> {
+    lazy_static! {
+        static ref RE_LABEL_NAME: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+        static ref RE_METRIC_PREFIX: Regex = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+    }
+
+    let mut problems: Vec<String> = Vec::new();
+
+    if let Some(glob) = &cfg.global {
+        if let Some(labels) = &glob.constant_labels {
+            for name in labels.keys() {
+                if !RE_LABEL_NAME.is_match(name) || name.starts_with("__") {
+                    problems.push(format!(
+                        "global: constant_labels: Invalid label name {}",
+                        name
+                    ));
+                }
+            }
+        }
+        if let Some(prefix) = &glob.metric_prefix {
+            if !RE_METRIC_PREFIX.is_match(prefix) {
+                problems.push(format!(
+                    "global: metric_prefix: Invalid metric name prefix {}",
+                    prefix
+                ));
             }
+        }
+        validate_feature_list(
+            &mut problems,
+            "global",
+            "excluded_features",
+            &glob.excluded_features,
+        );
+
+        if let Err(e) = resolver::parse_hosts(cfg) {
+            problems.push(format!("global: hosts: {}", e));
+        }
+
+        if glob.admin_token.as_deref() == Some("") {
+            problems.push("global: admin_token: Empty token".to_string());
+        }
+
+        if glob
+            .allowed_networks
+            .as_ref()
+            .map_or(false, |n| n.is_empty())
+        {
+            problems.push(
+                "global: allowed_networks: Empty list, no client could fetch the metrics"
+                    .to_string(),
+            );
+        }
 
-            if _lmx.license.is_empty() {
-                bail!("Missing license information for LM-X license {}", _lmx.name);
+        if let Some(admin_listen) = &glob.admin_listen {
+            if admin_listen.is_empty() {
+                problems.push("global: admin_listen: Empty address".to_string());
             }
+            if glob.admin_token.is_none() {
+                problems.push("global: admin_listen: admin_token is required".to_string());
+            }
+        }
 
-            for lsrv in _lmx.license.split(':') {
-                if lsrv.contains('@') && lsrv.split('@').count() != 2 {
-                    bail!("Invalid license for LM-X license {}", _lmx.name);
+        if let Some(admin_users) = &glob.admin_users {
+            for user in admin_users {
+                if !glob
+                    .basic_auth_users
+                    .as_ref()
+                    .map_or(false, |u| u.contains_key(user))
+                {
+                    problems.push(format!(
+                        "global: admin_users: User {} is not in basic_auth_users",
+                        user
+                    ));
                 }
             }
+        }
 
-            if _lmx.license.contains(':') {
-                let srvcnt: Vec<&str> = _lmx.license.split(':').collect();
-                if srvcnt.len() != 3 {
-                    bail!("Only three servers are allowed for LM-X HAL servers instead of {} for license {}", srvcnt.len(), _lmx.name);
+        if glob.scrape_token.as_deref() == Some("") {
+            problems.push("global: scrape_token: Empty token".to_string());
+        }
+
+        if let Some(users) = &glob.basic_auth_users {
+            for (user, hash) in users {
+                if user.is_empty() || user.contains(':') {
+                    problems.push(format!(
+                        "global: basic_auth_users: Invalid user name {}",
+                        user
+                    ));
+                } else if hash.parse::<bcrypt::HashParts>().is_err() {
+                    problems.push(format!(
+                        "global: basic_auth_users: Password of user {} is not a bcrypt hash",
+                        user
+                    ));
                 }
             }
         }
-    }
 
-    if let Some(dsls) = &cfg.dsls {
-        for _dsls in dsls {
-            if _dsls.name.is_empty() {
-                bail!("Empty name for DSLS license");
+        if let Some(peer_sync) = &glob.peer_sync {
+            validate_peer_sync(&mut problems, peer_sync);
+        }
+
+        for (field, value) in [
+            ("command_timeout", glob.command_timeout),
+            ("request_timeout", glob.request_timeout),
+            ("scrape_interval", glob.scrape_interval),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("global: {}: Must be greater than 0", field));
+            }
+        }
+        for (field, value) in [
+            ("max_requests", glob.max_requests),
+            ("parallelism", glob.parallelism),
+            ("top_users", glob.top_users),
+        ] {
+            if value == Some(0) {
+                problems.push(format!("global: {}: Must be greater than 0", field));
             }
+        }
 
-            if _dsls.license.is_empty() {
-                bail!(
-                    "Missing license information for DSLS license {}",
-                    _dsls.name
+        if let Some(groups) = &glob.scrape_groups {
+            if glob.scrape_interval.is_none() {
+                problems.push(
+                    "global: scrape_groups: Background collection is required, scrape_interval must be set"
+                        .to_string(),
                 );
             }
-
-            for lsrv in _dsls.license.split(':') {
-                if !lsrv.contains('@') {
-                    bail!("Invalid license for DSLS license {}", _dsls.name);
+            for (name, interval) in groups {
+                if *interval == 0 {
+                    problems.push(format!(
+                        "global: scrape_groups: Interval of group {} must be greater than 0",
+                        name
+                    ));
                 }
             }
+        }
+
+        if let Some(path) = &glob.metrics_path {
+            if let Err(e) = validate_metrics_path(path) {
+                problems.push(format!("global: metrics_path: {}", e));
+            }
+        }
+
+        for (i, hook) in glob.hooks.iter().flatten().enumerate() {
+            let location = format!("global: hooks[{}]", i);
+            if hook.name.is_empty() {
+                problems.push(format!("{}: name: Empty name", location));
+            }
+            if hook.command.is_empty() {
+                problems.push(format!("{}: command: Missing command", location));
+            }
+            if hook.timeout == Some(0) {
+                problems.push(format!("{}: timeout: Must be greater than 0", location));
+            }
+        }
 
-            if _dsls.license.contains(':') {
-                let srvcnt: Vec<&str> = _dsls.license.split(':').collect();
-                if srvcnt.len() != 3 {
-                    bail!("Only three servers are allowed for redundant DSLS servers instead of {} for license {}", srvcnt.len(), _dsls.name);
+        if let Some(projects) = &glob.projects {
+            for (project, features) in projects {
+                if project.is_empty() {
+                    problems.push("global: projects: Empty project name".to_string());
+                } else if features.is_empty() {
+                    problems.push(format!(
+                        "global: projects: No features defined for project {}",
+                        project
+                    ));
                 }
             }
         }
     }
 
-    if let Some(hasp) = &cfg.hasp {
-        for _hasp in hasp {
-            if _hasp.name.is_empty() {
-                bail!("Empty name for HASP license");
+    let groups = cfg.global.as_ref().and_then(|g| g.scrape_groups.as_ref());
+    for (name, group) in cfg.license_groups() {
+        if let Some(group) = group {
+            if !groups.map_or(false, |g| g.contains_key(group)) {
+                problems.push(format!(
+                    "license {}: scrape_group: Unknown scrape group {}",
+                    name, group
+                ));
             }
+        }
+    }
 
-            if _hasp.license.is_empty() {
-                bail!(
-                    "Missing license information for HASP license {}",
-                    _hasp.name
-                );
+    for (i, flex) in cfg.flexlm.iter().flatten().enumerate() {
+        let location = license_location("flexlm", i, &flex.name);
+        validate_license_name(&mut problems, &location, &flex.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &flex.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &flex.include_features);
+
+        if flex.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+        }
+
+        if flex.expiration_interval == Some(0) {
+            problems.push(format!(
+                "{}: expiration_interval: Must be greater than 0",
+                location
+            ));
+        }
+
+        if flex.native.unwrap_or(false) && !cfg!(feature = "experimental_native_flexlm") {
+            problems.push(format!(
+                "{}: native: The experimental_native_flexlm feature was not enabled at build time",
+                location
+            ));
+        }
+
+        if let Some(vendors) = &flex.vendors {
+            if vendors.is_empty() || vendors.iter().any(|v| v.is_empty()) {
+                problems.push(format!("{}: vendors: Empty vendor daemon", location));
+            }
+            if flex.native.unwrap_or(false) {
+                problems.push(format!(
+                    "{}: vendors: Can't be used with native queries",
+                    location
+                ));
             }
+        }
+    }
 
-            if let Some(auth) = &_hasp.authentication {
-                if auth.username.is_empty() {
-                    bail!(
-                        "HASP authentication requires a username for HASP license {}",
-                        _hasp.name
-                    );
-                }
-                if auth.password.is_empty() {
-                    bail!(
-                        "HASP authentication require a password for HASP license {}",
-                        _hasp.name
-                    );
-                }
+    for (i, rlm) in cfg.rlm.iter().flatten().enumerate() {
+        let location = license_location("rlm", i, &rlm.name);
+        validate_license_name(&mut problems, &location, &rlm.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &rlm.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &rlm.include_features);
+
+        if rlm.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+        }
+        if rlm.isv.is_empty() {
+            problems.push(format!("{}: isv: Missing ISV", location));
+        }
+        if let Some(failover) = &rlm.failover {
+            if failover.is_empty() || failover.contains(':') {
+                problems.push(format!(
+                    "{}: failover: Invalid failover server {}",
+                    location, failover
+                ));
             }
         }
+    }
 
-        if let Some(olicense) = &cfg.olicense {
-            for _olic in olicense {
-                if _olic.name.is_empty() {
-                    bail!("Empty name for OLicense license");
-                }
+    for (i, lmx) in cfg.lmx.iter().flatten().enumerate() {
+        let location = license_location("lmx", i, &lmx.name);
+        validate_license_name(&mut problems, &location, &lmx.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &lmx.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &lmx.include_features);
 
-                if _olic.license.is_empty() {
-                    bail!(
-                        "Missing license information for OLicense license {}",
-                        _olic.name
-                    );
-                }
+        if lmx.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+            continue;
+        }
+
+        let servers = lmx.license.split(':').count();
+        if servers != 1 && servers != 3 {
+            problems.push(format!(
+                "{}: license: Only three servers are allowed for LM-X HAL servers instead of {}",
+                location, servers
+            ));
+        }
+        validate_server_list(&mut problems, &location, &lmx.license, false);
+    }
+
+    for (i, dsls) in cfg.dsls.iter().flatten().enumerate() {
+        let location = license_location("dsls", i, &dsls.name);
+        validate_license_name(&mut problems, &location, &dsls.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &dsls.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &dsls.include_features);
+
+        if dsls.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+            continue;
+        }
+
+        if dsls.expiration_interval == Some(0) {
+            problems.push(format!(
+                "{}: expiration_interval: Must be greater than 0",
+                location
+            ));
+        }
+
+        let servers = dsls.license.split(':').count();
+        if servers != 1 && servers != 3 {
+            problems.push(format!(
+                "{}: license: Only three servers are allowed for redundant DSLS servers instead of {}",
+                location, servers
+            ));
+        }
+        validate_server_list(&mut problems, &location, &dsls.license, true);
+    }
+
+    for (i, licman20) in cfg.licman20.iter().flatten().enumerate() {
+        let location = license_location("licman20", i, &licman20.name);
+        validate_license_name(&mut problems, &location, &licman20.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &licman20.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &licman20.include_features);
+
+        if let Some(menu) = &licman20.menu {
+            validate_licman20_menu(&mut problems, &location, menu);
+        }
+    }
+
+    for (i, hasp) in cfg.hasp.iter().flatten().enumerate() {
+        let location = license_location("hasp", i, &hasp.name);
+        validate_license_name(&mut problems, &location, &hasp.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &hasp.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &hasp.include_features);
+
+        if hasp.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+        }
+
+        if let Some(ccert) = &hasp.client_certificate {
+            validate_client_certificate(&mut problems, &location, ccert);
+        }
+
+        if let Some(auth) = &hasp.authentication {
+            if auth.username.is_empty() == auth.username_file.is_none() {
+                problems.push(format!(
+                    "{}: authentication: Either username or username_file is required",
+                    location
+                ));
+            }
+            if auth.password.is_empty() == auth.password_file.is_none() {
+                problems.push(format!(
+                    "{}: authentication: Either password or password_file is required",
+                    location
+                ));
             }
         }
     }
 
-    Ok(())
+    for (i, olic) in cfg.olicense.iter().flatten().enumerate() {
+        let location = license_location("olicense", i, &olic.name);
+        validate_license_name(&mut problems, &location, &olic.name);
+        validate_feature_list(
+            &mut problems,
+            &location,
+            "excluded_features",
+            &olic.excluded_features,
+        );
+        validate_include_features(&mut problems, &location, &olic.include_features);
+
+        if olic.license.is_empty() {
+            problems.push(format!(
+                "{}: license: Missing license information",
+                location
+            ));
+        }
+
+        if let Some(modules) = &olic.modules {
+            if modules.iter().any(|m| m.is_empty()) {
+                problems.push(format!("{}: modules: Empty module name", location));
+            }
+        }
+
+        if let Some(ccert) = &olic.client_certificate {
+            validate_client_certificate(&mut problems, &location, ccert);
+        }
+    }
+
+    problems
+}
+
+fn validate_license_name(problems: &mut Vec<String>, location: &str, name: &str) {
+    if name.is_empty() {
+        problems.push(format!("{}: name: Empty name", location));
+    }
+}
+
+// Servers are given as port@host, separated by ':'. The port is optional for LM-X.
+fn validate_server_list(
+    problems: &mut Vec<String>,
+    location: &str,
+    license: &str,
+    port_required: bool,
+) {
+    lazy_static! {
+        static ref RE_HOST: Regex =
+            Regex::new(r"^[a-zA-Z0-9_]([a-zA-Z0-9_.\-]*[a-zA-Z0-9_])?$").unwrap();
+    }
+
+    for server in license.split(':') {
+        let (port, host) = match server.split_once('@') {
+            Some((p, h)) => (Some(p), h),
+            None => (None, server),
+        };
+
+        match port {
+            Some(p) if !matches!(p.parse::<u16>(), Ok(v) if v > 0) => problems.push(format!(
+                "{}: license: Invalid port {} of server {}",
+                location, p, server
+            )),
+            None if port_required => problems.push(format!(
+                "{}: license: Missing port of server {}, servers must be given as port@host",
+                location, server
+            )),
+            _ => {}
+        };
+
+        if !RE_HOST.is_match(host) {
+            problems.push(format!(
+                "{}: license: Invalid host name {} of server {}",
+                location, host, server
+            ));
+        }
+    }
+}
+
+fn validate_licman20_menu(problems: &mut Vec<String>, location: &str, menu: &Licman20Menu) {
+    for (option, value) in [
+        ("checkouts", &menu.checkouts),
+        ("licenses", &menu.licenses),
+        ("quit", &menu.quit),
+    ] {
+        if value.as_deref() == Some("") {
+            problems.push(format!("{}: menu.{}: Empty menu option", location, option));
+        }
+    }
+
+    if let Some(prompt) = &menu.prompt {
+        if let Err(e) = Regex::new(prompt) {
+            problems.push(format!(
+                "{}: menu.prompt: Invalid regular expression {}: {}",
+                location, prompt, e
+            ));
+        }
+    }
+
+    if menu.timeout == Some(0) {
+        problems.push(format!(
+            "{}: menu.timeout: Must be greater than 0",
+            location
+        ));
+    }
+}
+
+fn validate_peer_sync(problems: &mut Vec<String>, peer_sync: &PeerSync) {
+    match peer_sync.token.as_deref() {
+        None => problems.push("global: peer_sync.token: Missing token".to_string()),
+        Some("") => problems.push("global: peer_sync.token: Empty token".to_string()),
+        Some(_) => {}
+    };
+
+    if let Some(push_to) = &peer_sync.push_to {
+        if !push_to.starts_with("http://") && !push_to.starts_with("https://") {
+            problems.push(format!(
+                "global: peer_sync.push_to: {} is not a http:// or https:// URL",
+                push_to
+            ));
+        }
+    }
+    if peer_sync.max_age == Some(0) {
+        problems.push("global: peer_sync.max_age: Must be greater than 0".to_string());
+    }
+    if peer_sync.timeout == Some(0) {
+        problems.push("global: peer_sync.timeout: Must be greater than 0".to_string());
+    }
+}
+
+// Checks of the check-config command in addition to the validation of every loaded
+// configuration: the presence of referenced files, which are otherwise only opened on the first
+// scrape. Returns all problems found, reported like validation problems.
+pub fn check_configuration(cfg: &Configuration) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    // (location, field, file)
+    let mut files: Vec<(String, &str, &str)> = Vec::new();
+
+    if let Some(glob) = &cfg.global {
+        if let Some(f) = &glob.kerberos_keytab {
+            files.push(("global".to_string(), "kerberos_keytab", f));
+        }
+        if let Some(f) = glob.peer_sync.as_ref().and_then(|p| p.ca_file.as_ref()) {
+            files.push(("global".to_string(), "peer_sync.ca_file", f));
+        }
+    }
+    for (i, flex) in cfg.flexlm.iter().flatten().enumerate() {
+        let location = license_location("flexlm", i, &flex.name);
+        if let Some(f) = &flex.license_file {
+            files.push((location.clone(), "license_file", f));
+        }
+        if let Some(f) = &flex.options_file {
+            files.push((location, "options_file", f));
+        }
+    }
+    for (i, lmx) in cfg.lmx.iter().flatten().enumerate() {
+        if let Some(f) = &lmx.license_file {
+            files.push((license_location("lmx", i, &lmx.name), "license_file", f));
+        }
+    }
+    for (i, hasp) in cfg.hasp.iter().flatten().enumerate() {
+        let location = license_location("hasp", i, &hasp.name);
+        if let Some(f) = &hasp.ca_file {
+            files.push((location.clone(), "ca_file", f));
+        }
+        if let Some(auth) = &hasp.authentication {
+            if let Some(f) = &auth.username_file {
+                files.push((location.clone(), "authentication.username_file", f));
+            }
+            if let Some(f) = &auth.password_file {
+                files.push((location.clone(), "authentication.password_file", f));
+            }
+        }
+        push_certificate_files(&mut files, &hasp.client_certificate, location);
+    }
+    for (i, olic) in cfg.olicense.iter().flatten().enumerate() {
+        let location = license_location("olicense", i, &olic.name);
+        if let Some(f) = &olic.ca_file {
+            files.push((location.clone(), "ca_file", f));
+        }
+        push_certificate_files(&mut files, &olic.client_certificate, location);
+    }
+
+    for (location, field, file) in files {
+        if let Err(e) = fs::File::open(file) {
+            problems.push(format!(
+                "{}: {}: Can't open {}: {}",
+                location, field, file, e
+            ));
+        }
+    }
+
+    problems
+}
+
+fn push_certificate_files<'a>(
+    files: &mut Vec<(String, &str, &'a str)>,
+    ccert: &'a Option<ClientCertificate>,
+    location: String,
+) {
+    if let Some(ccert) = ccert {
+        files.push((
+            location.clone(),
+            "client_certificate.certificate",
+            &ccert.certificate,
+        ));
+        if let Some(key) = &ccert.key {
+            files.push((location, "client_certificate.key", key));
+        }
+    }
+}
+
+fn validate_client_certificate(
+    problems: &mut Vec<String>,
+    location: &str,
+    ccert: &ClientCertificate,
+) {
+    if ccert.certificate.is_empty() {
+        problems.push(format!(
+            "{}: client_certificate.certificate: Missing certificate file",
+            location
+        ));
+    }
+
+    if ccert.key.as_deref() == Some("") {
+        problems.push(format!(
+            "{}: client_certificate.key: Empty key file",
+            location
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn select_license() {
+        let cfg = testutil::parse_config(
+            "flexlm:
+  - name: flex1
+    license: 27000@lic1
+  - name: flex2
+    license: 27000@lic2
+rlm:
+  - name: rlm1
+    license: 5053@lic1
+    isv: demo
+",
+        );
+
+        let selected = cfg.select("flex2");
+        assert_eq!(selected.license_names("flexlm"), vec!["flex2"]);
+        assert!(selected.license_names("rlm").is_empty());
+
+        let selected = cfg.select("rlm1");
+        assert!(selected.license_names("flexlm").is_empty());
+        assert_eq!(selected.license_names("rlm"), vec!["rlm1"]);
+
+        let selected = cfg.select("unknown");
+        assert!(BACKENDS
+            .iter()
+            .all(|b| selected.license_names(b).is_empty()));
+    }
+
+    #[test]
+    fn metrics_path() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+        assert_eq!(cfg.metrics_path(), constants::DEFAULT_METRICS_PATH);
+
+        let cfg = testutil::parse_config("global:\n  metrics_path: /exporter/metrics\n");
+        assert_eq!(cfg.metrics_path(), "/exporter/metrics");
+
+        for path in [
+            "",
+            "/",
+            "metrics",
+            "/metrics/",
+            "/metrics?x=1",
+            "/a b",
+            "/healthz",
+        ] {
+            let cfg: Configuration =
+                serde_yaml::from_str(&format!("global:\n  metrics_path: '{}'\n", path)).unwrap();
+            assert!(validate_configuration(&cfg).is_err(), "{}", path);
+            assert!(validate_metrics_path(path).is_err(), "{}", path);
+        }
+
+        // --metrics-path replaces the path of the configuration file
+        let mut cfg: Configuration = serde_yaml::from_str("flexlm: []\n").unwrap();
+        set_metrics_path(&mut cfg, "/license/metrics".to_string());
+        assert_eq!(cfg.metrics_path(), "/license/metrics");
+    }
+
+    #[test]
+    fn native_flexlm() {
+        let cfg: Configuration = serde_yaml::from_str(
+            "flexlm:\n  - name: flex1\n    license: 27000@lic1\n    native: true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            validate_configuration(&cfg).is_ok(),
+            cfg!(feature = "experimental_native_flexlm")
+        );
+    }
+
+    #[test]
+    fn secret_files() {
+        let file = env::temp_dir().join(format!("license-exporter-token-{}", std::process::id()));
+        fs::write(&file, "VerySecretAdminToken\n").unwrap();
+
+        let cfg = parse_config(&format!(
+            "global:\n  admin_token_file: '{}'\n",
+            file.display()
+        ))
+        .unwrap();
+        assert_eq!(
+            cfg.global.unwrap().admin_token.unwrap(),
+            "VerySecretAdminToken"
+        );
+
+        assert!(parse_config(&format!(
+            "global:\n  admin_token: token\n  admin_token_file: '{}'\n",
+            file.display()
+        ))
+        .is_err());
+
+        fs::remove_file(&file).unwrap();
+        assert!(parse_config(&format!(
+            "global:\n  admin_token_file: '{}'\n",
+            file.display()
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn admin_listen() {
+        let cfg = testutil::parse_config(
+            "global:\n  admin_listen: 'localhost:9989'\n  admin_token: s3cr3t\n",
+        );
+        assert_eq!(cfg.admin_listen(), Some("localhost:9989"));
+
+        for global in [
+            "admin_listen: 'localhost:9989'",
+            "admin_listen: ''\n  admin_token: s3cr3t",
+        ] {
+            let cfg: Configuration =
+                serde_yaml::from_str(&format!("global:\n  {}\n", global)).unwrap();
+            assert!(validate_configuration(&cfg).is_err(), "{}", global);
+        }
+    }
+
+    #[test]
+    fn toml_configuration() {
+        let cfg = parse_config_as(
+            "[global]\nscrape_interval = 60\nexcluded_features = ['internal']\n\n[defaults.flexlm]\nexport_user = true\n\n[[flexlm]]\nname = 'flex'\nlicense = '27000@lic1'\n\n[[rlm]]\nname = 'rlm'\nlicense = '5053@lic2'\nisv = 'vendor'\n",
+            ConfigFormat::Toml,
+        )
+        .unwrap();
+        assert_eq!(cfg.scrape_interval(), Some(Duration::from_secs(60)));
+        let flexlm = cfg.flexlm.unwrap();
+        assert_eq!(flexlm[0].export_user, Some(true));
+        assert_eq!(
+            flexlm[0].excluded_features,
+            Some(vec!["internal".to_string()])
+        );
+        assert_eq!(cfg.rlm.unwrap()[0].isv, "vendor");
+
+        assert!(parse_config_as("[[flexlm]]\nname = 'flex'\n", ConfigFormat::Toml).is_err());
+
+        assert_eq!(
+            ConfigFormat::from_file("/etc/exporter.json"),
+            ConfigFormat::Json
+        );
+        assert_eq!(
+            ConfigFormat::from_file("/etc/exporter.TOML"),
+            ConfigFormat::Toml
+        );
+        assert_eq!(
+            ConfigFormat::from_file("/etc/exporter.yaml"),
+            ConfigFormat::Yaml
+        );
+        assert_eq!(ConfigFormat::from_file("/etc/exporter"), ConfigFormat::Yaml);
+        assert_eq!(ConfigFormat::from_name("TOML").unwrap(), ConfigFormat::Toml);
+        assert!(ConfigFormat::from_name("ini").is_err());
+    }
+
+    #[test]
+    fn json_configuration() {
+        let cfg = parse_config_as(
+            r##"{"global": {"top_users": 5}, "flexlm": [{"name": "flex", "license": "27000@lic1", "export_user": true, "excluded_features": ["a: b", "# c"]}]}"##,
+            ConfigFormat::Json,
+        )
+        .unwrap();
+        assert_eq!(cfg.top_users(), 5);
+        let flexlm = cfg.flexlm.unwrap();
+        assert_eq!(flexlm[0].export_user, Some(true));
+        assert_eq!(
+            flexlm[0].excluded_features,
+            Some(vec!["a: b".to_string(), "# c".to_string()])
+        );
+
+        assert!(parse_config_as(r#"{"flexlm": [{"name": "flex"}]}"#, ConfigFormat::Json).is_err());
+        assert!(parse_config_as("flexlm: []", ConfigFormat::Json).is_err());
+    }
+
+    #[test]
+    fn check_referenced_files() {
+        let cfg = testutil::parse_config(&format!(
+            "lmx:\n  - name: lmx-file\n    license: 'lic1'\n    license_file: '/nonexistent/license.lic'\nflexlm:\n  - name: flex\n    license: '27000@lic1'\n    options_file: '{}'\n",
+            testutil::fixture_path("flexlm/vendx.opt")
+        ));
+        assert_eq!(
+            check_configuration(&cfg),
+            vec![
+                "lmx[0] (lmx-file): license_file: Can't open /nonexistent/license.lic: No such file or directory (os error 2)".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn validation_problems_with_locations() {
+        let cfg: Configuration = serde_yaml::from_str(
+            "global:\n  parallelism: 0\n  peer_sync:\n    token: ''\nlmx:\n  - name: lmx\n    license: '6200@lic1:lic2:99999@lic3'\ndsls:\n  - name: dsls\n    license: '4085@lic1'\n  - name: dsls-bad\n    license: '4085@-lic1'\nflexlm:\n  - name: flex\n    license: '27000@lic1'\n  - name: ''\n    license: ''\n    excluded_features: ['/[/']\nolicense:\n  - name: olic\n    license: ''\n",
+        )
+        .unwrap();
+        let mut problems = validation_problems(&cfg);
+        assert!(problems
+            .remove(3)
+            .starts_with("flexlm[1]: excluded_features[0]: Invalid regular expression /[/"));
+        assert_eq!(
+            problems,
+            vec![
+                "global: peer_sync.token: Empty token",
+                "global: parallelism: Must be greater than 0",
+                "flexlm[1]: name: Empty name",
+                "flexlm[1]: license: Missing license information",
+                "lmx[0] (lmx): license: Invalid port 99999 of server 99999@lic3",
+                "dsls[1] (dsls-bad): license: Invalid host name -lic1 of server 4085@-lic1",
+                "olicense[0] (olic): license: Missing license information",
+            ]
+        );
+
+        let error = validate_configuration(&cfg).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid configuration (8 problems):\n  global: peer_sync.token"));
+    }
+
+    #[test]
+    fn scrape_groups() {
+        let cfg = testutil::parse_config(
+            "global:\n  scrape_interval: 60\n  scrape_groups:\n    hourly: 3600\nflexlm:\n  - name: flex1\n    license: 27000@lic1\n  - name: flex2\n    license: 27000@lic2\n    scrape_group: hourly\nrlm:\n  - name: rlm\n    license: 5053@lic3\n    isv: vendor\n    scrape_group: hourly\n",
+        );
+        assert_eq!(
+            cfg.scrape_groups(),
+            vec![
+                (None, Duration::from_secs(60)),
+                (Some("hourly".to_string()), Duration::from_secs(3600))
+            ]
+        );
+
+        let hourly = cfg.select_groups(&[Some("hourly".to_string())]);
+        assert_eq!(hourly.license_names("flexlm"), vec!["flex2"]);
+        assert_eq!(hourly.license_names("rlm"), vec!["rlm"]);
+        let default = cfg.select_groups(&[None]);
+        assert_eq!(default.license_names("flexlm"), vec!["flex1"]);
+        assert!(default.license_names("rlm").is_empty());
+
+        assert!(parse_config(
+            "global:\n  scrape_interval: 60\nflexlm:\n  - name: flex\n    license: 27000@lic1\n    scrape_group: hourly\n"
+        )
+        .is_err());
+        assert!(parse_config("global:\n  scrape_groups:\n    hourly: 3600\n").is_err());
+        assert!(
+            parse_config("global:\n  scrape_interval: 60\n  scrape_groups:\n    hourly: 0\n")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn config_version() {
+        let cfg =
+            parse_config("version: 1\nflexlm:\n  - name: flex\n    license: 27000@lic1\n").unwrap();
+        assert_eq!(cfg.version, Some(1));
+        assert!(parse_config("flexlm:\n  - name: flex\n    license: 27000@lic1\n").is_ok());
+
+        // A newer schema is rejected before the licenses are deserialized
+        let error = parse_config("version: 2\nflexlm:\n  - name: flex\n    servers: []\n")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("Configuration version 2 is not supported"),
+            "{}",
+            error
+        );
+        assert!(parse_config_as("version = 2\n", ConfigFormat::Toml).is_err());
+
+        for version in ["0", "-1", "'1'", "1.5"] {
+            assert!(
+                parse_config(&format!("version: {}\n", version)).is_err(),
+                "{}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn backend_defaults() {
+        let cfg = testutil::parse_config(
+            "defaults:\n  flexlm:\n    export_user: true\n    excluded_features: ['internal']\n    on_failure: 'zero'\nflexlm:\n  - name: flex1\n    license: 27000@lic1\n  - name: flex2\n    license: 27000@lic2\n    export_user: false\n    excluded_features: ['other']\nrlm:\n  - name: rlm\n    license: 5053@lic3\n    isv: vendor\n",
+        );
+        let flexlm = cfg.flexlm.unwrap();
+        assert_eq!(flexlm[0].export_user, Some(true));
+        assert_eq!(flexlm[0].on_failure, Some(OnFailure::Zero));
+        assert_eq!(
+            flexlm[0].excluded_features,
+            Some(vec!["internal".to_string()])
+        );
+        assert_eq!(flexlm[1].export_user, Some(false));
+        assert_eq!(flexlm[1].on_failure, Some(OnFailure::Zero));
+        assert_eq!(flexlm[1].excluded_features, Some(vec!["other".to_string()]));
+        assert_eq!(cfg.rlm.unwrap()[0].export_user, None);
+
+        assert!(parse_config("defaults:\n  flexml:\n    export_user: true\n").is_err());
+        assert!(parse_config("defaults:\n  flexlm:\n    name: 'flex'\n").is_err());
+        assert!(parse_config(
+            "defaults:\n  flexlm:\n    export_user: 'maybe'\nflexlm:\n  - name: flex\n    license: 27000@lic1\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn hasp_credential_files() {
+        let file = env::temp_dir().join(format!("license-exporter-hasp-{}", std::process::id()));
+        fs::write(&file, "hasp-first-secret\n").unwrap();
+
+        let cfg = testutil::parse_config(&format!(
+            "hasp:\n  - name: hasp\n    license: 'localhost'\n    hasp_key: '1234'\n    authentication:\n      username: 'admin'\n      password_file: '{}'\n",
+            file.display()
+        ));
+        let auth = cfg.hasp.unwrap()[0].authentication.clone().unwrap();
+        assert_eq!(
+            auth.credentials().unwrap(),
+            ("admin".to_string(), "hasp-first-secret".to_string())
+        );
+
+        // rotated secrets are picked up without a reload
+        fs::write(&file, "hasp-second-secret").unwrap();
+        assert_eq!(auth.credentials().unwrap().1, "hasp-second-secret");
+        assert_eq!(
+            logging::redact("login hasp-first-secret hasp-second-secret"),
+            format!("login {} {}", logging::REDACTED, logging::REDACTED)
+        );
+
+        fs::remove_file(&file).unwrap();
+        assert!(auth.credentials().is_err());
+
+        assert!(parse_config(
+            "hasp:\n  - name: hasp\n    license: 'localhost'\n    hasp_key: '1234'\n    authentication:\n      username: 'admin'\n      password: 'pass'\n      password_file: '/run/secrets/pass'\n"
+        )
+        .is_err());
+        assert!(parse_config(
+            "hasp:\n  - name: hasp\n    license: 'localhost'\n    hasp_key: '1234'\n    authentication:\n      password: 'pass'\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn environment_variables() {
+        env::set_var("CONFIG_TEST_LICENSE", "27000@lic1");
+        env::set_var("CONFIG_TEST_PASSWORD", "s3cr3t: \"quoted\"");
+        env::remove_var("CONFIG_TEST_UNSET");
+
+        let cfg = testutil::parse_config(
+            "flexlm:\n  - name: flex\n    license: '${CONFIG_TEST_LICENSE}'\nhasp:\n  - name: hasp\n    license: 'localhost:1947'\n    hasp_key: '1234'\n    authentication:\n      username: 'admin'\n      password: 'x${CONFIG_TEST_PASSWORD}$${CONFIG_TEST_PASSWORD}'\n",
+        );
+        assert_eq!(cfg.flexlm.unwrap()[0].license, "27000@lic1");
+        let auth = cfg.hasp.unwrap()[0].authentication.clone().unwrap();
+        assert_eq!(auth.password, "xs3cr3t: \"quoted\"${CONFIG_TEST_PASSWORD}");
+
+        assert!(
+            parse_config("flexlm:\n  - name: flex\n    license: '${CONFIG_TEST_UNSET}'\n").is_err()
+        );
+    }
+
+    #[test]
+    fn allowed_networks() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+        assert!(cfg.allowed_networks().is_none());
+
+        let cfg = testutil::parse_config(
+            "global:\n  allowed_networks: ['10.0.0.0/8', '192.0.2.1', 'fd00::/8']\n",
+        );
+        let nets: Vec<String> = cfg
+            .allowed_networks()
+            .unwrap()
+            .iter()
+            .map(|n| n.to_string())
+            .collect();
+        assert_eq!(nets, vec!["10.0.0.0/8", "192.0.2.1/32", "fd00::/8"]);
+
+        for networks in ["[]", "['10.0.0.0/33']", "['localhost']"] {
+            assert!(
+                parse_config(&format!("global:\n  allowed_networks: {}\n", networks)).is_err(),
+                "{}",
+                networks
+            );
+        }
+    }
+
+    #[test]
+    fn basic_auth_users() {
+        let cfg = testutil::parse_config(
+            "global:\n  basic_auth_users:\n    prometheus: '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'\n",
+        );
+        assert_eq!(cfg.basic_auth_users().unwrap().len(), 1);
+
+        for users in [
+            "prometheus: s3cr3t",
+            "'': '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'",
+        ] {
+            let cfg: Configuration =
+                serde_yaml::from_str(&format!("global:\n  basic_auth_users:\n    {}\n", users))
+                    .unwrap();
+            assert!(validate_configuration(&cfg).is_err(), "{}", users);
+        }
+    }
+
+    #[test]
+    fn on_failure() {
+        let cfg = testutil::parse_config(
+            "rlm:\n  - name: rlm1\n    license: 5053@lic1\n    isv: demo\n    on_failure: zero\n",
+        );
+        assert_eq!(cfg.rlm.unwrap()[0].on_failure, Some(OnFailure::Zero));
+
+        assert!(parse_config(
+            "rlm:\n  - name: rlm1\n    license: 5053@lic1\n    isv: demo\n    on_failure: forget\n",
+        )
+        .is_err());
+    }
 }
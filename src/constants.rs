@@ -5,18 +5,129 @@ pub const SOURCE: &str = "https://git.ypbind.de/cgit/prometheus-license-exporter
 pub const DEFAULT_TIMEOUT: u64 = 60;
 pub const DEFAULT_PROMETHEUS_ADDRESS: &str = "localhost:9998";
 
+pub const LOG_FORMAT_COLOR: &str = "color";
+pub const LOG_FORMAT_PLAIN: &str = "plain";
+pub const LOG_FORMATS: [&str; 2] = [LOG_FORMAT_COLOR, LOG_FORMAT_PLAIN];
+
+pub const CONFIG_FORMAT_JSON: &str = "json";
+pub const CONFIG_FORMAT_TOML: &str = "toml";
+pub const CONFIG_FORMAT_YAML: &str = "yaml";
+pub const CONFIG_FORMATS: [&str; 3] = [CONFIG_FORMAT_JSON, CONFIG_FORMAT_TOML, CONFIG_FORMAT_YAML];
+// Version of the configuration schema, configurations without version are version 1
+pub const CONFIG_VERSION: u64 = 1;
+
+pub const COMMAND_CHECK_CONFIG: &str = "check-config";
+pub const COMMAND_GENERATE_COMPLETIONS: &str = "generate-completions";
+pub const COMMAND_GENERATE_MAN: &str = "generate-man";
+pub const COMMAND_LIST_BACKENDS: &str = "list-backends";
+pub const COMMAND_MIGRATE_CONFIG: &str = "migrate-config";
+pub const COMMAND_RUN: &str = "run";
+pub const COMMAND_SCRAPE: &str = "scrape";
+pub const COMMAND_VERSION: &str = "version";
+
 pub const DEFAULT_LMUTIL: &str = "lmutil";
 pub const DEFAULT_RLMUTIL: &str = "rlmutil";
 pub const DEFAULT_LMXENDUTIL: &str = "lmxendutil";
 pub const DEFAULT_DSLICSRV: &str = "dslicsrv";
 pub const DEFAULT_LICMAN20_APPL: &str = "licman20_appl";
+// Menu options of licman20_appl and the prompt of the menu, by default any output
+pub const DEFAULT_LICMAN20_LICENSES_OPTION: &str = "4";
+pub const DEFAULT_LICMAN20_CHECKOUTS_OPTION: &str = "2";
+pub const DEFAULT_LICMAN20_QUIT_OPTION: &str = "X";
+pub const DEFAULT_LICMAN20_PROMPT: &str = r"\S\s*$";
+pub const DEFAULT_LICMAN20_PROMPT_TIMEOUT: u64 = 10;
 pub const DEFAULT_HASP_PORT: &str = "1947";
+#[cfg(feature = "experimental_native_flexlm")]
+pub const DEFAULT_FLEXLM_PORT: &str = "27000";
+pub const DNS_CACHE_TTL: u64 = 300;
+#[cfg(feature = "experimental_native_flexlm")]
+pub const DEFAULT_NATIVE_FLEXLM_TIMEOUT: u64 = 5;
+pub const EXPIRATION_METRIC_SUFFIX: &str = "_feature_expiration_seconds";
+pub const EXPIRATION_INFO_METRIC_SUFFIX: &str = "_feature_expiration_info";
+pub const SERVER_STATUS_METRIC_SUFFIX: &str = "_server_status";
+pub const SERVER_INFO_METRIC_SUFFIX: &str = "_server_info";
+pub const EXPIRATION_DATE_FORMAT: &str = "%Y-%m-%d";
+pub const EXPIRATION_DATE_PERMANENT: &str = "permanent";
+pub const FEATURE_USED_METRIC_SUFFIX: &str = "_feature_used";
+pub const FEATURE_ISSUED_METRIC_SUFFIX: &str = "_feature_issued";
+pub const PROJECT_USED_METRIC: &str = "license_project_used";
+pub const TOTAL_SEATS_ISSUED_METRIC: &str = "license_total_seats_issued";
+pub const TOTAL_SEATS_USED_METRIC: &str = "license_total_seats_used";
+pub const USAGE_ANOMALY_METRIC: &str = "license_feature_usage_anomaly";
+pub const ANOMALY_MIN_SAMPLES: u64 = 10;
+pub const SEATS_PER_USER_BUCKETS: [f64; 7] = [1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0];
+pub const EXCLUDED_FEATURES_METRIC: &str = "license_exporter_excluded_features";
+// FlexLM feature names can't contain @, so the vendor suffix can be told apart
+pub const VENDOR_SUFFIX_SEPARATOR: char = '@';
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 30;
+pub const COMMAND_POLL_INTERVAL_MS: u64 = 10;
+pub const SIGNAL_POLL_INTERVAL_MS: u64 = 500;
+pub const DEFAULT_COMMAND_TIMEOUT: u64 = 60;
+pub const CONFIG_YAML_ENV: &str = "CONFIG_YAML";
 pub const DEFAULT_METRICS_PATH: &str = "/metrics";
+pub const DEFAULT_MAX_REQUESTS: usize = 1;
+pub const DEFAULT_PARALLELISM: usize = 4;
+pub const RETRY_AFTER: u64 = 10;
+pub const ADMIN_QUIT_PATH: &str = "/-/quit";
+pub const ADMIN_RELOAD_PATH: &str = "/-/reload";
+pub const PEER_SNAPSHOT_PATH: &str = "/-/snapshot";
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const API_FEATURE_PATH: &str = "/api/v1/feature";
+pub const API_VENDORS_PATH: &str = "/api/v1/vendors";
+pub const READYZ_PATH: &str = "/readyz";
+pub const DEFAULT_PEER_SYNC_TIMEOUT: u64 = 10;
+pub const MAX_PEER_SNAPSHOT_SIZE: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_OLICENSE_MAX_RESPONSE_SIZE: u64 = 64 * 1024 * 1024;
+pub const HTTP_ACCEPT_HTML: &str = "text/html,application/xhtml+xml;q=0.9,*/*;q=0.8";
+pub const HTTP_ACCEPT_XML: &str = "application/xml,text/xml;q=0.9,*/*;q=0.8";
+pub const HTTP_PROGRESS_REPORT_BYTES: u64 = 1024 * 1024;
+pub const OLICENSE_PROGRESS_REPORT_FEATURES: usize = 1000;
 
-pub const ROOT_HTML: &str = "<html>\n<head><title>License exporter</title></head>\n<body>\n<h1>License exporter</h1>\n<p><a href=\"/metric\">Metrics</a></p>\n</body>\n</html>\n";
+pub const ROOT_HTML: &str = "<html>
+<head><title>License exporter</title></head>
+<body>
+<h1>License exporter</h1>
+<p><a href=\"{metrics_path}\">Metrics</a></p>
+<table>
+<tr><th>License</th><th>Type</th><th>Last scrape</th><th>Time</th></tr>
+{licenses}</table>
+</body>
+</html>
+";
 
+pub const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; charset=utf-8";
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_OPENMETRICS: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+pub const MEDIA_TYPE_OPENMETRICS: &str = "application/openmetrics-text";
+// Smaller replies aren't compressed, the gzip overhead would exceed the savings
+pub const GZIP_MIN_SIZE: usize = 1024;
+pub const REPLY_QUIT: &str = "Shutting down";
+pub const REPLY_RELOAD: &str = "Configuration reloaded";
+pub const REPLY_SNAPSHOT: &str = "Snapshot received";
+pub const REPLY_HEALTHY: &str = "OK";
+pub const REPLY_NOT_READY: &str = "Not ready";
+pub const REPLY_UNAUTHORIZED: &str = "Unauthorized";
+pub const REPLY_FORBIDDEN: &str = "Forbidden";
+pub const BASIC_AUTH_REALM: &str = "prometheus-license-exporter";
+// Verified instead of a hash for unknown basic auth users, so they take as long as known ones
+pub const BASIC_AUTH_DUMMY_HASH: &str =
+    "$2b$12$O61z6VvLmYLN7RtsSKpYnueWpcjJPDNVcdBPAUmM769sCAHWxdq/6";
+// Label of series identifying users, only served to the admin role
+pub const USER_LABEL: &str = "user";
+// FlexLM reservations and policy rules for a single user have type="user" and the user name
+// as group, they are only served to the admin role as well
+pub const TYPE_LABEL: &str = "type";
+pub const USER_TYPE: &str = "user";
 pub const REPLY_METHOD_NOT_ALLOWED: &str = "Method not allowed";
 pub const REPLY_NOT_FOUND: &str = "Not found";
+pub const REPLY_REDIRECT: &str = "Metrics are served at ";
+pub const REPLY_MISSING_FEATURE: &str = "Parameters app and feature are required";
+pub const REPLY_PAYLOAD_TOO_LARGE: &str = "Request body not allowed";
+pub const REPLY_SCRAPE_TIMEOUT: &str = "Scrape timed out";
+pub const REPLY_NO_SAMPLE: &str = "No metrics collected yet";
+pub const REPLY_TOO_MANY_REQUESTS: &str = "Too many concurrent requests";
 
 pub fn generate_default_user_agent() -> String {
     format!("{}/{} ({})", NAME, VERSION, SOURCE)
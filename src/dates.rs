@@ -0,0 +1,207 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use lazy_static::lazy_static;
+use regex::Regex;
+use simple_error::bail;
+use std::error::Error;
+
+// Markers used by the license servers for licenses without expiration
+const PERMANENT_MARKERS: [&str; 4] = ["permanent", "perpetual", "unlimited", "never"];
+
+// Date and time formats reported by the license servers
+//  - DSLS: 2024-12-31 23:59:59
+//  - HASP: Mon Mar 04, 2024 10:00
+const DATE_TIME_FORMATS: [&str; 5] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M:%S",
+    "%a %b %d, %Y %H:%M",
+    "%d-%b-%Y %H:%M:%S",
+];
+
+// Date only formats reported by the license servers
+//  - LM-X, OLicense: 2024-12-31
+//  - FlexLM, RLM, licman20: 31-dec-2024
+const DATE_FORMATS: [&str; 3] = ["%Y-%m-%d", "%d-%b-%Y", "%a %b %d, %Y"];
+
+lazy_static! {
+    // FlexLM and RLM report some licenses with two digit years or year 0 (permanent license)
+    static ref RE_DAY_MONTH_YEAR: Regex =
+        Regex::new(r"^(\d{1,2})-([a-z]{3})-(\d{1,4})$").unwrap();
+}
+
+/// Parse an expiration date as reported by one of the supported license servers and return it
+/// as seconds since epoch (UTC). Licenses without expiration are returned as `f64::INFINITY`.
+pub fn parse_expiration(raw: &str) -> Result<f64, Box<dyn Error>> {
+    let value = raw.trim().to_lowercase();
+
+    if value.is_empty() {
+        bail!("empty date");
+    }
+
+    if PERMANENT_MARKERS.iter().any(|m| value.starts_with(m)) {
+        return Ok(f64::INFINITY);
+    }
+
+    let normalised = match RE_DAY_MONTH_YEAR.captures(&value) {
+        Some(capt) => {
+            let day = capt.get(1).map_or("", |m| m.as_str());
+            let month = capt.get(2).map_or("", |m| m.as_str());
+            let _year = capt.get(3).map_or("", |m| m.as_str());
+            let mut year: i32 = _year.parse()?;
+
+            // FlexLM uses 1-jan-0 and 01-jan-0000 for permanent licenses
+            if year == 0 && (_year.len() == 1 || _year.len() == 4) {
+                return Ok(f64::INFINITY);
+            }
+
+            // Two digit years follow the POSIX strptime convention: 69-99 -> 19xx, 00-68 -> 20xx
+            if _year.len() <= 2 {
+                year += if year >= 69 { 1900 } else { 2000 };
+            }
+            format!("{}-{}-{:04}", day, month, year)
+        }
+        None => value,
+    };
+
+    match parse_date_time(&normalised) {
+        Some(v) => Ok(v.and_utc().timestamp() as f64),
+        None => bail!("unknown date format"),
+    }
+}
+
+fn parse_date_time(value: &str) -> Option<NaiveDateTime> {
+    if let Ok(v) = DateTime::parse_from_rfc3339(value) {
+        return Some(v.naive_utc());
+    }
+
+    for fmt in DATE_TIME_FORMATS {
+        if let Ok(v) = NaiveDateTime::parse_from_str(value, fmt) {
+            return Some(v);
+        }
+    }
+
+    for fmt in DATE_FORMATS {
+        if let Ok(v) = NaiveDate::parse_from_str(value, fmt) {
+            return v.and_hms_opt(0, 0, 0);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Datelike;
+    use proptest::prelude::*;
+
+    const TS_2024_12_31: f64 = 1735603200.0;
+
+    #[test]
+    fn parse_iso() {
+        assert_eq!(parse_expiration("2024-12-31").unwrap(), TS_2024_12_31);
+        assert_eq!(
+            parse_expiration("2024-12-31 23:59:59").unwrap(),
+            TS_2024_12_31 + 86399.0
+        );
+        assert_eq!(
+            parse_expiration("2024-12-31T10:00:00Z").unwrap(),
+            TS_2024_12_31 + 36000.0
+        );
+    }
+
+    #[test]
+    fn parse_day_month_year() {
+        assert_eq!(parse_expiration("31-dec-2024").unwrap(), TS_2024_12_31);
+        assert_eq!(parse_expiration("31-DEC-2024").unwrap(), TS_2024_12_31);
+        assert_eq!(parse_expiration(" 31-Dec-2024\n").unwrap(), TS_2024_12_31);
+    }
+
+    #[test]
+    fn parse_two_digit_years() {
+        assert_eq!(parse_expiration("31-dec-24").unwrap(), TS_2024_12_31);
+        assert_eq!(
+            parse_expiration("31-dec-99").unwrap(),
+            parse_expiration("31-dec-1999").unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_hasp() {
+        assert_eq!(
+            parse_expiration("Mon Mar 04, 2024 10:00").unwrap(),
+            1709546400.0
+        );
+        assert_eq!(
+            parse_expiration("mon mar 04, 2024 10:00").unwrap(),
+            1709546400.0
+        );
+    }
+
+    #[test]
+    fn parse_permanent() {
+        for p in [
+            "permanent",
+            "Permanent",
+            "permanent(no expiration date)",
+            "Perpetual",
+            "1-jan-0",
+            "01-jan-0000",
+            "1-JAN-0",
+        ] {
+            assert_eq!(parse_expiration(p).unwrap(), f64::INFINITY, "{}", p);
+        }
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for p in [
+            "",
+            "   ",
+            "31-foo-2024",
+            "2024-13-01",
+            "yesterday",
+            "32-dec-2024",
+        ] {
+            assert!(parse_expiration(p).is_err(), "{}", p);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(s in "\\PC*") {
+            let _ = parse_expiration(&s);
+        }
+
+        #[test]
+        fn parse_roundtrip(days in 0i64..40000, secs in 0u32..86400) {
+            let dt = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap()
+                + chrono::Duration::days(days)
+                + chrono::Duration::seconds(secs as i64);
+            let date = dt.date().and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() as f64;
+            let minutes = dt.format("%Y-%m-%d %H:%M:00").to_string();
+            let minutes = NaiveDateTime::parse_from_str(&minutes, "%Y-%m-%d %H:%M:%S")
+                .unwrap()
+                .and_utc()
+                .timestamp() as f64;
+
+            prop_assert_eq!(
+                parse_expiration(&dt.format("%Y-%m-%d %H:%M:%S").to_string()).unwrap(),
+                dt.and_utc().timestamp() as f64
+            );
+            prop_assert_eq!(parse_expiration(&dt.format("%Y-%m-%d").to_string()).unwrap(), date);
+            prop_assert_eq!(parse_expiration(&dt.format("%d-%b-%Y").to_string()).unwrap(), date);
+            prop_assert_eq!(
+                parse_expiration(&dt.format("%d-%b-%Y").to_string().to_uppercase()).unwrap(),
+                date
+            );
+            prop_assert_eq!(
+                parse_expiration(&dt.format("%a %b %d, %Y %H:%M").to_string()).unwrap(),
+                minutes
+            );
+            if (1969..2069).contains(&dt.year()) {
+                prop_assert_eq!(parse_expiration(&dt.format("%d-%b-%y").to_string()).unwrap(), date);
+            }
+        }
+    }
+}
@@ -1,17 +1,22 @@
+use crate::command;
 use crate::config;
-use crate::exporter;
+use crate::dates;
 use crate::license;
+use crate::series;
+use crate::servers;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use regex::Regex;
 use simple_error::bail;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::process::Command;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref DSLS_FEATURES_TOTAL: IntGaugeVec = IntGaugeVec::new(
@@ -29,9 +34,25 @@ lazy_static! {
         &["app", "name", "user"],
     )
     .unwrap();
+    pub static ref DSLS_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "dsls_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref DSLS_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("dsls_server_status", "Status of license server(s)"),
-        &["app", "fqdn", "port", "version"],
+        &["app", "fqdn", "master", "port", "version"],
+    )
+    .unwrap();
+    pub static ref DSLS_FEATURES_MODEL_TOTAL: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "dsls_feature_model_issued",
+            "Number of issued licenses by license model"
+        ),
+        &["app", "name", "model"],
     )
     .unwrap();
     pub static ref DSLS_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
@@ -50,11 +71,23 @@ lazy_static! {
         &["app", "features", "index", "licenses"]
     )
     .unwrap();
+    pub static ref DSLS_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "dsls_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
+    // Failover clusters report the current master of the cluster
+    static ref RE_DSLS_MASTER: Regex =
+        Regex::new(r"^\s+(?:Failover master|Master(?: server)?):\s+([\w.\-]+)").unwrap();
 }
 
 #[derive(Clone, Debug)]
-struct DslsLicenseUsage {
+pub struct DslsLicenseUsage {
     pub feature: String,
+    pub model: &'static str,
     pub count: i64,
     pub inuse: i64,
     pub user: Option<String>,
@@ -66,7 +99,11 @@ struct DslsLicenseExpiration {
     pub expiration: f64,
 }
 
-pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
+pub fn fetch(
+    lic: &config::Dsls,
+    dslicsrv: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
     lazy_static! {
         static ref RE_DSLS_VERSION: Regex =
             Regex::new(r"^\s+Software version:\s+([\d.\-]+)$").unwrap();
@@ -77,38 +114,33 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
     let mut fuv: HashMap<String, HashMap<String, i64>> = HashMap::new();
     let mut f_total: HashMap<String, i64> = HashMap::new();
     let mut f_used: HashMap<String, i64> = HashMap::new();
-    let mut server_port: HashMap<String, String> = HashMap::new();
-    let mut server_version: HashMap<String, String> = HashMap::new();
-    let mut server_status: HashMap<String, i64> = HashMap::new();
+    // dict -> "feature" -> "model" -> count
+    let mut f_model: BTreeMap<String, BTreeMap<&str, i64>> = BTreeMap::new();
+    let mut servers = servers::ServerTable::from_license(&lic.license, "");
     let mut license_data: Vec<DslsLicenseUsage> = Vec::new();
-
-    for (_, lserver) in lic.license.split(':').enumerate() {
-        let srvport: Vec<&str> = lserver.split('@').collect();
-
-        // NOTE: Configuration validation checks for valid server lines
-        let port = srvport[0].to_string();
-        let srv = srvport[1].to_string();
-
-        server_port.insert(srv, port);
-    }
+    let mut master = String::new();
 
     let mut features_exported = false;
     let mut csv_mode = false;
 
-    for (server, port) in &server_port {
-        env::set_var("LANG", "C");
+    for srv in servers.iter_mut() {
+        let server = &srv.fqdn;
+        let port = &srv.port;
         debug!(
             "dsls.rs:fetch: Running {} -admin -run \"connect {} {};getLicenseUsage -csv;quit;\"",
             dslicsrv, server, port
         );
-        let cmd = Command::new(dslicsrv)
-            .arg("-admin")
-            .arg("-run")
-            .arg(format!(
-                "connect {} {};getLicenseUsage -csv;quit;",
-                server, port
-            ))
-            .output()?;
+        let cmd = runner.run(
+            Command::new(dslicsrv)
+                .env("LANG", "C")
+                .arg("-admin")
+                .arg("-run")
+                .arg(format!(
+                    "connect {} {};getLicenseUsage -csv;quit;",
+                    server, port
+                )),
+            None,
+        )?;
 
         let rc = match cmd.status.code() {
             Some(v) => v,
@@ -143,7 +175,7 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
 
                 debug!("dsls.rs:fetch: RE_DSLS_VERSION match on '{}'", line);
                 let version = capt.get(1).map_or("", |m| m.as_str());
-                server_version.insert(server.clone(), version.to_string());
+                srv.version = version.to_string();
             } else if let Some(capt) = RE_DSLS_STATUS.captures(line) {
                 if capt.len() != 2 {
                     error!(
@@ -159,7 +191,7 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
                     "yes" => 1,
                     _ => 0,
                 };
-                server_status.insert(server.clone(), status);
+                srv.status = status;
                 if features_exported {
                     debug!(
                         "dsls.rs:fetch: Features were already exported, skipping for server {}",
@@ -167,6 +199,9 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
                     );
                     break;
                 }
+            } else if let Some(capt) = RE_DSLS_MASTER.captures(line) {
+                debug!("dsls.rs:fetch: RE_DSLS_MASTER match on '{}'", line);
+                master = capt.get(1).map_or("", |m| m.as_str()).to_string();
             } else if line == "admin >getLicenseUsage -csv" {
                 debug!("dsls.rs:fetch: enabling CSV mode");
                 csv_mode = true;
@@ -189,12 +224,12 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    for (server, port) in &server_port {
-        if let Some(status) = server_status.get(server) {
-            if *status == 1 {
-                match fetch_expiration(lic, dslicsrv, server, port) {
+    let refresh_expiration = || {
+        for srv in servers.iter() {
+            if srv.status == 1 {
+                match fetch_expiration(lic, dslicsrv, runner, &srv.fqdn, &srv.port) {
                     Ok(_) => {
-                        break;
+                        return Ok(());
                     }
                     Err(e) => {
                         error!("dsls.rs:fetch: Unable to fetch expiration dates: {}", e);
@@ -202,10 +237,26 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
                 };
             }
         }
+        bail!(
+            "No license server of {} returned expiration dates",
+            lic.name
+        );
+    };
+    if let Err(e) = series::cached(
+        "dsls",
+        &lic.name,
+        lic.expiration_interval.map(Duration::from_secs),
+        &[
+            series::Vector::Gauge(&DSLS_FEATURE_EXPIRATION),
+            series::Vector::Gauge(&DSLS_FEATURE_AGGREGATED_EXPIRATION),
+        ],
+        refresh_expiration,
+    ) {
+        debug!("dsls.rs:fetch: {}", e);
     }
 
     for l in license_data {
-        if license::is_excluded(&lic.excluded_features, l.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&l.feature) {
             debug!(
                 "dsls.rs:fetch: Skipping feature {} because it is in excluded_features list of {}",
                 l.feature, lic.name
@@ -216,14 +267,37 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
         f_used.entry(l.feature.clone()).or_insert(l.inuse);
         f_total.entry(l.feature.clone()).or_insert(l.count);
 
+        // NOTE: The summary line of a feature has no user information, checkouts are reported
+        //       in separate lines
+        if l.user.is_none() {
+            *f_model
+                .entry(l.feature.clone())
+                .or_default()
+                .entry(l.model)
+                .or_insert(0) += l.count;
+        }
+
         if let Some(user) = l.user {
-            let feat = fuv
-                .entry(l.feature.to_string())
-                .or_insert_with(HashMap::<String, i64>::new);
-            *feat.entry(user.to_string()).or_insert(0) += l.count;
+            let feat = fuv.entry(l.feature.to_string()).or_default();
+            *feat.entry(lic.anonymizer.user(&user)).or_insert(0) += l.count;
         }
     }
 
+    stats::features("dsls", &lic.name, f_used.len());
+    stats::users(
+        "dsls",
+        &lic.name,
+        stats::distinct_users(fuv.values().flat_map(|uv| uv.keys())),
+    );
+    topusers::export_fu(&lic.name, &lic.feature_filter, &fuv);
+    topusers::export_user_count(
+        &lic.name,
+        &lic.feature_filter,
+        &DSLS_FEATURES_USER_COUNT,
+        fuv.iter().map(|(f, u)| (f, u.len())),
+    );
+    stats::servers("dsls", &lic.name, servers.iter().count());
+
     for l in f_used.keys() {
         if let Some(used) = f_used.get(l) {
             debug!(
@@ -245,25 +319,26 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    for (k, v) in &server_status {
-        if let Some(port) = server_port.get(k) {
-            if let Some(ver) = server_version.get(k) {
-                debug!(
-                    "dsls.rs:fetch: Setting dsls_server_status {} {} {} {} -> {}",
-                    lic.name, k, port, ver, v
-                );
-                DSLS_SERVER_STATUS
-                    .with_label_values(&[&lic.name, k, port, ver])
-                    .set(*v);
-            }
+    for (feature, models) in f_model.iter() {
+        for (model, count) in models.iter() {
+            debug!(
+                "dsls.rs:fetch: Setting dsls_feature_model_issued {} {} {} -> {}",
+                lic.name, feature, model, count
+            );
+            DSLS_FEATURES_MODEL_TOTAL
+                .with_label_values(&[&lic.name, feature, model])
+                .set(*count);
         }
     }
 
+    set_master(&mut servers, &master);
+    servers.export_status(&lic.name, &DSLS_SERVER_STATUS);
+
     if let Some(export_user) = lic.export_user {
         if export_user {
             for (feat, uv) in fuv.iter() {
                 for (user, count) in uv.iter() {
-                    if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+                    if lic.feature_filter.is_excluded(feat) {
                         debug!("dsls.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
                         continue;
                     }
@@ -282,7 +357,7 @@ pub fn fetch(lic: &config::Dsls, dslicsrv: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn extract_data(line: &str) -> Result<DslsLicenseUsage, Box<dyn Error>> {
+pub fn extract_data(line: &str) -> Result<DslsLicenseUsage, Box<dyn Error>> {
     // Format is:
     // 0      1        2       3     4               5                  6                7                 8                   9               10          11    12    13     14                15   16 ...
     // Editor,EditorId,Feature,Model,Commercial Type,Max Release Number,Max Release Date,Pricing Structure,Max Casual Duration,Expiration Date,Customer ID,Count,Inuse,Tokens,Casual Usage (mn),Host,User,Internal ID,Active Process,Client Code Version,Session ID,Granted Since,Last Used At,Granted At,Queue Position,
@@ -296,6 +371,7 @@ fn extract_data(line: &str) -> Result<DslsLicenseUsage, Box<dyn Error>> {
     }
 
     let feature = splitted[2].to_string();
+    let model = license_model(splitted[3]);
 
     let count: i64 = splitted[11].parse()?;
     let inuse: i64 = splitted[12].parse()?;
@@ -307,15 +383,46 @@ fn extract_data(line: &str) -> Result<DslsLicenseUsage, Box<dyn Error>> {
 
     Ok(DslsLicenseUsage {
         feature,
+        model,
         count,
         inuse,
         user,
     })
 }
 
+// Mark the master reported by the cluster as master. Without failover information, the first
+// ready server is the master, because it serves the licenses.
+fn set_master(servers: &mut servers::ServerTable, master: &str) {
+    let short = |n: &str| n.split('.').next().unwrap_or("").to_lowercase();
+    let mut found = false;
+
+    for srv in servers.iter_mut() {
+        srv.master = !master.is_empty() && short(&srv.fqdn) == short(master);
+        found |= srv.master;
+    }
+
+    if !found {
+        if let Some(srv) = servers.iter_mut().find(|s| s.status == 1) {
+            srv.master = true;
+        }
+    }
+}
+
+// Map the license model reported by DSLS to the normalised license model
+fn license_model(model: &str) -> &'static str {
+    match model.trim().to_lowercase().as_str() {
+        "concurrent" | "concurrentuser" => license::MODEL_FLOATING,
+        "nameduser" => license::MODEL_NAMED_USER,
+        "nodelock" | "nodelocked" => license::MODEL_NODE_LOCKED,
+        "token" | "credit" => license::MODEL_TOKEN,
+        _ => license::MODEL_UNKNOWN,
+    }
+}
+
 fn fetch_expiration(
     lic: &config::Dsls,
     dslicsrv: &str,
+    runner: &dyn command::CommandRunner,
     server: &str,
     port: &str,
 ) -> Result<(), Box<dyn Error>> {
@@ -323,19 +430,21 @@ fn fetch_expiration(
     let mut aggregated_expiration: HashMap<String, Vec<DslsLicenseExpiration>> = HashMap::new();
     let mut expiration_dates = Vec::<f64>::new();
 
-    env::set_var("LANG", "C");
     debug!(
         "dsls.rs:fetch_expiration: Running {} -admin -run \"connect {} {};getLicenseUsage -short -csv;quit;\"",
         dslicsrv, server, port
     );
-    let cmd = Command::new(dslicsrv)
-        .arg("-admin")
-        .arg("-run")
-        .arg(format!(
-            "connect {} {};getLicenseUsage -short -csv;quit;",
-            server, port
-        ))
-        .output()?;
+    let cmd = runner.run(
+        Command::new(dslicsrv)
+            .env("LANG", "C")
+            .arg("-admin")
+            .arg("-run")
+            .arg(format!(
+                "connect {} {};getLicenseUsage -short -csv;quit;",
+                server, port
+            )),
+        None,
+    )?;
 
     let rc = match cmd.status.code() {
         Some(v) => v,
@@ -373,13 +482,12 @@ fn fetch_expiration(
                 let feature = splitted[2].to_string();
                 let expiration_date = splitted[9];
 
-                let expiration =
-                    match NaiveDateTime::parse_from_str(expiration_date, "%Y-%m-%d %H:%M:%S") {
-                        Ok(v) => v.timestamp() as f64,
-                        Err(e) => {
-                            bail!("Can't parse {} as date and time: {}", expiration_date, e);
-                        }
-                    };
+                let expiration = match dates::parse_expiration(expiration_date) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        bail!("Can't parse {} as date and time: {}", expiration_date, e);
+                    }
+                };
 
                 let lcount: i64 = match splitted[11].parse() {
                     Ok(v) => v,
@@ -400,9 +508,7 @@ fn fetch_expiration(
                 });
 
                 let expiration_str = expiration.to_string();
-                let aggregated = aggregated_expiration
-                    .entry(expiration_str)
-                    .or_insert_with(Vec::<DslsLicenseExpiration>::new);
+                let aggregated = aggregated_expiration.entry(expiration_str).or_default();
                 aggregated.push(DslsLicenseExpiration {
                     feature: feature.to_string(),
                     license_count: lcount,
@@ -414,18 +520,14 @@ fn fetch_expiration(
 
     let mut index: i64 = 1;
     for entry in expiring {
-        if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&entry.feature) {
             debug!("dsls.rs:fetch_expiration: Skipping feature {} because it is in excluded_features list of {}", entry.feature, lic.name);
             continue;
         }
 
         debug!(
             "dsls.rs:fetch_expiration: Setting dsls_feature_used_users {} {} {} {} -> {}",
-            lic.name,
-            index,
-            entry.license_count.to_string(),
-            entry.feature,
-            entry.expiration
+            lic.name, index, entry.license_count, entry.feature, entry.expiration
         );
         DSLS_FEATURE_EXPIRATION
             .with_label_values(&[
@@ -470,23 +572,122 @@ fn fetch_expiration(
     Ok(())
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(DSLS_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(DSLS_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(DSLS_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(DSLS_SERVER_STATUS.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(DSLS_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(DSLS_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(DSLS_FEATURES_TOTAL.clone()),
+        Box::new(DSLS_FEATURES_USED.clone()),
+        Box::new(DSLS_FEATURES_USER.clone()),
+        Box::new(DSLS_FEATURES_USER_COUNT.clone()),
+        Box::new(DSLS_SERVER_STATUS.clone()),
+        Box::new(DSLS_FEATURES_MODEL_TOTAL.clone()),
+        Box::new(DSLS_FEATURE_EXPIRATION.clone()),
+        Box::new(DSLS_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(DSLS_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&DSLS_FEATURES_TOTAL),
+        series::Vector::IntGauge(&DSLS_FEATURES_USED),
+        series::Vector::IntGauge(&DSLS_FEATURES_USER),
+        series::Vector::IntGauge(&DSLS_FEATURES_USER_COUNT),
+        series::Vector::IntGauge(&DSLS_SERVER_STATUS),
+        series::Vector::IntGauge(&DSLS_FEATURES_MODEL_TOTAL),
+        series::Vector::Gauge(&DSLS_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&DSLS_FEATURE_AGGREGATED_EXPIRATION),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let cfg = testutil::parse_config(
+            "dsls:\n  - name: dsls\n    license: 4085@lic1\n    export_user: true\n",
+        );
+        let registry = Registry::new();
+        register_into(&registry);
+
+        let runner = command::FixtureRunner::new()
+            .reply(
+                &[
+                    "-admin",
+                    "-run",
+                    "connect lic1 4085;getLicenseUsage -csv;quit;",
+                ],
+                &testutil::fixture("dsls/usage.txt"),
+            )
+            .reply(
+                &[
+                    "-admin",
+                    "-run",
+                    "connect lic1 4085;getLicenseUsage -short -csv;quit;",
+                ],
+                &testutil::fixture("dsls/usage_short.txt"),
+            );
+        fetch(&cfg.dsls.unwrap()[0], "dslicsrv", &runner).unwrap();
+        testutil::assert_golden("dsls", &testutil::render(&registry));
+    }
+
+    #[test]
+    fn license_models() {
+        let data = extract_data("Dassault Systemes,5E756A80-0001,CATIA,NamedUser,STD,426,2023-12-31 00:00:00,YLC,0,2030-12-31 23:59:00,200000000012345,10,2,0,0").unwrap();
+        assert_eq!(data.model, license::MODEL_NAMED_USER);
+
+        assert_eq!(license_model("ConcurrentUser"), license::MODEL_FLOATING);
+        assert_eq!(license_model("Concurrent"), license::MODEL_FLOATING);
+        assert_eq!(license_model("NodeLock"), license::MODEL_NODE_LOCKED);
+        assert_eq!(license_model("Token"), license::MODEL_TOKEN);
+        assert_eq!(license_model("Casual"), license::MODEL_UNKNOWN);
+    }
+
+    fn masters(servers: &servers::ServerTable) -> Vec<&str> {
+        servers
+            .iter()
+            .filter(|s| s.master)
+            .map(|s| s.fqdn.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn master_election() {
+        let mut servers =
+            servers::ServerTable::from_license("4085@lic1:4085@lic2.example.com:4085@lic3", "");
+        for srv in servers.iter_mut() {
+            srv.status = i64::from(srv.fqdn != "lic1");
+        }
+
+        // Master reported by the connect output of a failover cluster
+        let raw = String::from_utf8(testutil::fixture("dsls/status_failover.txt")).unwrap();
+        let master: Vec<&str> = raw
+            .lines()
+            .filter_map(|l| RE_DSLS_MASTER.captures(l))
+            .filter_map(|c| c.get(1))
+            .map(|m| m.as_str())
+            .collect();
+        assert_eq!(master, vec!["lic2.example.com"]);
+
+        set_master(&mut servers, master[0]);
+        assert_eq!(masters(&servers), vec!["lic2.example.com"]);
+
+        // First ready server without failover information
+        set_master(&mut servers, "");
+        assert_eq!(masters(&servers), vec!["lic2.example.com"]);
+
+        set_master(&mut servers, "LIC3");
+        assert_eq!(masters(&servers), vec!["lic3"]);
+    }
 }
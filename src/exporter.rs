@@ -1,63 +1,132 @@
+use crate::backend;
+use crate::chaos;
+use crate::command;
 use crate::config;
 use crate::constants;
 use crate::dsls;
 use crate::flexlm;
 use crate::hasp;
+use crate::history;
+use crate::hooks;
+use crate::http;
+use crate::license;
 use crate::licman20;
 use crate::lmx;
 use crate::olicense;
+use crate::peersync;
+use crate::resolver;
 use crate::rlm;
+use crate::runtime;
+use crate::series;
+use crate::stats;
+use crate::status;
+use crate::tls;
+use crate::topusers;
 
 use lazy_static::lazy_static;
-use log::error;
-use prometheus::{Registry, TextEncoder};
+use log::{debug, error, warn};
+use prometheus::core::Collector;
+use prometheus::proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
 // Global registry
 lazy_static! {
     pub static ref REGISTRY: Registry = Registry::new();
+    // (backend, name) of licenses removed by a configuration reload
+    static ref REMOVED: Mutex<HashSet<(String, String)>> = Mutex::new(HashSet::new());
+    pub static ref EXCLUDED_FEATURES: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            constants::EXCLUDED_FEATURES_METRIC,
+            "Number of features skipped by excluded_features or include_features in the last scrape"
+        ),
+        &["app", "backend"],
+    )
+    .unwrap();
+}
+
+// Apply the settings of cfg used by all backends, on start and on every reload
+pub fn configure(cfg: &config::Configuration) {
+    resolver::configure(cfg);
+    command::configure(cfg);
+    topusers::configure(cfg);
 }
 
 pub fn register(cfg: &config::Configuration) {
-    if let Some(flexlm) = &cfg.flexlm {
-        if !flexlm.is_empty() {
-            flexlm::register()
-        }
-    }
+    REGISTRY
+        .register(Box::new(EXCLUDED_FEATURES.clone()))
+        .unwrap();
+    stats::register_into(&REGISTRY);
+    command::register_into(&REGISTRY);
+    peersync::register_into(&REGISTRY);
+    tls::register_into(&REGISTRY);
+    topusers::register_into(&REGISTRY);
 
-    if let Some(rlm) = &cfg.rlm {
-        if !rlm.is_empty() {
-            rlm::register()
+    for backend in config::BACKENDS {
+        if !cfg.license_names(backend).is_empty() {
+            register_backend(backend);
         }
     }
+}
 
-    if let Some(lmx) = &cfg.lmx {
-        if !lmx.is_empty() {
-            lmx::register()
+// Update the registered metrics after a configuration reload. The metrics of backends
+// without licenses are unregistered and series of removed licenses aren't exported anymore.
+pub fn reregister(old: &config::Configuration, new: &config::Configuration) {
+    let mut removed = match REMOVED.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("exporter.rs:reregister: Can't lock removed licenses: {}", e);
+            return;
         }
-    }
+    };
+
+    for backend in config::BACKENDS {
+        let old_names = old.license_names(backend);
+        let new_names = new.license_names(backend);
 
-    if let Some(dsls) = &cfg.dsls {
-        if !dsls.is_empty() {
-            dsls::register()
+        if old_names.is_empty() && !new_names.is_empty() {
+            debug!("exporter.rs:reregister: Registering metrics of {}", backend);
+            register_backend(backend);
+        }
+        if !old_names.is_empty() && new_names.is_empty() {
+            debug!(
+                "exporter.rs:reregister: Unregistering metrics of {}",
+                backend
+            );
+            unregister_backend(backend);
         }
-    }
 
-    if let Some(licman20) = &cfg.licman20 {
-        if !licman20.is_empty() {
-            licman20::register()
+        for name in old_names.iter().filter(|n| !new_names.contains(n)) {
+            series::forget(backend, name);
+            topusers::forget(name);
+            removed.insert((backend.to_string(), name.to_string()));
+        }
+        for name in new_names {
+            removed.remove(&(backend.to_string(), name));
         }
     }
+}
 
-    if let Some(hasp) = &cfg.hasp {
-        if !hasp.is_empty() {
-            hasp::register();
+fn unregister_backend(backend: &str) {
+    let collectors = backend::by_name(backend).map_or_else(Vec::new, |b| b.collectors());
+
+    for collector in collectors {
+        if let Err(e) = REGISTRY.unregister(collector) {
+            error!(
+                "exporter.rs:unregister_backend: Can't unregister metric of {}: {}",
+                backend, e
+            );
         }
     }
+}
 
-    if let Some(olicense) = &cfg.olicense {
-        if !olicense.is_empty() {
-            olicense::register()
-        }
+fn register_backend(backend: &str) {
+    if let Some(b) = backend::by_name(backend) {
+        b.register_into(&REGISTRY);
     }
 }
 
@@ -65,151 +134,1069 @@ pub fn metrics(cfg: &config::Configuration) -> String {
     let encoder = TextEncoder::new();
     let mut buffer = String::new();
 
-    if let Some(flexlm) = &cfg.flexlm {
-        let mut lmutil = constants::DEFAULT_LMUTIL.to_string();
-        if let Some(glob) = cfg.global.clone() {
-            if let Some(_lmutil) = glob.lmutil {
-                lmutil = _lmutil;
-            }
+    collect(cfg);
+
+    let mut license_metrics = gather();
+    drop_removed(&mut license_metrics);
+    add_server_info(&mut license_metrics);
+    let mut process_metrics = prometheus::gather();
+    if let Some(glob) = &cfg.global {
+        if glob.export_expiration_info.unwrap_or(false) {
+            add_expiration_info(&mut license_metrics);
+        }
+        if !glob.project_filters.is_empty() {
+            add_project_usage(&mut license_metrics, &glob.project_filters);
+        }
+        if glob.export_totals.unwrap_or(false) {
+            add_totals(&mut license_metrics);
+        }
+        if glob.anomaly_detection.unwrap_or(false) {
+            add_usage_anomalies(&mut license_metrics);
         }
+        if let Some(hooks) = &glob.hooks {
+            hooks::run(hooks, &mut license_metrics);
+        }
+        customise_metrics(&mut license_metrics, glob);
+        customise_metrics(&mut process_metrics, glob);
+    }
 
-        for flex in flexlm {
-            match flexlm::fetch(flex, &lmutil) {
-                Ok(_) => {}
+    if let Err(e) = encoder.encode_utf8(&license_metrics, &mut buffer) {
+        error!(
+            "exporter.rs:metrics: Can't encode metrics as UTF8 string: {}",
+            e
+        );
+    }
+
+    if let Err(e) = encoder.encode_utf8(&process_metrics, &mut buffer) {
+        error!(
+            "exporter.rs:metrics: Can't encode metrics as UTF8 string: {}",
+            e
+        );
+    };
+    buffer
+}
+
+// Fetch the license information of all configured licenses, up to global.parallelism licenses
+// are queried at the same time
+fn collect(cfg: &config::Configuration) {
+    let global = cfg.global.as_ref();
+    let lmutil = global
+        .and_then(|g| g.lmutil.clone())
+        .unwrap_or_else(|| constants::DEFAULT_LMUTIL.to_string());
+    let rlmutil = global
+        .and_then(|g| g.rlmutil.clone())
+        .unwrap_or_else(|| constants::DEFAULT_RLMUTIL.to_string());
+    let lmxendutil = global
+        .and_then(|g| g.lmxendutil.clone())
+        .unwrap_or_else(|| constants::DEFAULT_LMXENDUTIL.to_string());
+    let dslicsrv = global
+        .and_then(|g| g.dslicsrv.clone())
+        .unwrap_or_else(|| constants::DEFAULT_DSLICSRV.to_string());
+    let licman20_appl = global
+        .and_then(|g| g.licman20_appl.clone())
+        .unwrap_or_else(|| constants::DEFAULT_LICMAN20_APPL.to_string());
+
+    let mut jobs: Vec<Box<dyn Fn() + Sync + '_>> = Vec::new();
+
+    for flex in cfg.flexlm.iter().flatten() {
+        let lmutil = flex.lmutil.as_deref().unwrap_or(&lmutil);
+        jobs.push(Box::new(move || {
+            match replace_series("flexlm", &flex.name, flex.on_failure, || {
+                chaos::inject(&flex.name).and_then(|_| fetch_flexlm(flex, lmutil))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&flexlm::FLEXLM_LAST_UPDATED, &flex.name);
+                    set_excluded_features("flexlm", &flex.name, &flex.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch FlexLM license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch FlexLM license information for {}: {}",
                         flex.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(rlm) = &cfg.rlm {
-        let mut rlmutil = constants::DEFAULT_RLMUTIL.to_string();
-        if let Some(glob) = cfg.global.clone() {
-            if let Some(_rlmutil) = glob.rlmutil {
-                rlmutil = _rlmutil;
-            }
-        }
-
-        for _rlm in rlm {
-            match rlm::fetch(_rlm, &rlmutil) {
-                Ok(_) => {}
+    for _rlm in cfg.rlm.iter().flatten() {
+        let rlmutil = _rlm.rlmutil.as_deref().unwrap_or(&rlmutil);
+        jobs.push(Box::new(move || {
+            match replace_series("rlm", &_rlm.name, _rlm.on_failure, || {
+                chaos::inject(&_rlm.name)
+                    .and_then(|_| rlm::fetch(_rlm, rlmutil, &command::SystemRunner))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&rlm::RLM_LAST_UPDATED, &_rlm.name);
+                    set_excluded_features("rlm", &_rlm.name, &_rlm.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch RLM license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch RLM license information for {}: {}",
                         _rlm.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(lmx) = &cfg.lmx {
-        let mut lmxendutil = constants::DEFAULT_LMXENDUTIL.to_string();
-        if let Some(glob) = cfg.global.clone() {
-            if let Some(_lmxendutil) = glob.lmxendutil {
-                lmxendutil = _lmxendutil;
-            }
-        }
-
-        for _lmx in lmx {
-            match lmx::fetch(_lmx, &lmxendutil) {
-                Ok(_) => {}
+    for _lmx in cfg.lmx.iter().flatten() {
+        let lmxendutil = _lmx.lmxendutil.as_deref().unwrap_or(&lmxendutil);
+        jobs.push(Box::new(move || {
+            match replace_series("lmx", &_lmx.name, _lmx.on_failure, || {
+                chaos::inject(&_lmx.name)
+                    .and_then(|_| lmx::fetch(_lmx, lmxendutil, &command::SystemRunner))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&lmx::LMX_LAST_UPDATED, &_lmx.name);
+                    set_excluded_features("lmx", &_lmx.name, &_lmx.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch LM-X license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch LM-X license information for {}: {}",
                         _lmx.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(dsls) = &cfg.dsls {
-        let mut dslicsrv = constants::DEFAULT_DSLICSRV.to_string();
-        if let Some(glob) = cfg.global.clone() {
-            if let Some(_dslicsrv) = glob.dslicsrv {
-                dslicsrv = _dslicsrv;
-            }
-        }
-
-        for _dsls in dsls {
-            match dsls::fetch(_dsls, &dslicsrv) {
-                Ok(_) => {}
+    for _dsls in cfg.dsls.iter().flatten() {
+        let dslicsrv = _dsls.dslicsrv.as_deref().unwrap_or(&dslicsrv);
+        jobs.push(Box::new(move || {
+            match replace_series("dsls", &_dsls.name, _dsls.on_failure, || {
+                chaos::inject(&_dsls.name)
+                    .and_then(|_| dsls::fetch(_dsls, dslicsrv, &command::SystemRunner))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&dsls::DSLS_LAST_UPDATED, &_dsls.name);
+                    set_excluded_features("dsls", &_dsls.name, &_dsls.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch DSLS license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch DSLS license information for {}: {}",
                         _dsls.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(licman20) = &cfg.licman20 {
-        let mut licman20_appl = constants::DEFAULT_LICMAN20_APPL.to_string();
-        if let Some(glob) = cfg.global.clone() {
-            if let Some(_licman20_appl) = glob.licman20_appl {
-                licman20_appl = _licman20_appl;
-            }
-        }
-
-        for _licman20 in licman20 {
-            match licman20::fetch(_licman20, &licman20_appl) {
-                Ok(_) => {}
+    for _licman20 in cfg.licman20.iter().flatten() {
+        let licman20_appl = _licman20.licman20_appl.as_deref().unwrap_or(&licman20_appl);
+        jobs.push(Box::new(move || {
+            match replace_series("licman20", &_licman20.name, _licman20.on_failure, || {
+                chaos::inject(&_licman20.name)
+                    .and_then(|_| licman20::fetch(_licman20, licman20_appl, &command::SystemRunner))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&licman20::LICMAN20_LAST_UPDATED, &_licman20.name);
+                    set_excluded_features("licman20", &_licman20.name, &_licman20.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch Licman20 license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch Licman20 license information for {}: {}",
                         _licman20.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(hasp) = &cfg.hasp {
-        for _hasp in hasp {
-            match hasp::fetch(_hasp) {
-                Ok(_) => {}
+    for _hasp in cfg.hasp.iter().flatten() {
+        jobs.push(Box::new(move || {
+            let proxy = http::ProxySettings::new(&cfg.global, &_hasp.proxy);
+            match replace_series("hasp", &_hasp.name, _hasp.on_failure, || {
+                chaos::inject(&_hasp.name).and_then(|_| hasp::fetch(_hasp, &proxy))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&hasp::HASP_LAST_UPDATED, &_hasp.name);
+                    set_excluded_features("hasp", &_hasp.name, &_hasp.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch HASP license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch HASP license information for {}: {}",
                         _hasp.name, e
                     );
                 }
             };
-        }
+        }));
     }
 
-    if let Some(olicense) = &cfg.olicense {
-        for _olic in olicense {
-            match olicense::fetch(_olic) {
-                Ok(_) => {}
+    for _olic in cfg.olicense.iter().flatten() {
+        jobs.push(Box::new(move || {
+            let proxy = http::ProxySettings::new(&cfg.global, &_olic.proxy);
+            match replace_series("olicense", &_olic.name, _olic.on_failure, || {
+                chaos::inject(&_olic.name).and_then(|_| olicense::fetch(_olic, &proxy))
+            }) {
+                Ok(_) => {
+                    set_last_updated(&olicense::OLICENSE_LAST_UPDATED, &_olic.name);
+                    set_excluded_features("olicense", &_olic.name, &_olic.feature_filter);
+                }
                 Err(e) => {
                     error!(
-                        "exporter.rs:metrics: Can't fetch OLicense license information for {}: {}",
+                        "exporter.rs:collect: Can't fetch OLicense license information for {}: {}",
                         _olic.name, e
                     );
                 }
             };
+        }));
+    }
+
+    run_jobs(&jobs, cfg.parallelism());
+}
+
+// Fetch a license with its previous series removed, so only current series are exported.
+// If the fetch fails, the previous series are handled according to the on_failure setting of
+// the license.
+fn replace_series<F>(
+    backend: &str,
+    name: &str,
+    on_failure: Option<config::OnFailure>,
+    fetch: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn Error>>,
+{
+    let mut vectors = backend::by_name(backend).map_or_else(Vec::new, |b| b.vectors());
+    vectors.push(series::Vector::IntGauge(&topusers::TOP_USER_SEATS));
+    let _replacing = series::replacing(backend, name);
+    let snapshot = series::take(&vectors, name);
+    let histograms = topusers::take_histograms(Some(name));
+
+    let result = fetch();
+    status::record(backend, name, &result);
+    if result.is_err() {
+        match on_failure.unwrap_or_default() {
+            config::OnFailure::Drop => {
+                debug!("exporter.rs:replace_series: Dropping series of {}", name);
+            }
+            config::OnFailure::Keep => {
+                snapshot.restore();
+                histograms.restore();
+            }
+            config::OnFailure::Zero => {
+                snapshot.restore_zero();
+                histograms.restore_zero();
+            }
+        };
+    }
+    result
+}
+
+// Run jobs with up to parallelism threads
+fn run_jobs(jobs: &[Box<dyn Fn() + Sync + '_>], parallelism: usize) {
+    let next = AtomicUsize::new(0);
+    runtime::queue(jobs.len());
+
+    thread::scope(|scope| {
+        for _ in 0..parallelism.min(jobs.len()) {
+            scope.spawn(|| {
+                while let Some(job) = jobs.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let _busy = runtime::Busy::start();
+                    job();
+                }
+            });
         }
+    });
+}
+
+// Licenses with native: true are queried without lmutil, see flexlm_native.rs
+#[cfg(feature = "experimental_native_flexlm")]
+fn fetch_flexlm(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
+    if lic.native.unwrap_or(false) {
+        return crate::flexlm_native::fetch(lic);
     }
+    flexlm::fetch(lic, lmutil, &command::SystemRunner)
+}
 
-    if let Err(e) = encoder.encode_utf8(&REGISTRY.gather(), &mut buffer) {
-        error!(
-            "exporter.rs:metrics: Can't encode metrics as UTF8 string: {}",
-            e
-        );
+#[cfg(not(feature = "experimental_native_flexlm"))]
+fn fetch_flexlm(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
+    flexlm::fetch(lic, lmutil, &command::SystemRunner)
+}
+
+fn set_last_updated(gauge: &GaugeVec, name: &str) {
+    let now = chrono::Local::now().timestamp() as f64;
+    debug!(
+        "exporter.rs:set_last_updated: Setting {} {} -> {}",
+        gauge.desc()[0].fq_name,
+        name,
+        now
+    );
+    gauge.with_label_values(&[name]).set(now);
+}
+
+fn set_excluded_features(backend: &str, name: &str, filter: &license::FeatureFilter) {
+    let excluded = filter.take_excluded() as i64;
+    debug!(
+        "exporter.rs:set_excluded_features: Setting {} {} {} -> {}",
+        constants::EXCLUDED_FEATURES_METRIC,
+        name,
+        backend,
+        excluded
+    );
+    EXCLUDED_FEATURES
+        .with_label_values(&[name, backend])
+        .set(excluded);
+}
+
+// Derive *_feature_expiration_info{app,name,date} from the *_feature_expiration_seconds metrics
+fn add_expiration_info(families: &mut Vec<MetricFamily>) {
+    let mut info_families: Vec<MetricFamily> = Vec::new();
+
+    for mf in families.iter() {
+        let prefix = match mf
+            .get_name()
+            .strip_suffix(constants::EXPIRATION_METRIC_SUFFIX)
+        {
+            Some(v) => v,
+            None => continue,
+        };
+
+        // Several license entries of a feature can share the same expiration date
+        let mut dates: BTreeSet<(String, String, String)> = BTreeSet::new();
+        for m in mf.get_metric() {
+            dates.insert((
+                label_value(m, "app").to_string(),
+                label_value(m, "name").to_string(),
+                format_expiration(m.get_gauge().get_value()),
+            ));
+        }
+
+        let mut info = MetricFamily::new();
+        info.set_name(format!(
+            "{}{}",
+            prefix,
+            constants::EXPIRATION_INFO_METRIC_SUFFIX
+        ));
+        info.set_help("Expiration date of license features".to_string());
+        info.set_field_type(MetricType::GAUGE);
+
+        for (app, name, date) in dates {
+            debug!(
+                "exporter.rs:add_expiration_info: Setting {} {} {} {} -> 1",
+                info.get_name(),
+                app,
+                name,
+                date
+            );
+            info.mut_metric().push(new_gauge_metric(
+                &[("app", &app), ("date", &date), ("name", &name)],
+                1.0,
+            ));
+        }
+        info_families.push(info);
     }
 
-    if let Err(e) = encoder.encode_utf8(&prometheus::gather(), &mut buffer) {
-        error!(
-            "exporter.rs:metrics: Can't encode metrics as UTF8 string: {}",
-            e
+    families.extend(info_families);
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+}
+
+// Software version of the license servers as <backend>_server_info, taken from the version label
+// of <backend>_server_status. Servers with unknown version, e.g. servers that are down or HASP
+// servers, are skipped.
+fn add_server_info(families: &mut Vec<MetricFamily>) {
+    let mut info_families: Vec<MetricFamily> = Vec::new();
+
+    for mf in families.iter() {
+        let backend = match mf
+            .get_name()
+            .strip_suffix(constants::SERVER_STATUS_METRIC_SUFFIX)
+        {
+            Some(v) if config::BACKENDS.contains(&v) => v,
+            _ => continue,
+        };
+
+        let mut servers: BTreeSet<(String, String, String)> = BTreeSet::new();
+        for m in mf.get_metric() {
+            let version = label_value(m, "version");
+            if version.is_empty() {
+                continue;
+            }
+            servers.insert((
+                label_value(m, "app").to_string(),
+                label_value(m, "fqdn").to_string(),
+                version.to_string(),
+            ));
+        }
+        if servers.is_empty() {
+            continue;
+        }
+
+        let mut info = MetricFamily::new();
+        info.set_name(format!(
+            "{}{}",
+            backend,
+            constants::SERVER_INFO_METRIC_SUFFIX
+        ));
+        info.set_help("Software version of license servers".to_string());
+        info.set_field_type(MetricType::GAUGE);
+
+        for (app, fqdn, version) in servers {
+            debug!(
+                "exporter.rs:add_server_info: Setting {} {} {} {} -> 1",
+                info.get_name(),
+                app,
+                fqdn,
+                version
+            );
+            info.mut_metric().push(new_gauge_metric(
+                &[
+                    ("app", &app),
+                    ("backend", backend),
+                    ("fqdn", &fqdn),
+                    ("version", &version),
+                ],
+                1.0,
+            ));
+        }
+        info_families.push(info);
+    }
+
+    families.extend(info_families);
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+}
+
+// Sum *_feature_used of all features mapped to a project as license_project_used{app,project}
+fn add_project_usage(
+    families: &mut Vec<MetricFamily>,
+    projects: &[(String, license::FeatureFilter)],
+) {
+    let mut usage: BTreeMap<(String, String), f64> = BTreeMap::new();
+
+    for mf in families.iter() {
+        if !mf
+            .get_name()
+            .ends_with(constants::FEATURE_USED_METRIC_SUFFIX)
+        {
+            continue;
+        }
+
+        for m in mf.get_metric() {
+            let feature = label_value(m, "name");
+            for (project, filter) in projects {
+                if filter.matches(feature) {
+                    *usage
+                        .entry((label_value(m, "app").to_string(), project.to_string()))
+                        .or_insert(0.0) += m.get_gauge().get_value();
+                }
+            }
+        }
+    }
+
+    let mut project_used = MetricFamily::new();
+    project_used.set_name(constants::PROJECT_USED_METRIC.to_string());
+    project_used.set_help("Number of used licenses of all features of a project".to_string());
+    project_used.set_field_type(MetricType::GAUGE);
+
+    for ((app, project), used) in usage {
+        debug!(
+            "exporter.rs:add_project_usage: Setting {} {} {} -> {}",
+            constants::PROJECT_USED_METRIC,
+            app,
+            project,
+            used
         );
+        project_used.mut_metric().push(new_gauge_metric(
+            &[("app", &app), ("project", &project)],
+            used,
+        ));
+    }
+
+    if !project_used.get_metric().is_empty() {
+        families.push(project_used);
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    }
+}
+
+// Sum of the issued and used licenses of all licenses of a backend. Excluded features aren't
+// exported, so they aren't part of the totals either. Licman20 reports the licenses of a product
+// key for every feature of the key, they are only counted once per product key.
+fn add_totals(families: &mut Vec<MetricFamily>) {
+    let mut totals = Vec::new();
+
+    for (suffix, name, help) in [
+        (
+            constants::FEATURE_ISSUED_METRIC_SUFFIX,
+            constants::TOTAL_SEATS_ISSUED_METRIC,
+            "Total number of issued licenses of all licenses of a backend",
+        ),
+        (
+            constants::FEATURE_USED_METRIC_SUFFIX,
+            constants::TOTAL_SEATS_USED_METRIC,
+            "Number of used licenses of all licenses of a backend",
+        ),
+    ] {
+        let mut total = MetricFamily::new();
+        total.set_name(name.to_string());
+        total.set_help(help.to_string());
+        total.set_field_type(MetricType::GAUGE);
+
+        for backend in config::BACKENDS {
+            let metric_name = format!("{}{}", backend, suffix);
+            let mf = match families.iter().find(|mf| mf.get_name() == metric_name) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let mut product_keys: HashSet<(&str, &str)> = HashSet::new();
+            let sum: f64 = mf
+                .get_metric()
+                .iter()
+                .filter(|m| {
+                    let product_key = label_value(m, "product_key");
+                    product_key.is_empty()
+                        || product_keys.insert((label_value(m, "app"), product_key))
+                })
+                .map(|m| m.get_gauge().get_value())
+                .sum();
+            debug!(
+                "exporter.rs:add_totals: Setting {} {} -> {}",
+                name, backend, sum
+            );
+            total
+                .mut_metric()
+                .push(new_gauge_metric(&[("backend", backend)], sum));
+        }
+
+        if !total.get_metric().is_empty() {
+            totals.push(total);
+        }
+    }
+
+    if !totals.is_empty() {
+        families.extend(totals);
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    }
+}
+
+// Flag sudden drops to zero and usage above the historical maximum of the *_feature_used
+// metrics as license_feature_usage_anomaly{app,name}, see history.rs. Series of a feature with
+// further labels, e.g. the versions of RLM features, are added up. The history gets a sample
+// per successful fetch of the license, not per scrape.
+fn add_usage_anomalies(families: &mut Vec<MetricFamily>) {
+    let mut anomalies = MetricFamily::new();
+    anomalies.set_name(constants::USAGE_ANOMALY_METRIC.to_string());
+    anomalies.set_help(
+        "1 if the usage of the feature dropped to zero or exceeds its historical maximum"
+            .to_string(),
+    );
+    anomalies.set_field_type(MetricType::GAUGE);
+
+    // (metric, app, name) -> used
+    let mut usage: BTreeMap<(&str, &str, &str), f64> = BTreeMap::new();
+    for mf in families.iter() {
+        if !mf
+            .get_name()
+            .ends_with(constants::FEATURE_USED_METRIC_SUFFIX)
+        {
+            continue;
+        }
+
+        for m in mf.get_metric() {
+            *usage
+                .entry((mf.get_name(), label_value(m, "app"), label_value(m, "name")))
+                .or_insert(0.0) += m.get_gauge().get_value();
+        }
+    }
+
+    // Licenses of different backends can have the same name, (app, name) -> anomaly
+    let mut flags: BTreeMap<(&str, &str), f64> = BTreeMap::new();
+    for ((metric, app, name), used) in usage {
+        let backend = metric
+            .strip_suffix(constants::FEATURE_USED_METRIC_SUFFIX)
+            .unwrap_or(metric);
+        let fetched = status::last_scrape(backend, app)
+            .filter(|s| s.error.is_none())
+            .map(|s| s.time);
+
+        let value = match history::record(metric, app, name, used, fetched) {
+            Some(anomaly) => {
+                warn!(
+                    "exporter.rs:add_usage_anomalies: Usage of feature {} of {} {}: {}",
+                    name,
+                    app,
+                    anomaly.as_str(),
+                    used
+                );
+                1.0
+            }
+            None => 0.0,
+        };
+        let flag = flags.entry((app, name)).or_insert(0.0);
+        *flag = flag.max(value);
+    }
+
+    for ((app, name), value) in flags {
+        anomalies
+            .mut_metric()
+            .push(new_gauge_metric(&[("app", app), ("name", name)], value));
+    }
+
+    if !anomalies.get_metric().is_empty() {
+        families.push(anomalies);
+        families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+    }
+}
+
+// Gather the license metrics, series of licenses being fetched by another scrape are awaited
+pub fn gather() -> Vec<MetricFamily> {
+    let _gathering = series::gathering();
+    REGISTRY.gather()
+}
+
+// The metrics of a removed license keep their last values, skip them. Metrics of a backend
+// are prefixed with the backend name, the license_exporter_* metrics have a backend label.
+fn drop_removed(families: &mut Vec<MetricFamily>) {
+    let removed = match REMOVED.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "exporter.rs:drop_removed: Can't lock removed licenses: {}",
+                e
+            );
+            return;
+        }
     };
-    buffer
+    if removed.is_empty() {
+        return;
+    }
+
+    for mf in families.iter_mut() {
+        let prefix = config::BACKENDS
+            .iter()
+            .find(|b| mf.get_name().starts_with(&format!("{}_", b)))
+            .map(|b| b.to_string());
+
+        let metrics: Vec<Metric> = mf
+            .take_metric()
+            .into_iter()
+            .filter(|m| {
+                let backend = match &prefix {
+                    Some(v) => v.as_str(),
+                    None => label_value(m, "backend"),
+                };
+                !removed.contains(&(backend.to_string(), label_value(m, "app").to_string()))
+            })
+            .collect();
+        mf.set_metric(metrics.into());
+    }
+
+    families.retain(|mf| !mf.get_metric().is_empty());
+}
+
+fn label_value<'a>(m: &'a Metric, name: &str) -> &'a str {
+    m.get_label()
+        .iter()
+        .find(|l| l.get_name() == name)
+        .map_or("", |l| l.get_value())
+}
+
+fn new_gauge_metric(labels: &[(&str, &str)], value: f64) -> Metric {
+    let mut pairs: Vec<LabelPair> = Vec::new();
+    for (name, lvalue) in labels {
+        let mut pair = LabelPair::new();
+        pair.set_name(name.to_string());
+        pair.set_value(lvalue.to_string());
+        pairs.push(pair);
+    }
+
+    let mut gauge = Gauge::new();
+    gauge.set_value(value);
+
+    let mut m = Metric::new();
+    m.set_label(pairs.into());
+    m.set_gauge(gauge);
+    m
+}
+
+fn format_expiration(expiration: f64) -> String {
+    if !expiration.is_finite() {
+        return constants::EXPIRATION_DATE_PERMANENT.to_string();
+    }
+
+    match chrono::DateTime::from_timestamp(expiration as i64, 0) {
+        Some(v) => v.format(constants::EXPIRATION_DATE_FORMAT).to_string(),
+        None => constants::EXPIRATION_DATE_PERMANENT.to_string(),
+    }
+}
+
+pub fn customise_metrics(families: &mut [MetricFamily], glob: &config::GlobalConfiguration) {
+    let empty = HashMap::new();
+    let help = glob.metric_help.as_ref().unwrap_or(&empty);
+    let labels = glob.constant_labels.as_ref().unwrap_or(&empty);
+
+    for mf in families.iter_mut() {
+        if let Some(h) = help.get(mf.get_name()) {
+            mf.set_help(h.to_string());
+        }
+        // Derived metrics, hooks and metric_help use the names without prefix
+        if let Some(prefix) = &glob.metric_prefix {
+            let name = format!("{}{}", prefix, mf.get_name());
+            mf.set_name(name);
+        }
+
+        if labels.is_empty() {
+            continue;
+        }
+
+        for m in mf.mut_metric().iter_mut() {
+            let mut pairs: Vec<LabelPair> = m.take_label().into();
+            for (name, value) in labels {
+                // Never overwrite labels set by the exporter itself
+                if pairs.iter().any(|p| p.get_name() == name) {
+                    continue;
+                }
+                let mut pair = LabelPair::new();
+                pair.set_name(name.to_string());
+                pair.set_value(value.to_string());
+                pairs.push(pair);
+            }
+            pairs.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+            m.set_label(pairs.into());
+        }
+    }
+}
+
+// Sample set without the series identifying users, e.g. *_feature_used_users, and the FlexLM
+// reservations and policy rules for single users, which carry the user name in the group label.
+// The sample set is filtered as text because it can come from the cache of the background
+// collection or from the primary of a hot standby.
+pub fn without_user_data(sample: &str) -> String {
+    let mut result = String::with_capacity(sample.len());
+    for line in sample.lines() {
+        if !line.starts_with('#') && identifies_user(line) {
+            continue;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result
+}
+
+fn identifies_user(line: &str) -> bool {
+    let labels = match line.split_once('{') {
+        Some((name, labels)) if !name.contains(' ') => labels,
+        _ => return false,
+    };
+
+    let mut name = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut escaped = false;
+    for c in labels.chars() {
+        if in_value {
+            if escaped {
+                escaped = false;
+                value.push(c);
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_value = false;
+                if name == constants::TYPE_LABEL && value == constants::USER_TYPE {
+                    return true;
+                }
+            } else {
+                value.push(c);
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_value = true;
+                value.clear();
+            }
+            '=' => {
+                if name.trim() == constants::USER_LABEL {
+                    return true;
+                }
+                name = name.trim().to_string();
+            }
+            ',' => name.clear(),
+            '}' => break,
+            _ => name.push(c),
+        };
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn remove_user_data() {
+        let sample = "# HELP flexlm_feature_used_users Number of licenses used by user\n\
+            flexlm_feature_used_users{app=\"flex1\",name=\"f1\",user=\"alice\",version=\"1\"} 1\n\
+            flexlm_feature_used{app=\"flex1\",name=\"user=\\\"x\\\",user=\"} 3\n\
+            license_top_user_seats{app=\"flex1\",rank=\"1\",user=\"bob\"} 2\n\
+            process_open_fds 12\n";
+        assert_eq!(
+            without_user_data(sample),
+            "# HELP flexlm_feature_used_users Number of licenses used by user\n\
+            flexlm_feature_used{app=\"flex1\",name=\"user=\\\"x\\\",user=\"} 3\n\
+            process_open_fds 12\n"
+        );
+    }
+
+    #[test]
+    fn run_jobs_parallelism() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let finished = AtomicUsize::new(0);
+
+        let job = || {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            finished.fetch_add(1, Ordering::SeqCst);
+        };
+        let jobs: Vec<Box<dyn Fn() + Sync + '_>> = (0..6)
+            .map(|_| Box::new(job) as Box<dyn Fn() + Sync>)
+            .collect();
+
+        run_jobs(&jobs, 2);
+        assert_eq!(finished.load(Ordering::SeqCst), 6);
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        assert!(max_running.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[test]
+    fn totals() {
+        let registry = Registry::new();
+        for name in [
+            "flexlm_feature_issued",
+            "flexlm_feature_used",
+            "rlm_feature_used",
+        ] {
+            let gauge = IntGaugeVec::new(Opts::new(name, "test"), &["app", "name"]).unwrap();
+            registry.register(Box::new(gauge.clone())).unwrap();
+            gauge.with_label_values(&["lic1", "feat1"]).set(10);
+            gauge.with_label_values(&["lic2", "feat1"]).set(5);
+        }
+
+        // The licenses of a product key are reported for each of its features
+        let licman20 = IntGaugeVec::new(
+            Opts::new("licman20_feature_issued", "test"),
+            &["app", "name", "product_key"],
+        )
+        .unwrap();
+        registry.register(Box::new(licman20.clone())).unwrap();
+        licman20
+            .with_label_values(&["lic1", "feat1", "key1"])
+            .set(4);
+        licman20
+            .with_label_values(&["lic1", "feat2", "key1"])
+            .set(4);
+        licman20
+            .with_label_values(&["lic1", "feat1", "key2"])
+            .set(2);
+        licman20
+            .with_label_values(&["lic2", "feat1", "key1"])
+            .set(1);
+
+        let mut families = registry.gather();
+        add_totals(&mut families);
+
+        let mut buffer = String::new();
+        TextEncoder::new()
+            .encode_utf8(
+                &families
+                    .into_iter()
+                    .filter(|mf| mf.get_name().starts_with("license_total"))
+                    .collect::<Vec<_>>(),
+                &mut buffer,
+            )
+            .unwrap();
+        assert_eq!(
+            buffer,
+            "# HELP license_total_seats_issued Total number of issued licenses of all licenses of a backend
+# TYPE license_total_seats_issued gauge
+license_total_seats_issued{backend=\"flexlm\"} 15
+license_total_seats_issued{backend=\"licman20\"} 7
+# HELP license_total_seats_used Number of used licenses of all licenses of a backend
+# TYPE license_total_seats_used gauge
+license_total_seats_used{backend=\"flexlm\"} 15
+license_total_seats_used{backend=\"rlm\"} 15
+"
+        );
+    }
+
+    #[test]
+    fn usage_anomalies() {
+        let registry = Registry::new();
+        let gauge = IntGaugeVec::new(
+            Opts::new("rlm_feature_used", "test"),
+            &["app", "name", "version"],
+        )
+        .unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        let render = |families: Vec<MetricFamily>| {
+            let mut buffer = String::new();
+            TextEncoder::new()
+                .encode_utf8(
+                    &families
+                        .into_iter()
+                        .filter(|mf| mf.get_name() == constants::USAGE_ANOMALY_METRIC)
+                        .collect::<Vec<_>>(),
+                    &mut buffer,
+                )
+                .unwrap();
+            buffer
+        };
+
+        // The versions of a feature are added up and reported once
+        gauge
+            .with_label_values(&["rlm-anomaly", "feat1", "1.0"])
+            .set(2);
+        gauge
+            .with_label_values(&["rlm-anomaly", "feat1", "2.0"])
+            .set(3);
+        status::record("rlm", "rlm-anomaly", &Ok(()));
+        let mut families = registry.gather();
+        add_usage_anomalies(&mut families);
+        assert!(render(families)
+            .ends_with("license_feature_usage_anomaly{app=\"rlm-anomaly\",name=\"feat1\"} 0\n"));
+
+        // Only one version dropped to zero
+        gauge
+            .with_label_values(&["rlm-anomaly", "feat1", "1.0"])
+            .set(0);
+        thread::sleep(std::time::Duration::from_millis(2));
+        status::record("rlm", "rlm-anomaly", &Ok(()));
+        let mut families = registry.gather();
+        add_usage_anomalies(&mut families);
+        assert!(render(families)
+            .ends_with("license_feature_usage_anomaly{app=\"rlm-anomaly\",name=\"feat1\"} 0\n"));
+
+        gauge
+            .with_label_values(&["rlm-anomaly", "feat1", "2.0"])
+            .set(0);
+        thread::sleep(std::time::Duration::from_millis(2));
+        status::record("rlm", "rlm-anomaly", &Ok(()));
+        let mut families = registry.gather();
+        add_usage_anomalies(&mut families);
+        assert!(render(families)
+            .ends_with("license_feature_usage_anomaly{app=\"rlm-anomaly\",name=\"feat1\"} 1\n"));
+
+        // A second scrape of the same fetch doesn't add a sample
+        let mut families = registry.gather();
+        add_usage_anomalies(&mut families);
+        assert!(render(families)
+            .ends_with("license_feature_usage_anomaly{app=\"rlm-anomaly\",name=\"feat1\"} 1\n"));
+    }
+
+    #[test]
+    fn metric_prefix() {
+        let registry = Registry::new();
+        let gauge =
+            IntGaugeVec::new(Opts::new("flexlm_feature_used", "test"), &["app", "name"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["lic1", "feat1"]).set(3);
+
+        let cfg = crate::testutil::parse_config(
+            "global:\n  metric_prefix: licexp_\n  metric_help:\n    flexlm_feature_used: Used seats\n",
+        );
+        let mut families = registry.gather();
+        customise_metrics(&mut families, cfg.global.as_ref().unwrap());
+
+        let mut buffer = String::new();
+        TextEncoder::new()
+            .encode_utf8(&families, &mut buffer)
+            .unwrap();
+        assert_eq!(
+            buffer,
+            "# HELP licexp_flexlm_feature_used Used seats
+# TYPE licexp_flexlm_feature_used gauge
+licexp_flexlm_feature_used{app=\"lic1\",name=\"feat1\"} 3
+"
+        );
+
+        assert!(crate::config::parse_config("global:\n  metric_prefix: 'lic-exp_'\n").is_err());
+    }
+
+    #[test]
+    fn server_info() {
+        let registry = Registry::new();
+        let status = IntGaugeVec::new(
+            Opts::new("rlm_server_status", "test"),
+            &["app", "fqdn", "master", "port", "version"],
+        )
+        .unwrap();
+        registry.register(Box::new(status.clone())).unwrap();
+        status
+            .with_label_values(&["lic1", "srv1", "true", "5053", "v15.1"])
+            .set(1);
+        status
+            .with_label_values(&["lic1", "srv2", "false", "5053", ""])
+            .set(0);
+
+        let mut families = registry.gather();
+        add_server_info(&mut families);
+
+        let mut buffer = String::new();
+        TextEncoder::new()
+            .encode_utf8(
+                &families
+                    .into_iter()
+                    .filter(|mf| mf.get_name().ends_with("_server_info"))
+                    .collect::<Vec<_>>(),
+                &mut buffer,
+            )
+            .unwrap();
+        assert_eq!(
+            buffer,
+            "# HELP rlm_server_info Software version of license servers
+# TYPE rlm_server_info gauge
+rlm_server_info{app=\"lic1\",backend=\"rlm\",fqdn=\"srv1\",version=\"v15.1\"} 1
+"
+        );
+    }
+
+    #[test]
+    fn drop_removed_licenses() {
+        let registry = Registry::new();
+        let gauge =
+            IntGaugeVec::new(Opts::new("rlm_feature_used", "test"), &["app", "name"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["drop_removed1", "feat1"]).set(1);
+        gauge.with_label_values(&["drop_removed2", "feat1"]).set(2);
+        let other =
+            IntGaugeVec::new(Opts::new("flexlm_feature_used", "test"), &["app", "name"]).unwrap();
+        registry.register(Box::new(other.clone())).unwrap();
+        other.with_label_values(&["drop_removed1", "feat1"]).set(3);
+
+        let old = crate::testutil::parse_config(
+            "rlm:
+  - name: drop_removed1
+    license: 5053@lic1
+    isv: demo
+  - name: drop_removed2
+    license: 5053@lic1
+    isv: demo
+",
+        );
+        let new = old.select("drop_removed2");
+        reregister(&old, &new);
+
+        let mut families = registry.gather();
+        drop_removed(&mut families);
+        let apps: Vec<(&str, &str)> = families
+            .iter()
+            .flat_map(|mf| {
+                mf.get_metric()
+                    .iter()
+                    .map(move |m| (mf.get_name(), label_value(m, "app")))
+            })
+            .collect();
+        assert_eq!(
+            apps,
+            vec![
+                ("flexlm_feature_used", "drop_removed1"),
+                ("rlm_feature_used", "drop_removed2")
+            ]
+        );
+    }
 }
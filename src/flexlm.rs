@@ -1,17 +1,26 @@
+use crate::command;
 use crate::config;
-use crate::exporter;
+use crate::constants;
+use crate::dates;
 use crate::license;
+use crate::licensefile;
+use crate::optionsfile;
+use crate::series;
+use crate::servers;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use regex::Regex;
 use simple_error::bail;
-use std::collections::HashMap;
-use std::env;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::process::Command;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref FLEXLM_FEATURES_TOTAL: IntGaugeVec = IntGaugeVec::new(
@@ -32,22 +41,110 @@ lazy_static! {
         &["app", "name", "user", "version"],
     )
     .unwrap();
+    pub static ref FLEXLM_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_BORROWED: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_borrowed_licenses",
+            "Number of licenses borrowed by user and host"
+        ),
+        &["app", "name", "user", "host", "expires"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_FEATURE_RESERVATIONS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_feature_reservations",
+            "Number of licenses reserved by the options file of the vendor daemon"
+        ),
+        &["app", "name", "group", "type"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_POLICY_MAX: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_policy_max",
+            "Maximal number of licenses allowed by MAX rules of the options file"
+        ),
+        &["app", "name", "group", "type"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_POLICY_RESERVE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_policy_reserve",
+            "Number of licenses reserved by RESERVE rules of the options file"
+        ),
+        &["app", "name", "group", "type"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_POLICY_INCLUDE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_policy_include",
+            "Feature is restricted to this user, host or group by INCLUDE rules of the options file"
+        ),
+        &["app", "name", "group", "type"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_POLICY_EXCLUDE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_policy_exclude",
+            "Feature is denied to this user, host or group by EXCLUDE rules of the options file"
+        ),
+        &["app", "name", "group", "type"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_QUEUE_WAIT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_queue_wait_seconds",
+            "Time users have been waiting in the queue for a license"
+        ),
+        &["app", "name", "user"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_FEATURES_UNCOUNTED: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_feature_uncounted",
+            "Feature is uncounted or has no issued licenses"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref FLEXLM_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("flexlm_server_status", "Status of license server(s)"),
         &["app", "fqdn", "master", "port", "version"],
     )
     .unwrap();
+    pub static ref FLEXLM_SERVER_STATE: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_server_state",
+            "State (up, down, unknown) of the license server(s)"
+        ),
+        &["app", "fqdn", "port", "state"],
+    )
+    .unwrap();
     pub static ref FLEXLM_VENDOR_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("flexlm_vendor_status", "Status of the vendor daemon"),
         &["app", "name", "version"],
     )
     .unwrap();
+    pub static ref FLEXLM_FEATURES_MODEL_TOTAL: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_feature_model_issued",
+            "Number of issued licenses by license model"
+        ),
+        &["app", "name", "model"],
+    )
+    .unwrap();
     pub static ref FLEXLM_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
         Opts::new(
             "flexlm_feature_expiration_seconds",
             "Time until license features will expire"
         ),
-        &["app", "index", "licenses", "name", "vendor", "version"]
+        &["app", "index", "licenses", "name", "type", "vendor", "version"]
     )
     .unwrap();
     pub static ref FLEXLM_FEATURE_AGGREGATED_EXPIRATION: GaugeVec = GaugeVec::new(
@@ -58,160 +155,551 @@ lazy_static! {
         &["app", "features", "index", "licenses"]
     )
     .unwrap();
+    pub static ref FLEXLM_LICENSE_FILE_MISMATCH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_license_file_mismatch",
+            "Number of features with different licenses on the server and in the license file"
+        ),
+        &["app"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_LICENSE_FILE_FEATURE_MISMATCH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "flexlm_license_file_feature_mismatch",
+            "Feature differs between the server and the license file"
+        ),
+        &["app", "name", "reason"],
+    )
+    .unwrap();
+    pub static ref FLEXLM_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "flexlm_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
 }
 
+const LICENSE_TYPE_FLOATING: &str = "floating";
+const LICENSE_TYPE_UNCOUNTED: &str = "uncounted";
+
 pub struct FlexLMLicenseExpiration {
     pub feature: String,
     pub version: String,
     pub license_count: i64,
     pub expiration: f64,
     pub vendor: String,
+    pub license_type: String,
 }
 
-pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
-    lazy_static! {
-        static ref RE_LMSTAT_USAGE: Regex = Regex::new(r"^Users of ([a-zA-Z0-9_\-+]+):\s+\(Total of (\d+) license[s]? issued;\s+Total of (\d+) license[s]? in use\)$").unwrap();
-        static ref RE_LMSTAT_USERS_SINGLE_LICENSE: Regex = Regex::new(r"^\s+(\w+) [\w.\-_]+\s+[\w/]+\s+\(([\w\-.]+)\).*, start [A-Z][a-z][a-z] \d+/\d+ \d+:\d+$").unwrap();
-        static ref RE_LMSTAT_USERS_MULTI_LICENSE: Regex = Regex::new(r"^\s+(\w+) [\w.\-_]+\s+[a-zA-Z0-9/]+\s+\(([\w.\-_]+)\)\s+\([\w./\s]+\),\s+start [A-Z][a-z][a-z] \d+/\d+ \d+:\d+,\s+(\d+) licenses$").unwrap();
-        static ref RE_LMSTAT_LICENSE_SERVER_STATUS: Regex = Regex::new(r"^License server status:\s+([\w.\-@,]+)$").unwrap();
-        static ref RE_LMSTAT_SERVER_STATUS: Regex = Regex::new(r"([\w.\-]+):\s+license server (\w+)\s+(\(MASTER\))?\s*([\w.]+)").unwrap();
-        static ref RE_LMSTAT_VENDOR_STATUS: Regex = Regex::new(r"\s+(\w+):\s+(\w+)\s+([\w.]+)$").unwrap();
+#[derive(Clone, Copy, PartialEq)]
+pub enum ServerState {
+    Up,
+    Down,
+    Unknown,
+}
+
+impl ServerState {
+    const ALL: [ServerState; 3] = [ServerState::Up, ServerState::Down, ServerState::Unknown];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ServerState::Up => "up",
+            ServerState::Down => "down",
+            ServerState::Unknown => "unknown",
+        }
     }
+}
 
-    // dict -> "feature" -> "user" -> "version" -> count
-    let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
-    let mut server_port: HashMap<String, String> = HashMap::new();
-    let mut server_status: HashMap<String, i64> = HashMap::new();
-    let mut server_master: HashMap<String, bool> = HashMap::new();
-    let mut server_version: HashMap<String, String> = HashMap::new();
-    let mut license_server = String::new();
+// A member of a (triad) license server setup, servers without any status information
+// in the lmstat output are in state unknown
+pub struct FlexLMServer {
+    pub fqdn: String,
+    pub port: String,
+    pub state: ServerState,
+    pub master: bool,
+    pub version: String,
+}
 
-    env::set_var("LANG", "C");
-    debug!(
-        "flexlm.rs:fetch: Running {} lmstat -c {} -a",
-        lmutil, &lic.license
-    );
-    let cmd = Command::new(lmutil)
-        .arg("lmstat")
-        .arg("-c")
-        .arg(&lic.license)
-        .arg("-a")
-        .output()?;
+impl FlexLMServer {
+    pub fn new(fqdn: &str) -> Self {
+        FlexLMServer {
+            fqdn: fqdn.to_string(),
+            port: String::new(),
+            state: ServerState::Unknown,
+            master: false,
+            version: String::new(),
+        }
+    }
+}
 
-    let rc = match cmd.status.code() {
+// lmstat reports server names as configured in the license file and in the status line,
+// either can be a FQDN or short name
+fn server_entry<'a>(servers: &'a mut Vec<FlexLMServer>, name: &str) -> &'a mut FlexLMServer {
+    let short = |n: &str| n.split('.').next().unwrap_or("").to_lowercase();
+
+    let idx = match servers
+        .iter()
+        .position(|s| s.fqdn.eq_ignore_ascii_case(name))
+        .or_else(|| servers.iter().position(|s| short(&s.fqdn) == short(name)))
+    {
         Some(v) => v,
         None => {
-            bail!("Can't get return code of {} command", lmutil);
+            servers.push(FlexLMServer::new(name));
+            servers.len() - 1
         }
     };
-    debug!(
-        "flexlm.rs:fetch: external command finished with exit code {}",
-        rc
-    );
+    &mut servers[idx]
+}
 
-    if !cmd.status.success() {
-        bail!(
-            "{} command exited with non-normal exit code {} for {}",
-            lmutil,
-            rc,
-            lic.name
+// Servers from the configured license, e.g. port@server or a triad port@server1,port@server2,port@server3
+// separated by ':' for multiple license servers. License files are skipped.
+pub(crate) fn configured_servers(license: &str) -> Vec<FlexLMServer> {
+    let mut servers: Vec<FlexLMServer> = Vec::new();
+
+    for entry in license.split(':').flat_map(|l| l.split(',')) {
+        if let Some((port, host)) = entry.trim().split_once('@') {
+            if host.is_empty() {
+                continue;
+            }
+            server_entry(&mut servers, host).port = port.to_string();
+        }
+    }
+
+    servers
+}
+
+// Report all configured servers if lmstat doesn't return any status information at all
+fn set_unknown_server_status(lic: &config::FlexLM, servers: &[FlexLMServer]) {
+    for server in servers.iter() {
+        set_server_status(lic, server);
+    }
+}
+
+pub(crate) fn set_server_status(lic: &config::FlexLM, server: &FlexLMServer) {
+    let info = servers::ServerInfo {
+        fqdn: server.fqdn.clone(),
+        port: server.port.clone(),
+        master: server.master,
+        status: i64::from(server.state == ServerState::Up),
+        version: server.version.clone(),
+    };
+    servers::export_status(&lic.name, &FLEXLM_SERVER_STATUS, &info);
+
+    for state in ServerState::ALL {
+        let value = i64::from(server.state == state);
+        debug!(
+            "flexlm.rs:set_server_status: Setting flexlm_server_state {} {} {} {} -> {}",
+            lic.name,
+            server.fqdn,
+            server.port,
+            state.as_str(),
+            value
         );
+        FLEXLM_SERVER_STATE
+            .with_label_values(&[&lic.name, &server.fqdn, &server.port, state.as_str()])
+            .set(value);
     }
+}
 
-    let stdout = String::from_utf8(cmd.stdout)?;
+// Borrowed licenses are reported by lmstat -a as checkouts with a linger time, e.g.
+// "jdoe host1 host1 (v1.0) (lic1/27000 301), start Sun 1/1 8:00 (linger: 1209600 / 5)"
+#[derive(Debug, PartialEq)]
+pub struct FlexLMBorrow {
+    pub feature: String,
+    pub user: String,
+    pub host: String,
+    // local time of the license server, "" if the start date can't be parsed
+    pub expires: String,
+    pub count: i64,
+}
 
-    let mut feature: &str = "";
-    for line in stdout.lines() {
+// Licenses reserved by the RESERVE option of the options file are reported by lmstat -a, e.g.
+// "1 RESERVATION for GROUP engineering (lic1/27000)"
+#[derive(Debug, PartialEq)]
+pub struct FlexLMReservation {
+    pub feature: String,
+    // GROUP, HOST_GROUP, USER, HOST, ... in lower case
+    pub kind: String,
+    pub group: String,
+    pub count: i64,
+}
+
+// Users waiting for a license are reported by lmstat -a as queued, e.g.
+// "jdoe host1 /dev/tty (v1.0) (lic1/27000 401) queued for 1 license since Mon 1/2 9:45"
+#[derive(Debug, PartialEq)]
+pub struct FlexLMQueued {
+    pub feature: String,
+    pub user: String,
+    pub count: i64,
+    // seconds since the user is queued at the time of the status report, None without "since"
+    pub wait: Option<i64>,
+}
+
+// Status of a vendor daemon reported by lmstat -a, e.g. "vendx: UP v11.16.2"
+#[derive(Debug, PartialEq)]
+pub struct FlexLMVendor {
+    pub name: String,
+    pub version: String,
+    pub up: bool,
+}
+
+// Usage data from lmstat -a output
+pub struct LmstatUsage {
+    // dict -> "feature" -> "user" -> "version" -> count
+    pub fuv: HashMap<String, HashMap<String, HashMap<String, i64>>>,
+    pub license_server: String,
+    pub uncounted: HashSet<String>,
+    pub issued: HashMap<String, i64>,
+    // Licenses in use of the counted features
+    pub used: HashMap<String, i64>,
+    pub vendors: Vec<FlexLMVendor>,
+    pub borrowed: Vec<FlexLMBorrow>,
+    pub queued: Vec<FlexLMQueued>,
+    pub reservations: Vec<FlexLMReservation>,
+}
+
+// lmstat reports the start of a checkout without year, e.g. "1/1 8:00". The year is taken from
+// the date of the status report, a start after the report date is from the previous year.
+fn start_time(start: &str, reference: NaiveDate) -> Result<NaiveDateTime, chrono::ParseError> {
+    let parse =
+        |y: i32| NaiveDateTime::parse_from_str(&format!("{}/{}", y, start), "%Y/%m/%d %H:%M");
+    let start_time = parse(reference.year())?;
+    if start_time.date() > reference {
+        return parse(reference.year() - 1);
+    }
+    Ok(start_time)
+}
+
+fn borrow_expiration(start: &str, linger: i64, status_date: Option<NaiveDate>) -> String {
+    let reference = status_date.unwrap_or_else(|| chrono::Local::now().date_naive());
+
+    let start_time = match start_time(start, reference) {
+        Ok(v) => v,
+        Err(e) => {
+            warn!(
+                "flexlm.rs:borrow_expiration: Can't parse start time {}: {}",
+                start, e
+            );
+            return String::new();
+        }
+    };
+
+    (start_time + chrono::Duration::seconds(linger))
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+// Time in the queue at the time of the status report
+fn queue_wait(since: &str, status_time: Option<NaiveDateTime>) -> Option<i64> {
+    let reference = status_time.unwrap_or_else(|| chrono::Local::now().naive_local());
+
+    match start_time(since, reference.date()) {
+        Ok(v) => Some((reference - v).num_seconds().max(0)),
+        Err(e) => {
+            warn!(
+                "flexlm.rs:queue_wait: Can't parse queue time {}: {}",
+                since, e
+            );
+            None
+        }
+    }
+}
+
+// Some lmutil builds, e.g. on Windows, wrap long user lines of lmstat -a at the width of the
+// console, even in the middle of a word. Continuation lines are joined with their user line
+// before parsing.
+fn join_wrapped_lines(raw: &str) -> String {
+    let mut result = String::with_capacity(raw.len());
+    let mut current: Option<String> = None;
+
+    for line in raw.lines() {
+        if let Some(cur) = current.as_mut() {
+            if is_continuation(cur, line) {
+                if line.starts_with(char::is_whitespace) {
+                    cur.truncate(cur.trim_end().len());
+                    cur.push(' ');
+                    cur.push_str(line.trim_start());
+                } else {
+                    cur.push_str(line);
+                }
+                continue;
+            }
+            result.push_str(cur);
+            result.push('\n');
+            current = None;
+        }
+
+        if is_user_line(line) {
+            current = Some(line.to_string());
+        } else {
+            result.push_str(line);
+            result.push('\n');
+        }
+    }
+
+    if let Some(cur) = current {
+        result.push_str(&cur);
+        result.push('\n');
+    }
+    result
+}
+
+// Start of a checkout or queue entry, "    user host ..."
+fn is_user_line(line: &str) -> bool {
+    lazy_static! {
+        static ref RE_LMSTAT_USER_LINE: Regex = Regex::new(r"^\s{4,}\w+ [\w.\-_]+\s").unwrap();
+    }
+    RE_LMSTAT_USER_LINE.is_match(line)
+}
+
+// The entries of a feature are followed by an empty line, so every other line after a user line
+// is part of it. A line looking like a user line is only a continuation if the user line is
+// incomplete, e.g. if the wrap happened in front of the indented display name.
+fn is_continuation(current: &str, line: &str) -> bool {
+    lazy_static! {
+        static ref RE_LMSTAT_USER_LINE_END: Regex = Regex::new(r"(?:RESERVATIONs? for \w+ \S+ \([^)]*\)|start [A-Z][a-z][a-z] \d+/\d+ \d+:\d+(?:,\s+\d+ licenses?)?(?:\s+\(linger:\s+\d+(?:\s*/\s*\d+)?\))?|queued for \d+ licenses?(?: since [A-Z][a-z][a-z] \d+/\d+ \d+:\d+)?)\s*$").unwrap();
+    }
+
+    if line.trim().is_empty() || line.starts_with("Users of ") {
+        return false;
+    }
+    !is_user_line(line) || !RE_LMSTAT_USER_LINE_END.is_match(current)
+}
+
+// Parse lmstat -a output into the feature usage and vendor status and update the status of the
+// license servers
+pub fn parse_lmstat(
+    lic: &config::FlexLM,
+    raw: &str,
+    servers: &mut Vec<FlexLMServer>,
+) -> LmstatUsage {
+    lazy_static! {
+        static ref RE_LMSTAT_USAGE: Regex = Regex::new(r"^Users of ([a-zA-Z0-9_\-+]+):\s+\(Total of (\d+) license[s]? issued;\s+Total of (\d+) license[s]? in use\)$").unwrap();
+        static ref RE_LMSTAT_USAGE_UNCOUNTED: Regex = Regex::new(r"^Users of ([a-zA-Z0-9_\-+]+):\s+\(Uncounted,\s+node-locked\)$").unwrap();
+        static ref RE_LMSTAT_USERS_SINGLE_LICENSE: Regex = Regex::new(r"^\s+(\w+) [\w.\-_]+\s+[\w/]+\s+\(([\w\-.]+)\).*, start [A-Z][a-z][a-z] \d+/\d+ \d+:\d+$").unwrap();
+        static ref RE_LMSTAT_USERS_MULTI_LICENSE: Regex = Regex::new(r"^\s+(\w+) [\w.\-_]+\s+[a-zA-Z0-9/]+\s+\(([\w.\-_]+)\)\s+\([\w./\s]+\),\s+start [A-Z][a-z][a-z] \d+/\d+ \d+:\d+,\s+(\d+) licenses$").unwrap();
+        static ref RE_LMSTAT_LICENSE_SERVER_STATUS: Regex = Regex::new(r"^License server status:\s+([\w.\-@,]+)$").unwrap();
+        static ref RE_LMSTAT_SERVER_STATUS: Regex = Regex::new(r"([\w.\-]+):\s+license server (\w+)\s+(\(MASTER\))?\s*([\w.]+)").unwrap();
+        static ref RE_LMSTAT_SERVER_UNREACHABLE: Regex = Regex::new(r"^\s*([\w.\-]+):\s+(?:Cannot|Unable to) (?:read data from|connect to|find) license server").unwrap();
+        static ref RE_LMSTAT_VENDOR_STATUS: Regex = Regex::new(r"\s+(\w+):\s+(\w+)\s+([\w.]+)$").unwrap();
+        static ref RE_LMSTAT_STATUS_DATE: Regex = Regex::new(r"^Flexible License Manager status on \w+ (\d+/\d+/\d+) (\d+:\d+)$").unwrap();
+        static ref RE_LMSTAT_RESERVATION: Regex = Regex::new(r"^\s+(\d+)\s+RESERVATIONs?\s+for\s+(\w+)\s+(\S+)\s+\(").unwrap();
+        static ref RE_LMSTAT_USERS_QUEUED: Regex = Regex::new(r"^\s+(\w+) [\w.\-_]+\s+[\w/.\-]+\s+\([\w\-.]+\)\s+\([\w./\s]+\)\s+queued for (\d+) licenses?(?:.*since [A-Z][a-z][a-z] (\d+/\d+ \d+:\d+))?").unwrap();
+        static ref RE_LMSTAT_USERS_BORROWED: Regex = Regex::new(r"^\s+(\w+) ([\w.\-_]+)\s+[\w/.\-]+\s+\(([\w\-.]+)\)\s+\([\w./\s]+\),\s+start [A-Z][a-z][a-z] (\d+/\d+ \d+:\d+)(?:,\s+(\d+) licenses)?\s+\(linger:\s+(\d+)(?:\s*/\s*\d+)?\)$").unwrap();
+    }
+
+    let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
+    let mut license_server = String::new();
+    let mut uncounted: HashSet<String> = HashSet::new();
+    let mut issued: HashMap<String, i64> = HashMap::new();
+    let mut used: HashMap<String, i64> = HashMap::new();
+    let mut vendors: Vec<FlexLMVendor> = Vec::new();
+    let mut borrowed: Vec<FlexLMBorrow> = Vec::new();
+    let mut queued: Vec<FlexLMQueued> = Vec::new();
+    let mut reservations: Vec<FlexLMReservation> = Vec::new();
+    let mut status_date: Option<NaiveDate> = None;
+    let mut status_time: Option<NaiveDateTime> = None;
+
+    let raw = join_wrapped_lines(raw);
+    let mut feature = String::new();
+    // With several vendor daemons, lmstat -a reports the status of each vendor daemon followed
+    // by the usage of its features
+    let mut vendor: &str = "";
+    for line in raw.lines() {
         if line.is_empty() {
             continue;
         }
 
-        if let Some(capt) = RE_LMSTAT_USAGE.captures(line) {
+        if let Some(capt) = RE_LMSTAT_STATUS_DATE.captures(line) {
+            let date = capt.get(1).map_or("", |m| m.as_str());
+            let time = capt.get(2).map_or("", |m| m.as_str());
+            status_date = NaiveDate::parse_from_str(date, "%m/%d/%Y").ok();
+            status_time =
+                NaiveDateTime::parse_from_str(&format!("{} {}", date, time), "%m/%d/%Y %H:%M").ok();
+        } else if let Some(capt) = RE_LMSTAT_RESERVATION.captures(line) {
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_RESERVATION match on '{}'",
+                line
+            );
+
+            let kind = capt.get(2).map_or("", |m| m.as_str()).to_lowercase();
+            let group = capt.get(3).map_or("", |m| m.as_str());
+            // The group of a reservation for a single user is the user name
+            let group = if kind == "user" {
+                lic.anonymizer.user(group)
+            } else {
+                group.to_string()
+            };
+            reservations.push(FlexLMReservation {
+                feature: feature.to_string(),
+                kind,
+                group,
+                count: capt
+                    .get(1)
+                    .and_then(|m| m.as_str().parse().ok())
+                    .unwrap_or(1),
+            });
+        } else if let Some(capt) = RE_LMSTAT_USERS_QUEUED.captures(line) {
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USERS_QUEUED match on '{}'",
+                line
+            );
+
+            // Queued users don't hold a license yet, so they aren't counted as checkouts
+            queued.push(FlexLMQueued {
+                feature: feature.to_string(),
+                user: lic.anonymizer.user(capt.get(1).map_or("", |m| m.as_str())),
+                count: capt
+                    .get(2)
+                    .and_then(|m| m.as_str().parse().ok())
+                    .unwrap_or(1),
+                wait: capt
+                    .get(3)
+                    .and_then(|m| queue_wait(m.as_str(), status_time)),
+            });
+        } else if let Some(capt) = RE_LMSTAT_USAGE.captures(line) {
             if capt.len() != 4 {
                 error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 4 for RE_LMSTAT_USAGE",
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 4 for RE_LMSTAT_USAGE",
                     capt.len()
                 );
                 continue;
             }
 
-            debug!("flexlm.rs:fetch: RE_LMSTAT_USAGE match on '{}'", line);
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USAGE match on '{}'",
+                line
+            );
 
-            feature = capt.get(1).map_or("", |m| m.as_str());
+            feature = feature_name(lic, capt.get(1).map_or("", |m| m.as_str()), vendor);
             let _total = capt.get(2).map_or("", |m| m.as_str());
             let _used = capt.get(3).map_or("", |m| m.as_str());
 
-            if license::is_excluded(&lic.excluded_features, feature.to_string()) {
-                debug!("flexlm.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feature, lic.name);
+            if lic.feature_filter.is_excluded(&feature) {
+                debug!("flexlm.rs:parse_lmstat: Skipping feature {} because it is in excluded_features list of {}", feature, lic.name);
                 continue;
             }
 
             let total: i64 = match _total.parse() {
                 Ok(v) => v,
                 Err(e) => {
-                    error!("flexlm.rs:fetch: Can't parse {} as integer: {}", _total, e);
+                    error!(
+                        "flexlm.rs:parse_lmstat: Can't parse {} as integer: {}",
+                        _total, e
+                    );
                     continue;
                 }
             };
 
-            let used: i64 = match _used.parse() {
+            let in_use: i64 = match _used.parse() {
                 Ok(v) => v,
                 Err(e) => {
-                    error!("flexlm.rs:fetch: Can't parse {} as integer: {}", _used, e);
+                    error!(
+                        "flexlm.rs:parse_lmstat: Can't parse {} as integer: {}",
+                        _used, e
+                    );
                     continue;
                 }
             };
 
+            issued.insert(feature.to_string(), total);
+
+            // NOTE: Features without issued licenses are handled like uncounted features, because
+            //       issued and used values are meaningless for them.
+            if total == 0 {
+                uncounted.insert(feature.to_string());
+                continue;
+            }
+
+            used.insert(feature.to_string(), in_use);
+        } else if let Some(capt) = RE_LMSTAT_USAGE_UNCOUNTED.captures(line) {
+            if capt.len() != 2 {
+                error!(
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 2 for RE_LMSTAT_USAGE_UNCOUNTED",
+                    capt.len()
+                );
+                continue;
+            }
+
             debug!(
-                "flexlm.rs:fetch: Setting flexlm_feature_issued {} {} -> {}",
-                lic.name, feature, total
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USAGE_UNCOUNTED match on '{}'",
+                line
             );
-            FLEXLM_FEATURES_TOTAL
-                .with_label_values(&[&lic.name, feature])
-                .set(total);
 
+            // NOTE: Uncounted (node-locked) licenses have no meaningful number of issued licenses,
+            //       so only the user checkouts are collected for them.
+            feature = feature_name(lic, capt.get(1).map_or("", |m| m.as_str()), vendor);
+            uncounted.insert(feature.to_string());
+            issued.insert(feature.to_string(), 0);
+        } else if let Some(capt) = RE_LMSTAT_USERS_BORROWED.captures(line) {
             debug!(
-                "flexlm.rs:fetch: Setting flexlm_feature_used {} {} -> {}",
-                lic.name, feature, used
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USERS_BORROWED match on '{}'",
+                line
             );
-            FLEXLM_FEATURES_USED
-                .with_label_values(&[&lic.name, feature])
-                .set(used);
+
+            let user = capt.get(1).map_or("", |m| m.as_str());
+            let host = capt.get(2).map_or("", |m| m.as_str());
+            let version = capt.get(3).map_or("", |m| m.as_str());
+            let start = capt.get(4).map_or("", |m| m.as_str());
+            let count: i64 = capt
+                .get(5)
+                .and_then(|m| m.as_str().parse().ok())
+                .unwrap_or(1);
+            let linger: i64 = match capt.get(6).map_or("", |m| m.as_str()).parse() {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "flexlm.rs:parse_lmstat: Can't parse linger time of '{}': {}",
+                        line, e
+                    );
+                    continue;
+                }
+            };
+
+            let feat = fuv.entry(feature.to_string()).or_default();
+            let usr = feat.entry(lic.anonymizer.user(user)).or_default();
+            *usr.entry(version.to_string()).or_insert(0) += count;
+
+            borrowed.push(FlexLMBorrow {
+                feature: feature.to_string(),
+                user: lic.anonymizer.user(user),
+                host: host.to_string(),
+                expires: borrow_expiration(start, linger, status_date),
+                count,
+            });
         } else if let Some(capt) = RE_LMSTAT_USERS_SINGLE_LICENSE.captures(line) {
             if capt.len() != 3 {
                 error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 3 RE_LMSTAT_USERS_SINGLE_LICENSE",
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 3 RE_LMSTAT_USERS_SINGLE_LICENSE",
                     capt.len(),
                 );
                 continue;
             }
 
             debug!(
-                "flexlm.rs:fetch: RE_LMSTAT_USERS_SINGLE_LICENSE match on '{}'",
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USERS_SINGLE_LICENSE match on '{}'",
                 line
             );
 
             let user = capt.get(1).map_or("", |m| m.as_str());
             let version = capt.get(2).map_or("", |m| m.as_str());
 
-            let feat = fuv
-                .entry(feature.to_string())
-                .or_insert_with(HashMap::<String, HashMap<String, i64>>::new);
-            let usr = feat
-                .entry(user.to_string())
-                .or_insert_with(HashMap::<String, i64>::new);
+            let feat = fuv.entry(feature.to_string()).or_default();
+            let usr = feat.entry(lic.anonymizer.user(user)).or_default();
             *usr.entry(version.to_string()).or_insert(0) += 1;
         } else if let Some(capt) = RE_LMSTAT_USERS_MULTI_LICENSE.captures(line) {
             if capt.len() != 4 {
                 error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 3 RE_LMSTAT_USERS_MULTI_LICENSE",
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 3 RE_LMSTAT_USERS_MULTI_LICENSE",
                     capt.len(),
                 );
                 continue;
             }
 
             debug!(
-                "flexlm.rs:fetch: RE_LMSTAT_USERS_MULTI_LICENSE match on '{}'",
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_USERS_MULTI_LICENSE match on '{}'",
                 line
             );
 
@@ -221,29 +709,28 @@ pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
             let count: i64 = match _count.parse() {
                 Ok(v) => v,
                 Err(e) => {
-                    error!("flexlm.rs:fetch: Can't parse {} as integer: {}", _count, e);
+                    error!(
+                        "flexlm.rs:parse_lmstat: Can't parse {} as integer: {}",
+                        _count, e
+                    );
                     continue;
                 }
             };
 
-            let feat = fuv
-                .entry(feature.to_string())
-                .or_insert_with(HashMap::<String, HashMap<String, i64>>::new);
-            let usr = feat
-                .entry(user.to_string())
-                .or_insert_with(HashMap::<String, i64>::new);
+            let feat = fuv.entry(feature.to_string()).or_default();
+            let usr = feat.entry(lic.anonymizer.user(user)).or_default();
             *usr.entry(version.to_string()).or_insert(0) += count;
         } else if let Some(capt) = RE_LMSTAT_LICENSE_SERVER_STATUS.captures(line) {
             if capt.len() != 2 {
                 error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 2 for RE_LMSTAT_LICENSE_SERVER_STATUS",
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 2 for RE_LMSTAT_LICENSE_SERVER_STATUS",
                     capt.len()
                 );
                 continue;
             }
 
             debug!(
-                "flexlm.rs:fetch: RE_LMSTAT_LICENSE_SERVER_STATUS match on '{}'",
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_LICENSE_SERVER_STATUS match on '{}'",
                 line
             );
 
@@ -251,73 +738,286 @@ pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
             license_server = status_line.to_string();
 
             for server_line in status_line.split(',') {
-                let srv_port: Vec<&str> = server_line.split('@').collect();
-                server_port.insert(srv_port[1].to_string(), srv_port[0].to_string());
-                server_status.insert(srv_port[1].to_string(), 0);
-                server_master.insert(srv_port[1].to_string(), false);
-                server_version.insert(srv_port[1].to_string(), String::new());
+                if let Some((port, host)) = server_line.split_once('@') {
+                    server_entry(servers, host).port = port.to_string();
+                }
             }
         } else if let Some(capt) = RE_LMSTAT_SERVER_STATUS.captures(line) {
             if capt.len() != 5 {
                 error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 5 for RE_LMSTAT_SERVER_STATUS",
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 5 for RE_LMSTAT_SERVER_STATUS",
                     capt.len()
                 );
                 continue;
             }
 
-            debug!(
-                "flexlm.rs:fetch: RE_LMSTAT_SERVER_STATUS match on '{}'",
-                line
-            );
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_SERVER_STATUS match on '{}'",
+                line
+            );
+
+            let server = capt.get(1).map_or("", |m| m.as_str());
+            let status = capt.get(2).map_or("", |m| m.as_str());
+            let master = capt.get(3).map_or("", |m| m.as_str());
+            let version = capt.get(4).map_or("", |m| m.as_str());
+
+            let entry = server_entry(servers, server);
+            entry.state = if status == "UP" {
+                ServerState::Up
+            } else {
+                ServerState::Down
+            };
+            entry.master = master == "(MASTER)";
+            entry.version = version.to_string();
+        } else if let Some(capt) = RE_LMSTAT_SERVER_UNREACHABLE.captures(line) {
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_SERVER_UNREACHABLE match on '{}'",
+                line
+            );
+
+            let server = capt.get(1).map_or("", |m| m.as_str());
+            server_entry(servers, server).state = ServerState::Down;
+        } else if let Some(capt) = RE_LMSTAT_VENDOR_STATUS.captures(line) {
+            if capt.len() != 4 {
+                error!(
+                    "flexlm.rs:parse_lmstat: Regular expression returns {} capture groups instead of 4 for RE_LMSTAT_VENDOR_STATUS",
+                    capt.len()
+                );
+                continue;
+            }
+
+            debug!(
+                "flexlm.rs:parse_lmstat: RE_LMSTAT_VENDOR_STATUS match on '{}'",
+                line
+            );
+
+            vendor = capt.get(1).map_or("", |m| m.as_str());
+            let status = capt.get(2).map_or("", |m| m.as_str());
+            let version = capt.get(3).map_or("", |m| m.as_str());
+
+            if !vendor_selected(lic, vendor) {
+                debug!(
+                    "flexlm.rs:parse_lmstat: Skipping vendor daemon {} because it isn't in vendors of {}",
+                    vendor, lic.name
+                );
+                continue;
+            }
+
+            vendors.push(FlexLMVendor {
+                name: vendor.to_string(),
+                version: version.to_string(),
+                up: status == "UP",
+            });
+        } else {
+            debug!("flexlm.rs:parse_lmstat: No regexp matches '{}'", line);
+        }
+    }
+
+    LmstatUsage {
+        fuv,
+        license_server,
+        uncounted,
+        issued,
+        used,
+        vendors,
+        borrowed,
+        queued,
+        reservations,
+    }
+}
+
+pub fn fetch(
+    lic: &config::FlexLM,
+    lmutil: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
+    let mut servers = configured_servers(&lic.license);
+
+    debug!(
+        "flexlm.rs:fetch: Running {} lmstat -c {} -a",
+        lmutil, &lic.license
+    );
+    let cmd = match runner.run(
+        Command::new(lmutil)
+            .env("LANG", "C")
+            .arg("lmstat")
+            .arg("-c")
+            .arg(&lic.license)
+            .arg("-a"),
+        None,
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            set_unknown_server_status(lic, &servers);
+            bail!("Can't run {} for {}: {}", lmutil, lic.name, e);
+        }
+    };
+
+    let rc = match cmd.status.code() {
+        Some(v) => v,
+        None => {
+            set_unknown_server_status(lic, &servers);
+            bail!("Can't get return code of {} command", lmutil);
+        }
+    };
+    debug!(
+        "flexlm.rs:fetch: external command finished with exit code {}",
+        rc
+    );
+
+    if !cmd.status.success() {
+        set_unknown_server_status(lic, &servers);
+        bail!(
+            "{} command exited with non-normal exit code {} for {}",
+            lmutil,
+            rc,
+            lic.name
+        );
+    }
+
+    let stdout = String::from_utf8(cmd.stdout)?;
+
+    if let Some(vendors) = &lic.vendors {
+        hide_vendors(lic, vendors, &stdout);
+    }
+
+    let LmstatUsage {
+        fuv,
+        license_server,
+        uncounted,
+        issued,
+        used,
+        vendors,
+        borrowed,
+        queued,
+        reservations,
+    } = parse_lmstat(lic, &stdout, &mut servers);
+
+    for (feature, in_use) in used.iter() {
+        let total = issued.get(feature).copied().unwrap_or_default();
+        debug!(
+            "flexlm.rs:fetch: Setting flexlm_feature_issued {} {} -> {}",
+            lic.name, feature, total
+        );
+        FLEXLM_FEATURES_TOTAL
+            .with_label_values(&[&lic.name, feature])
+            .set(total);
+
+        debug!(
+            "flexlm.rs:fetch: Setting flexlm_feature_used {} {} -> {}",
+            lic.name, feature, in_use
+        );
+        FLEXLM_FEATURES_USED
+            .with_label_values(&[&lic.name, feature])
+            .set(*in_use);
+    }
+
+    for vendor in vendors.iter() {
+        let status: i64 = if vendor.up { 1 } else { 0 };
+        debug!(
+            "flexlm.rs:fetch: Setting flexlm_vendor_status {} {} {} -> {}",
+            lic.name, vendor.name, vendor.version, status
+        );
+        FLEXLM_VENDOR_STATUS
+            .with_label_values(&[&lic.name, &vendor.name, &vendor.version])
+            .set(status);
+    }
+
+    stats::features(
+        "flexlm",
+        &lic.name,
+        issued
+            .keys()
+            .filter(|f| !lic.feature_filter.matches(f))
+            .count(),
+    );
+    stats::users(
+        "flexlm",
+        &lic.name,
+        stats::distinct_users(
+            fuv.iter()
+                .filter(|(f, _)| !lic.feature_filter.matches(f))
+                .flat_map(|(_, uv)| uv.keys()),
+        ),
+    );
+    topusers::export_fuv(&lic.name, &lic.feature_filter, &fuv);
+    topusers::export_user_count(
+        &lic.name,
+        &lic.feature_filter,
+        &FLEXLM_FEATURES_USER_COUNT,
+        fuv.iter().map(|(f, u)| (f, u.len())),
+    );
+    stats::servers(
+        "flexlm",
+        &lic.name,
+        servers
+            .iter()
+            .filter(|s| s.state != ServerState::Unknown)
+            .count(),
+    );
 
-            let server = capt.get(1).map_or("", |m| m.as_str());
-            let status = capt.get(2).map_or("", |m| m.as_str());
-            let master = capt.get(3).map_or("", |m| m.as_str());
-            let version = capt.get(4).map_or("", |m| m.as_str());
-            if status == "UP" {
-                server_status.insert(server.to_string(), 1);
-            }
-            if master == "(MASTER)" {
-                server_master.insert(server.to_string(), true);
-            }
-            server_version.insert(server.to_string(), version.to_string());
-        } else if let Some(capt) = RE_LMSTAT_VENDOR_STATUS.captures(line) {
-            if capt.len() != 4 {
-                error!(
-                    "flexlm.rs:fetch: Regular expression returns {} capture groups instead of 4 for RE_LMSTAT_VENDOR_STATUS",
-                    capt.len()
-                );
-                continue;
-            }
+    let exclude_uncounted = lic.exclude_uncounted.unwrap_or(false);
+    for feat in uncounted.iter() {
+        if lic.feature_filter.is_excluded(feat) {
+            debug!("flexlm.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
+            continue;
+        }
 
+        if exclude_uncounted {
             debug!(
-                "flexlm.rs:fetch: RE_LMSTAT_VENDOR_STATUS match on '{}'",
-                line
+                "flexlm.rs:fetch: Skipping uncounted feature {} of {} because exclude_uncounted is set",
+                feat, lic.name
             );
+            continue;
+        }
 
-            let vendor = capt.get(1).map_or("", |m| m.as_str());
-            let _status = capt.get(2).map_or("", |m| m.as_str());
-            let mut status: i64 = 0;
-            if _status == "UP" {
-                status = 1;
-            }
-            let version = capt.get(3).map_or("", |m| m.as_str());
+        debug!(
+            "flexlm.rs:fetch: Setting flexlm_feature_uncounted {} {} -> 1",
+            lic.name, feat
+        );
+        FLEXLM_FEATURES_UNCOUNTED
+            .with_label_values(&[&lic.name, feat])
+            .set(1);
+    }
 
-            debug!(
-                "flexlm.rs:fetch: Setting flexlm_vendor_status {} {} {} -> {}",
-                lic.name, vendor, version, status
+    if lic.export_borrow.unwrap_or(false) {
+        set_borrowed(lic, &borrowed);
+    }
+
+    set_reservations(lic, &reservations);
+
+    if let Some(options_file) = &lic.options_file {
+        let names = policy_names(issued.keys().chain(uncounted.iter()));
+        if let Err(e) = set_policy(lic, options_file, &names) {
+            error!(
+                "flexlm.rs:fetch: Can't parse options file {} of {}: {}",
+                options_file, lic.name, e
             );
-            FLEXLM_VENDOR_STATUS
-                .with_label_values(&[&lic.name, vendor, version])
-                .set(status);
+        }
+    }
+
+    if let Some(license_file) = &lic.license_file {
+        let issued = if lic.vendor_suffix.unwrap_or(false) {
+            issued_without_suffix(&issued)
         } else {
-            debug!("flexlm.rs:fetch: No regexp matches '{}'", line);
+            issued
+        };
+        if let Err(e) = check_license_file(lic, license_file, &issued) {
+            error!(
+                "flexlm.rs:fetch: Can't compare license file {} with license server for {}: {}",
+                license_file, lic.name, e
+            );
         }
     }
 
     if !license_server.is_empty() {
-        match fetch_expiration(lic, lmutil, license_server) {
+        match series::cached(
+            "flexlm",
+            &lic.name,
+            lic.expiration_interval.map(Duration::from_secs),
+            &expiration_vectors(),
+            || fetch_expiration(lic, lmutil, runner, license_server),
+        ) {
             Ok(_) => {}
             Err(e) => {
                 error!("flexlm.rs:fetch: Unable to fetch expiration dates: {}", e);
@@ -330,25 +1030,12 @@ pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
         );
     }
 
-    for server in server_status.keys() {
-        let status = server_status.get(server).unwrap_or(&0);
-        let _master = server_master.get(server).unwrap_or(&false);
-        let master = format!("{}", _master);
-        let port = match server_port.get(server) {
-            Some(v) => v,
-            None => "",
-        };
-        let version = match server_version.get(server) {
-            Some(v) => v,
-            None => "",
-        };
-        debug!(
-            "flexlm.rs:fetch: Setting flexlm_server_status {} {} {} {} {} -> {}",
-            lic.name, server, master, port, version, status
-        );
-        FLEXLM_SERVER_STATUS
-            .with_label_values(&[&lic.name, server, &master, port, version])
-            .set(*status);
+    for server in servers.iter() {
+        set_server_status(lic, server);
+    }
+
+    if lic.export_user.unwrap_or(false) {
+        set_queue_wait(lic, &queued);
     }
 
     if let Some(export_user) = lic.export_user {
@@ -356,10 +1043,14 @@ pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
             for (feat, uv) in fuv.iter() {
                 for (user, v) in uv.iter() {
                     for (version, count) in v.iter() {
-                        if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+                        if lic.feature_filter.is_excluded(feat) {
                             debug!("flexlm.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
                             continue;
                         }
+                        if exclude_uncounted && uncounted.contains(feat) {
+                            debug!("flexlm.rs:fetch: Skipping uncounted feature {} of {} because exclude_uncounted is set", feat, lic.name);
+                            continue;
+                        }
                         debug!(
                             "flexlm.rs:fetch: Setting flexlm_feature_used_users {} {} {} {} -> {}",
                             lic.name, feat, user, version, *count
@@ -376,34 +1067,116 @@ pub fn fetch(lic: &config::FlexLM, lmutil: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+fn set_borrowed(lic: &config::FlexLM, borrowed: &[FlexLMBorrow]) {
+    let mut counts: HashMap<(&str, &str, &str, &str), i64> = HashMap::new();
+    for b in borrowed.iter() {
+        if lic.feature_filter.is_excluded(&b.feature) {
+            debug!("flexlm.rs:set_borrowed: Skipping feature {} because it is in excluded_features list of {}", b.feature, lic.name);
+            continue;
+        }
+        *counts
+            .entry((&b.feature, &b.user, &b.host, &b.expires))
+            .or_insert(0) += b.count;
+    }
+
+    for ((feature, user, host, expires), count) in counts {
+        debug!(
+            "flexlm.rs:set_borrowed: Setting flexlm_borrowed_licenses {} {} {} {} {} -> {}",
+            lic.name, feature, user, host, expires, count
+        );
+        FLEXLM_BORROWED
+            .with_label_values(&[&lic.name, feature, user, host, expires])
+            .set(count);
+    }
+}
+
+// Reservations for single users are user-level data and only exported if export_user is set
+fn set_reservations(lic: &config::FlexLM, reservations: &[FlexLMReservation]) {
+    let export_user = lic.export_user.unwrap_or(false);
+
+    let mut counts: HashMap<(&str, &str, &str), i64> = HashMap::new();
+    for r in reservations.iter() {
+        if lic.feature_filter.is_excluded(&r.feature) {
+            debug!("flexlm.rs:set_reservations: Skipping feature {} because it is in excluded_features list of {}", r.feature, lic.name);
+            continue;
+        }
+        if r.kind == "user" && !export_user {
+            continue;
+        }
+        *counts.entry((&r.feature, &r.group, &r.kind)).or_insert(0) += r.count;
+    }
+
+    for ((feature, group, kind), count) in counts {
+        debug!(
+            "flexlm.rs:set_reservations: Setting flexlm_feature_reservations {} {} {} {} -> {}",
+            lic.name, feature, group, kind, count
+        );
+        FLEXLM_FEATURE_RESERVATIONS
+            .with_label_values(&[&lic.name, feature, group, kind])
+            .set(count);
+    }
+}
+
+// Longest wait per feature and user, a user can be queued several times for a feature
+fn set_queue_wait(lic: &config::FlexLM, queued: &[FlexLMQueued]) {
+    let mut waits: HashMap<(&str, &str), i64> = HashMap::new();
+    for q in queued.iter() {
+        if lic.feature_filter.is_excluded(&q.feature) {
+            debug!("flexlm.rs:set_queue_wait: Skipping feature {} because it is in excluded_features list of {}", q.feature, lic.name);
+            continue;
+        }
+        let wait = match q.wait {
+            Some(v) => v,
+            None => continue,
+        };
+        let max = waits.entry((&q.feature, &q.user)).or_insert(0);
+        *max = (*max).max(wait);
+    }
+
+    for ((feature, user), wait) in waits {
+        debug!(
+            "flexlm.rs:set_queue_wait: Setting flexlm_queue_wait_seconds {} {} {} -> {}",
+            lic.name, feature, user, wait
+        );
+        FLEXLM_QUEUE_WAIT
+            .with_label_values(&[&lic.name, feature, user])
+            .set(wait);
+    }
+}
+
 fn fetch_expiration(
     lic: &config::FlexLM,
     lmutil: &str,
+    runner: &dyn command::CommandRunner,
     license_server: String,
 ) -> Result<(), Box<dyn Error>> {
     lazy_static! {
-        static ref RE_LMSTAT_EXPIRATION: Regex = Regex::new(r"^([\w\-+]+)\s+([\d.]+)\s+(\d+)\s+([\w-]+)\s+(\w+)$").unwrap();
+        // NOTE: #licenses is reported as "uncounted" for uncounted, node-locked licenses
+        static ref RE_LMSTAT_EXPIRATION: Regex = Regex::new(r"^([\w\-+]+)\s+([\d.]+)\s+(\d+|uncounted)\s+([\w-]+)\s+(\w+)$").unwrap();
         // Some license servers, especially on MICROS~1 Windows, report Feature,Version,#licenses,Vendor.Expires instead of Feature,Version,#licenses,Expires,Vendor
-        static ref RE_LMSTAT_ALTERNATIVE_EXPIRATION: Regex = Regex::new(r"^([\w\-+]+)\s+([\d.]+)\s+(\d+)\s+(\w+)\s+([\w-]+)$").unwrap();
+        static ref RE_LMSTAT_ALTERNATIVE_EXPIRATION: Regex = Regex::new(r"^([\w\-+]+)\s+([\d.]+)\s+(\d+|uncounted)\s+(\w+)\s+([\w-]+)$").unwrap();
     }
 
     let mut expiring = Vec::<FlexLMLicenseExpiration>::new();
     let mut aggregated_expiration: HashMap<String, Vec<FlexLMLicenseExpiration>> = HashMap::new();
     let mut expiration_dates = Vec::<f64>::new();
+    let exclude_uncounted = lic.exclude_uncounted.unwrap_or(false);
 
     // NOTE: lmutil lmstat -i queries the  local license file. To avoid stale data, we query the extracted
     //       license servers from  lmstat -c ... -a output instead.
-    env::set_var("LANG", "C");
     debug!(
         "flexlm.rs:fetch_expiration: Running {} lmstat -c {} -i",
         lmutil, license_server
     );
-    let cmd = Command::new(lmutil)
-        .arg("lmstat")
-        .arg("-c")
-        .arg(license_server)
-        .arg("-i")
-        .output()?;
+    let cmd = runner.run(
+        Command::new(lmutil)
+            .env("LANG", "C")
+            .arg("lmstat")
+            .arg("-c")
+            .arg(license_server)
+            .arg("-i"),
+        None,
+    )?;
 
     let rc = match cmd.status.code() {
         Some(v) => v,
@@ -444,57 +1217,61 @@ fn fetch_expiration(
             let feature = capt.get(1).map_or("", |m| m.as_str());
             let version = capt.get(2).map_or("", |m| m.as_str());
             let _count = capt.get(3).map_or("", |m| m.as_str());
-            let count: i64 = match _count.parse() {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Can't parse {} as integer: {}", _count, e);
-                    continue;
-                }
-            };
-
-            let _expiration = capt.get(4).map_or("", |m| m.as_str());
-            let expiration: f64 = if _expiration == "1-jan-0"
-                || _expiration == "01-jan-0000"
-                || _expiration.starts_with("permanent")
-            {
-                f64::INFINITY
+            let license_type = parse_license_type(_count);
+            if exclude_uncounted && license_type == LICENSE_TYPE_UNCOUNTED {
+                debug!("flexlm.rs:fetch_expiration: Skipping uncounted feature {} of {} because exclude_uncounted is set", feature, lic.name);
+                continue;
+            }
+            let count: i64 = if license_type == LICENSE_TYPE_UNCOUNTED {
+                0
             } else {
-                match NaiveDateTime::parse_from_str(
-                    &format!("{} 00:00:00", _expiration),
-                    "%d-%b-%Y %H:%M:%S",
-                ) {
-                    Ok(v) => v.timestamp() as f64,
+                match _count.parse() {
+                    Ok(v) => v,
                     Err(e) => {
-                        error!(
-                            "flexlm.rs:fetch_expiration: Can't parse {} as date and time: {}",
-                            _expiration, e
-                        );
+                        error!("Can't parse {} as integer: {}", _count, e);
                         continue;
                     }
                 }
             };
 
+            let _expiration = capt.get(4).map_or("", |m| m.as_str());
+            let expiration: f64 = match dates::parse_expiration(_expiration) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "flexlm.rs:fetch_expiration: Can't parse {} as date and time: {}",
+                        _expiration, e
+                    );
+                    continue;
+                }
+            };
+
             let vendor = capt.get(5).map_or("", |m| m.as_str());
+            learn_vendors(lic, [(feature.to_string(), vendor.to_string())]);
+            if !vendor_selected(lic, vendor) {
+                continue;
+            }
+            let feature = feature_name(lic, feature, vendor);
 
             expiration_dates.push(expiration);
             expiring.push(FlexLMLicenseExpiration {
-                feature: feature.to_string(),
+                feature: feature.clone(),
                 version: version.to_string(),
                 license_count: count,
                 expiration,
                 vendor: vendor.to_string(),
+                license_type: license_type.to_string(),
             });
 
             let expiration_str = expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<FlexLMLicenseExpiration>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(FlexLMLicenseExpiration {
-                feature: feature.to_string(),
+                feature,
                 version: version.to_string(),
                 license_count: count,
                 expiration,
                 vendor: vendor.to_string(),
+                license_type: license_type.to_string(),
             });
         } else if let Some(capt) = RE_LMSTAT_ALTERNATIVE_EXPIRATION.captures(line) {
             if capt.len() != 6 {
@@ -513,78 +1290,92 @@ fn fetch_expiration(
             let feature = capt.get(1).map_or("", |m| m.as_str());
             let version = capt.get(2).map_or("", |m| m.as_str());
             let _count = capt.get(3).map_or("", |m| m.as_str());
-            let count: i64 = match _count.parse() {
-                Ok(v) => v,
-                Err(e) => {
-                    error!(
-                        "flexlm.rs:fetch_expiration: Can't parse {} as integer: {}",
-                        _count, e
-                    );
-                    continue;
-                }
-            };
-
-            let _expiration = capt.get(5).map_or("", |m| m.as_str());
-            let expiration: f64 = if _expiration == "1-jan-0"
-                || _expiration == "01-jan-0000"
-                || _expiration.starts_with("permanent")
-            {
-                f64::INFINITY
+            let license_type = parse_license_type(_count);
+            if exclude_uncounted && license_type == LICENSE_TYPE_UNCOUNTED {
+                debug!("flexlm.rs:fetch_expiration: Skipping uncounted feature {} of {} because exclude_uncounted is set", feature, lic.name);
+                continue;
+            }
+            let count: i64 = if license_type == LICENSE_TYPE_UNCOUNTED {
+                0
             } else {
-                match NaiveDateTime::parse_from_str(
-                    &format!("{} 00:00:00", _expiration),
-                    "%d-%b-%Y %H:%M:%S",
-                ) {
-                    Ok(v) => v.timestamp() as f64,
+                match _count.parse() {
+                    Ok(v) => v,
                     Err(e) => {
                         error!(
-                            "flexlm.rs:fetch_expiration: Can't parse {} as date and time: {}",
-                            _expiration, e
+                            "flexlm.rs:fetch_expiration: Can't parse {} as integer: {}",
+                            _count, e
                         );
                         continue;
                     }
                 }
             };
 
-            expiration_dates.push(expiration);
+            let _expiration = capt.get(5).map_or("", |m| m.as_str());
+            let expiration: f64 = match dates::parse_expiration(_expiration) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "flexlm.rs:fetch_expiration: Can't parse {} as date and time: {}",
+                        _expiration, e
+                    );
+                    continue;
+                }
+            };
+
             let vendor = capt.get(4).map_or("", |m| m.as_str());
+            learn_vendors(lic, [(feature.to_string(), vendor.to_string())]);
+            if !vendor_selected(lic, vendor) {
+                continue;
+            }
+            let feature = feature_name(lic, feature, vendor);
+
+            expiration_dates.push(expiration);
             expiring.push(FlexLMLicenseExpiration {
-                feature: feature.to_string(),
+                feature: feature.clone(),
                 version: version.to_string(),
                 license_count: count,
                 expiration,
                 vendor: vendor.to_string(),
+                license_type: license_type.to_string(),
             });
 
             let expiration_str = expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<FlexLMLicenseExpiration>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(FlexLMLicenseExpiration {
-                feature: feature.to_string(),
+                feature,
                 version: version.to_string(),
                 license_count: count,
                 expiration,
                 vendor: vendor.to_string(),
+                license_type: license_type.to_string(),
             });
         } else {
             debug!("flexlm.rs:fetch_expiration: No regexp matches '{}'", line);
         }
     }
 
+    // dict -> "feature" -> "model" -> count
+    let mut model_issued: BTreeMap<String, BTreeMap<&str, i64>> = BTreeMap::new();
     let mut index: i64 = 1;
     for entry in expiring {
-        if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&entry.feature) {
             debug!("flexlm.rs:fetch_expiration: Skipping feature {} because it is in excluded_features list of {}", entry.feature, lic.name);
             continue;
         }
 
+        *model_issued
+            .entry(entry.feature.clone())
+            .or_default()
+            .entry(license_model(&entry.license_type))
+            .or_insert(0) += entry.license_count;
+
         debug!(
-            "flexlm.rs:fetch_expiration: Setting flexlm_feature_used_users {} {} {} {} {} {} -> {}",
+            "flexlm.rs:fetch_expiration: Setting flexlm_feature_used_users {} {} {} {} {} {} {} -> {}",
             lic.name,
             index,
-            entry.license_count.to_string(),
+            entry.license_count,
             entry.feature,
+            entry.license_type,
             entry.vendor,
             entry.version,
             entry.expiration
@@ -595,6 +1386,7 @@ fn fetch_expiration(
                 &index.to_string(),
                 &entry.license_count.to_string(),
                 &entry.feature,
+                &entry.license_type,
                 &entry.vendor,
                 &entry.version,
             ])
@@ -602,6 +1394,18 @@ fn fetch_expiration(
         index += 1;
     }
 
+    for (feature, models) in model_issued.iter() {
+        for (model, count) in models.iter() {
+            debug!(
+                "flexlm.rs:fetch_expiration: Setting flexlm_feature_model_issued {} {} {} -> {}",
+                lic.name, feature, model, count
+            );
+            FLEXLM_FEATURES_MODEL_TOTAL
+                .with_label_values(&[&lic.name, feature, model])
+                .set(*count);
+        }
+    }
+
     index = 0;
 
     expiration_dates.sort_by(|a, b| a.partial_cmp(b).unwrap());
@@ -636,26 +1440,452 @@ fn fetch_expiration(
     Ok(())
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_SERVER_STATUS.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_VENDOR_STATUS.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(FLEXLM_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
+// Rules for single users are user-level data and only exported if export_user is set. names
+// maps the features of the options file to their name in the usage metrics.
+fn set_policy(
+    lic: &config::FlexLM,
+    options_file: &str,
+    names: &HashMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+    let export_user = lic.export_user.unwrap_or(false);
+    let rules = optionsfile::parse_flexlm(options_file)?;
+
+    let mut values: HashMap<(optionsfile::RuleKind, &str, String, &str), i64> = HashMap::new();
+    for r in rules.iter() {
+        if lic.feature_filter.is_excluded(&r.feature) {
+            debug!("flexlm.rs:set_policy: Skipping feature {} because it is in excluded_features list of {}", r.feature, lic.name);
+            continue;
+        }
+        if r.target_type == "user" && !export_user {
+            continue;
+        }
+        let target = if r.target_type == "user" {
+            lic.anonymizer.user(&r.target)
+        } else {
+            r.target.to_string()
+        };
+        let feature = names.get(&r.feature).unwrap_or(&r.feature);
+        // Limits for several versions of a feature add up
+        *values
+            .entry((r.kind, feature, target, &r.target_type))
+            .or_insert(0) += r.count.unwrap_or(1);
+    }
+
+    for ((kind, feature, group, target_type), value) in values {
+        let (gauge, value) = match kind {
+            optionsfile::RuleKind::Max => (&*FLEXLM_POLICY_MAX, value),
+            optionsfile::RuleKind::Reserve => (&*FLEXLM_POLICY_RESERVE, value),
+            optionsfile::RuleKind::Include => (&*FLEXLM_POLICY_INCLUDE, 1),
+            optionsfile::RuleKind::Exclude => (&*FLEXLM_POLICY_EXCLUDE, 1),
+        };
+        debug!(
+            "flexlm.rs:set_policy: Setting {} {} {} {} {} -> {}",
+            gauge.desc()[0].fq_name,
+            lic.name,
+            feature,
+            group,
+            target_type,
+            value
+        );
+        gauge
+            .with_label_values(&[&lic.name, feature, &group, target_type])
+            .set(value);
+    }
+
+    Ok(())
+}
+
+fn check_license_file(
+    lic: &config::FlexLM,
+    license_file: &str,
+    issued: &HashMap<String, i64>,
+) -> Result<(), Box<dyn Error>> {
+    let from_file = licensefile::parse_flexlm(license_file)?;
+    let mismatches = licensefile::compare(&from_file, issued, &lic.feature_filter);
+
+    for m in mismatches.iter() {
+        debug!(
+            "flexlm.rs:check_license_file: Setting flexlm_license_file_feature_mismatch {} {} {} -> 1 (license file: {}, server: {})",
+            lic.name, m.feature, m.reason, m.file_count, m.server_count
+        );
+        FLEXLM_LICENSE_FILE_FEATURE_MISMATCH
+            .with_label_values(&[&lic.name, &m.feature, m.reason])
+            .set(1);
+    }
+
+    debug!(
+        "flexlm.rs:check_license_file: Setting flexlm_license_file_mismatch {} -> {}",
+        lic.name,
+        mismatches.len()
+    );
+    FLEXLM_LICENSE_FILE_MISMATCH
+        .with_label_values(&[&lic.name])
+        .set(mismatches.len() as i64);
+
+    Ok(())
+}
+
+// Name of a feature in the usage metrics, with vendor_suffix the vendor daemon is appended to
+// tell features of the same name of several vendor daemons apart, e.g. feat_a@vendx
+fn feature_name(lic: &config::FlexLM, feature: &str, vendor: &str) -> String {
+    if lic.vendor_suffix.unwrap_or(false) && !vendor.is_empty() {
+        format!(
+            "{}{}{}",
+            feature,
+            constants::VENDOR_SUFFIX_SEPARATOR,
+            vendor
+        )
+    } else {
+        feature.to_string()
+    }
+}
+
+// Names of the features in the usage metrics by their name in the options file, which doesn't
+// know about the vendor suffix. The options file belongs to a single vendor daemon but doesn't
+// name it, so features served by several vendor daemons keep their plain name.
+fn policy_names<'a, I>(features: I) -> HashMap<String, String>
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let mut names: HashMap<String, HashSet<&String>> = HashMap::new();
+    for feature in features {
+        let plain = feature
+            .split(constants::VENDOR_SUFFIX_SEPARATOR)
+            .next()
+            .unwrap_or(feature);
+        names.entry(plain.to_string()).or_default().insert(feature);
+    }
+
+    names
+        .into_iter()
+        .filter(|(_, suffixed)| suffixed.len() == 1)
+        .filter_map(|(plain, suffixed)| suffixed.into_iter().next().map(|n| (plain, n.clone())))
+        .collect()
+}
+
+// Licenses of all vendor daemons of a feature add up, the license file doesn't know about the
+// vendor suffix
+fn issued_without_suffix(issued: &HashMap<String, i64>) -> HashMap<String, i64> {
+    let mut result: HashMap<String, i64> = HashMap::new();
+    for (feature, count) in issued {
+        let name = feature
+            .split(constants::VENDOR_SUFFIX_SEPARATOR)
+            .next()
+            .unwrap_or(feature);
+        *result.entry(name.to_string()).or_insert(0) += count;
+    }
+    result
+}
+
+fn vendor_selected(lic: &config::FlexLM, vendor: &str) -> bool {
+    match &lic.vendors {
+        Some(v) => v.iter().any(|v| v == vendor),
+        None => true,
+    }
+}
+
+// Remember the vendor daemons of (feature, vendor) for licenses with vendors. lmstat -a only
+// reports the vendor of features with checkouts, all features are listed with their vendor by
+// lmstat -i.
+fn learn_vendors<I>(lic: &config::FlexLM, features: I)
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    if lic.vendors.is_none() {
+        return;
+    }
+
+    match lic.feature_vendors.lock() {
+        Ok(mut v) => v.extend(features),
+        Err(e) => error!("flexlm.rs:learn_vendors: Can't lock feature vendors: {}", e),
+    };
+}
+
+// Additionally exclude the features of all vendor daemons not listed in vendors. Features of
+// unknown vendor daemons are kept until their vendor has been reported.
+fn hide_vendors(lic: &config::FlexLM, vendors: &[String], raw: &str) {
+    lazy_static! {
+        static ref RE_LMSTAT_FEATURE_VENDOR: Regex =
+            Regex::new(r#"^\s+"([^"]+)" v\S+, vendor: (\w+)"#).unwrap();
+    }
+
+    learn_vendors(
+        lic,
+        raw.lines()
+            .filter_map(|l| RE_LMSTAT_FEATURE_VENDOR.captures(l))
+            .map(|capt| {
+                (
+                    capt.get(1).map_or("", |m| m.as_str()).to_string(),
+                    capt.get(2).map_or("", |m| m.as_str()).to_string(),
+                )
+            }),
+    );
+
+    match lic.feature_vendors.lock() {
+        Ok(v) => lic.feature_filter.hide(
+            v.iter()
+                .filter(|(_, v)| !vendors.contains(v))
+                .map(|(f, _)| f.clone()),
+        ),
+        Err(e) => error!("flexlm.rs:hide_vendors: Can't lock feature vendors: {}", e),
+    };
+}
+
+fn parse_license_type(count: &str) -> &'static str {
+    if count.eq_ignore_ascii_case(LICENSE_TYPE_UNCOUNTED) {
+        LICENSE_TYPE_UNCOUNTED
+    } else {
+        LICENSE_TYPE_FLOATING
+    }
+}
+
+// lmstat -i only distinguishes counted and uncounted licenses. Uncounted licenses are always
+// node-locked, counted licenses are reported as floating.
+fn license_model(license_type: &str) -> &'static str {
+    if license_type == LICENSE_TYPE_UNCOUNTED {
+        license::MODEL_NODE_LOCKED
+    } else {
+        license::MODEL_FLOATING
+    }
+}
+
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(FLEXLM_FEATURES_TOTAL.clone()),
+        Box::new(FLEXLM_FEATURES_USED.clone()),
+        Box::new(FLEXLM_FEATURES_USER.clone()),
+        Box::new(FLEXLM_FEATURES_USER_COUNT.clone()),
+        Box::new(FLEXLM_BORROWED.clone()),
+        Box::new(FLEXLM_FEATURE_RESERVATIONS.clone()),
+        Box::new(FLEXLM_POLICY_MAX.clone()),
+        Box::new(FLEXLM_POLICY_RESERVE.clone()),
+        Box::new(FLEXLM_POLICY_INCLUDE.clone()),
+        Box::new(FLEXLM_POLICY_EXCLUDE.clone()),
+        Box::new(FLEXLM_QUEUE_WAIT.clone()),
+        Box::new(FLEXLM_FEATURES_UNCOUNTED.clone()),
+        Box::new(FLEXLM_SERVER_STATUS.clone()),
+        Box::new(FLEXLM_SERVER_STATE.clone()),
+        Box::new(FLEXLM_VENDOR_STATUS.clone()),
+        Box::new(FLEXLM_FEATURES_MODEL_TOTAL.clone()),
+        Box::new(FLEXLM_FEATURE_EXPIRATION.clone()),
+        Box::new(FLEXLM_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(FLEXLM_LICENSE_FILE_MISMATCH.clone()),
+        Box::new(FLEXLM_LICENSE_FILE_FEATURE_MISMATCH.clone()),
+        Box::new(FLEXLM_LAST_UPDATED.clone()),
+    ]
+}
+
+// Vectors set by fetch_expiration
+fn expiration_vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&FLEXLM_FEATURES_MODEL_TOTAL),
+        series::Vector::Gauge(&FLEXLM_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&FLEXLM_FEATURE_AGGREGATED_EXPIRATION),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    let mut vectors = vec![
+        series::Vector::IntGauge(&FLEXLM_FEATURES_TOTAL),
+        series::Vector::IntGauge(&FLEXLM_FEATURES_USED),
+        series::Vector::IntGauge(&FLEXLM_FEATURES_USER),
+        series::Vector::IntGauge(&FLEXLM_FEATURES_USER_COUNT),
+        series::Vector::IntGauge(&FLEXLM_BORROWED),
+        series::Vector::IntGauge(&FLEXLM_FEATURE_RESERVATIONS),
+        series::Vector::IntGauge(&FLEXLM_POLICY_MAX),
+        series::Vector::IntGauge(&FLEXLM_POLICY_RESERVE),
+        series::Vector::IntGauge(&FLEXLM_POLICY_INCLUDE),
+        series::Vector::IntGauge(&FLEXLM_POLICY_EXCLUDE),
+        series::Vector::IntGauge(&FLEXLM_QUEUE_WAIT),
+        series::Vector::IntGauge(&FLEXLM_FEATURES_UNCOUNTED),
+        series::Vector::IntGauge(&FLEXLM_SERVER_STATUS),
+        series::Vector::IntGauge(&FLEXLM_SERVER_STATE),
+        series::Vector::IntGauge(&FLEXLM_VENDOR_STATUS),
+        series::Vector::IntGauge(&FLEXLM_LICENSE_FILE_MISMATCH),
+        series::Vector::IntGauge(&FLEXLM_LICENSE_FILE_FEATURE_MISMATCH),
+    ];
+    vectors.extend(expiration_vectors());
+    vectors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let cfg = testutil::parse_config(&format!(
+            "flexlm:\n  - name: flex\n    license: 27000@lic1\n    export_user: true\n    export_borrow: true\n    options_file: '{0}'\n  - name: flex-suffix\n    license: 27000@lic1\n    vendor_suffix: true\n    options_file: '{0}'\n",
+            testutil::fixture_path("flexlm/vendx.opt")
+        ));
+        let registry = Registry::new();
+        register_into(&registry);
+
+        let runner = command::FixtureRunner::new()
+            .reply(
+                &["lmstat", "-c", "27000@lic1", "-a"],
+                &testutil::fixture("flexlm/lmstat_a.txt"),
+            )
+            .reply(
+                &["lmstat", "-c", "27000@lic1", "-i"],
+                &testutil::fixture("flexlm/lmstat_i.txt"),
+            );
+        let flexlm = cfg.flexlm.unwrap();
+        fetch(&flexlm[0], "lmutil", &runner).unwrap();
+        fetch(&flexlm[1], "lmutil", &runner).unwrap();
+        let rendered = testutil::render(&registry);
+        testutil::assert_golden("flexlm", &rendered);
+
+        // With vendor_suffix, the usage, expiration and policy metrics can be joined on the
+        // feature name
+        for metric in [
+            "flexlm_feature_issued",
+            "flexlm_feature_expiration_seconds",
+            "flexlm_feature_model_issued",
+            "flexlm_policy_max",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l.starts_with(metric)
+                    && l.contains("app=\"flex-suffix\"")
+                    && l.contains("name=\"feat_a@vendx\"")),
+                "{}",
+                metric
+            );
+        }
+    }
+
+    #[test]
+    fn parse_lmstat_data() {
+        let cfg =
+            testutil::parse_config("flexlm:\n  - name: flex-parse\n    license: 27000@lic1\n");
+        let lic = &cfg.flexlm.unwrap()[0];
+        let raw = String::from_utf8(testutil::fixture("flexlm/lmstat_a.txt")).unwrap();
+
+        let mut servers = Vec::new();
+        let usage = parse_lmstat(lic, &raw, &mut servers);
+        assert_eq!(usage.issued["feat_a"], 10);
+        assert_eq!(usage.used["feat_a"], 5);
+        assert!(usage.uncounted.contains("feat_b"));
+        assert!(!usage.used.contains_key("feat_b"));
+        assert_eq!(
+            usage.vendors,
+            vec![FlexLMVendor {
+                name: "vendx".to_string(),
+                version: "v11.16.2".to_string(),
+                up: true,
+            }]
+        );
+        assert_eq!(usage.license_server, "27000@lic1");
+        assert!(servers.iter().any(|s| s.fqdn == "lic1" && s.master));
+    }
+
+    #[test]
+    fn vendor_filter() {
+        let cfg = testutil::parse_config(
+            "flexlm:\n  - name: flex-vendors\n    license: 27000@lic1\n    vendors: ['other']\n",
+        );
+        let lic = &cfg.flexlm.unwrap()[0];
+        let vendors = lic.vendors.clone().unwrap();
+        let raw = String::from_utf8(testutil::fixture("flexlm/lmstat_a.txt")).unwrap();
+
+        assert!(!lic.feature_filter.matches("feat_a"));
+        hide_vendors(lic, &vendors, &raw);
+        assert!(lic.feature_filter.matches("feat_a"));
+        assert!(lic.feature_filter.matches("feat_b"));
+        // feat_c has no checkouts, its vendor is only known from lmstat -i
+        assert!(!lic.feature_filter.matches("feat_c"));
+
+        learn_vendors(lic, [("feat_c".to_string(), "vendx".to_string())]);
+        hide_vendors(lic, &vendors, &raw);
+        assert!(lic.feature_filter.matches("feat_c"));
+
+        // A reloaded license starts without the learned vendors
+        let cfg = testutil::parse_config(
+            "flexlm:\n  - name: flex-vendors\n    license: 27000@lic1\n    vendors: ['other']\n",
+        );
+        let lic = &cfg.flexlm.unwrap()[0];
+        assert!(lic.feature_vendors.lock().unwrap().is_empty());
+        assert!(!lic.feature_filter.matches("feat_a"));
+
+        assert!(!vendor_selected(lic, "vendx"));
+        assert!(vendor_selected(lic, "other"));
+    }
+
+    #[test]
+    fn vendor_suffix() {
+        let cfg = testutil::parse_config(
+            "flexlm:\n  - name: flex-plain\n    license: 27000@lic1\n  - name: flex-suffix\n    license: 27000@lic2\n    vendor_suffix: true\n    excluded_features: ['feat_b']\n",
+        );
+        let flexlm = cfg.flexlm.unwrap();
+        assert_eq!(feature_name(&flexlm[0], "feat_a", "vendx"), "feat_a");
+        assert_eq!(feature_name(&flexlm[1], "feat_a", "vendx"), "feat_a@vendx");
+        assert_eq!(feature_name(&flexlm[1], "feat_a", ""), "feat_a");
+
+        // excluded_features don't include the vendor daemon
+        assert!(flexlm[1].feature_filter.matches("feat_b@vendy"));
+        assert!(!flexlm[1].feature_filter.matches("feat_a@vendy"));
+
+        let issued: HashMap<String, i64> = [
+            ("feat_a@vendx".to_string(), 10),
+            ("feat_a@vendy".to_string(), 5),
+            ("feat_c".to_string(), 1),
+        ]
+        .into_iter()
+        .collect();
+        let plain = issued_without_suffix(&issued);
+        assert_eq!(plain.len(), 2);
+        assert_eq!(plain["feat_a"], 15);
+        assert_eq!(plain["feat_c"], 1);
+    }
+
+    #[test]
+    fn wrapped_user_lines() {
+        let raw = String::from_utf8(testutil::fixture("flexlm/lmstat_a.txt")).unwrap();
+        let wrapped = String::from_utf8(testutil::fixture("flexlm/lmstat_a_wrapped.txt")).unwrap();
+        assert_eq!(join_wrapped_lines(&wrapped), join_wrapped_lines(&raw));
+        assert_eq!(
+            join_wrapped_lines(&raw).lines().count(),
+            raw.lines().count()
+        );
+
+        // Continuation lines indented by the console
+        assert_eq!(
+            join_wrapped_lines(
+                "    alice host1 /dev/tty (v1.0)\n        (lic1/27000 101), start Mon 1/2 9:00\n\n"
+            ),
+            "    alice host1 /dev/tty (v1.0) (lic1/27000 101), start Mon 1/2 9:00\n\n"
+        );
+    }
+
+    #[test]
+    fn borrow_expiration_year() {
+        let status_date = NaiveDate::from_ymd_opt(2023, 1, 2);
+        assert_eq!(
+            borrow_expiration("12/30 8:00", 1209600, status_date),
+            "2023-01-13 08:00"
+        );
+        assert_eq!(
+            borrow_expiration("1/2 9:00", 3600, status_date),
+            "2023-01-02 10:00"
+        );
+        assert_eq!(borrow_expiration("13/40 9:00", 3600, status_date), "");
+    }
+
+    #[test]
+    fn queue_wait_seconds() {
+        let status_time = NaiveDate::from_ymd_opt(2023, 1, 2).and_then(|d| d.and_hms_opt(10, 0, 0));
+        assert_eq!(queue_wait("1/2 9:45", status_time), Some(900));
+        assert_eq!(queue_wait("12/31 10:00", status_time), Some(172800));
+        assert_eq!(queue_wait("13/40 9:00", status_time), None);
+    }
 }
@@ -0,0 +1,114 @@
+use crate::config;
+use crate::constants;
+use crate::flexlm::{self, FlexLMServer, ServerState};
+use crate::resolver;
+
+use log::debug;
+use simple_error::bail;
+use std::error::Error;
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Experimental query of the license servers without lmutil, enabled by the
+// experimental_native_flexlm feature and the native setting of a FlexLM license.
+//
+// The lmgrd protocol is not documented, so for now only the reachability of lmgrd is
+// checked and reported as flexlm_server_status/flexlm_server_state. Feature counts still
+// require lmutil.
+pub fn fetch(lic: &config::FlexLM) -> Result<(), Box<dyn Error>> {
+    let mut servers = flexlm::configured_servers(&lic.license);
+    if servers.is_empty() {
+        bail!(
+            "No license server (port@server) configured for {}, native queries can't use license files",
+            lic.name
+        );
+    }
+
+    if let Some(first) = servers.first_mut() {
+        first.master = true;
+    }
+
+    let timeout = Duration::from_secs(constants::DEFAULT_NATIVE_FLEXLM_TIMEOUT);
+    for server in servers.iter_mut() {
+        if server.port.is_empty() {
+            server.port = constants::DEFAULT_FLEXLM_PORT.to_string();
+        }
+        server.state = probe(server, timeout);
+        debug!(
+            "flexlm_native.rs:fetch: lmgrd on {}@{} of {} is {}",
+            server.port,
+            server.fqdn,
+            lic.name,
+            server.state.as_str()
+        );
+    }
+
+    for server in servers.iter() {
+        flexlm::set_server_status(lic, server);
+    }
+
+    if servers.iter().all(|s| s.state != ServerState::Up) {
+        bail!("No license server of {} is reachable", lic.name);
+    }
+
+    Ok(())
+}
+
+// lmgrd accepts connections on the configured port, the connection is closed without sending
+// any request
+fn probe(server: &FlexLMServer, timeout: Duration) -> ServerState {
+    let port: u16 = match server.port.parse() {
+        Ok(v) => v,
+        Err(e) => {
+            debug!(
+                "flexlm_native.rs:probe: Invalid port {} for {}: {}",
+                server.port, server.fqdn, e
+            );
+            return ServerState::Unknown;
+        }
+    };
+    let addresses = match resolver::socket_addrs(&server.fqdn, port) {
+        Ok(v) => v,
+        Err(e) => {
+            debug!("flexlm_native.rs:probe: {}", e);
+            return ServerState::Unknown;
+        }
+    };
+
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(_) => return ServerState::Up,
+            Err(e) => {
+                debug!(
+                    "flexlm_native.rs:probe: Can't connect to {}: {}",
+                    address, e
+                );
+            }
+        };
+    }
+
+    ServerState::Down
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn probe_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port().to_string();
+        let timeout = Duration::from_secs(1);
+
+        let mut server = FlexLMServer::new("127.0.0.1");
+        server.port = port;
+        assert!(probe(&server, timeout) == ServerState::Up);
+
+        drop(listener);
+        assert!(probe(&server, timeout) == ServerState::Down);
+
+        server.fqdn = String::new();
+        assert!(probe(&server, timeout) == ServerState::Unknown);
+    }
+}
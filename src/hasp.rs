@@ -1,18 +1,21 @@
 use crate::config;
 use crate::constants;
-use crate::exporter;
+use crate::dates;
 use crate::http;
-use crate::license;
+use crate::series;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use regex::Regex;
 use serde::Deserialize;
 use simple_error::bail;
 use std::collections::HashMap;
 use std::error::Error;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref HASP_FEATURES_TOTAL: IntGaugeVec = IntGaugeVec::new(
@@ -46,15 +49,31 @@ lazy_static! {
         &["app", "name", "user"],
     )
     .unwrap();
+    pub static ref HASP_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "hasp_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref HASP_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("hasp_server_status", "Status of license server"),
         &["app", "fqdn", "port", "hasp_key"],
     )
     .unwrap();
+    pub static ref HASP_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "hasp_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
 }
 
 #[derive(Deserialize)]
-struct HaspFeature {
+pub struct HaspFeature {
     pub fid: Option<String>,
     #[serde(rename = "fn")]
     pub fname: Option<String>,
@@ -64,7 +83,7 @@ struct HaspFeature {
 }
 
 #[derive(Deserialize)]
-struct HaspSession {
+pub struct HaspSession {
     pub fid: Option<String>,
     #[serde(rename = "fn")]
     pub fname: Option<String>,
@@ -77,13 +96,81 @@ struct HaspExpiration {
     pub license_count: i64,
 }
 
-pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
-    lazy_static! {
-        static ref RE_HASP_EXPIRATION: Regex =
-            Regex::new(r"^.*(\w{3} \w{3} \d+, \d+ \d+:\d+).*$").unwrap();
+// Perpetual licenses in the languages of the admin control center
+const HASP_PERPETUAL: [&str; 7] = [
+    "perpetual",
+    "unbefristet",
+    "unbegrenzt",
+    "perpétuel",
+    "permanent",
+    "perpetua",
+    "perpétua",
+];
+
+// Month names (or their unique prefix) in the languages of the admin control center,
+// English, German, French, Spanish, Italian and Portuguese
+const HASP_MONTHS: [(&str, u32); 35] = [
+    ("jan", 1),
+    ("ene", 1),
+    ("gen", 1),
+    ("feb", 2),
+    ("fév", 2),
+    ("fev", 2),
+    ("mar", 3),
+    ("mär", 3),
+    ("mrz", 3),
+    ("apr", 4),
+    ("avr", 4),
+    ("abr", 4),
+    ("may", 5),
+    ("mai", 5),
+    ("mag", 5),
+    ("jun", 6),
+    ("juin", 6),
+    ("giu", 6),
+    ("jul", 7),
+    ("juil", 7),
+    ("lug", 7),
+    ("aug", 8),
+    ("aoû", 8),
+    ("ago", 8),
+    ("sep", 9),
+    ("set", 9),
+    ("oct", 10),
+    ("okt", 10),
+    ("ott", 10),
+    ("out", 10),
+    ("nov", 11),
+    ("dec", 12),
+    ("dez", 12),
+    ("déc", 12),
+    ("dic", 12),
+];
+
+// The admin control center redirects to its login page if authentication is required but
+// fails, so redirects are reported as error instead of parsing the login page.
+// Features and sessions are requested from the same server, one idle connection is sufficient.
+fn client_settings(lic: &config::Hasp, proxy: &http::ProxySettings) -> http::ClientSettings {
+    http::ClientSettings {
+        accept: constants::HTTP_ACCEPT_HTML,
+        ca_file: lic.ca_file.clone().unwrap_or_default(),
+        client_certificate: lic.client_certificate.clone(),
+        hosts: vec![lic
+            .license
+            .rsplit('@')
+            .next()
+            .unwrap_or_default()
+            .to_string()],
+        insecure_ssl: lic.insecure_ssl.unwrap_or(false),
+        max_redirects: 0,
+        pool_max_idle_per_host: 1,
+        proxy: proxy.clone(),
+        timeout: Duration::from_secs(constants::DEFAULT_TIMEOUT),
     }
+}
 
-    let mut http_client = http::build_client(false, "", constants::DEFAULT_TIMEOUT)?;
+pub fn fetch(lic: &config::Hasp, proxy: &http::ProxySettings) -> Result<(), Box<dyn Error>> {
+    let mut http_client = http::build_client(&client_settings(lic, proxy))?;
     let mut expiring = Vec::<HaspExpiration>::new();
     let mut aggregated_expiration: HashMap<String, Vec<HaspExpiration>> = HashMap::new();
     let mut expiration_dates = Vec::<f64>::new();
@@ -99,17 +186,24 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
     }
 
     let url = format!(
-        "http://{}:{}/_int_/tab_feat.html?haspid={}",
-        server, port, lic.hasp_key
+        "{}://{}:{}/_int_/tab_feat.html?haspid={}",
+        http::scheme(lic.use_https),
+        server,
+        port,
+        lic.hasp_key
     );
-    let mut user: &str = "";
-    let mut pass: &str = "";
-    if let Some(auth) = &lic.authentication {
-        user = &auth.username;
-        pass = &auth.password;
-    }
+    let (user, pass) = match &lic.authentication {
+        Some(auth) => auth.credentials()?,
+        None => (String::new(), String::new()),
+    };
 
-    let reply = match http::get(&mut http_client, &url, user, pass) {
+    let reply = match http::get(
+        &mut http_client,
+        &url,
+        &user,
+        &pass,
+        lic.spnego.unwrap_or(false),
+    ) {
         Ok(v) => v,
         Err(e) => {
             debug!(
@@ -122,7 +216,7 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
             return Err(e);
         }
     };
-    let features: Vec<HaspFeature> = match serde_json::from_str(&massage(reply)) {
+    let features = match parse_features(reply) {
         Ok(v) => v,
         Err(e) => {
             debug!(
@@ -148,7 +242,9 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
     HASP_SERVER_STATUS
         .with_label_values(&[&lic.name, server, port, &lic.hasp_key])
         .set(1);
+    stats::servers("hasp", &lic.name, 1);
 
+    let mut feature_count: usize = 0;
     for feat in features {
         if feat.fid.is_some() {
             let fid = match feat.fid {
@@ -159,7 +255,7 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            if license::is_excluded(&lic.excluded_features, fid.to_string()) {
+            if lic.feature_filter.is_excluded(&fid) {
                 debug!("hasp.rs:fetch: Skipping feature id {} because it is in excluded_features list of {}", fid, lic.name);
                 continue;
             }
@@ -222,6 +318,7 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
             HASP_FEATURES_USED
                 .with_label_values(&[&lic.name, &fname])
                 .set(logc);
+            feature_count += 1;
 
             let _licexp = match feat.lic {
                 Some(v) => v,
@@ -241,40 +338,16 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
                 continue;
             }
 
-            let expiration: f64;
-            if _licexp == "Perpetual" {
-                expiration = f64::INFINITY
-            } else if let Some(capt) = RE_HASP_EXPIRATION.captures(&_licexp) {
-                let _expiration = capt.get(1).map_or("", |m| m.as_str());
-
-                if _expiration.is_empty() {
-                    bail!(
-                        "BUG - can't parse HASP license expiration from {} of feature id {} for {}",
-                        _licexp,
-                        fid,
-                        lic.name
+            let expiration = match parse_expiration(&_licexp) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!(
+                        "hasp.rs:fetch: Can't parse license expiration {} of feature id {} for {}: {}",
+                        _licexp, fid, lic.name, e
                     );
+                    continue;
                 }
-
-                expiration = match NaiveDateTime::parse_from_str(_expiration, "%a %b %d, %Y %H:%M")
-                {
-                    Ok(v) => v.timestamp() as f64,
-                    Err(e) => {
-                        error!(
-                            "hasp.rs:fetch: Can't parse {} as date and time: {}",
-                            _expiration, e
-                        );
-                        continue;
-                    }
-                }
-            } else {
-                bail!(
-                    "BUG - can't parse HASP license expiration from {} of feature id {} for {}",
-                    _licexp,
-                    fid,
-                    lic.name
-                );
-            }
+            };
 
             expiration_dates.push(expiration);
             expiring.push(HaspExpiration {
@@ -284,9 +357,7 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
             });
 
             let expiration_str = expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<HaspExpiration>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(HaspExpiration {
                 feature: fname,
                 license_count: logl,
@@ -295,9 +366,11 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
         }
     }
 
+    stats::features("hasp", &lic.name, feature_count);
+
     let mut index: i64 = 1;
     for entry in expiring {
-        if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&entry.feature) {
             debug!(
                 "hasp.rs:fetch: Skipping feature {} because it is in excluded_features list of {}",
                 entry.feature, lic.name
@@ -306,11 +379,7 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
         }
         debug!(
             "hasp.rs:fetch: Setting hasp_feature_used_users {} {} {} {} -> {}",
-            lic.name,
-            index,
-            entry.license_count.to_string(),
-            entry.feature,
-            entry.expiration
+            lic.name, index, entry.license_count, entry.feature, entry.expiration
         );
         HASP_FEATURE_EXPIRATION
             .with_label_values(&[
@@ -355,13 +424,12 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    if let Some(export_users) = lic.export_user {
-        if export_users {
-            match fetch_checkouts(lic) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("hasp.rs:fetch: Unable to get license checkouts: {}", e);
-                }
+    // Checkouts are also needed for the top users if export_user isn't set
+    if lic.export_user.unwrap_or(false) || topusers::enabled() {
+        match fetch_checkouts(lic, &mut http_client) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("hasp.rs:fetch: Unable to get license checkouts: {}", e);
             }
         }
     }
@@ -369,11 +437,13 @@ pub fn fetch(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
+fn fetch_checkouts(
+    lic: &config::Hasp,
+    http_client: &mut reqwest::blocking::Client,
+) -> Result<(), Box<dyn Error>> {
     // dict -> "feature" -> "user" -> count
     let mut fu: HashMap<String, HashMap<String, i64>> = HashMap::new();
     let mut fidmap: HashMap<String, String> = HashMap::new();
-    let mut http_client = http::build_client(false, "", constants::DEFAULT_TIMEOUT)?;
 
     let server: &str;
     let mut port: &str = constants::DEFAULT_HASP_PORT;
@@ -386,17 +456,18 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
     }
 
     let url = format!(
-        "http://{}:{}/_int_/tab_sessions.html?haspid={}",
-        server, port, lic.hasp_key
+        "{}://{}:{}/_int_/tab_sessions.html?haspid={}",
+        http::scheme(lic.use_https),
+        server,
+        port,
+        lic.hasp_key
     );
-    let mut user: &str = "";
-    let mut pass: &str = "";
-    if let Some(auth) = &lic.authentication {
-        user = &auth.username;
-        pass = &auth.password;
-    }
+    let (user, pass) = match &lic.authentication {
+        Some(auth) => auth.credentials()?,
+        None => (String::new(), String::new()),
+    };
 
-    let reply = match http::get(&mut http_client, &url, user, pass) {
+    let reply = match http::get(http_client, &url, &user, &pass, lic.spnego.unwrap_or(false)) {
         Ok(v) => v,
         Err(e) => {
             debug!(
@@ -410,7 +481,7 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
             return Err(e);
         }
     };
-    let sessions: Vec<HaspSession> = match serde_json::from_str(&massage(reply)) {
+    let sessions = match parse_sessions(reply) {
         Ok(v) => v,
         Err(e) => {
             debug!(
@@ -440,7 +511,7 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            if license::is_excluded(&lic.excluded_features, fid.clone()) {
+            if lic.feature_filter.is_excluded(&fid) {
                 debug!(
                     "hasp.rs:fetch: Skipping feature {} because it is in excluded_features list of {}",
                     fid, lic.name
@@ -472,11 +543,32 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            let usr = fu.entry(fid).or_insert_with(HashMap::<String, i64>::new);
-            *usr.entry(user).or_insert(0) += 1;
+            let usr = fu.entry(fid).or_default();
+            *usr.entry(lic.anonymizer.user(&user)).or_insert(0) += 1;
         }
     }
 
+    stats::users(
+        "hasp",
+        &lic.name,
+        stats::distinct_users(
+            fu.iter()
+                .filter(|(f, _)| !lic.feature_filter.matches(f))
+                .flat_map(|(_, uv)| uv.keys()),
+        ),
+    );
+    topusers::export_fu(&lic.name, &lic.feature_filter, &fu);
+    topusers::export_user_count(
+        &lic.name,
+        &lic.feature_filter,
+        &HASP_FEATURES_USER_COUNT,
+        fu.iter().map(|(f, u)| (f, u.len())),
+    );
+
+    if !lic.export_user.unwrap_or(false) {
+        return Ok(());
+    }
+
     for (feat, uv) in fu.iter() {
         let fname = match fidmap.get(feat) {
             Some(v) => v,
@@ -484,7 +576,7 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
         };
 
         for (user, count) in uv.iter() {
-            if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+            if lic.feature_filter.is_excluded(feat) {
                 debug!("hasp.rs:fetch_checkouts: Skipping product_key {} because it is in excluded_features list of {}", feat, lic.name);
                 continue;
             }
@@ -501,34 +593,250 @@ fn fetch_checkouts(lic: &config::Hasp) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn massage(b0rken: String) -> String {
+// The content of the admin control center depends on the language of its user interface,
+// e.g. "Expires Tue Dec 31, 2030 23:59" or "Expire le Mar Déc 31, 2030 23:59". The weekday is
+// ignored and the month name is looked up in the month names of all supported languages.
+fn parse_expiration(raw: &str) -> Result<f64, Box<dyn Error>> {
     lazy_static! {
-        static ref RE_C_STYLE_COMMENT: Regex = Regex::new(r"/\*.*?\*/").unwrap();
+        static ref RE_HASP_EXPIRATION: Regex =
+            Regex::new(r"\b(\p{L}+)\.?\s+(\d{1,2})\.?,?\s+(\d{4})\s+(\d{1,2}:\d{2})").unwrap();
     }
-    // HASP data is in JSON format but it includes C-style  comments (/* ... */) and it lacks
-    // the JSON notation for an array. Remove line breaks to make it trivial to construct a regexp
-    // for its removal.
-    let massaged = b0rken.replace('\r', "").replace('\n', "");
-    format!("[ {} ]", RE_C_STYLE_COMMENT.replace_all(&massaged, ""))
+
+    let value = raw.trim().to_lowercase();
+    if HASP_PERPETUAL.iter().any(|p| value.contains(p)) {
+        return Ok(f64::INFINITY);
+    }
+
+    let capt = match RE_HASP_EXPIRATION.captures(&value) {
+        Some(v) => v,
+        None => bail!("unknown expiration format"),
+    };
+
+    let month = capt.get(1).map_or("", |m| m.as_str());
+    let day = capt.get(2).map_or("", |m| m.as_str());
+    let year = capt.get(3).map_or("", |m| m.as_str());
+    let time = capt.get(4).map_or("", |m| m.as_str());
+
+    let month = match HASP_MONTHS.iter().find(|(name, _)| month.starts_with(name)) {
+        Some((_, v)) => v,
+        None => bail!("unknown month name {}", month),
+    };
+
+    dates::parse_expiration(&format!("{}-{:02}-{:0>2} {}", year, month, day, time))
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(HASP_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(HASP_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(HASP_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(HASP_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(HASP_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(HASP_SERVER_STATUS.clone()))
+// Decode the feature list from /_int_/tab_feat.html
+pub fn parse_features(raw: String) -> Result<Vec<HaspFeature>, serde_json::Error> {
+    serde_json::from_str(&massage(&raw))
+}
+
+// Decode the session list from /_int_/tab_sessions.html
+pub fn parse_sessions(raw: String) -> Result<Vec<HaspSession>, serde_json::Error> {
+    serde_json::from_str(&massage(&raw))
+}
+
+// HASP data is in JSON format but it includes C-style comments (/* ... */), trailing commas
+// and it lacks the JSON notation for an array. Comments and trailing commas outside of strings
+// are removed and the objects are wrapped into an array.
+fn massage(b0rken: &str) -> String {
+    let mut result = String::with_capacity(b0rken.len() + 2);
+    let mut chars = b0rken.chars().peekable();
+    let mut in_string = false;
+    let mut escaped = false;
+
+    result.push('[');
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            // Line breaks are not allowed in JSON strings
+            if c == '\r' || c == '\n' {
+                continue;
+            }
+
+            result.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match c {
+            '"' => {
+                in_string = true;
+                result.push(c);
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = '\0';
+                for cc in chars.by_ref() {
+                    if previous == '*' && cc == '/' {
+                        break;
+                    }
+                    previous = cc;
+                }
+            }
+            ']' | '}' => {
+                remove_trailing_comma(&mut result);
+                result.push(c);
+            }
+            _ => result.push(c),
+        };
+    }
+
+    remove_trailing_comma(&mut result);
+    result.push(']');
+    result
+}
+
+fn remove_trailing_comma(json: &mut String) {
+    let trimmed = json.trim_end().len();
+    json.truncate(trimmed);
+    if json.ends_with(',') {
+        json.pop();
+    }
+}
+
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(HASP_FEATURES_TOTAL.clone()),
+        Box::new(HASP_FEATURES_USED.clone()),
+        Box::new(HASP_FEATURE_EXPIRATION.clone()),
+        Box::new(HASP_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(HASP_FEATURES_USER.clone()),
+        Box::new(HASP_FEATURES_USER_COUNT.clone()),
+        Box::new(HASP_SERVER_STATUS.clone()),
+        Box::new(HASP_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&HASP_FEATURES_TOTAL),
+        series::Vector::IntGauge(&HASP_FEATURES_USED),
+        series::Vector::Gauge(&HASP_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&HASP_FEATURE_AGGREGATED_EXPIRATION),
+        series::Vector::IntGauge(&HASP_FEATURES_USER),
+        series::Vector::IntGauge(&HASP_FEATURES_USER_COUNT),
+        series::Vector::IntGauge(&HASP_SERVER_STATUS),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let server = testutil::FixtureServer::start(&[
+            ("/_int_/tab_feat.html", "hasp/tab_feat.html"),
+            ("/_int_/tab_sessions.html", "hasp/tab_sessions.html"),
+        ]);
+        let cfg = testutil::parse_config(&format!(
+            "hasp:\n  - name: hasp\n    license: {}@127.0.0.1\n    hasp_key: \"123456789\"\n    export_user: true\n",
+            server.port
+        ));
+        let registry = Registry::new();
+        register_into(&registry);
+
+        fetch(&cfg.hasp.unwrap()[0], &testutil::no_proxy()).unwrap();
+        testutil::assert_golden("hasp", &server.mask_port(&testutil::render(&registry)));
+    }
+
+    #[test]
+    fn massage_comments_and_trailing_commas() {
+        assert_eq!(
+            massage("/* c1 */\n{\"a\":\"1\",},\n/* c2 */\n{\"a\":\"2\"},\n"),
+            "[\n{\"a\":\"1\"},\n\n{\"a\":\"2\"}]"
+        );
+        assert_eq!(massage(""), "[]");
+        assert_eq!(massage("/**/{}/* unterminated"), "[{}]");
+    }
+
+    #[test]
+    fn massage_keeps_strings() {
+        assert_eq!(
+            massage(r#"{"fn":"a /* b */ c","x":"d\"/*","y":"e\\"}"#),
+            r#"[{"fn":"a /* b */ c","x":"d\"/*","y":"e\\"}]"#
+        );
+        assert_eq!(massage("{\"fn\":\"a\r\nb\"}"), "[{\"fn\":\"ab\"}]");
+    }
+
+    #[test]
+    fn parse_acc_features() {
+        let features =
+            parse_features(include_str!("../tests/fixtures/hasp/tab_feat_acc.html").to_string())
+                .unwrap();
+        assert_eq!(features.len(), 3);
+        assert_eq!(features[0].fname.as_deref(), Some("Solver /* Pro */"));
+        assert_eq!(features[0].logc.as_deref(), Some("3"));
+        assert_eq!(features[1].fid.as_deref(), Some("20"));
+        assert_eq!(features[1].lic.as_deref(), Some("Perpetual"));
+        assert_eq!(
+            features[2].fname.as_deref(),
+            Some("Post processing // batch")
+        );
+    }
+
+    #[test]
+    fn parse_acc_sessions() {
+        let sessions = parse_sessions(
+            include_str!("../tests/fixtures/hasp/tab_sessions_acc.html").to_string(),
+        )
         .unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].fname.as_deref(), Some("Solver /* Pro */"));
+        assert_eq!(sessions[1].usr.as_deref(), Some("bob"));
+    }
+
+    #[test]
+    fn parse_localised_expiration() {
+        // Tue Dec 31, 2030 23:59 UTC
+        let expected = 1924991940.0;
+        for raw in [
+            "Expires Tue Dec 31, 2030 23:59",
+            "Läuft ab Di Dez 31, 2030 23:59",
+            "Gültig bis Di. Dez. 31, 2030 23:59",
+            "Expire le Mar Déc 31, 2030 23:59",
+            "Caduca Mar Dic 31, 2030 23:59",
+            "Scade Mar Dic 31, 2030 23:59",
+            "Expira Ter Dez 31, 2030 23:59",
+        ] {
+            assert_eq!(parse_expiration(raw).unwrap(), expected, "{}", raw);
+        }
+        assert_eq!(
+            parse_expiration("Expire le Lun Mars 04, 2024 10:00").unwrap(),
+            1709546400.0
+        );
+        assert_eq!(
+            parse_expiration("Läuft ab Mo Mär 04, 2024 10:00").unwrap(),
+            1709546400.0
+        );
+    }
+
+    #[test]
+    fn parse_localised_perpetual() {
+        for raw in ["Perpetual", "Unbefristet", "Perpétuelle", "Perpetua"] {
+            assert_eq!(parse_expiration(raw).unwrap(), f64::INFINITY, "{}", raw);
+        }
+    }
+
+    #[test]
+    fn parse_unknown_expiration() {
+        assert!(parse_expiration("Expires soon").is_err());
+        assert!(parse_expiration("Expires Xyz 31, 2030 23:59").is_err());
+    }
 }
@@ -0,0 +1,130 @@
+use crate::constants;
+
+use chrono::{DateTime, Local};
+use lazy_static::lazy_static;
+use log::error;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// Usage history of the license features, used to detect anomalies like vendor daemon restarts
+// which silently reset the usage counts (global.anomaly_detection)
+lazy_static! {
+    // (metric, app, feature) -> history
+    static ref HISTORY: Mutex<HashMap<(String, String, String), FeatureHistory>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Default)]
+struct FeatureHistory {
+    last: f64,
+    max: f64,
+    samples: u64,
+    // Fetch of the license the last sample is from and its result
+    fetched: Option<DateTime<Local>>,
+    anomaly: Option<Anomaly>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Anomaly {
+    // usage dropped from a non-zero value to zero
+    DropToZero,
+    // usage exceeds the maximal usage seen so far
+    AboveMax,
+}
+
+impl Anomaly {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Anomaly::DropToZero => "dropped to zero",
+            Anomaly::AboveMax => "exceeds historical maximum",
+        }
+    }
+}
+
+// Add the usage of a feature from the fetch of the license at fetched to its history and check
+// it against the previous samples. Without a new fetch, e.g. for every scrape of the same sample
+// set or if the fetch failed, no sample is added and the previous result is returned.
+pub fn record(
+    metric: &str,
+    app: &str,
+    feature: &str,
+    used: f64,
+    fetched: Option<DateTime<Local>>,
+) -> Option<Anomaly> {
+    let mut history = match HISTORY.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("history.rs:record: Can't lock usage history: {}", e);
+            return None;
+        }
+    };
+
+    let entry = history
+        .entry((metric.to_string(), app.to_string(), feature.to_string()))
+        .or_default();
+    if fetched.is_none() || fetched == entry.fetched {
+        return entry.anomaly;
+    }
+
+    entry.fetched = fetched;
+    entry.anomaly = check(entry, used);
+    entry.anomaly
+}
+
+fn check(history: &mut FeatureHistory, used: f64) -> Option<Anomaly> {
+    let result = if history.samples == 0 {
+        None
+    } else if history.last > 0.0 && used == 0.0 {
+        Some(Anomaly::DropToZero)
+    } else if history.samples >= constants::ANOMALY_MIN_SAMPLES && used > history.max {
+        // The maximum isn't meaningful until enough samples have been seen
+        Some(Anomaly::AboveMax)
+    } else {
+        None
+    };
+
+    history.last = used;
+    history.max = history.max.max(used);
+    history.samples += 1;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_anomalies() {
+        let mut history = FeatureHistory::default();
+
+        assert_eq!(check(&mut history, 5.0), None);
+        assert_eq!(check(&mut history, 0.0), Some(Anomaly::DropToZero));
+        assert_eq!(check(&mut history, 0.0), None);
+
+        // No AboveMax until ANOMALY_MIN_SAMPLES have been seen
+        assert_eq!(check(&mut history, 8.0), None);
+        for _ in 0..constants::ANOMALY_MIN_SAMPLES {
+            assert_eq!(check(&mut history, 3.0), None);
+        }
+        assert_eq!(check(&mut history, 100.0), Some(Anomaly::AboveMax));
+        assert_eq!(check(&mut history, 99.0), None);
+    }
+
+    #[test]
+    fn record_once_per_fetch() {
+        let first = Local::now();
+        let second = first + chrono::Duration::seconds(60);
+        let record = |used, fetched| record("test_feature_used", "lic1", "feat1", used, fetched);
+
+        assert_eq!(record(5.0, Some(first)), None);
+        assert_eq!(record(0.0, Some(second)), Some(Anomaly::DropToZero));
+        // Scrapes of the same fetch and failed fetches don't add samples
+        assert_eq!(record(0.0, Some(second)), Some(Anomaly::DropToZero));
+        assert_eq!(record(0.0, None), Some(Anomaly::DropToZero));
+        assert_eq!(
+            record(0.0, Some(second + chrono::Duration::seconds(60))),
+            None
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use crate::command;
+use crate::config;
+use crate::constants;
+
+use lazy_static::lazy_static;
+use log::{debug, error};
+use prometheus::proto::{Gauge, LabelPair, Metric, MetricFamily, MetricType};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::process::Command;
+use std::time::Duration;
+
+lazy_static! {
+    static ref RE_METRIC_NAME: Regex = Regex::new(r"^[a-zA-Z_:][a-zA-Z0-9_:]*$").unwrap();
+    static ref RE_LABEL_NAME: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+}
+
+// Data passed to the hook on standard input
+#[derive(Serialize)]
+struct HookInput {
+    timestamp: i64,
+    metrics: Vec<HookInputMetric>,
+}
+
+#[derive(Serialize)]
+struct HookInputMetric {
+    name: String,
+    help: String,
+    samples: Vec<HookSample>,
+}
+
+#[derive(Serialize)]
+struct HookSample {
+    labels: BTreeMap<String, String>,
+    value: f64,
+}
+
+// Data expected from the hook on standard output
+#[derive(Deserialize)]
+struct HookOutput {
+    metrics: Vec<HookOutputMetric>,
+}
+
+#[derive(Deserialize)]
+struct HookOutputMetric {
+    name: String,
+    help: Option<String>,
+    labels: Option<BTreeMap<String, String>>,
+    value: f64,
+}
+
+pub fn run(hooks: &[config::Hook], families: &mut Vec<MetricFamily>) {
+    for hook in hooks {
+        match run_hook(hook, families) {
+            Ok(v) => {
+                debug!(
+                    "hooks.rs:run: Hook {} returned {} metrics",
+                    hook.name,
+                    v.metrics.len()
+                );
+                apply(hook, v, families);
+            }
+            Err(e) => {
+                error!("hooks.rs:run: Hook {} failed: {}", hook.name, e);
+            }
+        };
+    }
+}
+
+fn run_hook(hook: &config::Hook, families: &[MetricFamily]) -> Result<HookOutput, Box<dyn Error>> {
+    let input = serde_json::to_vec(&build_input(families))?;
+    let timeout = Duration::from_secs(hook.timeout.unwrap_or(constants::DEFAULT_HOOK_TIMEOUT));
+    let args = hook.args.clone().unwrap_or_default();
+
+    debug!(
+        "hooks.rs:run_hook: Running {} {} for hook {}",
+        hook.command,
+        args.join(" "),
+        hook.name
+    );
+
+    let result = command::output(
+        Command::new(&hook.command).args(&args),
+        Some(input),
+        timeout,
+    )?;
+    if !result.status.success() {
+        bail!(
+            "command exited with {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr).trim()
+        );
+    }
+
+    let output: HookOutput = serde_json::from_slice(&result.stdout)?;
+    Ok(output)
+}
+
+fn build_input(families: &[MetricFamily]) -> HookInput {
+    let mut metrics = Vec::new();
+
+    for mf in families {
+        let samples = mf
+            .get_metric()
+            .iter()
+            .filter_map(|m| {
+                let value = match mf.get_field_type() {
+                    MetricType::GAUGE => m.get_gauge().get_value(),
+                    MetricType::COUNTER => m.get_counter().get_value(),
+                    _ => return None,
+                };
+                Some(HookSample {
+                    labels: m
+                        .get_label()
+                        .iter()
+                        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                        .collect(),
+                    value,
+                })
+            })
+            .collect();
+
+        metrics.push(HookInputMetric {
+            name: mf.get_name().to_string(),
+            help: mf.get_help().to_string(),
+            samples,
+        });
+    }
+
+    HookInput {
+        timestamp: chrono::Local::now().timestamp(),
+        metrics,
+    }
+}
+
+fn apply(hook: &config::Hook, output: HookOutput, families: &mut Vec<MetricFamily>) {
+    for metric in output.metrics {
+        if !RE_METRIC_NAME.is_match(&metric.name) {
+            error!(
+                "hooks.rs:apply: Hook {} returned invalid metric name {}",
+                hook.name, metric.name
+            );
+            continue;
+        }
+
+        let labels = metric.labels.unwrap_or_default();
+        if let Some(invalid) = labels.keys().find(|l| !RE_LABEL_NAME.is_match(l)) {
+            error!(
+                "hooks.rs:apply: Hook {} returned invalid label name {} for {}",
+                hook.name, invalid, metric.name
+            );
+            continue;
+        }
+
+        let mf = match families.iter().position(|f| f.get_name() == metric.name) {
+            Some(idx) => &mut families[idx],
+            None => {
+                let mut mf = MetricFamily::new();
+                mf.set_name(metric.name.clone());
+                mf.set_help(
+                    metric
+                        .help
+                        .clone()
+                        .unwrap_or_else(|| format!("Metric provided by hook {}", hook.name)),
+                );
+                mf.set_field_type(MetricType::GAUGE);
+                families.push(mf);
+                families.last_mut().unwrap()
+            }
+        };
+
+        if mf.get_field_type() != MetricType::GAUGE {
+            error!(
+                "hooks.rs:apply: Hook {} can't modify {}, only gauges can be modified",
+                hook.name, metric.name
+            );
+            continue;
+        }
+
+        debug!(
+            "hooks.rs:apply: Setting {} {:?} -> {}",
+            metric.name, labels, metric.value
+        );
+
+        // Replace the value of an existing series, add a new series otherwise
+        let existing = mf.mut_metric().iter_mut().find(|m| {
+            m.get_label().len() == labels.len()
+                && m.get_label()
+                    .iter()
+                    .all(|l| labels.get(l.get_name()).map(|v| v.as_str()) == Some(l.get_value()))
+        });
+
+        match existing {
+            Some(m) => m.mut_gauge().set_value(metric.value),
+            None => {
+                let mut pairs: Vec<LabelPair> = Vec::new();
+                for (name, value) in labels {
+                    let mut pair = LabelPair::new();
+                    pair.set_name(name);
+                    pair.set_value(value);
+                    pairs.push(pair);
+                }
+
+                let mut gauge = Gauge::new();
+                gauge.set_value(metric.value);
+
+                let mut m = Metric::new();
+                m.set_label(pairs.into());
+                m.set_gauge(gauge);
+                mf.mut_metric().push(m);
+            }
+        };
+    }
+
+    families.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Histogram, HistogramOpts, IntCounter, IntGaugeVec, Opts, Registry};
+
+    fn hook(script: &str) -> config::Hook {
+        config::Hook {
+            args: Some(vec!["-c".to_string(), script.to_string()]),
+            command: "sh".to_string(),
+            name: "test".to_string(),
+            timeout: Some(10),
+        }
+    }
+
+    fn families() -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        let used = IntGaugeVec::new(
+            Opts::new("flexlm_feature_used", "Used licenses"),
+            &["app", "name"],
+        )
+        .unwrap();
+        registry.register(Box::new(used.clone())).unwrap();
+        used.with_label_values(&["lic1", "cad"]).set(3);
+        used.with_label_values(&["lic1", "cam"]).set(1);
+
+        let errors = IntCounter::new("scrape_errors_total", "Scrape errors").unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        errors.inc();
+
+        let duration =
+            Histogram::with_opts(HistogramOpts::new("scrape_duration", "Scrape duration")).unwrap();
+        registry.register(Box::new(duration.clone())).unwrap();
+        duration.observe(0.5);
+
+        registry.gather()
+    }
+
+    fn value(families: &[MetricFamily], name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+        families
+            .iter()
+            .find(|f| f.get_name() == name)?
+            .get_metric()
+            .iter()
+            .find(|m| {
+                m.get_label().len() == labels.len()
+                    && labels.iter().all(|(n, v)| {
+                        m.get_label()
+                            .iter()
+                            .any(|l| l.get_name() == *n && l.get_value() == *v)
+                    })
+            })
+            .map(|m| m.get_gauge().get_value())
+    }
+
+    #[test]
+    fn input() {
+        let input = build_input(&families());
+        let names: Vec<&str> = input.metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "flexlm_feature_used",
+                "scrape_duration",
+                "scrape_errors_total"
+            ]
+        );
+
+        let used = &input.metrics[0];
+        assert_eq!(used.help, "Used licenses");
+        assert_eq!(used.samples.len(), 2);
+        assert_eq!(used.samples[0].labels.get("name").unwrap(), "cad");
+        assert_eq!(used.samples[0].labels.get("app").unwrap(), "lic1");
+        assert_eq!(used.samples[0].value, 3.0);
+        assert_eq!(used.samples[1].value, 1.0);
+
+        // Histograms aren't passed to the hook
+        assert!(input.metrics[1].samples.is_empty());
+        assert_eq!(input.metrics[2].samples[0].value, 1.0);
+    }
+
+    #[test]
+    fn apply_output() {
+        let output: HookOutput = serde_json::from_str(
+            r#"{"metrics": [
+                {"name": "flexlm_feature_used", "labels": {"app": "lic1", "name": "cad"}, "value": 5},
+                {"name": "flexlm_feature_used", "labels": {"app": "lic1", "name": "cae"}, "value": 2},
+                {"name": "kpi_usage_ratio", "value": 0.25},
+                {"name": "kpi_seats", "help": "Seats", "labels": {"site": "example"}, "value": 4},
+                {"name": "scrape_errors_total", "value": 7},
+                {"name": "invalid-name", "value": 1},
+                {"name": "kpi_invalid", "labels": {"invalid-label": "x"}, "value": 1}
+            ]}"#,
+        )
+        .unwrap();
+
+        let mut families = families();
+        apply(&hook("true"), output, &mut families);
+
+        let names: Vec<&str> = families.iter().map(|f| f.get_name()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "flexlm_feature_used",
+                "kpi_seats",
+                "kpi_usage_ratio",
+                "scrape_duration",
+                "scrape_errors_total"
+            ]
+        );
+
+        let used = [("app", "lic1"), ("name", "cad")];
+        assert_eq!(value(&families, "flexlm_feature_used", &used), Some(5.0));
+        let used = [("app", "lic1"), ("name", "cam")];
+        assert_eq!(value(&families, "flexlm_feature_used", &used), Some(1.0));
+        let used = [("app", "lic1"), ("name", "cae")];
+        assert_eq!(value(&families, "flexlm_feature_used", &used), Some(2.0));
+
+        assert_eq!(value(&families, "kpi_usage_ratio", &[]), Some(0.25));
+        assert_eq!(families[2].get_help(), "Metric provided by hook test");
+        assert_eq!(
+            value(&families, "kpi_seats", &[("site", "example")]),
+            Some(4.0)
+        );
+        assert_eq!(families[1].get_help(), "Seats");
+
+        // Only gauges can be modified
+        let errors = &families[4].get_metric()[0];
+        assert_eq!(errors.get_counter().get_value(), 1.0);
+    }
+
+    #[test]
+    fn run_hooks() {
+        let mut families = families();
+        run(
+            &[hook(
+                r#"grep flexlm_feature_used >/dev/null && echo '{"metrics": [{"name": "kpi_hook_ok", "value": 1}]}'"#,
+            )],
+            &mut families,
+        );
+        assert_eq!(value(&families, "kpi_hook_ok", &[]), Some(1.0));
+    }
+
+    #[test]
+    fn failing_hook() {
+        let result = run_hook(
+            &hook("cat >/dev/null; echo broken >&2; exit 1"),
+            &families(),
+        );
+        let err = result.err().unwrap().to_string();
+        assert!(err.starts_with("command exited with"), "{}", err);
+        assert!(err.ends_with("broken"), "{}", err);
+
+        let result = run_hook(&hook("cat >/dev/null; echo 'no json'"), &families());
+        assert!(result.is_err());
+    }
+}
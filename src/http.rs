@@ -1,42 +1,134 @@
+use crate::api;
+use crate::collector::Collector;
 use crate::config;
 use crate::constants;
 use crate::exporter;
+use crate::logging;
+use crate::openmetrics;
+use crate::peersync;
+use crate::resolver;
+use crate::runtime;
+use crate::signals;
+use crate::spnego;
+use crate::status;
+use crate::tls;
 
-use log::{debug, error, info};
+use base64::Engine;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ipnet::IpNet;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use prometheus::TextEncoder;
+use sha2::{Digest, Sha256};
 use simple_error::bail;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
+#[derive(Clone, Debug, Default)]
+pub struct ProxySettings {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+impl ProxySettings {
+    pub fn new(global: &Option<config::GlobalConfiguration>, proxy: &Option<String>) -> Self {
+        let mut settings = match global {
+            Some(glob) => ProxySettings {
+                http_proxy: glob.http_proxy.clone(),
+                https_proxy: glob.https_proxy.clone(),
+                no_proxy: glob.no_proxy.clone(),
+            },
+            None => ProxySettings::default(),
+        };
+
+        // An explicit proxy of a license entry replaces the global proxies for all schemes,
+        // hosts listed in the global no_proxy are still accessed directly
+        if let Some(p) = proxy {
+            settings.http_proxy = Some(p.to_string());
+            settings.https_proxy = Some(p.to_string());
+        }
+        settings
+    }
+}
+
+// Settings of the HTTP client of a collector. Every HTTP based collector builds its own
+// settings, because the license servers differ in their reply formats and behaviour.
+#[derive(Clone, Debug)]
+pub struct ClientSettings {
+    // sent as Accept header, because some servers select the format of the reply by it
+    pub accept: &'static str,
+    pub ca_file: String,
+    pub client_certificate: Option<config::ClientCertificate>,
+    // names of the license servers, resolved by the resolver module instead of reqwest
+    pub hosts: Vec<String>,
+    pub insecure_ssl: bool,
+    // maximal number of redirects to follow, 0 disables redirects
+    pub max_redirects: usize,
+    // maximal number of idle connections kept open per host
+    pub pool_max_idle_per_host: usize,
+    pub proxy: ProxySettings,
+    pub timeout: Duration,
+}
+
 pub fn build_client(
-    insecure_ssl: bool,
-    ca_file: &str,
-    timeout_sec: u64,
+    settings: &ClientSettings,
 ) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
-    let timeout = Duration::from_secs(timeout_sec);
     let mut headers = reqwest::header::HeaderMap::new();
     headers.insert(
         "X-Clacks-Overhead",
         reqwest::header::HeaderValue::from_static("GNU Terry Pratchett"),
     );
-    headers.insert(
-        "Accept",
-        reqwest::header::HeaderValue::from_static("application/json"),
-    );
+    let accept_value = match reqwest::header::HeaderValue::from_str(settings.accept) {
+        Ok(v) => v,
+        Err(e) => bail!("can't use {} as Accept header: {}", settings.accept, e),
+    };
+    headers.insert(reqwest::header::ACCEPT, accept_value);
+
+    let redirect_policy = if settings.max_redirects == 0 {
+        reqwest::redirect::Policy::none()
+    } else {
+        reqwest::redirect::Policy::limited(settings.max_redirects)
+    };
 
     let mut http_client_builder = reqwest::blocking::ClientBuilder::new()
         .user_agent(constants::generate_default_user_agent())
         .default_headers(headers)
-        .timeout(timeout);
+        .redirect(redirect_policy)
+        .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+        .timeout(settings.timeout)
+        // for the expiration of the server certificate, see tls.rs
+        .tls_info(true);
 
-    if insecure_ssl {
+    // NOTE: reqwest ignores the port of the addresses and uses the port of the URL
+    for (host, ip) in resolver::overrides() {
+        http_client_builder = http_client_builder.resolve(&host, SocketAddr::new(ip, 0));
+    }
+    for host in settings.hosts.iter() {
+        match resolver::socket_addrs(host, 0) {
+            Ok(v) => http_client_builder = http_client_builder.resolve_to_addrs(host, &v),
+            Err(e) => debug!(
+                "http.rs:build_client: {}, using the resolver of the HTTP client",
+                e
+            ),
+        };
+    }
+
+    if settings.insecure_ssl {
         http_client_builder = http_client_builder
             .danger_accept_invalid_hostnames(true)
             .danger_accept_invalid_certs(true);
-    } else if !ca_file.is_empty() {
+    } else if !settings.ca_file.is_empty() {
         let mut ca_buffer = Vec::new();
-        let mut fd = match File::open(ca_file) {
+        let mut fd = match File::open(&settings.ca_file) {
             Ok(v) => v,
             Err(e) => bail!("can't open CA file: {}", e),
         };
@@ -51,6 +143,39 @@ pub fn build_client(
 
         http_client_builder = http_client_builder.add_root_certificate(ca_cert);
     }
+
+    if let Some(ccert) = &settings.client_certificate {
+        let identity = load_identity(ccert)?;
+        http_client_builder = http_client_builder.identity(identity);
+    }
+
+    // NOTE: Without any proxy configuration reqwest uses the http_proxy/https_proxy/no_proxy
+    //       environment variables. An empty proxy string disables the use of a proxy.
+    let proxy = &settings.proxy;
+    let no_proxy = proxy
+        .no_proxy
+        .as_ref()
+        .and_then(|np| reqwest::NoProxy::from_string(np));
+    if let Some(http_proxy) = &proxy.http_proxy {
+        if http_proxy.is_empty() {
+            http_client_builder = http_client_builder.no_proxy();
+        } else {
+            let p = match reqwest::Proxy::http(http_proxy) {
+                Ok(v) => v,
+                Err(e) => bail!("can't use {} as HTTP proxy: {}", http_proxy, e),
+            };
+            http_client_builder = http_client_builder.proxy(p.no_proxy(no_proxy.clone()));
+        }
+    }
+    if let Some(https_proxy) = &proxy.https_proxy {
+        if !https_proxy.is_empty() {
+            let p = match reqwest::Proxy::https(https_proxy) {
+                Ok(v) => v,
+                Err(e) => bail!("can't use {} as HTTPS proxy: {}", https_proxy, e),
+            };
+            http_client_builder = http_client_builder.proxy(p.no_proxy(no_proxy));
+        }
+    }
     let http_client = match http_client_builder.build() {
         Ok(v) => v,
         Err(e) => bail!("can't create HTTP client: {}", e),
@@ -59,22 +184,159 @@ pub fn build_client(
     Ok(http_client)
 }
 
+pub fn scheme(use_https: Option<bool>) -> &'static str {
+    if use_https.unwrap_or(false) {
+        "https"
+    } else {
+        "http"
+    }
+}
+
+fn load_identity(ccert: &config::ClientCertificate) -> Result<reqwest::Identity, Box<dyn Error>> {
+    let cert = match fs::read(&ccert.certificate) {
+        Ok(v) => v,
+        Err(e) => bail!("can't read client certificate {}: {}", ccert.certificate, e),
+    };
+
+    let identity = match &ccert.key {
+        Some(key_file) => {
+            let key = match fs::read(key_file) {
+                Ok(v) => v,
+                Err(e) => bail!("can't read client certificate key {}: {}", key_file, e),
+            };
+            match reqwest::Identity::from_pkcs8_pem(&cert, &key) {
+                Ok(v) => v,
+                Err(e) => bail!(
+                    "can't decode client certificate {} and key {} as PEM: {}",
+                    ccert.certificate,
+                    key_file,
+                    e
+                ),
+            }
+        }
+        None => {
+            let password = ccert.password.clone().unwrap_or_default();
+            match reqwest::Identity::from_pkcs12_der(&cert, &password) {
+                Ok(v) => v,
+                Err(e) => bail!(
+                    "can't decode client certificate {} as PKCS#12: {}",
+                    ccert.certificate,
+                    e
+                ),
+            }
+        }
+    };
+
+    Ok(identity)
+}
+
 pub fn get(
     http_client: &mut reqwest::blocking::Client,
     url: &str,
     user: &str,
     password: &str,
+    negotiate: bool,
 ) -> Result<String, Box<dyn Error>> {
+    let response = send_get(http_client, url, user, password, negotiate)?;
+    let reply = response.text()?;
+    Ok(reply)
+}
+
+// Read the response body as stream, but abort if more than max_size bytes are received
+pub fn get_reader(
+    http_client: &mut reqwest::blocking::Client,
+    url: &str,
+    user: &str,
+    password: &str,
+    negotiate: bool,
+    max_size: u64,
+) -> Result<BufReader<LimitedReader<reqwest::blocking::Response>>, Box<dyn Error>> {
+    let response = send_get(http_client, url, user, password, negotiate)?;
+
+    if let Some(length) = response.content_length() {
+        if length > max_size {
+            bail!(
+                "response from {} has {} bytes, exceeding the limit of {} bytes",
+                url,
+                length,
+                max_size
+            );
+        }
+    }
+
+    Ok(BufReader::new(LimitedReader {
+        inner: response,
+        url: url.to_string(),
+        limit: max_size,
+        received: 0,
+        next_report: constants::HTTP_PROGRESS_REPORT_BYTES,
+    }))
+}
+
+pub struct LimitedReader<R: Read> {
+    inner: R,
+    url: String,
+    limit: u64,
+    received: u64,
+    next_report: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.received += n as u64;
+
+        if self.received > self.limit {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "response from {} exceeds the limit of {} bytes",
+                    self.url, self.limit
+                ),
+            ));
+        }
+
+        if self.received >= self.next_report {
+            debug!(
+                "http.rs:LimitedReader::read: Received {} bytes from {}",
+                self.received, self.url
+            );
+            self.next_report += constants::HTTP_PROGRESS_REPORT_BYTES;
+        }
+
+        Ok(n)
+    }
+}
+
+fn send_get(
+    http_client: &mut reqwest::blocking::Client,
+    url: &str,
+    user: &str,
+    password: &str,
+    negotiate: bool,
+) -> Result<reqwest::blocking::Response, Box<dyn Error>> {
     debug!("http.rs:get: GET {}", &url);
 
-    let response = if user.is_empty() {
-        http_client.get(url).send()?
-    } else {
-        http_client
-            .get(url)
-            .basic_auth(user, Some(password))
-            .send()?
-    };
+    let mut request = http_client.get(url);
+    if negotiate {
+        let parsed = reqwest::Url::parse(url)?;
+        let host = match parsed.host_str() {
+            Some(v) => v,
+            None => bail!(
+                "can't extract host name from {} for SPNEGO authentication",
+                url
+            ),
+        };
+        let token = spnego::negotiate_token(host)?;
+        request = request.header(
+            reqwest::header::AUTHORIZATION,
+            format!("Negotiate {}", token),
+        );
+    } else if !user.is_empty() {
+        request = request.basic_auth(user, Some(password));
+    }
+
+    let response = request.send()?;
 
     if response.status() != reqwest::StatusCode::OK {
         bail!(
@@ -82,12 +344,16 @@ pub fn get(
             response.status()
         );
     }
+    tls::observe(&response);
 
-    let reply = response.text()?;
-    Ok(reply)
+    Ok(response)
 }
 
-pub fn server(cfg: config::Configuration, listen_address: &str) -> Result<(), Box<dyn Error>> {
+pub fn server(
+    cfg: config::Configuration,
+    source: &config::ConfigSource,
+    listen_address: &str,
+) -> Result<(), Box<dyn Error>> {
     let headers: Vec<tiny_http::Header> =
         vec![
             tiny_http::Header::from_bytes(&b"X-Clacks-Overhead"[..], &b"GNU Terry Pratchett"[..])
@@ -95,59 +361,1587 @@ pub fn server(cfg: config::Configuration, listen_address: &str) -> Result<(), Bo
         ];
 
     let http_server = tiny_http::Server::http(listen_address).unwrap();
-
     info!("http.rs:server: Listening on {}", listen_address);
 
+    // Requests of both listeners are processed by this loop, so admin requests and scrapes
+    // see the same configuration
+    let (sender, receiver) = mpsc::channel();
+    accept(http_server, Listener::Metrics, sender.clone());
+
+    // NOTE: Like the listen address, admin_listen can't be changed by a reload
+    let separate_admin = match cfg.admin_listen() {
+        Some(admin_listen) => {
+            let admin_server = match tiny_http::Server::http(admin_listen) {
+                Ok(v) => v,
+                Err(e) => bail!("can't listen on {} for admin requests: {}", admin_listen, e),
+            };
+            info!(
+                "http.rs:server: Listening on {} for admin requests",
+                admin_listen
+            );
+            accept(admin_server, Listener::Admin, sender);
+            true
+        }
+        None => false,
+    };
+
+    let mut cfg = Arc::new(cfg);
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let collector = Collector::new(Arc::clone(&cfg));
+
     loop {
-        let request = match http_server.recv() {
+        if signals::reload_requested() {
+            info!("http.rs:server: Received SIGHUP, reloading configuration");
+            match reload(&cfg, source) {
+                Ok(v) => {
+                    cfg = Arc::new(v);
+                    collector.set_config(Arc::clone(&cfg));
+                }
+                Err(e) => error!("http.rs:server: Can't reload configuration: {}", e),
+            };
+        }
+
+        // Wake up regularly to process signals
+        let (listener, mut request) = match receiver
+            .recv_timeout(Duration::from_millis(constants::SIGNAL_POLL_INTERVAL_MS))
+        {
             Ok(v) => v,
-            Err(e) => {
-                error!("http.rs:server: Can't process incoming request: {}", e);
-                continue;
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                error!("http.rs:server: All listeners stopped");
+                break;
             }
         };
-        let method = request.method();
-        let url = request.url();
 
         info!(
             "http.rs:server: HTTP {} request to {} from {:?}",
-            method,
-            url,
+            request.method(),
+            request.url(),
             request.remote_addr()
         );
 
-        let status_code: tiny_http::StatusCode;
-        let payload: String;
+        // Health probes are answered for every client, e.g. for the kubelet
+        if listener == Listener::Metrics
+            && !is_health_path(request.url())
+            && !allowed(cfg.allowed_networks(), request.remote_addr())
+        {
+            warn!(
+                "http.rs:server: Rejecting request from {:?}, not in allowed_networks",
+                request.remote_addr()
+            );
+            let reply = Reply::new(
+                403,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_FORBIDDEN.to_string(),
+            );
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        let authorization = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Authorization"))
+            .map(|h| h.value.to_string());
+
+        // Sample sets pushed by the primary, only served on the listen address of the metrics
+        // because the primary runs on another host
+        if listener == Listener::Metrics && request.url() == constants::PEER_SNAPSHOT_PATH {
+            let reply = match snapshot_request(&cfg, request.method(), authorization.as_deref()) {
+                Ok(()) => receive_snapshot(&mut request),
+                Err(v) => v,
+            };
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        // None of the other endpoints expects a request body
+        if has_body(&request) {
+            warn!(
+                "http.rs:server: Rejecting request with body from {:?}",
+                request.remote_addr()
+            );
+            let reply = Reply::new(
+                413,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_PAYLOAD_TOO_LARGE.to_string(),
+            );
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        // Probes are answered on both listeners, e.g. for a Kubernetes liveness probe on the
+        // admin port
+        let ready = cfg.scrape_interval().is_none() || collector.sample().is_some();
+        if let Some(reply) = health_request(request.method(), request.url(), ready) {
+            respond(request, reply, &headers);
+            continue;
+        }
 
-        if method == &tiny_http::Method::Get {
-            match url {
-                "/" => {
-                    status_code = tiny_http::StatusCode::from(302_i16);
-                    payload = constants::ROOT_HTML.to_string();
+        let admin = if separate_admin && listener == Listener::Metrics {
+            None
+        } else {
+            admin_request(
+                &cfg,
+                request.method(),
+                request.url(),
+                authorization.as_deref(),
+            )
+        };
+        if let Some(admin) = admin {
+            match admin {
+                Ok(AdminAction::Quit) => {
+                    info!("http.rs:server: Shutdown requested");
+                    let reply = Reply::new(
+                        200,
+                        constants::CONTENT_TYPE_TEXT,
+                        constants::REPLY_QUIT.to_string(),
+                    );
+                    respond(request, reply, &headers);
+                    break;
                 }
-                constants::DEFAULT_METRICS_PATH => {
-                    let reply = exporter::metrics(&cfg);
-                    status_code = tiny_http::StatusCode::from(200_i16);
-                    payload = reply;
+                Ok(AdminAction::Reload) => {
+                    let reply = match reload(&cfg, source) {
+                        Ok(v) => {
+                            cfg = Arc::new(v);
+                            collector.set_config(Arc::clone(&cfg));
+                            Reply::new(
+                                200,
+                                constants::CONTENT_TYPE_TEXT,
+                                constants::REPLY_RELOAD.to_string(),
+                            )
+                        }
+                        Err(e) => {
+                            error!("http.rs:server: Can't reload configuration: {}", e);
+                            Reply::new(
+                                500,
+                                constants::CONTENT_TYPE_TEXT,
+                                format!("Can't reload configuration: {}", e),
+                            )
+                        }
+                    };
+                    respond(request, reply, &headers);
                 }
-                _ => {
-                    status_code = tiny_http::StatusCode::from(404_i16);
-                    payload = constants::REPLY_NOT_FOUND.to_string();
+                Err(reply) => respond(request, reply, &headers),
+            };
+            continue;
+        }
+
+        // Only the admin endpoints are served on admin_listen
+        if listener == Listener::Admin {
+            let reply = Reply::new(
+                404,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_NOT_FOUND.to_string(),
+            );
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        // The feature API exposes user names like the metrics
+        if request.url().split('?').next() == Some(constants::API_FEATURE_PATH) {
+            let reply = match scrape_auth(&cfg, authorization.as_deref()) {
+                Ok(role) => feature_request(request.method(), request.url(), role),
+                Err(v) => v,
+            };
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        if request.url().split('?').next() == Some(constants::API_VENDORS_PATH) {
+            let reply = match scrape_auth(&cfg, authorization.as_deref()) {
+                Ok(_) => vendors_request(request.method(), request.url()),
+                Err(v) => v,
+            };
+            respond(request, reply, &headers);
+            continue;
+        }
+
+        // Browsers get the landing page at /, other clients are redirected to the metrics. The
+        // landing page shows the license names and scrape errors, so it is protected like the
+        // metrics.
+        let html = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Accept"))
+            .map_or(false, |h| accepts_html(h.value.as_str()));
+        if request.url() == "/" && html {
+            if let Err(reply) = scrape_auth(&cfg, authorization.as_deref()) {
+                respond(request, reply, &headers);
+                continue;
+            }
+        }
+
+        let role = match metrics_request(
+            &cfg,
+            request.method(),
+            request.url(),
+            authorization.as_deref(),
+        ) {
+            Some(Ok(v)) => v,
+            Some(Err(reply)) => {
+                respond(request, reply, &headers);
+                continue;
+            }
+            None => {
+                let reply = handle_request(&cfg, request.method(), request.url(), html);
+                respond(request, reply, &headers);
+                continue;
+            }
+        };
+
+        let format = request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv("Accept"))
+            .map_or(Format::Text, |h| negotiate(h.value.as_str()));
+
+        // Standby with a recent sample set of the primary
+        if let Some(v) = peersync::snapshot(&cfg) {
+            let reply = restrict(Reply::new(200, prometheus::TEXT_FORMAT, v), role);
+            respond(request, encode(reply, format), &headers);
+            continue;
+        }
+
+        if cfg.scrape_interval().is_some() {
+            let reply = restrict(cached(&collector, &cfg), role);
+            respond(request, encode(reply, format), &headers);
+            continue;
+        }
+
+        // Scrapes are processed by worker threads, so a slow scrape doesn't block other requests
+        let guard = match InFlight::acquire(&in_flight, cfg.max_requests()) {
+            Some(v) => v,
+            None => {
+                warn!(
+                    "http.rs:server: Rejecting request from {:?}, {} scrape(s) already in progress",
+                    request.remote_addr(),
+                    cfg.max_requests()
+                );
+                let mut reply = Reply::new(
+                    503,
+                    constants::CONTENT_TYPE_TEXT,
+                    constants::REPLY_TOO_MANY_REQUESTS.to_string(),
+                );
+                reply
+                    .headers
+                    .push(("Retry-After", constants::RETRY_AFTER.to_string()));
+                respond(request, reply, &headers);
+                continue;
+            }
+        };
+
+        let scrape_cfg = Arc::clone(&cfg);
+        let scrape_headers = headers.clone();
+        thread::spawn(move || {
+            let reply = restrict(scrape(scrape_cfg, guard), role);
+            respond(request, encode(reply, format), &scrape_headers);
+        });
+    }
+
+    // Let running scrapes finish before exiting
+    while in_flight.load(Ordering::SeqCst) > 0 {
+        thread::sleep(Duration::from_millis(100));
+    }
+    info!("http.rs:server: Stopped");
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Listener {
+    Metrics,
+    Admin,
+}
+
+// Pass the requests of a listener to the request loop of the server
+fn accept(
+    server: tiny_http::Server,
+    listener: Listener,
+    sender: mpsc::Sender<(Listener, tiny_http::Request)>,
+) {
+    thread::spawn(move || loop {
+        match server.recv() {
+            Ok(v) => {
+                if sender.send((listener, v)).is_err() {
+                    break;
                 }
+            }
+            Err(e) => error!("http.rs:accept: Can't process incoming request: {}", e),
+        };
+    });
+}
+
+#[derive(Debug, PartialEq)]
+enum AdminAction {
+    Quit,
+    Reload,
+}
+
+// GET and HEAD requests for the metrics must pass the scrape authentication. None if the
+// request isn't for the metrics, the role for the scrape of a GET request or the reply to send
+// otherwise.
+fn metrics_request(
+    cfg: &config::Configuration,
+    method: &tiny_http::Method,
+    url: &str,
+    authorization: Option<&str>,
+) -> Option<Result<Role, Reply>> {
+    if url != cfg.metrics_path()
+        || (method != &tiny_http::Method::Get && method != &tiny_http::Method::Head)
+    {
+        return None;
+    }
+
+    let role = match scrape_auth(cfg, authorization) {
+        Ok(v) => v,
+        Err(reply) => return Some(Err(reply)),
+    };
+
+    if method == &tiny_http::Method::Head {
+        // Don't query all license servers for a health check, the size of the metrics is unknown
+        // without a scrape so no Content-Length is sent
+        let mut reply = Reply::new(200, prometheus::TEXT_FORMAT, String::new());
+        reply.content_length = None;
+        return Some(Err(reply));
+    }
+
+    Some(Ok(role))
+}
+
+// Admin endpoints are only available if an admin token is configured. None if the request
+// isn't for an admin endpoint, the error reply if the request can't be processed.
+fn admin_request(
+    cfg: &config::Configuration,
+    method: &tiny_http::Method,
+    url: &str,
+    authorization: Option<&str>,
+) -> Option<Result<AdminAction, Reply>> {
+    let token = cfg.admin_token()?;
+    let action = match url {
+        constants::ADMIN_QUIT_PATH => AdminAction::Quit,
+        constants::ADMIN_RELOAD_PATH => AdminAction::Reload,
+        _ => return None,
+    };
+
+    if method != &tiny_http::Method::Post && method != &tiny_http::Method::Put {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "POST, PUT".to_string()));
+        return Some(Err(reply));
+    }
+
+    if let Err(reply) = authorize(token, authorization, url) {
+        return Some(Err(reply));
+    }
+
+    Some(Ok(action))
+}
+
+// True if the URL is the path of the liveness or readiness probe, regardless of the query
+fn is_health_path(url: &str) -> bool {
+    let path = url.split('?').next().unwrap_or("");
+    path == constants::HEALTHZ_PATH || path == constants::READYZ_PATH
+}
+
+// True if the client address is in one of the networks, IPv4 clients connected to an IPv6
+// socket are matched as IPv4 address
+fn allowed(networks: Option<&[IpNet]>, remote_addr: Option<&SocketAddr>) -> bool {
+    let networks = match networks {
+        Some(v) => v,
+        None => return true,
+    };
+    let ip = match remote_addr.map(|a| a.ip()) {
+        Some(IpAddr::V6(v6)) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        Some(v) => v,
+        None => return false,
+    };
+    networks.iter().any(|n| n.contains(&ip))
+}
+
+// Liveness and readiness probes, None if the request isn't for a probe. The exporter is ready
+// once the configuration is loaded and, with background collection, the first collection has
+// finished.
+fn health_request(method: &tiny_http::Method, url: &str, ready: bool) -> Option<Reply> {
+    let (status, payload) = match url {
+        constants::HEALTHZ_PATH => (200, constants::REPLY_HEALTHY),
+        constants::READYZ_PATH if ready => (200, constants::REPLY_HEALTHY),
+        constants::READYZ_PATH => (503, constants::REPLY_NOT_READY),
+        _ => return None,
+    };
+
+    if method != &tiny_http::Method::Get && method != &tiny_http::Method::Head {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "GET, HEAD".to_string()));
+        return Some(reply);
+    }
+
+    Some(Reply::new(
+        status,
+        constants::CONTENT_TYPE_TEXT,
+        payload.to_string(),
+    ))
+}
+
+// Usage of a single feature from the metrics of the last collection, selected by the app and
+// feature parameters of the query
+fn feature_request(method: &tiny_http::Method, url: &str, role: Role) -> Reply {
+    if method != &tiny_http::Method::Get {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "GET".to_string()));
+        return reply;
+    }
+
+    let query = url.split_once('?').map_or("", |(_, q)| q);
+    let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    let (app, feature) = match (params.get("app"), params.get("feature")) {
+        (Some(a), Some(f)) if !a.is_empty() && !f.is_empty() => (a, f),
+        _ => {
+            return Reply::new(
+                400,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_MISSING_FEATURE.to_string(),
+            )
+        }
+    };
+
+    let feature = api::feature(&exporter::gather(), app, feature).map(|mut f| {
+        if role == Role::Metrics {
+            f.users.clear();
+        }
+        f
+    });
+    match feature {
+        Some(v) => match serde_json::to_string(&v) {
+            Ok(payload) => Reply::new(200, constants::CONTENT_TYPE_JSON, payload),
+            Err(e) => {
+                error!(
+                    "http.rs:feature_request: Can't encode feature as JSON: {}",
+                    e
+                );
+                Reply::new(
+                    500,
+                    constants::CONTENT_TYPE_TEXT,
+                    format!("Can't encode feature as JSON: {}", e),
+                )
+            }
+        },
+        None => Reply::new(
+            404,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_NOT_FOUND.to_string(),
+        ),
+    }
+}
+
+// Vendor daemons reported by the FlexLM license servers in the last collection, of the license
+// selected by the optional app parameter of the query
+fn vendors_request(method: &tiny_http::Method, url: &str) -> Reply {
+    if method != &tiny_http::Method::Get {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "GET".to_string()));
+        return reply;
+    }
+
+    let query = url.split_once('?').map_or("", |(_, q)| q);
+    let params: HashMap<String, String> = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+
+    let vendors = api::vendors(&exporter::gather(), params.get("app").map(|a| a.as_str()));
+    match serde_json::to_string(&vendors) {
+        Ok(payload) => Reply::new(200, constants::CONTENT_TYPE_JSON, payload),
+        Err(e) => {
+            error!(
+                "http.rs:vendors_request: Can't encode vendors as JSON: {}",
+                e
+            );
+            Reply::new(
+                500,
+                constants::CONTENT_TYPE_TEXT,
+                format!("Can't encode vendors as JSON: {}", e),
+            )
+        }
+    }
+}
+
+// Pushes of the primary are only accepted if peer_sync is configured, with its token
+fn snapshot_request(
+    cfg: &config::Configuration,
+    method: &tiny_http::Method,
+    authorization: Option<&str>,
+) -> Result<(), Reply> {
+    let token = match cfg.peer_sync().and_then(|p| p.token.as_deref()) {
+        Some(v) => v,
+        None => {
+            return Err(Reply::new(
+                404,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_NOT_FOUND.to_string(),
+            ))
+        }
+    };
+
+    if method != &tiny_http::Method::Post && method != &tiny_http::Method::Put {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "POST, PUT".to_string()));
+        return Err(reply);
+    }
+
+    authorize(token, authorization, constants::PEER_SNAPSHOT_PATH)
+}
+
+fn authorize(token: &str, authorization: Option<&str>, url: &str) -> Result<(), Reply> {
+    let presented = authorization
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .unwrap_or("");
+    if !constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+        warn!("http.rs:authorize: Unauthorized request to {}", url);
+        let mut reply = Reply::new(
+            401,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_UNAUTHORIZED.to_string(),
+        );
+        reply
+            .headers
+            .push(("WWW-Authenticate", "Bearer".to_string()));
+        return Err(reply);
+    }
+
+    Ok(())
+}
+
+// Role of a request for the metrics. Series and fields identifying users are only served to
+// the admin role.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Admin,
+    Metrics,
+}
+
+// The metrics are only served to the basic_auth_users or with the scrape_token if one of them
+// is configured. If admin_users is set, only these users and requests with the admin_token get
+// the admin role.
+fn scrape_auth(cfg: &config::Configuration, authorization: Option<&str>) -> Result<Role, Reply> {
+    let users = cfg.basic_auth_users();
+    let token = cfg.scrape_token();
+    let admin_users = cfg.admin_users();
+    let role = |user: Option<&str>| match (admin_users, user) {
+        (None, _) => Role::Admin,
+        (Some(a), Some(u)) if a.iter().any(|v| v == u) => Role::Admin,
+        _ => Role::Metrics,
+    };
+
+    if users.is_none() && token.is_none() {
+        return Ok(Role::Admin);
+    }
+
+    let authorized = match authorization {
+        Some(a) if a.starts_with("Basic ") => users
+            .and_then(|u| basic_auth(u, a))
+            .map(|user| role(Some(&user))),
+        Some(a) => {
+            let presented = a.strip_prefix("Bearer ").unwrap_or("");
+            let matches = |t: Option<&str>| {
+                t.map_or(false, |t| {
+                    constant_time_eq(presented.as_bytes(), t.as_bytes())
+                })
             };
-        } else {
-            status_code = tiny_http::StatusCode::from(405_i16);
-            payload = constants::REPLY_METHOD_NOT_ALLOWED.to_string();
+            if matches(cfg.admin_token()) {
+                Some(Role::Admin)
+            } else if matches(token) {
+                Some(role(None))
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+
+    match authorized {
+        Some(v) => Ok(v),
+        None => {
+            warn!(
+                "http.rs:scrape_auth: Unauthorized request to {}",
+                cfg.metrics_path()
+            );
+            let mut reply = Reply::new(
+                401,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_UNAUTHORIZED.to_string(),
+            );
+            if users.is_some() {
+                reply.headers.push((
+                    "WWW-Authenticate",
+                    format!("Basic realm=\"{}\"", constants::BASIC_AUTH_REALM),
+                ));
+            }
+            if token.is_some() {
+                reply
+                    .headers
+                    .push(("WWW-Authenticate", "Bearer".to_string()));
+            }
+            Err(reply)
+        }
+    }
+}
+
+lazy_static! {
+    // Digests of user, password and hash of successful basic auth verifications, bcrypt is
+    // too slow to run on every request
+    static ref VERIFIED: Mutex<HashSet<[u8; 32]>> = Mutex::new(HashSet::new());
+}
+
+// Check the credentials of a basic authorization header against the bcrypt hashes of the
+// users, the name of the authenticated user
+fn basic_auth(users: &HashMap<String, String>, authorization: &str) -> Option<String> {
+    let credentials = authorization
+        .strip_prefix("Basic ")
+        .and_then(|c| {
+            base64::engine::general_purpose::STANDARD
+                .decode(c.trim())
+                .ok()
+        })
+        .and_then(|c| String::from_utf8(c).ok())?;
+
+    let (user, password) = credentials.split_once(':')?;
+    let hash = match users.get(user) {
+        Some(v) => v,
+        None => {
+            // Don't reveal whether the user exists by answering faster
+            let _ = bcrypt::verify(password, constants::BASIC_AUTH_DUMMY_HASH);
+            return None;
+        }
+    };
+
+    let mut hasher = Sha256::new();
+    for part in [user, password, hash] {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    let digest: [u8; 32] = hasher.finalize().into();
+    if VERIFIED.lock().unwrap().contains(&digest) {
+        return Some(user.to_string());
+    }
+
+    if bcrypt::verify(password, hash).unwrap_or(false) {
+        VERIFIED.lock().unwrap().insert(digest);
+        Some(user.to_string())
+    } else {
+        None
+    }
+}
+
+// Remove the user data from a successful reply for the metrics role
+fn restrict(mut reply: Reply, role: Role) -> Reply {
+    if role == Role::Metrics && reply.status == 200 {
+        reply.payload = exporter::without_user_data(&reply.payload);
+        reply.content_length = Some(reply.payload.len());
+    }
+    reply
+}
+
+fn receive_snapshot(request: &mut tiny_http::Request) -> Reply {
+    let mut sample = String::new();
+    let result = request
+        .as_reader()
+        .take(constants::MAX_PEER_SNAPSHOT_SIZE + 1)
+        .read_to_string(&mut sample);
+
+    if let Err(e) = result {
+        error!("http.rs:receive_snapshot: Can't read snapshot: {}", e);
+        return Reply::new(
+            400,
+            constants::CONTENT_TYPE_TEXT,
+            format!("Can't read snapshot: {}", e),
+        );
+    }
+    if sample.len() as u64 > constants::MAX_PEER_SNAPSHOT_SIZE {
+        return Reply::new(
+            413,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_PAYLOAD_TOO_LARGE.to_string(),
+        );
+    }
+
+    debug!(
+        "http.rs:receive_snapshot: Received {} bytes from the primary",
+        sample.len()
+    );
+    peersync::receive(sample);
+    Reply::new(
+        200,
+        constants::CONTENT_TYPE_TEXT,
+        constants::REPLY_SNAPSHOT.to_string(),
+    )
+}
+
+// Compare secrets without leaking the position of the first difference by the response time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn reload(
+    cfg: &config::Configuration,
+    source: &config::ConfigSource,
+) -> Result<config::Configuration, Box<dyn Error>> {
+    let new_cfg = source.load()?;
+
+    logging::register_secrets(&new_cfg);
+    exporter::configure(&new_cfg);
+    exporter::reregister(cfg, &new_cfg);
+    info!("http.rs:reload: Configuration reloaded from {}", source);
+
+    Ok(new_cfg)
+}
+
+fn has_body(request: &tiny_http::Request) -> bool {
+    request.body_length().unwrap_or(0) > 0
+        || request
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Transfer-Encoding"))
+}
+
+fn respond(request: tiny_http::Request, mut reply: Reply, headers: &[tiny_http::Header]) {
+    let gzip = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept-Encoding"))
+        .map_or(false, |h| accepts_gzip(h.value.as_str()));
+    let payload = match compress(&mut reply, gzip) {
+        Some(v) => v,
+        None => reply.payload.clone().into_bytes(),
+    };
+
+    let mut reply_headers = headers.to_vec();
+    for (name, value) in reply.headers.iter() {
+        match tiny_http::Header::from_bytes(name.as_bytes(), value.as_bytes()) {
+            Ok(v) => reply_headers.push(v),
+            Err(_) => error!("http.rs:respond: Invalid {} header {}", name, value),
+        };
+    }
+
+    // NOTE: tiny_http doesn't send the body of replies to HEAD requests
+    if let Err(e) = request.respond(tiny_http::Response::new(
+        tiny_http::StatusCode::from(reply.status),
+        reply_headers,
+        payload.as_slice(),
+        reply.content_length,
+        None,
+    )) {
+        error!("http.rs:respond: Can't send response to client: {}", e);
+    }
+}
+
+// Collect the metrics in a separate thread. If the request timeout expires, the client gets
+// an error but the collection continues and holds its slot until it is finished, so a client
+// can't pile up collections by retrying.
+fn scrape(cfg: Arc<config::Configuration>, guard: InFlight) -> Reply {
+    let (sender, receiver) = mpsc::channel();
+    let timeout = cfg.request_timeout();
+
+    let collect_cfg = Arc::clone(&cfg);
+    thread::spawn(move || {
+        let _guard = guard;
+        let sample = exporter::metrics(&collect_cfg);
+        peersync::push(&collect_cfg, &sample);
+        let _ = sender.send(sample);
+    });
+
+    let result = match timeout {
+        Some(t) => receiver.recv_timeout(t).map_err(|e| e.to_string()),
+        None => receiver.recv().map_err(|e| e.to_string()),
+    };
+
+    match result {
+        Ok(v) => Reply::new(200, prometheus::TEXT_FORMAT, v + &live_metrics(&cfg, None)),
+        Err(e) => {
+            error!(
+                "http.rs:scrape: Metrics weren't collected within request_timeout: {}",
+                e
+            );
+            Reply::new(
+                503,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_SCRAPE_TIMEOUT.to_string(),
+            )
         }
+    }
+}
+
+// Metrics rendered on every scrape instead of being part of the sample set, with the
+// customisations of the global section applied like to the sample set
+fn live_metrics(cfg: &config::Configuration, collector: Option<&Collector>) -> String {
+    let mut families = collector.map_or_else(Vec::new, |c| c.heartbeat());
+    families.extend(runtime::families(cfg));
+    if let Some(glob) = &cfg.global {
+        exporter::customise_metrics(&mut families, glob);
+    }
+
+    let mut buffer = String::new();
+    if let Err(e) = TextEncoder::new().encode_utf8(&families, &mut buffer) {
+        error!("http.rs:live_metrics: Can't encode metrics: {}", e);
+    }
+    buffer
+}
 
-        if let Err(e) = request.respond(tiny_http::Response::new(
-            status_code,
-            headers.clone(),
-            payload.as_bytes(),
-            Some(payload.len()),
+// Reply with the last sample set of the background collection
+fn cached(collector: &Collector, cfg: &config::Configuration) -> Reply {
+    match collector.sample() {
+        Some(v) => Reply::new(
+            200,
+            prometheus::TEXT_FORMAT,
+            v + &live_metrics(cfg, Some(collector)),
+        ),
+        None => {
+            let mut reply = Reply::new(
+                503,
+                constants::CONTENT_TYPE_TEXT,
+                constants::REPLY_NO_SAMPLE.to_string(),
+            );
+            reply
+                .headers
+                .push(("Retry-After", constants::RETRY_AFTER.to_string()));
+            reply
+        }
+    }
+}
+
+// Slot of a running scrape, released when dropped
+struct InFlight {
+    count: Arc<AtomicUsize>,
+}
+
+impl InFlight {
+    fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        if count.fetch_add(1, Ordering::SeqCst) >= max {
+            count.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(InFlight {
+            count: Arc::clone(count),
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Compress large payloads if the client accepts gzip, e.g. metrics of many features with
+// user-level series. The headers of reply are updated for the compressed payload.
+fn compress(reply: &mut Reply, gzip: bool) -> Option<Vec<u8>> {
+    if !gzip || reply.content_length.is_none() || reply.payload.len() < constants::GZIP_MIN_SIZE {
+        return None;
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder
+        .write_all(reply.payload.as_bytes())
+        .and_then(|_| encoder.finish())
+    {
+        Ok(v) => v,
+        Err(e) => {
+            error!("http.rs:compress: Can't compress reply: {}", e);
+            return None;
+        }
+    };
+
+    reply.headers.push(("Content-Encoding", "gzip".to_string()));
+    reply.headers.push(("Vary", "Accept-Encoding".to_string()));
+    reply.content_length = Some(compressed.len());
+    Some(compressed)
+}
+
+// Browsers ask for HTML, e.g. Accept: text/html,application/xhtml+xml,*/*;q=0.8
+fn accepts_html(accept: &str) -> bool {
+    accept.split(',').any(|range| {
+        let mut parts = range.split(';').map(|p| p.trim());
+        let media_type = parts.next().unwrap_or("").to_ascii_lowercase();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+        (media_type == "text/html" || media_type == "application/xhtml+xml") && q > 0.0
+    })
+}
+
+// True if gzip is listed in an Accept-Encoding header without q=0
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|e| {
+        let mut parts = e.split(';').map(|p| p.trim());
+        let coding = parts.next().unwrap_or("");
+        let disabled =
+            parts.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f64>().ok()) == Some(0.0));
+        (coding.eq_ignore_ascii_case("gzip") || coding == "*") && !disabled
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Text,
+    OpenMetrics,
+}
+
+// Format of the metrics requested by an Accept header. Prometheus prefers OpenMetrics, e.g.
+// "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1",
+// other clients get the text format unless they rank OpenMetrics at least as high.
+fn negotiate(accept: &str) -> Format {
+    let mut openmetrics: f64 = 0.0;
+    let mut text: f64 = 0.0;
+
+    for range in accept.split(',') {
+        let mut parts = range.split(';').map(|p| p.trim());
+        let media_type = parts.next().unwrap_or("").to_ascii_lowercase();
+        let q = parts
+            .find_map(|p| p.strip_prefix("q="))
+            .and_then(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+
+        match media_type.as_str() {
+            constants::MEDIA_TYPE_OPENMETRICS => openmetrics = openmetrics.max(q),
+            "text/plain" | "text/*" | "*/*" => text = text.max(q),
+            _ => {}
+        };
+    }
+
+    if openmetrics > 0.0 && openmetrics >= text {
+        Format::OpenMetrics
+    } else {
+        Format::Text
+    }
+}
+
+// Convert a successful metrics reply to the negotiated format
+fn encode(mut reply: Reply, format: Format) -> Reply {
+    reply.headers.push(("Vary", "Accept".to_string()));
+    if format == Format::OpenMetrics && reply.status == 200 {
+        reply.payload = openmetrics::from_text(&reply.payload);
+        reply.content_length = Some(reply.payload.len());
+        for (name, value) in reply.headers.iter_mut() {
+            if *name == "Content-Type" {
+                *value = constants::CONTENT_TYPE_OPENMETRICS.to_string();
+            }
+        }
+    }
+    reply
+}
+
+#[derive(Debug)]
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    payload: String,
+    content_length: Option<usize>,
+}
+
+impl Reply {
+    fn new(status: u16, content_type: &str, payload: String) -> Self {
+        Reply {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            content_length: Some(payload.len()),
+            payload,
+        }
+    }
+}
+
+fn handle_request(
+    cfg: &config::Configuration,
+    method: &tiny_http::Method,
+    url: &str,
+    html: bool,
+) -> Reply {
+    if method != &tiny_http::Method::Get && method != &tiny_http::Method::Head {
+        let mut reply = Reply::new(
+            405,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_METHOD_NOT_ALLOWED.to_string(),
+        );
+        reply.headers.push(("Allow", "GET, HEAD".to_string()));
+        return reply;
+    }
+
+    if url != "/" {
+        return Reply::new(
+            404,
+            constants::CONTENT_TYPE_TEXT,
+            constants::REPLY_NOT_FOUND.to_string(),
+        );
+    }
+
+    let mut reply = if html {
+        Reply::new(200, constants::CONTENT_TYPE_HTML, status::landing_page(cfg))
+    } else {
+        let metrics_path = cfg.metrics_path();
+        let mut reply = Reply::new(
+            302,
+            constants::CONTENT_TYPE_TEXT,
+            format!("{}{}\n", constants::REPLY_REDIRECT, metrics_path),
+        );
+        reply.headers.push(("Location", metrics_path.to_string()));
+        reply
+    };
+    reply.headers.push(("Vary", "Accept".to_string()));
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn test_settings(accept: &'static str) -> ClientSettings {
+        ClientSettings {
+            accept,
+            ca_file: String::new(),
+            client_certificate: None,
+            hosts: Vec::new(),
+            insecure_ssl: false,
+            max_redirects: 0,
+            pool_max_idle_per_host: 1,
+            proxy: testutil::no_proxy(),
+            timeout: Duration::from_secs(constants::DEFAULT_TIMEOUT),
+        }
+    }
+
+    #[test]
+    fn proxy_settings() {
+        let cfg = testutil::parse_config(
+            "global:\n  http_proxy: 'http://global:3128'\n  no_proxy: 'localhost,.example.com'\n",
+        );
+
+        let proxy = ProxySettings::new(&cfg.global, &None);
+        assert_eq!(proxy.http_proxy.as_deref(), Some("http://global:3128"));
+        assert_eq!(proxy.https_proxy, None);
+        assert_eq!(proxy.no_proxy.as_deref(), Some("localhost,.example.com"));
+
+        let proxy = ProxySettings::new(&cfg.global, &Some("http://entry:8080".to_string()));
+        assert_eq!(proxy.http_proxy.as_deref(), Some("http://entry:8080"));
+        assert_eq!(proxy.https_proxy.as_deref(), Some("http://entry:8080"));
+        assert_eq!(proxy.no_proxy.as_deref(), Some("localhost,.example.com"));
+
+        let proxy = ProxySettings::new(&None, &Some(String::new()));
+        assert_eq!(proxy.http_proxy.as_deref(), Some(""));
+        assert_eq!(proxy.no_proxy, None);
+    }
+
+    #[test]
+    fn accept_header() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let handle = thread::spawn(move || {
+            let request = server.recv().unwrap();
+            let accept: Vec<String> = request
+                .headers()
+                .iter()
+                .filter(|h| h.field.equiv("Accept"))
+                .map(|h| h.value.to_string())
+                .collect();
+            let _ = request.respond(tiny_http::Response::from_string("ok"));
+            accept
+        });
+
+        let mut client = build_client(&test_settings(constants::HTTP_ACCEPT_XML)).unwrap();
+        get(
+            &mut client,
+            &format!("http://127.0.0.1:{}/", port),
+            "",
+            "",
+            false,
+        )
+        .unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            vec![constants::HTTP_ACCEPT_XML.to_string()]
+        );
+    }
+
+    fn header<'a>(reply: &'a Reply, name: &str) -> Option<&'a str> {
+        reply
+            .headers
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn root_page() {
+        let cfg = testutil::parse_config("global:\n  metrics_path: /exporter/metrics\n");
+
+        let reply = handle_request(&cfg, &tiny_http::Method::Get, "/", true);
+        assert_eq!(reply.status, 200);
+        assert_eq!(
+            header(&reply, "Content-Type"),
+            Some(constants::CONTENT_TYPE_HTML)
+        );
+        assert!(reply.payload.contains("<a href=\"/exporter/metrics\">"));
+
+        // Clients not asking for HTML are redirected to the metrics
+        let reply = handle_request(&cfg, &tiny_http::Method::Get, "/", false);
+        assert_eq!(reply.status, 302);
+        assert_eq!(header(&reply, "Location"), Some("/exporter/metrics"));
+
+        for (accept, html) in [
+            (
+                "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+                true,
+            ),
+            ("application/xhtml+xml", true),
+            ("text/html;q=0", false),
+            ("*/*", false),
+            ("text/plain;version=0.0.4", false),
+        ] {
+            assert_eq!(accepts_html(accept), html, "{}", accept);
+        }
+
+        assert!(
+            metrics_request(&cfg, &tiny_http::Method::Get, "/exporter/metrics", None)
+                .unwrap()
+                .is_ok()
+        );
+        assert!(metrics_request(
+            &cfg,
+            &tiny_http::Method::Get,
+            constants::DEFAULT_METRICS_PATH,
+            None
+        )
+        .is_none());
+
+        let reply = handle_request(
+            &cfg,
+            &tiny_http::Method::Get,
+            constants::DEFAULT_METRICS_PATH,
+            false,
+        );
+        assert_eq!(reply.status, 404);
+    }
+
+    #[test]
+    fn methods_and_content_type() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+
+        assert_eq!(
+            metrics_request(&cfg, &tiny_http::Method::Get, "/metrics", None)
+                .unwrap()
+                .unwrap(),
+            Role::Admin
+        );
+
+        let reply = metrics_request(&cfg, &tiny_http::Method::Head, "/metrics", None)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(reply.status, 200);
+        assert_eq!(
+            header(&reply, "Content-Type"),
+            Some("text/plain; version=0.0.4")
+        );
+        assert_eq!(reply.content_length, None);
+
+        let reply = handle_request(&cfg, &tiny_http::Method::Head, "/", true);
+        assert_eq!(reply.status, 200);
+        let reply = handle_request(&cfg, &tiny_http::Method::Head, "/", false);
+        assert_eq!(reply.status, 302);
+
+        assert!(metrics_request(&cfg, &tiny_http::Method::Post, "/metrics", None).is_none());
+        let reply = handle_request(&cfg, &tiny_http::Method::Post, "/metrics", false);
+        assert_eq!(reply.status, 405);
+        assert_eq!(header(&reply, "Allow"), Some("GET, HEAD"));
+    }
+
+    #[test]
+    fn admin_endpoints() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+        assert!(admin_request(&cfg, &tiny_http::Method::Post, "/-/quit", None).is_none());
+
+        let cfg = testutil::parse_config("global:\n  admin_token: s3cr3t\n");
+        assert!(admin_request(&cfg, &tiny_http::Method::Post, "/metrics", None).is_none());
+        assert_eq!(
+            admin_request(
+                &cfg,
+                &tiny_http::Method::Post,
+                "/-/quit",
+                Some("Bearer s3cr3t")
+            )
+            .unwrap()
+            .unwrap(),
+            AdminAction::Quit
+        );
+        assert_eq!(
+            admin_request(
+                &cfg,
+                &tiny_http::Method::Put,
+                "/-/reload",
+                Some("Bearer s3cr3t")
+            )
+            .unwrap()
+            .unwrap(),
+            AdminAction::Reload
+        );
+
+        let reply = admin_request(
+            &cfg,
+            &tiny_http::Method::Get,
+            "/-/quit",
+            Some("Bearer s3cr3t"),
+        )
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(reply.status, 405);
+
+        for authorization in [None, Some("Bearer s3cr3"), Some("Basic s3cr3t")] {
+            let reply = admin_request(&cfg, &tiny_http::Method::Post, "/-/reload", authorization)
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(reply.status, 401);
+            assert_eq!(header(&reply, "WWW-Authenticate"), Some("Bearer"));
+        }
+    }
+
+    #[test]
+    fn basic_auth_users() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+        assert!(scrape_auth(&cfg, None).is_ok());
+
+        let cfg = testutil::parse_config(
+            "global:\n  basic_auth_users:\n    prometheus: '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'\n",
+        );
+        // prometheus:s3cr3t, verified and then cached
+        for _ in 0..2 {
+            assert_eq!(
+                scrape_auth(&cfg, Some("Basic cHJvbWV0aGV1czpzM2NyM3Q=")).unwrap(),
+                Role::Admin
+            );
+        }
+
+        // prometheus:wrong, nobody:s3cr3t
+        for authorization in [
             None,
-        )) {
-            error!("http.rs:server: Can't send response to client: {}", e);
+            Some("Basic cHJvbWV0aGV1czp3cm9uZw=="),
+            Some("Basic bm9ib2R5OnMzY3IzdA=="),
+            Some("Basic !!!"),
+            Some("Bearer s3cr3t"),
+        ] {
+            let reply = scrape_auth(&cfg, authorization).unwrap_err();
+            assert_eq!(reply.status, 401);
+            assert_eq!(
+                header(&reply, "WWW-Authenticate"),
+                Some("Basic realm=\"prometheus-license-exporter\"")
+            );
+        }
+    }
+
+    #[test]
+    fn scrape_token() {
+        let cfg = testutil::parse_config("global:\n  scrape_token: s3cr3t\n");
+        assert!(scrape_auth(&cfg, Some("Bearer s3cr3t")).is_ok());
+
+        for authorization in [
+            None,
+            Some("Bearer s3cr3"),
+            Some("Basic cHJvbWV0aGV1czpzM2NyM3Q="),
+        ] {
+            let reply = scrape_auth(&cfg, authorization).unwrap_err();
+            assert_eq!(reply.status, 401);
+            assert_eq!(header(&reply, "WWW-Authenticate"), Some("Bearer"));
+
+            // HEAD requests for the metrics are authenticated as well
+            let reply = metrics_request(&cfg, &tiny_http::Method::Head, "/metrics", authorization)
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(reply.status, 401);
         }
+        let reply = metrics_request(
+            &cfg,
+            &tiny_http::Method::Head,
+            "/metrics",
+            Some("Bearer s3cr3t"),
+        )
+        .unwrap()
+        .unwrap_err();
+        assert_eq!(reply.status, 200);
+
+        let cfg = testutil::parse_config(
+            "global:\n  scrape_token: s3cr3t\n  basic_auth_users:\n    prometheus: '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'\n",
+        );
+        assert!(scrape_auth(&cfg, Some("Bearer s3cr3t")).is_ok());
+        assert!(scrape_auth(&cfg, Some("Basic cHJvbWV0aGV1czpzM2NyM3Q=")).is_ok());
+    }
+
+    #[test]
+    fn roles() {
+        // prometheus and grafana with the password s3cr3t
+        let cfg = testutil::parse_config(
+            "global:\n  admin_token: adm1n\n  scrape_token: s3cr3t\n  admin_users: [grafana]\n  basic_auth_users:\n    prometheus: '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'\n    grafana: '$2b$04$CbvfQurxAEOwpgYWeX14jOK9BVQcsHfAX8f/mn36acQBpnzHna6ka'\n",
+        );
+        for (authorization, role) in [
+            ("Basic cHJvbWV0aGV1czpzM2NyM3Q=", Role::Metrics),
+            ("Basic Z3JhZmFuYTpzM2NyM3Q=", Role::Admin),
+            ("Bearer s3cr3t", Role::Metrics),
+            ("Bearer adm1n", Role::Admin),
+        ] {
+            assert_eq!(
+                scrape_auth(&cfg, Some(authorization)).unwrap(),
+                role,
+                "{}",
+                authorization
+            );
+        }
+
+        let sample = "# TYPE flexlm_feature_used gauge\nflexlm_feature_used{app=\"flex1\",name=\"f1\"} 2\n# TYPE flexlm_feature_used_users gauge\nflexlm_feature_used_users{app=\"flex1\",name=\"f1\",user=\"alice\"} 2\n";
+        let reply = restrict(
+            Reply::new(200, prometheus::TEXT_FORMAT, sample.to_string()),
+            Role::Metrics,
+        );
+        assert!(!reply.payload.contains("alice"));
+        assert!(reply.payload.contains("flexlm_feature_used{"));
+        assert_eq!(reply.content_length, Some(reply.payload.len()));
+    }
+
+    #[test]
+    fn restrict_user_reservations() {
+        let sample = "# TYPE flexlm_feature_reservations gauge\n\
+            flexlm_feature_reservations{app=\"flex1\",group=\"alice\",name=\"f1\",type=\"user\"} 1\n\
+            flexlm_feature_reservations{app=\"flex1\",group=\"eng\",name=\"f1\",type=\"group\"} 2\n\
+            # TYPE flexlm_policy_max gauge\n\
+            flexlm_policy_max{app=\"flex1\",group=\"bob\",name=\"f1\",type=\"user\"} 3\n\
+            flexlm_policy_max{app=\"flex1\",group=\"ws1\",name=\"f1\",type=\"host\"} 4\n";
+
+        let reply = restrict(
+            Reply::new(200, prometheus::TEXT_FORMAT, sample.to_string()),
+            Role::Metrics,
+        );
+        assert!(!reply.payload.contains("alice"));
+        assert!(!reply.payload.contains("bob"));
+        assert!(reply.payload.contains("group=\"eng\""));
+        assert!(reply.payload.contains("group=\"ws1\""));
+        assert_eq!(reply.content_length, Some(reply.payload.len()));
+
+        let reply = restrict(
+            Reply::new(200, prometheus::TEXT_FORMAT, sample.to_string()),
+            Role::Admin,
+        );
+        assert_eq!(reply.payload, sample);
+    }
+
+    #[test]
+    fn customised_live_metrics() {
+        let cfg = testutil::parse_config(
+            "global:\n  scrape_interval: 60\n  metric_prefix: licexp_\n  constant_labels:\n    site: lab\n  metric_help:\n    license_exporter_workers_busy: Busy workers\n",
+        );
+        let collector = Collector::new(Arc::new(testutil::parse_config(
+            "global:\n  lmutil: lmutil\n",
+        )));
+
+        let rendered = live_metrics(&cfg, Some(&collector));
+        assert!(rendered.contains("# HELP licexp_license_exporter_workers_busy Busy workers\n"));
+        for line in rendered.lines().filter(|l| !l.starts_with('#')) {
+            assert!(line.starts_with("licexp_license_exporter_"), "{}", line);
+            assert!(line.contains("site=\"lab\""), "{}", line);
+        }
+    }
+
+    #[test]
+    fn openmetrics_negotiation() {
+        for (accept, format) in [
+            (
+                "application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1",
+                Format::OpenMetrics,
+            ),
+            ("text/plain;version=0.0.4;q=1,application/openmetrics-text;q=0.5", Format::Text),
+            ("application/openmetrics-text;q=0", Format::Text),
+            ("*/*", Format::Text),
+            ("", Format::Text),
+        ] {
+            assert_eq!(negotiate(accept), format, "{}", accept);
+        }
+
+        let sample =
+            "# TYPE flexlm_feature_used gauge\nflexlm_feature_used{app=\"flex1\",name=\"f1\"} 2\n";
+        let reply = encode(
+            Reply::new(200, prometheus::TEXT_FORMAT, sample.to_string()),
+            Format::OpenMetrics,
+        );
+        assert!(reply.payload.ends_with("# EOF\n"));
+        assert_eq!(reply.content_length, Some(reply.payload.len()));
+        assert_eq!(
+            header(&reply, "Content-Type"),
+            Some(constants::CONTENT_TYPE_OPENMETRICS)
+        );
+
+        let reply = encode(
+            Reply::new(200, prometheus::TEXT_FORMAT, sample.to_string()),
+            Format::Text,
+        );
+        assert_eq!(reply.payload, sample);
+        assert_eq!(header(&reply, "Vary"), Some("Accept"));
+    }
+
+    #[test]
+    fn gzip_compression() {
+        for (header, expected) in [
+            ("gzip", true),
+            ("deflate, GZIP;q=0.5", true),
+            ("*", true),
+            ("gzip;q=0", false),
+            ("deflate, br", false),
+            ("", false),
+        ] {
+            assert_eq!(accepts_gzip(header), expected, "{}", header);
+        }
+
+        let payload = "flexlm_feature_used{app=\"flex1\",name=\"f1\"} 1\n".repeat(100);
+        let mut reply = Reply::new(200, prometheus::TEXT_FORMAT, payload.clone());
+        assert!(compress(&mut reply, false).is_none());
+
+        let compressed = compress(&mut reply, true).unwrap();
+        assert_eq!(header(&reply, "Content-Encoding"), Some("gzip"));
+        assert_eq!(reply.content_length, Some(compressed.len()));
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, payload);
+
+        let mut reply = Reply::new(200, constants::CONTENT_TYPE_TEXT, "OK".to_string());
+        assert!(compress(&mut reply, true).is_none());
+    }
+
+    #[test]
+    fn allowed_networks() {
+        let cfg = testutil::parse_config(
+            "global:\n  allowed_networks: ['10.0.0.0/8', '2001:db8::/32']\n",
+        );
+        for (addr, expected) in [
+            ("10.1.2.3:40000", true),
+            ("[::ffff:10.1.2.3]:40000", true),
+            ("[2001:db8::1]:40000", true),
+            ("192.0.2.1:40000", false),
+            ("[2001:db9::1]:40000", false),
+        ] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(
+                allowed(cfg.allowed_networks(), Some(&addr)),
+                expected,
+                "{}",
+                addr
+            );
+        }
+        assert!(!allowed(cfg.allowed_networks(), None));
+        assert!(allowed(None, None));
+
+        assert!(is_health_path("/healthz"));
+        assert!(is_health_path("/readyz?verbose"));
+        assert!(!is_health_path("/metrics"));
+    }
+
+    #[test]
+    fn health_endpoints() {
+        let reply = health_request(&tiny_http::Method::Get, "/healthz", false).unwrap();
+        assert_eq!(reply.status, 200);
+        let reply = health_request(&tiny_http::Method::Get, "/readyz", false).unwrap();
+        assert_eq!(reply.status, 503);
+        let reply = health_request(&tiny_http::Method::Head, "/readyz", true).unwrap();
+        assert_eq!(reply.status, 200);
+        let reply = health_request(&tiny_http::Method::Post, "/healthz", true).unwrap();
+        assert_eq!(reply.status, 405);
+        assert!(health_request(&tiny_http::Method::Get, "/metrics", true).is_none());
+    }
+
+    #[test]
+    fn feature_endpoint() {
+        let reply = feature_request(
+            &tiny_http::Method::Get,
+            "/api/v1/feature?app=flex1",
+            Role::Admin,
+        );
+        assert_eq!(reply.status, 400);
+        let reply = feature_request(&tiny_http::Method::Post, "/api/v1/feature", Role::Admin);
+        assert_eq!(reply.status, 405);
+        let reply = feature_request(
+            &tiny_http::Method::Get,
+            "/api/v1/feature?app=no%20such%20license&feature=MATLAB",
+            Role::Admin,
+        );
+        assert_eq!(reply.status, 404);
+    }
+
+    #[test]
+    fn snapshot_endpoint() {
+        let cfg = testutil::parse_config("global:\n  admin_token: s3cr3t\n");
+        let reply =
+            snapshot_request(&cfg, &tiny_http::Method::Post, Some("Bearer s3cr3t")).unwrap_err();
+        assert_eq!(reply.status, 404);
+
+        let cfg = testutil::parse_config("global:\n  peer_sync:\n    token: s3cr3t\n");
+        assert!(snapshot_request(&cfg, &tiny_http::Method::Post, Some("Bearer s3cr3t")).is_ok());
+        let reply =
+            snapshot_request(&cfg, &tiny_http::Method::Get, Some("Bearer s3cr3t")).unwrap_err();
+        assert_eq!(reply.status, 405);
+        let reply =
+            snapshot_request(&cfg, &tiny_http::Method::Put, Some("Bearer wrong")).unwrap_err();
+        assert_eq!(reply.status, 401);
+    }
+
+    #[test]
+    fn in_flight_limit() {
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let first = InFlight::acquire(&count, 2).unwrap();
+        let second = InFlight::acquire(&count, 2).unwrap();
+        assert!(InFlight::acquire(&count, 2).is_none());
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        drop(first);
+        let third = InFlight::acquire(&count, 2).unwrap();
+        assert!(InFlight::acquire(&count, 2).is_none());
+
+        drop(second);
+        drop(third);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn cached_without_sample() {
+        let cfg = testutil::parse_config("global:\n  lmutil: lmutil\n");
+        let cfg = Arc::new(cfg);
+        let collector = Collector::new(Arc::clone(&cfg));
+
+        let reply = cached(&collector, &cfg);
+        assert_eq!(reply.status, 503);
+        assert_eq!(header(&reply, "Retry-After"), Some("10"));
+    }
+
+    #[test]
+    fn invalid_accept_header() {
+        assert!(build_client(&test_settings("text/xml\n")).is_err());
     }
 }
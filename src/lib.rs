@@ -0,0 +1,63 @@
+//! Prometheus exporter for license servers. Besides the exporter binary, the parsers of the
+//! license tool output, the configuration and the metrics of the backends can be used by
+//! other tools, e.g. to parse lmstat output without running the exporter. The parsers only
+//! return the parsed data, the metrics are set by the exporter.
+
+mod api;
+pub mod backend;
+mod chaos;
+mod cli;
+mod collector;
+mod command;
+mod config;
+mod constants;
+mod dates;
+mod dsls;
+mod exporter;
+mod flexlm;
+#[cfg(feature = "experimental_native_flexlm")]
+mod flexlm_native;
+mod hasp;
+mod history;
+mod hooks;
+mod http;
+mod license;
+mod licensefile;
+mod licman20;
+mod lmx;
+mod logging;
+mod migrate;
+mod olicense;
+mod openmetrics;
+mod optionsfile;
+mod peersync;
+mod resolver;
+mod rlm;
+mod runtime;
+mod series;
+mod servers;
+mod signals;
+mod spnego;
+mod stats;
+mod status;
+#[cfg(test)]
+mod testutil;
+mod tls;
+mod topusers;
+mod usage;
+
+pub use backend::Backend;
+pub use cli::run;
+pub use config::{parse_config, parse_config_as, parse_config_file, ConfigFormat, Configuration};
+pub use dsls::{extract_data as parse_dsls_line, DslsLicenseUsage};
+pub use exporter::{configure, metrics, register};
+pub use flexlm::{
+    parse_lmstat, FlexLMBorrow, FlexLMQueued, FlexLMReservation, FlexLMServer, FlexLMVendor,
+    LmstatUsage, ServerState,
+};
+pub use hasp::{
+    parse_features as parse_hasp_features, parse_sessions as parse_hasp_sessions, HaspFeature,
+    HaspSession,
+};
+pub use lmx::{parse_xml as parse_lmx_xml, LmxLicenseData};
+pub use olicense::{parse_xml as parse_olicense_xml, OLicenseData};
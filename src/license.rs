@@ -1,14 +1,229 @@
-pub fn is_excluded(excludes: &Option<Vec<String>>, feature: String) -> bool {
-    let mut excluded: bool = false;
-
-    if let Some(excl) = excludes {
-        for f in excl {
-            if *f == feature {
-                excluded = true;
-                break;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use simple_error::bail;
+use std::collections::HashSet;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+// Normalised license models for the model label, vendor specific names are mapped by the backends
+pub const MODEL_FLOATING: &str = "floating";
+pub const MODEL_NAMED_USER: &str = "named_user";
+pub const MODEL_NODE_LOCKED: &str = "node_locked";
+pub const MODEL_TOKEN: &str = "token";
+pub const MODEL_UNKNOWN: &str = "unknown";
+
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFilter {
+    names: HashSet<String>,
+    patterns: Vec<Regex>,
+    // Allowlist of include_features, all other features are skipped if set
+    includes: Option<(HashSet<String>, Vec<Regex>)>,
+    // Feature names are compared without a suffix starting with this separator
+    suffix: Option<char>,
+    // Features skipped since the last call of take_excluded
+    excluded: Arc<Mutex<HashSet<String>>>,
+    // Features additionally skipped as set by the backend on every fetch, e.g. the features of
+    // unselected FlexLM vendor daemons
+    hidden: Arc<Mutex<HashSet<String>>>,
+}
+
+impl FeatureFilter {
+    pub fn new(
+        excludes: &Option<Vec<String>>,
+        includes: &Option<Vec<String>>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut result = FeatureFilter::default();
+
+        if let Some(excl) = excludes {
+            let (names, patterns) = parse_entries(excl)?;
+            result.names = names;
+            result.patterns = patterns;
+        }
+        if let Some(incl) = includes {
+            result.includes = Some(parse_entries(incl)?);
+        }
+
+        Ok(result)
+    }
+
+    // Replace the features additionally excluded by the backend
+    pub fn hide<I>(&self, names: I)
+    where
+        I: IntoIterator<Item = String>,
+    {
+        if let Ok(mut hidden) = self.hidden.lock() {
+            *hidden = names.into_iter().collect();
+        }
+    }
+
+    // Copy of the filter for feature names with a suffix, e.g. the vendor daemon of FlexLM
+    // features with vendor_suffix
+    pub fn with_suffix(&self, separator: char) -> Self {
+        let mut result = self.clone();
+        result.suffix = Some(separator);
+        result
+    }
+
+    pub fn is_excluded(&self, feature: &str) -> bool {
+        let result = self.matches(feature);
+        if result {
+            if let Ok(mut excluded) = self.excluded.lock() {
+                excluded.insert(feature.to_string());
+            }
+        }
+        result
+    }
+
+    // Number of distinct features skipped since the last call
+    pub fn take_excluded(&self) -> usize {
+        match self.excluded.lock() {
+            Ok(mut excluded) => excluded.drain().count(),
+            Err(_) => 0,
+        }
+    }
+
+    pub fn matches(&self, feature: &str) -> bool {
+        let feature = match self.suffix {
+            Some(c) => feature.split(c).next().unwrap_or(feature),
+            None => feature,
+        };
+        if let Some((names, patterns)) = &self.includes {
+            if !names.contains(feature) && !patterns.iter().any(|re| re.is_match(feature)) {
+                return true;
+            }
+        }
+        if let Ok(hidden) = self.hidden.lock() {
+            if hidden.contains(feature) {
+                return true;
             }
         }
+        self.names.contains(feature) || self.patterns.iter().any(|re| re.is_match(feature))
+    }
+}
+
+fn parse_entries(entries: &[String]) -> Result<(HashSet<String>, Vec<Regex>), Box<dyn Error>> {
+    let mut names = HashSet::new();
+    let mut patterns = Vec::new();
+
+    for f in entries {
+        if let Some(re) = pattern(f) {
+            match Regex::new(re) {
+                Ok(v) => patterns.push(v),
+                Err(e) => bail!("Invalid regular expression {}: {}", f, e),
+            };
+        } else {
+            names.insert(f.to_string());
+        }
+    }
+
+    Ok((names, patterns))
+}
+
+// User names of a license with anonymize_users are replaced by the first 16 hex digits of the
+// SHA-256 hash of the salt and the name. The same user gets the same label on every scrape.
+#[derive(Clone, Debug, Default)]
+pub struct Anonymizer {
+    // None if the user names are exported as they are
+    salt: Option<String>,
+}
+
+impl Anonymizer {
+    pub fn new(anonymize_users: Option<bool>, salt: &Option<String>) -> Self {
+        Anonymizer {
+            salt: match anonymize_users {
+                Some(true) => Some(salt.clone().unwrap_or_default()),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn user(&self, name: &str) -> String {
+        let salt = match &self.salt {
+            Some(v) => v,
+            None => return name.to_string(),
+        };
+
+        let mut hasher = Sha256::new();
+        hasher.update(salt.as_bytes());
+        hasher.update(name.as_bytes());
+        hasher.finalize()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+// Entries of excluded_features and include_features enclosed in slashes are regular expressions, e.g. /^internal_/
+pub fn pattern(entry: &str) -> Option<&str> {
+    if entry.len() > 1 && entry.starts_with('/') && entry.ends_with('/') {
+        Some(&entry[1..entry.len() - 1])
+    } else {
+        None
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_excluded_features() {
+        let filter = FeatureFilter::new(
+            &Some(vec!["internal".to_string(), "/^test_/".to_string()]),
+            &None,
+        )
+        .unwrap();
+
+        assert!(filter.is_excluded("internal"));
+        assert!(filter.is_excluded("test_1"));
+        assert!(filter.is_excluded("test_1"));
+        assert!(!filter.is_excluded("feature"));
+        // Project filters use matches, matching features aren't skipped
+        assert!(filter.matches("test_2"));
+
+        assert_eq!(filter.take_excluded(), 2);
+        assert_eq!(filter.take_excluded(), 0);
+    }
+
+    #[test]
+    fn include_features() {
+        let filter = FeatureFilter::new(
+            &Some(vec!["solver_beta".to_string()]),
+            &Some(vec!["modeler".to_string(), "/^solver_/".to_string()]),
+        )
+        .unwrap();
 
-    excluded
+        assert!(!filter.is_excluded("modeler"));
+        assert!(!filter.is_excluded("solver_hpc"));
+        // excluded_features still apply to included features
+        assert!(filter.is_excluded("solver_beta"));
+        assert!(filter.is_excluded("viewer"));
+        assert_eq!(filter.take_excluded(), 2);
+
+        assert!(FeatureFilter::new(&None, &Some(vec!["/(/".to_string()])).is_err());
+
+        let suffixed = filter.with_suffix('@');
+        assert!(!suffixed.matches("modeler@vendx"));
+        assert!(suffixed.matches("solver_beta@vendx"));
+        assert!(suffixed.matches("viewer@vendx"));
+    }
+
+    #[test]
+    fn anonymize_users() {
+        assert_eq!(Anonymizer::new(None, &None).user("jdoe"), "jdoe");
+        assert_eq!(
+            Anonymizer::new(Some(false), &Some("salt".to_string())).user("jdoe"),
+            "jdoe"
+        );
+
+        let unsalted = Anonymizer::new(Some(true), &None);
+        // SHA-256 of "jdoe"
+        assert_eq!(unsalted.user("jdoe"), "d30a5f57532a6036");
+        assert_eq!(unsalted.user("jdoe"), unsalted.user("jdoe"));
+
+        let salted = Anonymizer::new(Some(true), &Some("salt".to_string()));
+        assert_eq!(salted.user("jdoe").len(), 16);
+        assert_ne!(salted.user("jdoe"), unsalted.user("jdoe"));
+        assert_ne!(salted.user("jdoe"), salted.user("asmith"));
+    }
 }
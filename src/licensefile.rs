@@ -0,0 +1,233 @@
+use crate::license;
+
+use log::debug;
+use simple_error::bail;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+
+pub const MISMATCH_COUNT: &str = "count";
+pub const MISMATCH_MISSING_ON_SERVER: &str = "missing_on_server";
+pub const MISMATCH_NOT_IN_FILE: &str = "not_in_file";
+
+pub struct Mismatch {
+    pub feature: String,
+    pub reason: &'static str,
+    pub file_count: i64,
+    pub server_count: i64,
+}
+
+// Number of issued licenses per feature from a FlexLM license file.
+//
+// FEATURE|INCREMENT <feature> <vendor> <version> <expiration> <count> ...
+//
+// Lines can be continued by a trailing backslash, uncounted licenses have a count
+// of 0 or "uncounted".
+pub fn parse_flexlm(file: &str) -> Result<HashMap<String, i64>, Box<dyn Error>> {
+    let raw = fs::read_to_string(file)?;
+    let mut result: HashMap<String, i64> = HashMap::new();
+
+    for line in raw.replace("\\\r\n", " ").replace("\\\n", " ").lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            continue;
+        }
+
+        if fields[0] != "FEATURE" && fields[0] != "INCREMENT" {
+            continue;
+        }
+
+        let count: i64 = if fields[5].eq_ignore_ascii_case("uncounted") {
+            0
+        } else {
+            match fields[5].parse() {
+                Ok(v) => v,
+                Err(e) => bail!(
+                    "Can't parse license count {} of feature {} in {}: {}",
+                    fields[5],
+                    fields[1],
+                    file,
+                    e
+                ),
+            }
+        };
+
+        debug!(
+            "licensefile.rs:parse_flexlm: {} {} {} -> {}",
+            file, fields[0], fields[1], count
+        );
+        *result.entry(fields[1].to_string()).or_insert(0) += count;
+    }
+
+    Ok(result)
+}
+
+// Number of issued licenses per feature from a LM-X license file.
+//
+// FEATURE <feature>
+// {
+//   VENDOR = <vendor>
+//   COUNT = <count>
+//   ...
+// }
+pub fn parse_lmx(file: &str) -> Result<HashMap<String, i64>, Box<dyn Error>> {
+    let raw = fs::read_to_string(file)?;
+    let mut result: HashMap<String, i64> = HashMap::new();
+    let mut feature = String::new();
+    let mut count: i64 = 0;
+
+    for line in raw.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix("FEATURE ") {
+            feature = name.trim().to_string();
+            count = 0;
+        } else if line == "}" {
+            if !feature.is_empty() {
+                debug!(
+                    "licensefile.rs:parse_lmx: {} FEATURE {} -> {}",
+                    file, feature, count
+                );
+                *result.entry(feature.clone()).or_insert(0) += count;
+            }
+            feature.clear();
+        } else if !feature.is_empty() {
+            let kv: Vec<&str> = line.splitn(2, '=').map(|s| s.trim()).collect();
+            if kv.len() == 2 && kv[0].eq_ignore_ascii_case("COUNT") {
+                count = match kv[1].parse() {
+                    Ok(v) => v,
+                    Err(e) => bail!(
+                        "Can't parse license count {} of feature {} in {}: {}",
+                        kv[1],
+                        feature,
+                        file,
+                        e
+                    ),
+                };
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+// Compare issued licenses from the license file with the licenses reported by the server
+pub fn compare(
+    from_file: &HashMap<String, i64>,
+    from_server: &HashMap<String, i64>,
+    filter: &license::FeatureFilter,
+) -> Vec<Mismatch> {
+    let mut result: BTreeMap<String, Mismatch> = BTreeMap::new();
+
+    for (feature, file_count) in from_file {
+        if filter.is_excluded(feature) {
+            continue;
+        }
+
+        match from_server.get(feature) {
+            Some(server_count) => {
+                if server_count != file_count {
+                    result.insert(
+                        feature.to_string(),
+                        Mismatch {
+                            feature: feature.to_string(),
+                            reason: MISMATCH_COUNT,
+                            file_count: *file_count,
+                            server_count: *server_count,
+                        },
+                    );
+                }
+            }
+            None => {
+                result.insert(
+                    feature.to_string(),
+                    Mismatch {
+                        feature: feature.to_string(),
+                        reason: MISMATCH_MISSING_ON_SERVER,
+                        file_count: *file_count,
+                        server_count: 0,
+                    },
+                );
+            }
+        };
+    }
+
+    for (feature, server_count) in from_server {
+        if filter.is_excluded(feature) || from_file.contains_key(feature) {
+            continue;
+        }
+        result.insert(
+            feature.to_string(),
+            Mismatch {
+                feature: feature.to_string(),
+                reason: MISMATCH_NOT_IN_FILE,
+                file_count: 0,
+                server_count: *server_count,
+            },
+        );
+    }
+
+    result.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn counts(values: &[(&str, i64)]) -> HashMap<String, i64> {
+        values.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+    }
+
+    #[test]
+    fn flexlm_license_file() {
+        let result = parse_flexlm(&testutil::fixture_path("flexlm/license.lic")).unwrap();
+        assert_eq!(
+            result,
+            counts(&[("cad", 15), ("cam", 4), ("viewer", 0), ("trial", 2)])
+        );
+    }
+
+    #[test]
+    fn lmx_license_file() {
+        let result = parse_lmx(&testutil::fixture_path("lmx/license.lic")).unwrap();
+        assert_eq!(result, counts(&[("solver", 20), ("mesher", 8)]));
+    }
+
+    #[test]
+    fn compare_counts() {
+        let from_file = parse_flexlm(&testutil::fixture_path("flexlm/license.lic")).unwrap();
+        let from_server = counts(&[("cad", 15), ("cam", 2), ("viewer", 0), ("extra", 1)]);
+        let filter = license::FeatureFilter::new(&None, &None).unwrap();
+
+        let mismatches = compare(&from_file, &from_server, &filter);
+        let result: Vec<(&str, &str, i64, i64)> = mismatches
+            .iter()
+            .map(|m| (m.feature.as_str(), m.reason, m.file_count, m.server_count))
+            .collect();
+        assert_eq!(
+            result,
+            vec![
+                ("cam", MISMATCH_COUNT, 4, 2),
+                ("extra", MISMATCH_NOT_IN_FILE, 0, 1),
+                ("trial", MISMATCH_MISSING_ON_SERVER, 2, 0),
+            ]
+        );
+
+        // Excluded features aren't compared
+        let filter = license::FeatureFilter::new(&Some(vec!["trial".to_string()]), &None).unwrap();
+        let result: Vec<String> = compare(&from_file, &from_server, &filter)
+            .into_iter()
+            .map(|m| m.feature)
+            .collect();
+        assert_eq!(result, vec!["cam", "extra"]);
+    }
+}
@@ -1,18 +1,21 @@
+use crate::command;
 use crate::config;
-use crate::exporter;
-use crate::license;
+use crate::constants;
+use crate::dates;
+use crate::series;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use regex::Regex;
 use simple_error::bail;
 use std::collections::HashMap;
-use std::env;
 use std::error::Error;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref LICMAN20_FEATURES_TOTAL: IntGaugeVec = IntGaugeVec::new(
@@ -33,6 +36,14 @@ lazy_static! {
         &["app", "name", "product_key", "user"],
     )
     .unwrap();
+    pub static ref LICMAN20_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "licman20_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref LICMAN20_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
         Opts::new(
             "licman20_feature_expiration_seconds",
@@ -49,6 +60,14 @@ lazy_static! {
         &["app", "features", "index", "licenses"]
     )
     .unwrap();
+    pub static ref LICMAN20_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "licman20_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
 }
 
 struct Licman20LicenseData {
@@ -58,6 +77,15 @@ struct Licman20LicenseData {
     pub used: i64,
 }
 
+// Licenses of a product key, parsed from the output of licman20_appl
+struct Licman20ProductKey {
+    pub product_key: String,
+    pub features: Vec<String>,
+    pub total: i64,
+    pub used: i64,
+    pub expiration: f64,
+}
+
 struct Licman20LicenseExpiration {
     pub product_key: String,
     pub feature: String,
@@ -65,7 +93,11 @@ struct Licman20LicenseExpiration {
     pub expiration: f64,
 }
 
-pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn Error>> {
+pub fn fetch(
+    lic: &config::Licman20,
+    licman20_appl: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
     lazy_static! {
         static ref RE_LICMAN20_PRODUCT_KEY: Regex =
             Regex::new(r"^Product key\s+:\s+(\d+)$").unwrap();
@@ -80,23 +112,17 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
     let mut expiring = Vec::<Licman20LicenseExpiration>::new();
     let mut aggregated_expiration: HashMap<String, Vec<Licman20LicenseExpiration>> = HashMap::new();
     let mut expiration_dates = Vec::<f64>::new();
-    let mut product_key_map: HashMap<String, String> = HashMap::new();
+    let mut product_key_map: HashMap<String, Vec<String>> = HashMap::new();
+    let mut product_keys: Vec<Licman20ProductKey> = Vec::new();
 
-    env::set_var("LANG", "C");
     debug!("licman20.rs:fetch: Running {}", licman20_appl);
 
-    let mut cmd = Command::new(licman20_appl)
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    cmd.stdin
-        .as_mut()
-        .ok_or("Unable to connect to stdin for command")?
-        .write_all(b"4\nX\n")?;
-
-    let stdout_and_err = cmd.wait_with_output()?;
+    let (steps, prompt_timeout) = menu_steps(lic, Selection::Licenses)?;
+    let stdout_and_err = runner.interact(
+        Command::new(licman20_appl).env("LANG", "C"),
+        &steps,
+        prompt_timeout,
+    )?;
 
     let rc = match stdout_and_err.status.code() {
         Some(v) => v,
@@ -121,7 +147,7 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
     // Note: licman20_appl will print it's result to stderr and only the menu to stdout
     let stderr = String::from_utf8(stdout_and_err.stderr)?;
 
-    let mut feature: &str = "";
+    let mut features: Vec<&str> = Vec::new();
     let mut product_key: &str = "";
     let mut total: i64 = 0;
     let mut used: i64 = 0;
@@ -147,35 +173,15 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
 
             // Flush collected data
             if !product_key.is_empty() {
-                licenses.push(Licman20LicenseData {
+                product_keys.push(Licman20ProductKey {
                     product_key: product_key.to_string(),
-                    feature: feature.to_string(),
+                    features: features.iter().map(|f| f.to_string()).collect(),
                     total,
                     used,
-                });
-
-                expiration_dates.push(expiration);
-                expiring.push(Licman20LicenseExpiration {
-                    product_key: product_key.to_string(),
-                    feature: feature.to_string(),
-                    expiration,
-                    license_count: total,
-                });
-
-                let expiration_str = expiration.to_string();
-                let aggregated = aggregated_expiration
-                    .entry(expiration_str)
-                    .or_insert_with(Vec::<Licman20LicenseExpiration>::new);
-
-                aggregated.push(Licman20LicenseExpiration {
-                    product_key: product_key.to_string(),
-                    feature: feature.to_string(),
                     expiration,
-                    license_count: total,
                 });
-
-                product_key_map.insert(product_key.to_string(), feature.to_string());
             }
+            features.clear();
 
             product_key = capt.get(1).map_or("", |m| m.as_str());
         } else if let Some(capt) = RE_LICMAN20_FEATURE.captures(line) {
@@ -187,7 +193,7 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
                 continue;
             }
             debug!("licman20.rs:fetch: RE_LICMAN20_FEATURE match on {}", line);
-            feature = capt.get(1).map_or("", |m| m.as_str());
+            features.push(capt.get(1).map_or("", |m| m.as_str()));
         } else if let Some(capt) = RE_LICMAN20_TOTAL_LICENSES.captures(line) {
             if capt.len() != 2 {
                 error!(
@@ -241,11 +247,8 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
             }
             debug!("licman20.rs:fetch: RE_LICMAN20_END_DATE match on {}", line);
             let end_date = capt.get(1).map_or("", |m| m.as_str());
-            expiration = match NaiveDateTime::parse_from_str(
-                &format!("{} 00:00:00", end_date),
-                "%d-%b-%Y %H:%M:%S",
-            ) {
-                Ok(v) => v.timestamp() as f64,
+            expiration = match dates::parse_expiration(end_date) {
+                Ok(v) => v,
                 Err(e) => {
                     error!(
                         "licman20.rs:fetch: Can't parse {} as date and time: {}",
@@ -261,38 +264,65 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
 
     // Push last collected entry
     if !product_key.is_empty() {
-        licenses.push(Licman20LicenseData {
+        product_keys.push(Licman20ProductKey {
             product_key: product_key.to_string(),
-            feature: feature.to_string(),
+            features: features.iter().map(|f| f.to_string()).collect(),
             total,
             used,
-        });
-
-        expiration_dates.push(expiration);
-        expiring.push(Licman20LicenseExpiration {
-            product_key: product_key.to_string(),
-            feature: feature.to_string(),
             expiration,
-            license_count: total,
         });
+    }
 
-        let expiration_str = expiration.to_string();
-        let aggregated = aggregated_expiration
-            .entry(expiration_str)
-            .or_insert_with(Vec::<Licman20LicenseExpiration>::new);
+    for pk in product_keys {
+        // A product key can list several features, the licenses of the key are exported for
+        // every feature
+        let pk_features = if pk.features.is_empty() {
+            vec![String::new()]
+        } else {
+            pk.features
+        };
 
-        aggregated.push(Licman20LicenseExpiration {
-            product_key: product_key.to_string(),
-            feature: feature.to_string(),
-            expiration,
-            license_count: total,
-        });
+        for feature in pk_features.iter() {
+            licenses.push(Licman20LicenseData {
+                product_key: pk.product_key.clone(),
+                feature: feature.to_string(),
+                total: pk.total,
+                used: pk.used,
+            });
+            expiring.push(Licman20LicenseExpiration {
+                product_key: pk.product_key.clone(),
+                feature: feature.to_string(),
+                expiration: pk.expiration,
+                license_count: pk.total,
+            });
+        }
 
-        product_key_map.insert(product_key.to_string(), feature.to_string());
+        // The licenses of a product key are only counted once for the aggregated expiration
+        expiration_dates.push(pk.expiration);
+        aggregated_expiration
+            .entry(pk.expiration.to_string())
+            .or_default()
+            .push(Licman20LicenseExpiration {
+                product_key: pk.product_key.clone(),
+                feature: pk_features.join(","),
+                expiration: pk.expiration,
+                license_count: pk.total,
+            });
+
+        product_key_map.insert(pk.product_key, pk_features);
     }
 
+    stats::features(
+        "licman20",
+        &lic.name,
+        licenses
+            .iter()
+            .filter(|l| !lic.feature_filter.matches(&l.feature))
+            .count(),
+    );
+
     for l in licenses {
-        if license::is_excluded(&lic.excluded_features, l.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&l.feature) {
             debug!("licman20.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", l.feature, lic.name);
             continue;
         }
@@ -308,14 +338,14 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
             "Setting licman20_feature_used {} {} {} -> {}",
             lic.name, l.feature, l.product_key, l.used
         );
-        LICMAN20_FEATURES_TOTAL
+        LICMAN20_FEATURES_USED
             .with_label_values(&[&lic.name, &l.feature, &l.product_key])
             .set(l.used);
     }
 
     let mut index: i64 = 1;
     for entry in expiring {
-        if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&entry.feature) {
             debug!("licman20.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", entry.feature, lic.name);
             continue;
         }
@@ -324,7 +354,7 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
             "licman20.rs:fetch: Setting licman20_feature_used_users {} {} {} {} {} -> {}",
             lic.name,
             index,
-            entry.license_count.to_string(),
+            entry.license_count,
             entry.feature,
             entry.product_key,
             entry.expiration
@@ -334,8 +364,8 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
                 &lic.name,
                 &index.to_string(),
                 &entry.license_count.to_string(),
-                &entry.product_key,
                 &entry.feature,
+                &entry.product_key,
             ])
             .set(entry.expiration);
         index += 1;
@@ -373,16 +403,15 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
         }
     }
 
-    if let Some(export_users) = lic.export_user {
-        if export_users {
-            match fetch_checkouts(lic, licman20_appl, &product_key_map) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!(
-                        "licman20.rs:fetch_expiration: Unable to get license checkouts: {}",
-                        e
-                    );
-                }
+    // Checkouts are also needed for the top users if export_user isn't set
+    if lic.export_user.unwrap_or(false) || topusers::enabled() {
+        match fetch_checkouts(lic, licman20_appl, runner, &product_key_map) {
+            Ok(_) => {}
+            Err(e) => {
+                error!(
+                    "licman20.rs:fetch_expiration: Unable to get license checkouts: {}",
+                    e
+                );
             }
         }
     }
@@ -393,7 +422,8 @@ pub fn fetch(lic: &config::Licman20, licman20_appl: &str) -> Result<(), Box<dyn
 fn fetch_checkouts(
     lic: &config::Licman20,
     licman20_appl: &str,
-    pmap: &HashMap<String, String>,
+    runner: &dyn command::CommandRunner,
+    pmap: &HashMap<String, Vec<String>>,
 ) -> Result<(), Box<dyn Error>> {
     lazy_static! {
         static ref RE_LICMAN20_CHECKOUT: Regex =
@@ -403,21 +433,14 @@ fn fetch_checkouts(
 
     let mut fu: HashMap<String, HashMap<String, i64>> = HashMap::new();
 
-    env::set_var("LANG", "C");
     debug!("licman20.rs:fetch_checkouts: Running {}", licman20_appl);
 
-    let mut cmd = Command::new(licman20_appl)
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdout(Stdio::piped())
-        .spawn()?;
-
-    cmd.stdin
-        .as_mut()
-        .ok_or("Unable to connect to stdin for command")?
-        .write_all(b"2\nX\n")?;
-
-    let stdout_and_err = cmd.wait_with_output()?;
+    let (steps, prompt_timeout) = menu_steps(lic, Selection::Checkouts)?;
+    let stdout_and_err = runner.interact(
+        Command::new(licman20_appl).env("LANG", "C"),
+        &steps,
+        prompt_timeout,
+    )?;
 
     let rc = match stdout_and_err.status.code() {
         Some(v) => v,
@@ -463,53 +486,183 @@ fn fetch_checkouts(
             let user = capt.get(1).map_or("", |m| m.as_str());
             let product_key = capt.get(2).map_or("", |m| m.as_str());
 
-            let usr = fu
-                .entry(product_key.to_string())
-                .or_insert_with(HashMap::<String, i64>::new);
-            *usr.entry(user.to_string()).or_insert(0) += 1;
+            let usr = fu.entry(product_key.to_string()).or_default();
+            *usr.entry(lic.anonymizer.user(user)).or_insert(0) += 1;
         } else {
             debug!("licman20.rs:fetch_checkouts: No regexp matches '{}'", line);
         }
     }
 
+    stats::users(
+        "licman20",
+        &lic.name,
+        stats::distinct_users(
+            fu.iter()
+                .filter(|(f, _)| !lic.feature_filter.matches(f))
+                .flat_map(|(_, uv)| uv.keys()),
+        ),
+    );
+    topusers::export_fu(&lic.name, &lic.feature_filter, &fu);
+    topusers::export_user_count(
+        &lic.name,
+        &lic.feature_filter,
+        &LICMAN20_FEATURES_USER_COUNT,
+        fu.iter().map(|(f, u)| (f, u.len())),
+    );
+
+    if !lic.export_user.unwrap_or(false) {
+        return Ok(());
+    }
+
     for (feat, uv) in fu.iter() {
-        let fname = match pmap.get(feat) {
-            Some(v) => v,
-            None => feat,
+        // Checkouts of product keys with several features are exported for every feature
+        let fnames = match pmap.get(feat) {
+            Some(v) => v.clone(),
+            None => vec![feat.to_string()],
         };
 
         for (user, count) in uv.iter() {
-            if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+            if lic.feature_filter.is_excluded(feat) {
                 debug!("licman20.rs:fetch_checkouts: Skipping product_key {} because it is in excluded_features list of {}", feat, lic.name);
                 continue;
             }
-            debug!(
-                "licman20.rs:fetch_checkouts: Setting licman20_feature_used_users {} {} {} {} -> {}",
-                lic.name, fname, feat, user, *count
-            );
-            LICMAN20_FEATURES_USER
-                .with_label_values(&[&lic.name, fname, feat, user])
-                .set(*count);
+            for fname in fnames.iter() {
+                debug!(
+                    "licman20.rs:fetch_checkouts: Setting licman20_feature_used_users {} {} {} {} -> {}",
+                    lic.name, fname, feat, user, *count
+                );
+                LICMAN20_FEATURES_USER
+                    .with_label_values(&[&lic.name, fname, feat, user])
+                    .set(*count);
+            }
         }
     }
 
     Ok(())
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(LICMAN20_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LICMAN20_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LICMAN20_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LICMAN20_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LICMAN20_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
+enum Selection {
+    Licenses,
+    Checkouts,
+}
+
+// Select the menu option, then quit licman20_appl. Every option is sent after the menu prompt
+// appeared.
+fn menu_steps(
+    lic: &config::Licman20,
+    selection: Selection,
+) -> Result<(Vec<command::Step>, Duration), Box<dyn Error>> {
+    let menu = lic.menu.as_ref();
+    let option = match selection {
+        Selection::Licenses => menu
+            .and_then(|m| m.licenses.as_deref())
+            .unwrap_or(constants::DEFAULT_LICMAN20_LICENSES_OPTION),
+        Selection::Checkouts => menu
+            .and_then(|m| m.checkouts.as_deref())
+            .unwrap_or(constants::DEFAULT_LICMAN20_CHECKOUTS_OPTION),
+    };
+    let quit = menu
+        .and_then(|m| m.quit.as_deref())
+        .unwrap_or(constants::DEFAULT_LICMAN20_QUIT_OPTION);
+    let prompt = Regex::new(
+        menu.and_then(|m| m.prompt.as_deref())
+            .unwrap_or(constants::DEFAULT_LICMAN20_PROMPT),
+    )?;
+    let timeout = menu
+        .and_then(|m| m.timeout)
+        .unwrap_or(constants::DEFAULT_LICMAN20_PROMPT_TIMEOUT);
+
+    let steps = vec![
+        command::Step {
+            expect: prompt.clone(),
+            send: format!("{}\n", option).into_bytes(),
+        },
+        command::Step {
+            expect: prompt,
+            send: format!("{}\n", quit).into_bytes(),
+        },
+    ];
+    Ok((steps, Duration::from_secs(timeout)))
+}
+
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(LICMAN20_FEATURES_TOTAL.clone()),
+        Box::new(LICMAN20_FEATURES_USED.clone()),
+        Box::new(LICMAN20_FEATURES_USER.clone()),
+        Box::new(LICMAN20_FEATURES_USER_COUNT.clone()),
+        Box::new(LICMAN20_FEATURE_EXPIRATION.clone()),
+        Box::new(LICMAN20_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(LICMAN20_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&LICMAN20_FEATURES_TOTAL),
+        series::Vector::IntGauge(&LICMAN20_FEATURES_USED),
+        series::Vector::IntGauge(&LICMAN20_FEATURES_USER),
+        series::Vector::IntGauge(&LICMAN20_FEATURES_USER_COUNT),
+        series::Vector::Gauge(&LICMAN20_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&LICMAN20_FEATURE_AGGREGATED_EXPIRATION),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let cfg = testutil::parse_config("licman20:\n  - name: licman\n    export_user: true\n");
+        let registry = Registry::new();
+        register_into(&registry);
+
+        let runner = command::FixtureRunner::new()
+            .reply_to_input(
+                &[],
+                b"4\nX\n",
+                b"Licman20 menu\n",
+                &testutil::fixture("licman20/licenses.txt"),
+            )
+            .reply_to_input(
+                &[],
+                b"2\nX\n",
+                b"Licman20 menu\n",
+                &testutil::fixture("licman20/checkouts.txt"),
+            );
+        fetch(&cfg.licman20.unwrap()[0], "licman20_appl", &runner).unwrap();
+        testutil::assert_golden("licman20", &testutil::render(&registry));
+    }
+
+    #[test]
+    fn menu_options() {
+        let cfg = testutil::parse_config(
+            "licman20:\n  - name: licman\n    menu:\n      licenses: '5'\n      quit: 'Q'\n      prompt: 'choice:\\s*$'\n",
+        );
+        let lic = &cfg.licman20.unwrap()[0];
+
+        let (steps, timeout) = menu_steps(lic, Selection::Licenses).unwrap();
+        assert_eq!(
+            timeout,
+            Duration::from_secs(constants::DEFAULT_LICMAN20_PROMPT_TIMEOUT)
+        );
+        assert_eq!(steps[0].send, b"5\n");
+        assert_eq!(steps[1].send, b"Q\n");
+        assert!(steps[0].expect.is_match("Your choice: "));
+        assert!(!steps[0].expect.is_match("Loading"));
+
+        let (steps, _) = menu_steps(lic, Selection::Checkouts).unwrap();
+        assert_eq!(steps[0].send, b"2\n");
+    }
 }
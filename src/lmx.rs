@@ -1,19 +1,24 @@
+use crate::command;
 use crate::config;
-use crate::exporter;
-use crate::license;
+use crate::dates;
+use crate::licensefile;
+use crate::series;
+use crate::servers;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry};
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use simple_error::bail;
 use std::collections::HashMap;
-use std::env;
 use std::error::Error;
 use std::process::Command;
 use std::str;
+use std::sync::Mutex;
 
 lazy_static! {
     pub static ref LMX_FEATURES_TOTAL: IntGaugeVec = IntGaugeVec::new(
@@ -34,6 +39,14 @@ lazy_static! {
         &["app", "name"],
     )
     .unwrap();
+    pub static ref LMX_FEATURES_DENIED_TOTAL: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "lmx_feature_denied_total",
+            "Number of denied license checkouts, not reset by restarts of the license server"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref LMX_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("lmx_server_status", "Status of license server(s)"),
         &["app", "fqdn", "master", "port", "version"],
@@ -44,6 +57,14 @@ lazy_static! {
         &["app", "name", "user", "version"],
     )
     .unwrap();
+    pub static ref LMX_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "lmx_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref LMX_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
         Opts::new(
             "lmx_feature_expiration_seconds",
@@ -60,6 +81,37 @@ lazy_static! {
         &["app", "features", "index", "licenses"]
     )
     .unwrap();
+    pub static ref LMX_LICENSE_FILE_MISMATCH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "lmx_license_file_mismatch",
+            "Number of features with different licenses on the server and in the license file"
+        ),
+        &["app"],
+    )
+    .unwrap();
+    pub static ref LMX_LICENSE_FILE_FEATURE_MISMATCH: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "lmx_license_file_feature_mismatch",
+            "Feature differs between the server and the license file"
+        ),
+        &["app", "name", "reason"],
+    )
+    .unwrap();
+    pub static ref LMX_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "lmx_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
+}
+
+// Last denied checkouts reported by the server as app -> feature -> denied for
+// lmx_feature_denied_total
+lazy_static! {
+    static ref LAST_DENIED: Mutex<HashMap<String, HashMap<String, i64>>> =
+        Mutex::new(HashMap::new());
 }
 
 pub struct LmxLicenseExpiration {
@@ -70,8 +122,8 @@ pub struct LmxLicenseExpiration {
     pub expiration: f64,
 }
 
-#[derive(Debug)]
-struct LmxLicenseData {
+#[derive(Debug, Default)]
+pub struct LmxLicenseData {
     pub server_version: String,
     pub server_status: String,
     pub features: Vec<LmxLicenseFeatures>,
@@ -87,8 +139,8 @@ impl LmxLicenseData {
     }
 }
 
-#[derive(Clone, Debug)]
-struct LmxLicenseFeatures {
+#[derive(Clone, Debug, Default)]
+pub struct LmxLicenseFeatures {
     pub feature: String,
     pub version: String,
     pub vendor: String,
@@ -115,39 +167,19 @@ impl LmxLicenseFeatures {
 }
 
 #[derive(Clone, Debug)]
-struct LmxLicenseCheckouts {
+pub struct LmxLicenseCheckouts {
     pub user: String,
     pub used: i64,
 }
 
-pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>> {
+pub fn fetch(
+    lic: &config::Lmx,
+    lmxendutil: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
     // dict -> "feature" -> "user" -> "version" -> count
     let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
-    let mut server_port: HashMap<String, String> = HashMap::new();
-    let mut server_master: HashMap<String, bool> = HashMap::new();
-
-    for (i, lserver) in lic.license.split(':').enumerate() {
-        let mut port = "6200".to_string();
-        let srv: String;
-
-        if lserver.contains('@') {
-            let srvport: Vec<&str> = lserver.split('@').collect();
-            // NOTE: Configuration validation checks for valid server lines
-            port = srvport[0].to_string();
-            srv = srvport[1].to_string();
-        } else {
-            srv = lserver.to_string();
-        }
-        server_port.insert(srv.clone(), port);
-        match i {
-            0 => {
-                server_master.insert(srv.clone(), true);
-            }
-            _ => {
-                server_master.insert(srv.clone(), false);
-            }
-        };
-    }
+    let mut servers = servers::ServerTable::from_license(&lic.license, "6200");
 
     /*
       Note: Due to the HA method of LM-X we will not process data returned from all other servers if we already
@@ -176,19 +208,23 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
     let mut server_is_ok: bool;
     let mut features_exported = false;
 
-    for (server, port) in server_port {
-        env::set_var("LANG", "C");
+    for srv in servers.iter_mut() {
+        let server = &srv.fqdn;
+        let port = &srv.port;
         debug!(
             "lmx.rs:fetch: Running {} -licstatxml -host {} -port {}",
             lmxendutil, server, port
         );
-        let cmd = Command::new(lmxendutil)
-            .arg("-licstatxml")
-            .arg("-host")
-            .arg(&server)
-            .arg("-port")
-            .arg(&port)
-            .output()?;
+        let cmd = runner.run(
+            Command::new(lmxendutil)
+                .env("LANG", "C")
+                .arg("-licstatxml")
+                .arg("-host")
+                .arg(server)
+                .arg("-port")
+                .arg(port),
+            None,
+        )?;
 
         let rc = match cmd.status.code() {
             Some(v) => v,
@@ -213,28 +249,10 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
         let stdout = String::from_utf8(cmd.stdout)?;
         let parsed = parse_xml(stdout)?;
 
-        let _master = server_master.get(&server).unwrap_or(&false);
-        let master = format!("{}", _master);
-
-        if parsed.server_status == "SUCCESS" {
-            debug!(
-                "lmx.rs:fetch: Setting lmx_server_status {} {} {} {} {} -> 1",
-                lic.name, server, master, port, parsed.server_version
-            );
-            LMX_SERVER_STATUS
-                .with_label_values(&[&lic.name, &server, &master, &port, &parsed.server_version])
-                .set(1);
-            server_is_ok = true;
-        } else {
-            debug!(
-                "lmx.rs:fetch: Setting lmx_server_status {} {} {} {} {} -> 0",
-                lic.name, server, master, port, parsed.server_version
-            );
-            LMX_SERVER_STATUS
-                .with_label_values(&[&lic.name, &server, &master, &port, &parsed.server_version])
-                .set(0);
-            server_is_ok = false;
-        }
+        srv.version = parsed.server_version.clone();
+        srv.status = i64::from(parsed.server_status == "SUCCESS");
+        servers::export_status(&lic.name, &LMX_SERVER_STATUS, srv);
+        server_is_ok = srv.status == 1;
 
         // Only report feature usage for a healthy server
         if !server_is_ok {
@@ -249,13 +267,18 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
         let mut expiring = Vec::<LmxLicenseExpiration>::new();
         let mut aggregated_expiration: HashMap<String, Vec<LmxLicenseExpiration>> = HashMap::new();
         let mut expiration_dates = Vec::<f64>::new();
+        let mut issued: HashMap<String, i64> = HashMap::new();
+        let mut denied: HashMap<String, i64> = HashMap::new();
 
         for feature in parsed.features {
-            if license::is_excluded(&lic.excluded_features, feature.feature.clone()) {
+            if lic.feature_filter.is_excluded(&feature.feature) {
                 debug!("lmx.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feature.feature, lic.name);
                 continue;
             }
 
+            *issued.entry(feature.feature.clone()).or_insert(0) += feature.total;
+            *denied.entry(feature.feature.clone()).or_insert(0) += feature.denied;
+
             debug!(
                 "lmx.rs:fetch: Setting lmx_feature_issued {} {} -> {}",
                 lic.name, feature.feature, feature.total
@@ -279,20 +302,13 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
                 .set(feature.denied);
 
             for co in feature.checkouts {
-                let feat = fuv
-                    .entry(feature.feature.to_string())
-                    .or_insert_with(HashMap::<String, HashMap<String, i64>>::new);
-                let usr = feat
-                    .entry(co.user.to_string())
-                    .or_insert_with(HashMap::<String, i64>::new);
+                let feat = fuv.entry(feature.feature.to_string()).or_default();
+                let usr = feat.entry(lic.anonymizer.user(&co.user)).or_default();
                 *usr.entry(feature.version.to_string()).or_insert(0) += co.used;
             }
 
-            let expiration: f64 = match NaiveDateTime::parse_from_str(
-                &format!("{} 00:00:00", feature.expiration_str),
-                "%Y-%m-%d %H:%M:%S",
-            ) {
-                Ok(v) => v.timestamp() as f64,
+            let expiration: f64 = match dates::parse_expiration(&feature.expiration_str) {
+                Ok(v) => v,
                 Err(e) => {
                     error!(
                         "lmx.rs:fetch: Can't parse {} as date and time: {}",
@@ -311,9 +327,7 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
             });
 
             let expiration_str = expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<LmxLicenseExpiration>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(LmxLicenseExpiration {
                 feature: feature.feature.to_string(),
                 version: feature.version.to_string(),
@@ -323,12 +337,28 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
             });
         }
 
+        export_denied_total(&lic.name, &denied);
+
+        stats::features("lmx", &lic.name, issued.len());
+        stats::users(
+            "lmx",
+            &lic.name,
+            stats::distinct_users(fuv.values().flat_map(|uv| uv.keys())),
+        );
+        topusers::export_fuv(&lic.name, &lic.feature_filter, &fuv);
+        topusers::export_user_count(
+            &lic.name,
+            &lic.feature_filter,
+            &LMX_FEATURES_USER_COUNT,
+            fuv.iter().map(|(f, u)| (f, u.len())),
+        );
+
         if let Some(export_user) = lic.export_user {
             if export_user {
                 for (feat, uv) in fuv.iter() {
                     for (user, v) in uv.iter() {
                         for (version, count) in v.iter() {
-                            if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+                            if lic.feature_filter.is_excluded(feat) {
                                 debug!("lmx.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
                                 continue;
                             }
@@ -347,7 +377,7 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
 
         let mut index: i64 = 1;
         for entry in expiring {
-            if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+            if lic.feature_filter.is_excluded(&entry.feature) {
                 debug!("lmx.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", entry.feature, lic.name);
                 continue;
             }
@@ -356,7 +386,7 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
                 "lmx.rs:fetch: Setting lmx_feature_used_users {} {} {} {} {} {} -> {}",
                 lic.name,
                 index,
-                entry.license_count.to_string(),
+                entry.license_count,
                 entry.feature,
                 entry.vendor,
                 entry.version,
@@ -407,13 +437,54 @@ pub fn fetch(lic: &config::Lmx, lmxendutil: &str) -> Result<(), Box<dyn Error>>
             }
         }
 
+        if let Some(license_file) = &lic.license_file {
+            if let Err(e) = check_license_file(lic, license_file, &issued) {
+                error!(
+                    "lmx.rs:fetch: Can't compare license file {} with license server for {}: {}",
+                    license_file, lic.name, e
+                );
+            }
+        }
+
         features_exported = true;
     }
 
+    stats::servers("lmx", &lic.name, servers.iter().count());
+
+    Ok(())
+}
+
+fn check_license_file(
+    lic: &config::Lmx,
+    license_file: &str,
+    issued: &HashMap<String, i64>,
+) -> Result<(), Box<dyn Error>> {
+    let from_file = licensefile::parse_lmx(license_file)?;
+    let mismatches = licensefile::compare(&from_file, issued, &lic.feature_filter);
+
+    for m in mismatches.iter() {
+        debug!(
+            "lmx.rs:check_license_file: Setting lmx_license_file_feature_mismatch {} {} {} -> 1 (license file: {}, server: {})",
+            lic.name, m.feature, m.reason, m.file_count, m.server_count
+        );
+        LMX_LICENSE_FILE_FEATURE_MISMATCH
+            .with_label_values(&[&lic.name, &m.feature, m.reason])
+            .set(1);
+    }
+
+    debug!(
+        "lmx.rs:check_license_file: Setting lmx_license_file_mismatch {} -> {}",
+        lic.name,
+        mismatches.len()
+    );
+    LMX_LICENSE_FILE_MISMATCH
+        .with_label_values(&[&lic.name])
+        .set(mismatches.len() as i64);
+
     Ok(())
 }
 
-fn parse_xml(raw: String) -> Result<LmxLicenseData, Box<dyn Error>> {
+pub fn parse_xml(raw: String) -> Result<LmxLicenseData, Box<dyn Error>> {
     let mut result = LmxLicenseData::new();
     let mut reader = Reader::from_str(&raw);
     let mut buffer = Vec::new();
@@ -549,6 +620,10 @@ fn parse_xml(raw: String) -> Result<LmxLicenseData, Box<dyn Error>> {
                 };
             }
             Ok(Event::Eof) => {
+                // Last feature
+                if !_fname.is_empty() {
+                    result.features.push(feature.clone());
+                }
                 break;
             }
             Err(e) => {
@@ -560,26 +635,133 @@ fn parse_xml(raw: String) -> Result<LmxLicenseData, Box<dyn Error>> {
     Ok(result)
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(LMX_SERVER_STATUS.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURES_DENIED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(LMX_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
+// The server only reports the number of denied checkouts since its start, the increase is added
+// to lmx_feature_denied_total to keep the counter monotonic across server restarts
+fn export_denied_total(app: &str, denied: &HashMap<String, i64>) {
+    let mut last_denied = match LAST_DENIED.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "lmx.rs:export_denied_total: Can't lock denied checkouts: {}",
+                e
+            );
+            return;
+        }
+    };
+    let last = last_denied.entry(app.to_string()).or_default();
+
+    for (feature, count) in denied {
+        let increase = denied_increase(last.get(feature).copied(), *count);
+        debug!(
+            "lmx.rs:export_denied_total: Adding {} to lmx_feature_denied_total {} {}",
+            increase, app, feature
+        );
+        LMX_FEATURES_DENIED_TOTAL
+            .with_label_values(&[app, feature])
+            .inc_by(increase);
+        last.insert(feature.to_string(), *count);
+    }
+}
+
+// A lower count than in the last scrape means the server was restarted and counts from zero
+fn denied_increase(last: Option<i64>, current: i64) -> u64 {
+    let current = current.max(0);
+    match last {
+        Some(v) if current >= v => (current - v) as u64,
+        _ => current as u64,
+    }
+}
+
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(LMX_SERVER_STATUS.clone()),
+        Box::new(LMX_FEATURES_USED.clone()),
+        Box::new(LMX_FEATURES_DENIED.clone()),
+        Box::new(LMX_FEATURES_DENIED_TOTAL.clone()),
+        Box::new(LMX_FEATURES_TOTAL.clone()),
+        Box::new(LMX_FEATURES_USER.clone()),
+        Box::new(LMX_FEATURES_USER_COUNT.clone()),
+        Box::new(LMX_FEATURE_EXPIRATION.clone()),
+        Box::new(LMX_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(LMX_LICENSE_FILE_MISMATCH.clone()),
+        Box::new(LMX_LICENSE_FILE_FEATURE_MISMATCH.clone()),
+        Box::new(LMX_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&LMX_SERVER_STATUS),
+        series::Vector::IntGauge(&LMX_FEATURES_USED),
+        series::Vector::IntGauge(&LMX_FEATURES_DENIED),
+        series::Vector::IntGauge(&LMX_FEATURES_TOTAL),
+        series::Vector::IntGauge(&LMX_FEATURES_USER),
+        series::Vector::IntGauge(&LMX_FEATURES_USER_COUNT),
+        series::Vector::Gauge(&LMX_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&LMX_FEATURE_AGGREGATED_EXPIRATION),
+        series::Vector::IntGauge(&LMX_LICENSE_FILE_MISMATCH),
+        series::Vector::IntGauge(&LMX_LICENSE_FILE_FEATURE_MISMATCH),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let cfg = testutil::parse_config(
+            "lmx:\n  - name: lmx\n    license: 6200@lic1\n    export_user: true\n",
+        );
+        let registry = Registry::new();
+        register_into(&registry);
+
+        let runner = command::FixtureRunner::new().reply(
+            &["-licstatxml", "-host", "lic1", "-port", "6200"],
+            &testutil::fixture("lmx/licstat.xml"),
+        );
+        fetch(&cfg.lmx.unwrap()[0], "lmxendutil", &runner).unwrap();
+        testutil::assert_golden("lmx", &testutil::render(&registry));
+    }
+
+    #[test]
+    fn denied_increase_after_restart() {
+        assert_eq!(denied_increase(None, 3), 3);
+        assert_eq!(denied_increase(Some(3), 5), 2);
+        assert_eq!(denied_increase(Some(5), 5), 0);
+        // Server restarted and denied 1 checkout since
+        assert_eq!(denied_increase(Some(5), 1), 1);
+        assert_eq!(denied_increase(Some(5), -1), 0);
+    }
+
+    #[test]
+    fn command_failures() {
+        let cfg = testutil::parse_config("lmx:\n  - name: lmx-down\n    license: 6200@lic1\n");
+        let lic = &cfg.lmx.unwrap()[0];
+        let args = ["-licstatxml", "-host", "lic1", "-port", "6200"];
+
+        let runner = command::FixtureRunner::new().time_out(&args);
+        let error = fetch(lic, "lmxendutil", &runner).unwrap_err();
+        assert!(error.to_string().contains("timed out"), "{}", error);
+
+        let runner = command::FixtureRunner::new().exit(&args, 2, b"", b"Connection refused");
+        let error = fetch(lic, "lmxendutil", &runner).unwrap_err();
+        assert!(error.to_string().contains("exit code 2"), "{}", error);
+    }
+
+    #[test]
+    fn parse_xml_unterminated_comment() {
+        // Found by fuzzing, older quick-xml versions panic on an unterminated comment
+        assert!(parse_xml("<LM-X><!-->>-".to_string()).is_err());
+    }
 }
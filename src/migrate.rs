@@ -0,0 +1,232 @@
+use crate::config::{self, ConfigFormat};
+use crate::constants;
+
+use simple_error::bail;
+use std::collections::BTreeMap;
+use std::error::Error;
+
+// Migration of a configuration to the current schema version for the migrate-config command.
+// YAML and TOML configurations are edited as text, so comments and formatting are kept. JSON
+// configurations (and text edits changing the meaning of the configuration) are serialized
+// again.
+pub struct Migration {
+    pub config: String,
+    // Version of the configuration before the migration, None if it wasn't set
+    pub from_version: Option<u64>,
+    // Suggestions which aren't applied to keep the configuration as it is, e.g. settings which
+    // could be moved to the defaults section
+    pub hints: Vec<String>,
+}
+
+pub fn migrate(raw: &str, format: ConfigFormat) -> Result<Migration, Box<dyn Error>> {
+    let value = config::parse_value(raw, format)?;
+    let from_version = config::schema_version(&value)?;
+    let hints = defaults_hints(&value);
+
+    // Unversioned configurations use the schema of version 1, so only the version is added
+    let config = match from_version {
+        Some(_) => raw.to_string(),
+        None => add_version(raw, format, &value)?,
+    };
+
+    Ok(Migration {
+        config,
+        from_version,
+        hints,
+    })
+}
+
+fn add_version(
+    raw: &str,
+    format: ConfigFormat,
+    value: &serde_yaml::Value,
+) -> Result<String, Box<dyn Error>> {
+    let line = match format {
+        ConfigFormat::Yaml => format!("version: {}", constants::CONFIG_VERSION),
+        ConfigFormat::Toml => format!("version = {}", constants::CONFIG_VERSION),
+        ConfigFormat::Json => return add_json_version(raw),
+    };
+
+    // Top-level keys of TOML must precede the first table, so the version is inserted in front
+    // of the first line which isn't a comment or a document marker
+    let mut result = String::with_capacity(raw.len() + line.len() + 2);
+    let mut inserted = false;
+    for l in raw.lines() {
+        let trimmed = l.trim();
+        if !inserted
+            && !trimmed.is_empty()
+            && !trimmed.starts_with('#')
+            && !trimmed.starts_with("---")
+            && !trimmed.starts_with('%')
+        {
+            result.push_str(&line);
+            result.push_str("\n\n");
+            inserted = true;
+        }
+        result.push_str(l);
+        result.push('\n');
+    }
+    if !inserted {
+        result.push_str(&line);
+        result.push('\n');
+    }
+
+    // Flow style YAML or inline tables can't be edited line by line
+    let mut migrated = match config::parse_value(&result, format) {
+        Ok(v) => v,
+        Err(_) => return serialize_yaml(value),
+    };
+    if let Some(map) = migrated.as_mapping_mut() {
+        map.remove("version");
+    }
+    if &migrated != value {
+        return serialize_yaml(value);
+    }
+
+    Ok(result)
+}
+
+fn add_json_version(raw: &str) -> Result<String, Box<dyn Error>> {
+    let mut value: serde_json::Value = serde_json::from_str(raw)?;
+    match value.as_object_mut() {
+        Some(v) => {
+            v.insert(
+                "version".to_string(),
+                serde_json::Value::from(constants::CONFIG_VERSION),
+            );
+        }
+        None => bail!("The configuration must be an object"),
+    };
+
+    let mut result = serde_json::to_string_pretty(&value)?;
+    result.push('\n');
+    Ok(result)
+}
+
+// Configurations which can't be edited as text are written as YAML without comments
+fn serialize_yaml(value: &serde_yaml::Value) -> Result<String, Box<dyn Error>> {
+    let mut value = value.clone();
+    match value.as_mapping_mut() {
+        Some(v) => {
+            v.insert(
+                serde_yaml::Value::from("version"),
+                serde_yaml::Value::from(constants::CONFIG_VERSION),
+            );
+        }
+        None => bail!("The configuration must be a map"),
+    };
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+// Settings with the same value in all licenses of a backend, which aren't set in the defaults
+// section yet
+fn defaults_hints(value: &serde_yaml::Value) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    let defaults = value.get("defaults");
+
+    for backend in config::BACKENDS {
+        let licenses = match value.get(backend).and_then(|v| v.as_sequence()) {
+            Some(v) if v.len() > 1 => v,
+            _ => continue,
+        };
+
+        let mut common: BTreeMap<String, &serde_yaml::Value> = BTreeMap::new();
+        if let Some(first) = licenses[0].as_mapping() {
+            for (k, v) in first {
+                if let Some(k) = k.as_str() {
+                    common.insert(k.to_string(), v);
+                }
+            }
+        }
+        for lic in &licenses[1..] {
+            common.retain(|k, v| lic.get(k.as_str()) == Some(*v));
+        }
+
+        for (key, v) in common {
+            if key == "name"
+                || defaults
+                    .and_then(|d| d.get(backend))
+                    .and_then(|d| d.get(&key))
+                    .is_some()
+            {
+                continue;
+            }
+            let v = serde_yaml::to_string(v).unwrap_or_default();
+            let v = v.trim_start_matches("---").trim();
+            result.push(format!(
+                "All {} {} licenses set {} to {}, it can be moved to the defaults section",
+                licenses.len(),
+                backend,
+                key,
+                v
+            ));
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrate_versions() {
+        let raw = "---
+# License servers
+global:
+  # Collect every minute
+  scrape_interval: 60
+
+flexlm:
+  - name: flex1
+    license: 27000@lic1
+    export_user: true
+  - name: flex2
+    license: 27000@lic2
+    export_user: true
+";
+        let migration = migrate(raw, ConfigFormat::Yaml).unwrap();
+        assert_eq!(migration.from_version, None);
+        assert_eq!(
+            migration.config,
+            raw.replace("global:\n", "version: 1\n\nglobal:\n")
+        );
+        assert_eq!(
+            migration.hints,
+            vec!["All 2 flexlm licenses set export_user to true, it can be moved to the defaults section"]
+        );
+
+        // Already versioned configurations are kept
+        let migration = migrate(&migration.config, ConfigFormat::Yaml).unwrap();
+        assert_eq!(migration.from_version, Some(1));
+        assert!(migration
+            .config
+            .starts_with("---\n# License servers\nversion: 1\n"));
+
+        let toml = "# Licenses\n[[flexlm]]\nname = 'flex'\nlicense = '27000@lic1'\n";
+        assert_eq!(
+            migrate(toml, ConfigFormat::Toml).unwrap().config,
+            "# Licenses\nversion = 1\n\n[[flexlm]]\nname = 'flex'\nlicense = '27000@lic1'\n"
+        );
+
+        let json = migrate(
+            r#"{"flexlm": [{"name": "flex", "license": "27000@lic1"}]}"#,
+            ConfigFormat::Json,
+        )
+        .unwrap();
+        assert!(json.config.contains("\"version\": 1"));
+
+        // Flow style can't be edited line by line
+        let flow = migrate(
+            "{flexlm: [{name: flex, license: 27000@lic1}]}\n",
+            ConfigFormat::Yaml,
+        )
+        .unwrap();
+        let value = config::parse_value(&flow.config, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config::schema_version(&value).unwrap(), Some(1));
+        assert_eq!(value["flexlm"][0]["name"], serde_yaml::Value::from("flex"));
+
+        assert!(migrate("version: 2\n", ConfigFormat::Yaml).is_err());
+    }
+}
@@ -1,20 +1,26 @@
 use crate::config;
 use crate::constants;
-use crate::exporter;
+use crate::dates;
 use crate::http;
 use crate::license;
+use crate::series;
+use crate::servers;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use quick_xml::events::Event;
 use quick_xml::Reader;
 use regex::Regex;
 use simple_error::bail;
 use std::collections::HashMap;
 use std::error::Error;
+use std::io::BufRead;
 use std::str;
+use std::time::Duration;
 
 lazy_static! {
     pub static ref OLICENSE_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
@@ -27,6 +33,14 @@ lazy_static! {
         &["app", "vendor", "name", "module"],
     )
     .unwrap();
+    pub static ref OLICENSE_FEATURES_MODEL_TOTAL: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "olicense_feature_model_issued",
+            "Number of issued licenses by license model"
+        ),
+        &["app", "vendor", "name", "module", "model"],
+    )
+    .unwrap();
     pub static ref OLICENSE_FEATURES_USED: IntGaugeVec = IntGaugeVec::new(
         Opts::new("olicense_feature_used", "Number of used licenses"),
         &["app", "vendor", "name", "module"],
@@ -40,6 +54,14 @@ lazy_static! {
         &["app", "name", "user", "version"],
     )
     .unwrap();
+    pub static ref OLICENSE_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "olicense_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref OLICENSE_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
         Opts::new(
             "olicense_feature_expiration_seconds",
@@ -56,10 +78,18 @@ lazy_static! {
         &["app", "features", "index", "licenses"]
     )
     .unwrap();
+    pub static ref OLICENSE_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "olicense_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
+    )
+    .unwrap();
 }
 
-#[derive(Clone, Debug)]
-struct OLicenseData {
+#[derive(Clone, Debug, Default)]
+pub struct OLicenseData {
     pub server_version: String,
     pub features: Vec<OLicenseFeature>,
 }
@@ -85,8 +115,8 @@ const OLIC_TAG_SERVER_VERSION: u8 = 0x08;
 const OLIC_TAG_CHECKOUTS: u8 = 0x09;
 const OLIC_TAG_VERSION_RANGE: u8 = 0x0a;
 
-#[derive(Clone, Debug)]
-struct OLicenseFeature {
+#[derive(Clone, Debug, Default)]
+pub struct OLicenseFeature {
     pub name: String,
     pub module: String,
     pub vendor: String,
@@ -99,7 +129,7 @@ struct OLicenseFeature {
 }
 
 #[derive(Clone, Debug)]
-struct OLicenseCheckout {
+pub struct OLicenseCheckout {
     pub user: String,
     pub count: i64,
 }
@@ -130,56 +160,107 @@ pub struct OLicenseExpiration {
     pub expiration: f64,
 }
 
-pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
-    // dict -> "feature" -> "user" -> "version" -> count
-    let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
-    let mut server_port: HashMap<String, String> = HashMap::new();
-    let mut server_master: HashMap<String, bool> = HashMap::new();
-    let mut http_client = http::build_client(false, "", constants::DEFAULT_TIMEOUT)?;
-
-    for (i, lserver) in lic.license.split(':').enumerate() {
-        let mut port = "8080".to_string();
-        let srv: String;
-
-        if lserver.contains('@') {
-            let srvport: Vec<&str> = lserver.split('@').collect();
-            // NOTE: Configuration validation checks for valid server lines
-            port = srvport[0].to_string();
-            srv = srvport[1].to_string();
-        } else {
-            srv = lserver.to_string();
+// The servers of a failover setup are queried one after another and the status request may be
+// redirected by a load balancer in front of the license servers.
+fn client_settings(lic: &config::Olicense, proxy: &http::ProxySettings) -> http::ClientSettings {
+    http::ClientSettings {
+        accept: constants::HTTP_ACCEPT_XML,
+        ca_file: lic.ca_file.clone().unwrap_or_default(),
+        client_certificate: lic.client_certificate.clone(),
+        hosts: servers::ServerTable::from_license(&lic.license, "")
+            .iter()
+            .map(|s| s.fqdn.clone())
+            .collect(),
+        insecure_ssl: lic.insecure_ssl.unwrap_or(false),
+        max_redirects: 5,
+        pool_max_idle_per_host: 1,
+        proxy: proxy.clone(),
+        timeout: Duration::from_secs(constants::DEFAULT_TIMEOUT),
+    }
+}
+
+// Features are reported per module. Only keep the modules listed in modules and, if
+// aggregate_modules is set, merge the modules of a feature into a single feature with an
+// empty module name, the earliest expiration of the modules is used for the merged feature.
+fn select_modules(lic: &config::Olicense, features: Vec<OLicenseFeature>) -> Vec<OLicenseFeature> {
+    let mut result: Vec<OLicenseFeature> = Vec::new();
+
+    for f in features {
+        if let Some(modules) = &lic.modules {
+            if !modules.contains(&f.module) {
+                debug!(
+                    "olicense.rs:select_modules: Skipping module {} of feature {} because it is not in modules list of {}",
+                    f.module, f.name, lic.name
+                );
+                continue;
+            }
         }
-        server_port.insert(srv.clone(), port);
-        match i {
-            0 => {
-                server_master.insert(srv.clone(), true);
+
+        if !lic.aggregate_modules.unwrap_or(false) {
+            result.push(f);
+            continue;
+        }
+
+        match result
+            .iter_mut()
+            .find(|r| r.vendor == f.vendor && r.name == f.name)
+        {
+            Some(merged) => {
+                merged.total += f.total;
+                merged.used += f.used;
+                if f.expiration < merged.expiration {
+                    merged.expiration = f.expiration;
+                    merged.expiration_date = f.expiration_date;
+                }
+                merged.checkouts.extend(f.checkouts);
             }
-            _ => {
-                server_master.insert(srv.clone(), false);
+            None => {
+                result.push(OLicenseFeature {
+                    module: String::new(),
+                    ..f
+                });
             }
         };
     }
 
+    result
+}
+
+pub fn fetch(lic: &config::Olicense, proxy: &http::ProxySettings) -> Result<(), Box<dyn Error>> {
+    // dict -> "feature" -> "user" -> "version" -> count
+    let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
+    let mut servers = servers::ServerTable::from_license(&lic.license, "8080");
+    let mut http_client = http::build_client(&client_settings(lic, proxy))?;
+
     let mut server_is_ok: bool;
     let mut features_exported = false;
 
-    for (server, port) in server_port {
-        let url = format!("http://{}:{}/LicenseStatusXML", server, port);
+    for srv in servers.iter_mut() {
+        let server = &srv.fqdn;
+        let port = &srv.port;
+        let url = format!(
+            "{}://{}:{}/LicenseStatusXML",
+            http::scheme(lic.use_https),
+            server,
+            port
+        );
 
-        let reply = match http::get(&mut http_client, &url, "", "") {
+        let reply = match http::get_reader(
+            &mut http_client,
+            &url,
+            "",
+            "",
+            lic.spnego.unwrap_or(false),
+            lic.max_response_size
+                .unwrap_or(constants::DEFAULT_OLICENSE_MAX_RESPONSE_SIZE),
+        ) {
             Ok(v) => v,
             Err(e) => {
                 error!(
                     "olicense.rs:fetch: Can't fetch license information from OLicense server {}:{}: {}",
                     server, port, e
                 );
-                debug!(
-                    "Setting olicense_server_status {} {} {} {} -> 0",
-                    lic.name, server, port, "",
-                );
-                OLICENSE_SERVER_STATUS
-                    .with_label_values(&[&lic.name, &server, &port, ""])
-                    .set(0);
+                servers::export_status(&lic.name, &OLICENSE_SERVER_STATUS, srv);
                 continue;
             }
         };
@@ -191,17 +272,16 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
                     "olicense.rs:fetch: Can't parse license information from OLicense server {}:{}: {}",
                     server, port, e
                 );
-                debug!(
-                    "Setting olicense_server_status {} {} {} {} -> 0",
-                    lic.name, server, port, ""
-                );
-                OLICENSE_SERVER_STATUS
-                    .with_label_values(&[&lic.name, &server, &port, ""])
-                    .set(0);
+                servers::export_status(&lic.name, &OLICENSE_SERVER_STATUS, srv);
                 continue;
             }
         };
-        debug!("{:?}", parsed);
+        debug!(
+            "olicense.rs:fetch: Parsed {} licenses from OLicense server {}:{}",
+            parsed.features.len(),
+            server,
+            port
+        );
 
         server_is_ok = true;
 
@@ -210,13 +290,9 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
             continue;
         }
 
-        debug!(
-            "Setting olicense_server_status {} {} {} {} -> 1",
-            lic.name, server, port, parsed.server_version,
-        );
-        OLICENSE_SERVER_STATUS
-            .with_label_values(&[&lic.name, &server, &port, &parsed.server_version])
-            .set(1);
+        srv.status = 1;
+        srv.version = parsed.server_version.clone();
+        servers::export_status(&lic.name, &OLICENSE_SERVER_STATUS, srv);
 
         // Only export feature usage once
         if features_exported {
@@ -226,12 +302,14 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
         let mut expiring = Vec::<OLicenseExpiration>::new();
         let mut aggregated_expiration: HashMap<String, Vec<OLicenseExpiration>> = HashMap::new();
         let mut expiration_dates = Vec::<f64>::new();
+        let mut feature_count: usize = 0;
 
-        for f in parsed.features {
-            if license::is_excluded(&lic.excluded_features, f.name.clone()) {
+        for f in select_modules(lic, parsed.features) {
+            if lic.feature_filter.is_excluded(&f.name) {
                 debug!("olicense.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", f.name, lic.name);
                 continue;
             }
+            feature_count += 1;
 
             debug!(
                 "Setting olicense_feature_issued {} {} {} {} -> {}",
@@ -241,6 +319,26 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
                 .with_label_values(&[&lic.name, &f.vendor, &f.name, &f.module])
                 .set(f.total);
 
+            // NOTE: The license status only reports floating licenses (floatCount)
+            debug!(
+                "Setting olicense_feature_model_issued {} {} {} {} {} -> {}",
+                lic.name,
+                f.vendor,
+                f.name,
+                f.module,
+                license::MODEL_FLOATING,
+                f.total
+            );
+            OLICENSE_FEATURES_MODEL_TOTAL
+                .with_label_values(&[
+                    &lic.name,
+                    &f.vendor,
+                    &f.name,
+                    &f.module,
+                    license::MODEL_FLOATING,
+                ])
+                .set(f.total);
+
             debug!(
                 "Setting olicense_feature_used {} {} {} {} -> {}",
                 lic.name, f.vendor, f.name, f.module, f.used
@@ -250,12 +348,8 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
                 .set(f.used);
 
             for co in f.checkouts {
-                let feat = fuv
-                    .entry(f.name.to_string())
-                    .or_insert_with(HashMap::<String, HashMap<String, i64>>::new);
-                let usr = feat
-                    .entry(co.user.to_string())
-                    .or_insert_with(HashMap::<String, i64>::new);
+                let feat = fuv.entry(f.name.to_string()).or_default();
+                let usr = feat.entry(lic.anonymizer.user(&co.user)).or_default();
                 *usr.entry(f.version_range.to_string()).or_insert(0) += co.count;
             }
 
@@ -270,9 +364,7 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
             });
 
             let expiration_str = f.expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<OLicenseExpiration>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(OLicenseExpiration {
                 feature: f.name.to_string(),
                 version: f.version_range.to_string(),
@@ -283,12 +375,26 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
             });
         }
 
+        stats::features("olicense", &lic.name, feature_count);
+        stats::users(
+            "olicense",
+            &lic.name,
+            stats::distinct_users(fuv.values().flat_map(|uv| uv.keys())),
+        );
+        topusers::export_fuv(&lic.name, &lic.feature_filter, &fuv);
+        topusers::export_user_count(
+            &lic.name,
+            &lic.feature_filter,
+            &OLICENSE_FEATURES_USER_COUNT,
+            fuv.iter().map(|(f, u)| (f, u.len())),
+        );
+
         if let Some(export_user) = lic.export_user {
             if export_user {
                 for (feat, uv) in fuv.iter() {
                     for (user, v) in uv.iter() {
                         for (version, count) in v.iter() {
-                            if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+                            if lic.feature_filter.is_excluded(feat) {
                                 debug!("olicense.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
                                 continue;
                             }
@@ -307,7 +413,7 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
 
         let mut index: i64 = 1;
         for entry in expiring {
-            if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+            if lic.feature_filter.is_excluded(&entry.feature) {
                 debug!("olicense.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", entry.feature, lic.name);
                 continue;
             }
@@ -316,7 +422,7 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
                 "olicense.rs:fetch: Setting olicense_feature_used_users {} {} {} {} {} {} {} -> {}",
                 lic.name,
                 index,
-                entry.license_count.to_string(),
+                entry.license_count,
                 entry.feature,
                 entry.module,
                 entry.vendor,
@@ -372,12 +478,18 @@ pub fn fetch(lic: &config::Olicense) -> Result<(), Box<dyn Error>> {
         features_exported = true;
     }
 
+    stats::servers(
+        "olicense",
+        &lic.name,
+        servers.iter().filter(|s| s.status == 1).count(),
+    );
+
     Ok(())
 }
 
-fn parse_xml(raw: String) -> Result<OLicenseData, Box<dyn Error>> {
+pub fn parse_xml<R: BufRead>(raw: R) -> Result<OLicenseData, Box<dyn Error>> {
     let mut result = OLicenseData::new();
-    let mut reader = Reader::from_str(&raw);
+    let mut reader = Reader::from_reader(raw);
     let mut buffer = Vec::new();
     let mut feature = OLicenseFeature::new();
     let mut _fname = String::new();
@@ -433,6 +545,12 @@ fn parse_xml(raw: String) -> Result<OLicenseData, Box<dyn Error>> {
                 let tag_name = _tag_name.as_ref();
                 if let b"license" = tag_name {
                     result.features.push(feature.clone());
+                    if result.features.len() % constants::OLICENSE_PROGRESS_REPORT_FEATURES == 0 {
+                        debug!(
+                            "olicense.rs:parse_xml: Parsed {} licenses",
+                            result.features.len()
+                        );
+                    }
                 };
                 xml_tag = 0;
             }
@@ -456,11 +574,8 @@ fn parse_xml(raw: String) -> Result<OLicenseData, Box<dyn Error>> {
                     }
                     OLIC_TAG_EXPIRATION_DATE => {
                         feature.expiration_date = value.to_string().clone();
-                        feature.expiration = match NaiveDateTime::parse_from_str(
-                            &format!("{} 00:00:00", value.to_string().clone()),
-                            "%Y-%m-%d %H:%M:%S",
-                        ) {
-                            Ok(v) => v.timestamp() as f64,
+                        feature.expiration = match dates::parse_expiration(&value) {
+                            Ok(v) => v,
                             Err(e) => {
                                 bail!(
                                     "Can't parse {} as date and time: {}",
@@ -490,6 +605,7 @@ fn parse_xml(raw: String) -> Result<OLicenseData, Box<dyn Error>> {
             }
             _ => {}
         }
+        buffer.clear();
     }
 
     Ok(result)
@@ -520,24 +636,112 @@ fn parse_checkouts(raw: String) -> Result<Vec<OLicenseCheckout>, Box<dyn Error>>
     Ok(result)
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_SERVER_STATUS.clone()))
-        .unwrap();
-
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(OLICENSE_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(OLICENSE_SERVER_STATUS.clone()),
+        Box::new(OLICENSE_FEATURES_USED.clone()),
+        Box::new(OLICENSE_FEATURES_TOTAL.clone()),
+        Box::new(OLICENSE_FEATURES_MODEL_TOTAL.clone()),
+        Box::new(OLICENSE_FEATURES_USER.clone()),
+        Box::new(OLICENSE_FEATURES_USER_COUNT.clone()),
+        Box::new(OLICENSE_FEATURE_EXPIRATION.clone()),
+        Box::new(OLICENSE_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(OLICENSE_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&OLICENSE_SERVER_STATUS),
+        series::Vector::IntGauge(&OLICENSE_FEATURES_USED),
+        series::Vector::IntGauge(&OLICENSE_FEATURES_TOTAL),
+        series::Vector::IntGauge(&OLICENSE_FEATURES_MODEL_TOTAL),
+        series::Vector::IntGauge(&OLICENSE_FEATURES_USER),
+        series::Vector::IntGauge(&OLICENSE_FEATURES_USER_COUNT),
+        series::Vector::Gauge(&OLICENSE_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&OLICENSE_FEATURE_AGGREGATED_EXPIRATION),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn golden_metrics() {
+        let server =
+            testutil::FixtureServer::start(&[("/LicenseStatusXML", "olicense/LicenseStatusXML")]);
+        let cfg = testutil::parse_config(&format!(
+            "olicense:\n  - name: olic\n    license: {}@127.0.0.1\n    export_user: true\n",
+            server.port
+        ));
+        let registry = Registry::new();
+        register_into(&registry);
+
+        fetch(&cfg.olicense.unwrap()[0], &testutil::no_proxy()).unwrap();
+        testutil::assert_golden("olicense", &server.mask_port(&testutil::render(&registry)));
+    }
+
+    fn feature(
+        name: &str,
+        module: &str,
+        total: i64,
+        used: i64,
+        expiration: f64,
+    ) -> OLicenseFeature {
+        OLicenseFeature {
+            name: name.to_string(),
+            module: module.to_string(),
+            vendor: "Optimal".to_string(),
+            total,
+            used,
+            expiration,
+            ..OLicenseFeature::new()
+        }
+    }
+
+    #[test]
+    fn select_and_aggregate_modules() {
+        let features = vec![
+            feature("solver", "core", 10, 3, 2.0),
+            feature("solver", "gui", 5, 1, 1.0),
+            feature("solver", "batch", 2, 2, 3.0),
+            feature("viewer", "gui", 4, 0, 1.0),
+        ];
+
+        let cfg = testutil::parse_config(
+            "olicense:\n  - name: olic\n    license: 8080@lic1\n    modules: [core, gui]\n",
+        );
+        let selected = select_modules(&cfg.olicense.unwrap()[0], features.clone());
+        assert_eq!(
+            selected
+                .iter()
+                .map(|f| format!("{}/{}", f.name, f.module))
+                .collect::<Vec<String>>(),
+            vec!["solver/core", "solver/gui", "viewer/gui"]
+        );
+
+        let cfg = testutil::parse_config(
+            "olicense:\n  - name: olic\n    license: 8080@lic1\n    aggregate_modules: true\n    modules: [core, gui]\n",
+        );
+        let aggregated = select_modules(&cfg.olicense.unwrap()[0], features);
+        assert_eq!(aggregated.len(), 2);
+        assert_eq!(aggregated[0].name, "solver");
+        assert_eq!(aggregated[0].module, "");
+        assert_eq!(aggregated[0].total, 15);
+        assert_eq!(aggregated[0].used, 4);
+        assert_eq!(aggregated[0].expiration, 1.0);
+        assert_eq!(aggregated[1].name, "viewer");
+        assert_eq!(aggregated[1].total, 4);
+    }
 }
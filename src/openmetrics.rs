@@ -0,0 +1,224 @@
+// Conversion of sample sets from the Prometheus text format (version 0.0.4), as produced by
+// the TextEncoder and pushed by peersync, to the OpenMetrics text format (version 1.0.0).
+//
+// The formats differ in the metadata of some metric types:
+//  * the name of a counter family doesn't include the _total suffix of its samples
+//  * gauges named *_info with a value of 1 are info families named without the _info suffix
+//  * untyped metrics have the type unknown
+//  * timestamps are seconds instead of milliseconds
+//  * the exposition ends with "# EOF"
+
+struct Family<'a> {
+    name: &'a str,
+    help: Option<&'a str>,
+    kind: &'a str,
+    samples: Vec<&'a str>,
+}
+
+pub fn from_text(sample: &str) -> String {
+    let mut families: Vec<Family> = Vec::new();
+
+    for line in sample.lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, ' ');
+            let (keyword, name, rest) = match (parts.next(), parts.next()) {
+                (Some(k), Some(n)) => (k, n, parts.next().unwrap_or("")),
+                _ => continue,
+            };
+            if keyword != "HELP" && keyword != "TYPE" {
+                continue;
+            }
+
+            let family = family(&mut families, name);
+            if keyword == "HELP" {
+                family.help = Some(rest);
+            } else {
+                family.kind = rest.trim();
+            }
+            continue;
+        }
+
+        let (name, _, _) = split_sample(line);
+        match families.last_mut() {
+            Some(f) if belongs_to(name, f.name) => f.samples.push(line),
+            _ => family(&mut families, name).samples.push(line),
+        };
+    }
+
+    let mut result = String::with_capacity(sample.len() + 16);
+    for f in families.iter() {
+        render(&mut result, f);
+    }
+    result.push_str("# EOF\n");
+    result
+}
+
+// Family of the metadata line for name, a new family unless the last one has the same name
+fn family<'a, 'b>(families: &'b mut Vec<Family<'a>>, name: &'a str) -> &'b mut Family<'a> {
+    if families.last().map(|f| f.name) != Some(name) {
+        families.push(Family {
+            name,
+            help: None,
+            kind: "untyped",
+            samples: Vec::new(),
+        });
+    }
+    families.last_mut().unwrap()
+}
+
+// Histograms and summaries have samples with suffixes in addition to the family name
+fn belongs_to(sample: &str, family: &str) -> bool {
+    match sample.strip_prefix(family) {
+        Some(suffix) => ["", "_bucket", "_count", "_sum", "_total"].contains(&suffix),
+        None => false,
+    }
+}
+
+fn render(result: &mut String, f: &Family) {
+    let mut name = f.name;
+    let mut kind = match f.kind {
+        "untyped" => "unknown",
+        k => k,
+    };
+    // samples of counters are always named <family>_total
+    let mut sample_suffix = "";
+
+    if kind == "counter" {
+        match name.strip_suffix("_total") {
+            Some(v) => name = v,
+            None => sample_suffix = "_total",
+        };
+    } else if kind == "gauge" && !f.samples.is_empty() {
+        if let Some(v) = name.strip_suffix("_info") {
+            let info = f.samples.iter().all(|s| {
+                let (_, _, rest) = split_sample(s);
+                rest.split_whitespace().next() == Some("1")
+            });
+            if info {
+                name = v;
+                kind = "info";
+            }
+        }
+    }
+
+    if let Some(help) = f.help {
+        result.push_str(&format!("# HELP {} {}\n", name, help.replace('"', "\\\"")));
+    }
+    result.push_str(&format!("# TYPE {} {}\n", name, kind));
+
+    for s in f.samples.iter() {
+        let (sample_name, labels, rest) = split_sample(s);
+        let mut values = rest.split_whitespace();
+        let value = values.next().unwrap_or("");
+
+        result.push_str(sample_name);
+        if sample_name == f.name {
+            result.push_str(sample_suffix);
+        }
+        result.push_str(labels);
+        result.push(' ');
+        result.push_str(value);
+        if let Some(timestamp) = values.next().and_then(|t| t.parse::<i64>().ok()) {
+            result.push_str(&format!(" {}", timestamp as f64 / 1000.0));
+        }
+        result.push('\n');
+    }
+}
+
+// Split a sample line into name, labels (including the braces) and the value with an optional
+// timestamp. Label values can contain spaces and escaped quotes.
+fn split_sample(line: &str) -> (&str, &str, &str) {
+    let end_of_name = line.find(['{', ' ']).unwrap_or(line.len());
+    let name = &line[..end_of_name];
+    if !line[end_of_name..].starts_with('{') {
+        return (name, "", line[end_of_name..].trim());
+    }
+
+    let mut in_value = false;
+    let mut escaped = false;
+    for (i, c) in line[end_of_name..].char_indices() {
+        if in_value {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_value = false;
+            }
+        } else if c == '"' {
+            in_value = true;
+        } else if c == '}' {
+            let end_of_labels = end_of_name + i + 1;
+            return (
+                name,
+                &line[end_of_name..end_of_labels],
+                line[end_of_labels..].trim(),
+            );
+        }
+    }
+
+    (name, &line[end_of_name..], "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_to_openmetrics() {
+        let sample = "# HELP license_exporter_command_timeouts_total Number of \"lmutil\" timeouts
+# TYPE license_exporter_command_timeouts_total counter
+license_exporter_command_timeouts_total{app=\"flex\"} 2
+# HELP flexlm_feature_expiration_info Expiration date of license features
+# TYPE flexlm_feature_expiration_info gauge
+flexlm_feature_expiration_info{app=\"flex\",date=\"2030-12-31\",name=\"a b}\\\"c\"} 1
+# HELP flexlm_feature_used Number of used licenses
+# TYPE flexlm_feature_used gauge
+flexlm_feature_used{app=\"flex\",name=\"feat_a\"} 5 1672650000000
+# HELP requests Number of requests
+# TYPE requests counter
+requests 7
+# HELP peer_info Not an info metric
+# TYPE peer_info gauge
+peer_info 0
+# HELP scrape_duration Duration of scrapes
+# TYPE scrape_duration histogram
+scrape_duration_bucket{le=\"+Inf\"} 3
+scrape_duration_sum 1.5
+scrape_duration_count 3
+some_untyped 4
+";
+
+        assert_eq!(
+            from_text(sample),
+            "# HELP license_exporter_command_timeouts Number of \\\"lmutil\\\" timeouts
+# TYPE license_exporter_command_timeouts counter
+license_exporter_command_timeouts_total{app=\"flex\"} 2
+# HELP flexlm_feature_expiration Expiration date of license features
+# TYPE flexlm_feature_expiration info
+flexlm_feature_expiration_info{app=\"flex\",date=\"2030-12-31\",name=\"a b}\\\"c\"} 1
+# HELP flexlm_feature_used Number of used licenses
+# TYPE flexlm_feature_used gauge
+flexlm_feature_used{app=\"flex\",name=\"feat_a\"} 5 1672650000
+# HELP requests Number of requests
+# TYPE requests counter
+requests_total 7
+# HELP peer_info Not an info metric
+# TYPE peer_info gauge
+peer_info 0
+# HELP scrape_duration Duration of scrapes
+# TYPE scrape_duration histogram
+scrape_duration_bucket{le=\"+Inf\"} 3
+scrape_duration_sum 1.5
+scrape_duration_count 3
+# TYPE some_untyped unknown
+some_untyped 4
+# EOF
+"
+        );
+    }
+}
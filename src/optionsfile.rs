@@ -0,0 +1,146 @@
+use log::debug;
+use simple_error::bail;
+use std::error::Error;
+use std::fs;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum RuleKind {
+    Max,
+    Reserve,
+    Include,
+    Exclude,
+}
+
+// Rule of a FlexLM options file for a feature and a user, host, group, ...
+#[derive(Debug, PartialEq)]
+pub struct Rule {
+    pub kind: RuleKind,
+    // feature name without qualifiers like :VERSION=1.0
+    pub feature: String,
+    // USER, HOST, DISPLAY, GROUP, HOST_GROUP, INTERNET or PROJECT in lower case
+    pub target_type: String,
+    pub target: String,
+    // number of licenses for MAX and RESERVE
+    pub count: Option<i64>,
+}
+
+// Entitlement rules of a FlexLM options file.
+//
+// MAX <count> <feature>[:<keyword>=<value>] <type> <name>
+// RESERVE <count> <feature>[:<keyword>=<value>] <type> <name>
+// INCLUDE <feature>[:<keyword>=<value>] <type> <name>
+// EXCLUDE <feature>[:<keyword>=<value>] <type> <name>
+//
+// Lines can be continued by a trailing backslash, other keywords like GROUP or TIMEOUT are
+// ignored.
+pub fn parse_flexlm(file: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let raw = fs::read_to_string(file)?;
+    parse(&raw, file)
+}
+
+fn parse(raw: &str, file: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let mut result: Vec<Rule> = Vec::new();
+
+    for line in raw.replace("\\\r\n", " ").replace("\\\n", " ").lines() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let keyword = match fields.first() {
+            Some(v) => v.to_uppercase(),
+            None => continue,
+        };
+
+        let (kind, has_count) = match keyword.as_str() {
+            "MAX" => (RuleKind::Max, true),
+            "RESERVE" => (RuleKind::Reserve, true),
+            "INCLUDE" => (RuleKind::Include, false),
+            "EXCLUDE" => (RuleKind::Exclude, false),
+            _ => continue,
+        };
+
+        let args = &fields[1..];
+        let expected = if has_count { 4 } else { 3 };
+        if args.len() < expected {
+            bail!("Incomplete {} rule in {}: {}", keyword, file, line);
+        }
+
+        let (count, args) = if has_count {
+            match args[0].parse::<i64>() {
+                Ok(v) => (Some(v), &args[1..]),
+                Err(e) => bail!(
+                    "Can't parse license count {} of {} rule in {}: {}",
+                    args[0],
+                    keyword,
+                    file,
+                    e
+                ),
+            }
+        } else {
+            (None, args)
+        };
+
+        let feature = args[0].trim_matches('"');
+        let feature = feature.split(':').next().unwrap_or(feature);
+
+        let rule = Rule {
+            kind,
+            feature: feature.to_string(),
+            target_type: args[1].to_lowercase(),
+            target: args[2].to_string(),
+            count,
+        };
+        debug!("optionsfile.rs:parse: {} {:?}", file, rule);
+        result.push(rule);
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rules() {
+        let raw = "# Engineering
+GROUP engineering alice bob
+MAX 5 feat_a GROUP engineering
+max 2 feat_a:VERSION=1.0 USER alice
+RESERVE 2 \"feat_b:SERIAL=1234\" \\
+    HOST_GROUP labs
+INCLUDE feat_a GROUP engineering
+EXCLUDE feat_c USER carol
+TIMEOUT feat_a 3600
+";
+        let rules = parse(raw, "test.opt").unwrap();
+        assert_eq!(rules.len(), 5);
+        assert_eq!(
+            rules[1],
+            Rule {
+                kind: RuleKind::Max,
+                feature: "feat_a".to_string(),
+                target_type: "user".to_string(),
+                target: "alice".to_string(),
+                count: Some(2),
+            }
+        );
+        assert_eq!(
+            rules[2],
+            Rule {
+                kind: RuleKind::Reserve,
+                feature: "feat_b".to_string(),
+                target_type: "host_group".to_string(),
+                target: "labs".to_string(),
+                count: Some(2),
+            }
+        );
+        assert_eq!(rules[4].kind, RuleKind::Exclude);
+        assert_eq!(rules[4].count, None);
+
+        assert!(parse("MAX five feat_a USER alice\n", "test.opt").is_err());
+        assert!(parse("RESERVE 1 feat_a USER\n", "test.opt").is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::config;
+use crate::constants;
+use crate::http;
+use crate::status;
+
+use lazy_static::lazy_static;
+use log::{debug, error, warn};
+use prometheus::{IntCounter, Registry};
+use simple_error::bail;
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Hot standby. The primary pushes every collected sample set to the standby (peer_sync.push_to)
+// and the standby serves the pushed sample set as long as it isn't older than peer_sync.max_age.
+// If the primary stops pushing, e.g. because it or its license tools are unavailable, the
+// standby collects the metrics itself again. Sample sets with failed fetches aren't pushed.
+lazy_static! {
+    static ref SNAPSHOT: RwLock<Option<(String, Instant)>> = RwLock::new(None);
+    static ref PUSHING: AtomicBool = AtomicBool::new(false);
+    pub static ref PEER_SYNC_FAILURES: IntCounter = IntCounter::new(
+        "license_exporter_peer_sync_failures_total",
+        "Number of failed pushes of the collected metrics to the standby instance"
+    )
+    .unwrap();
+}
+
+// Push the sample set collected for the licenses of cfg to the standby in the background, a
+// slow standby must not delay scrapes. If the previous push is still running or a license
+// couldn't be fetched, the sample set is skipped, so the standby collects the metrics itself
+// once the last pushed sample set is older than max_age.
+pub fn push(cfg: &config::Configuration, sample: &str) {
+    let peer_sync = match cfg.peer_sync() {
+        Some(v) if v.push_to.is_some() => v.clone(),
+        _ => return,
+    };
+    let failed = status::failed(cfg);
+    if !failed.is_empty() {
+        warn!(
+            "peersync.rs:push: Fetching {} failed, skipping sample set",
+            failed.join(", ")
+        );
+        return;
+    }
+    if PUSHING.swap(true, Ordering::SeqCst) {
+        warn!("peersync.rs:push: Previous push still running, skipping sample set");
+        return;
+    }
+
+    let proxy = http::ProxySettings::new(&cfg.global, &None);
+    let sample = sample.to_string();
+    thread::spawn(move || {
+        if let Err(e) = send(&peer_sync, proxy, sample) {
+            error!("peersync.rs:push: Can't push metrics to standby: {}", e);
+            PEER_SYNC_FAILURES.inc();
+        }
+        PUSHING.store(false, Ordering::SeqCst);
+    });
+}
+
+fn send(
+    peer_sync: &config::PeerSync,
+    proxy: http::ProxySettings,
+    sample: String,
+) -> Result<(), Box<dyn Error>> {
+    let base = peer_sync.push_to.as_deref().unwrap_or_default();
+    let url = format!(
+        "{}{}",
+        base.trim_end_matches('/'),
+        constants::PEER_SNAPSHOT_PATH
+    );
+
+    let client = http::build_client(&http::ClientSettings {
+        accept: constants::CONTENT_TYPE_TEXT,
+        ca_file: peer_sync.ca_file.clone().unwrap_or_default(),
+        client_certificate: None,
+        hosts: Vec::new(),
+        insecure_ssl: false,
+        max_redirects: 0,
+        pool_max_idle_per_host: 1,
+        proxy,
+        timeout: Duration::from_secs(
+            peer_sync
+                .timeout
+                .unwrap_or(constants::DEFAULT_PEER_SYNC_TIMEOUT),
+        ),
+    })?;
+
+    debug!(
+        "peersync.rs:send: Pushing {} bytes to {}",
+        sample.len(),
+        url
+    );
+    let response = client
+        .post(&url)
+        .bearer_auth(peer_sync.token.as_deref().unwrap_or_default())
+        .header(reqwest::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+        .body(sample)
+        .send()?;
+
+    if response.status() != reqwest::StatusCode::OK {
+        bail!(
+            "standby {} returned HTTP status code \"{}\" instead of \"200 OK\"",
+            url,
+            response.status()
+        );
+    }
+
+    Ok(())
+}
+
+// Store a sample set pushed by the primary
+pub fn receive(sample: String) {
+    match SNAPSHOT.write() {
+        Ok(mut v) => *v = Some((sample, Instant::now())),
+        Err(e) => error!("peersync.rs:receive: Can't store snapshot: {}", e),
+    };
+}
+
+// Sample set of the primary, None if this instance isn't a standby or the last push is older
+// than max_age
+pub fn snapshot(cfg: &config::Configuration) -> Option<String> {
+    let max_age = Duration::from_secs(cfg.peer_sync()?.max_age?);
+
+    let snapshot = match SNAPSHOT.read() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("peersync.rs:snapshot: Can't read snapshot: {}", e);
+            return None;
+        }
+    };
+
+    match snapshot.as_ref() {
+        Some((sample, received)) if received.elapsed() <= max_age => Some(sample.to_string()),
+        Some((_, received)) => {
+            debug!(
+                "peersync.rs:snapshot: Last snapshot of the primary is {} seconds old",
+                received.elapsed().as_secs()
+            );
+            None
+        }
+        None => None,
+    }
+}
+
+pub fn register_into(registry: &Registry) {
+    registry
+        .register(Box::new(PEER_SYNC_FAILURES.clone()))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn standby_snapshot() {
+        let cfg = testutil::parse_config("global:\n  peer_sync:\n    token: s3cr3t\n");
+        receive("license_up 1\n".to_string());
+        assert_eq!(snapshot(&cfg), None);
+
+        let cfg =
+            testutil::parse_config("global:\n  peer_sync:\n    max_age: 60\n    token: s3cr3t\n");
+        assert_eq!(snapshot(&cfg), Some("license_up 1\n".to_string()));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::config;
+use crate::constants;
+
+use lazy_static::lazy_static;
+use log::{debug, error};
+use simple_error::bail;
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+// Name resolution of license servers. Names in the hosts map of the global configuration are
+// resolved to the configured address, for environments where the license servers aren't in
+// the DNS of the exporter host. Other names are resolved by the system resolver and cached
+// for DNS_CACHE_TTL seconds, because the HTTP clients are built again for every scrape.
+lazy_static! {
+    static ref HOSTS: RwLock<HashMap<String, IpAddr>> = RwLock::new(HashMap::new());
+    static ref CACHE: RwLock<HashMap<String, (Vec<IpAddr>, Instant)>> = RwLock::new(HashMap::new());
+}
+
+// Replace the host overrides by the hosts map of cfg and drop all cached lookups
+pub fn configure(cfg: &config::Configuration) {
+    let hosts = match parse_hosts(cfg) {
+        Ok(v) => v,
+        Err(e) => {
+            // NOTE: The hosts map has been checked by the configuration validation
+            error!("resolver.rs:configure: {}", e);
+            return;
+        }
+    };
+
+    match HOSTS.write() {
+        Ok(mut v) => *v = hosts,
+        Err(e) => error!("resolver.rs:configure: Can't set host overrides: {}", e),
+    };
+    match CACHE.write() {
+        Ok(mut v) => v.clear(),
+        Err(e) => error!("resolver.rs:configure: Can't clear DNS cache: {}", e),
+    };
+}
+
+pub fn parse_hosts(cfg: &config::Configuration) -> Result<HashMap<String, IpAddr>, Box<dyn Error>> {
+    let mut result = HashMap::new();
+
+    let hosts = match cfg.global.as_ref().and_then(|g| g.hosts.as_ref()) {
+        Some(v) => v,
+        None => return Ok(result),
+    };
+
+    for (name, address) in hosts {
+        if name.is_empty() {
+            bail!("Empty host name in hosts");
+        }
+        let ip: IpAddr = match address.parse() {
+            Ok(v) => v,
+            Err(e) => bail!(
+                "Invalid IP address {} for host {} in hosts: {}",
+                address,
+                name,
+                e
+            ),
+        };
+        result.insert(name.to_lowercase(), ip);
+    }
+
+    Ok(result)
+}
+
+// All host overrides, used for every HTTP client so redirects to other hosts use them too
+pub fn overrides() -> Vec<(String, IpAddr)> {
+    match HOSTS.read() {
+        Ok(v) => v.iter().map(|(n, a)| (n.to_string(), *a)).collect(),
+        Err(e) => {
+            error!("resolver.rs:overrides: Can't read host overrides: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+pub fn resolve(host: &str) -> Result<Vec<IpAddr>, Box<dyn Error>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+
+    let name = host.to_lowercase();
+    if let Some(ip) = HOSTS.read().ok().and_then(|h| h.get(&name).copied()) {
+        debug!("resolver.rs:resolve: Using override {} for {}", ip, host);
+        return Ok(vec![ip]);
+    }
+
+    let ttl = Duration::from_secs(constants::DNS_CACHE_TTL);
+    if let Some((addresses, _)) = CACHE
+        .read()
+        .ok()
+        .and_then(|c| c.get(&name).filter(|(_, t)| t.elapsed() < ttl).cloned())
+    {
+        debug!(
+            "resolver.rs:resolve: Using cached addresses {:?} for {}",
+            addresses, host
+        );
+        return Ok(addresses);
+    }
+
+    let addresses: Vec<IpAddr> = match (host, 0).to_socket_addrs() {
+        Ok(v) => v.map(|a| a.ip()).collect(),
+        Err(e) => bail!("Can't resolve {}: {}", host, e),
+    };
+    if addresses.is_empty() {
+        bail!("No address found for {}", host);
+    }
+    debug!("resolver.rs:resolve: {} resolved to {:?}", host, addresses);
+
+    if let Ok(mut cache) = CACHE.write() {
+        cache.insert(name, (addresses.clone(), Instant::now()));
+    }
+
+    Ok(addresses)
+}
+
+// Socket addresses of host and port, e.g. for TCP connections to a license server
+pub fn socket_addrs(host: &str, port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
+    Ok(resolve(host)?
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn host_overrides() {
+        let cfg = testutil::parse_config(
+            "global:\n  hosts:\n    LicServer1.example.com: 192.0.2.1\n    lic2: '2001:db8::2'\n",
+        );
+        configure(&cfg);
+
+        assert_eq!(
+            resolve("licserver1.example.com").unwrap(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+        assert_eq!(
+            socket_addrs("lic2", 27000).unwrap(),
+            vec!["[2001:db8::2]:27000".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolve("198.51.100.7").unwrap(),
+            vec!["198.51.100.7".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[test]
+    fn invalid_hosts() {
+        for hosts in ["lic1: lic2", "lic1: 192.0.2.300", "'': 192.0.2.1"] {
+            let cfg: config::Configuration =
+                serde_yaml::from_str(&format!("global:\n  hosts:\n    {}\n", hosts)).unwrap();
+            assert!(parse_hosts(&cfg).is_err(), "{}", hosts);
+        }
+    }
+}
@@ -1,15 +1,18 @@
+use crate::command;
 use crate::config;
-use crate::exporter;
-use crate::license;
+use crate::dates;
+use crate::series;
+use crate::servers;
+use crate::stats;
+use crate::topusers;
 
-use chrono::NaiveDateTime;
 use lazy_static::lazy_static;
 use log::{debug, error, warn};
-use prometheus::{GaugeVec, IntGaugeVec, Opts};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec, Opts, Registry};
 use regex::Regex;
 use simple_error::bail;
 use std::collections::HashMap;
-use std::env;
 use std::error::Error;
 use std::process::Command;
 
@@ -24,11 +27,27 @@ lazy_static! {
         &["app", "name", "version"],
     )
     .unwrap();
+    pub static ref RLM_FEATURES_UNCOUNTED: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rlm_feature_uncounted",
+            "Feature is uncounted or has no issued licenses"
+        ),
+        &["app", "name", "version"],
+    )
+    .unwrap();
     pub static ref RLM_FEATURES_USER: IntGaugeVec = IntGaugeVec::new(
         Opts::new("rlm_feature_used_users", "Number of licenses used by user"),
         &["app", "name", "user", "version"],
     )
     .unwrap();
+    pub static ref RLM_FEATURES_USER_COUNT: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rlm_feature_user_count",
+            "Number of distinct users holding licenses of the feature"
+        ),
+        &["app", "name"],
+    )
+    .unwrap();
     pub static ref RLM_FEATURE_EXPIRATION: GaugeVec = GaugeVec::new(
         Opts::new(
             "rlm_feature_expiration_seconds",
@@ -47,26 +66,82 @@ lazy_static! {
     .unwrap();
     pub static ref RLM_SERVER_STATUS: IntGaugeVec = IntGaugeVec::new(
         Opts::new("rlm_server_status", "Status of license server(s)"),
-        &["app", "fqdn", "port", "version"],
+        &["app", "fqdn", "master", "port", "version"],
+    )
+    .unwrap();
+    pub static ref RLM_SERVER_MAJOR_VERSION: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rlm_server_major_version",
+            "Detected major version of the RLM license server"
+        ),
+        &["app", "fqdn"],
+    )
+    .unwrap();
+    pub static ref RLM_FAILOVER_STATUS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rlm_failover_status",
+            "Status of the servers of a RLM failover pair"
+        ),
+        &["app", "fqdn", "port", "role"],
+    )
+    .unwrap();
+    pub static ref RLM_SERVING_SERVER: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "rlm_serving_server",
+            "Server of a RLM failover pair currently serving the licenses"
+        ),
+        &["app", "fqdn", "port", "role"],
+    )
+    .unwrap();
+    pub static ref RLM_LAST_UPDATED: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "rlm_last_updated_seconds",
+            "Time of the last successful update of the license data"
+        ),
+        &["app"],
     )
     .unwrap();
 }
 
+const RLM_ROLE_PRIMARY: &str = "primary";
+const RLM_ROLE_FAILOVER: &str = "failover";
+
+#[derive(Default)]
+pub struct RlmServerStatus {
+    pub major_version: Option<i64>,
+    pub port: String,
+    pub status: i64,
+    pub version: String,
+}
+
+impl RlmServerStatus {
+    pub fn new() -> Self {
+        RlmServerStatus {
+            major_version: None,
+            port: String::new(),
+            status: 0,
+            version: String::new(),
+        }
+    }
+}
+
 pub struct RlmLicenseData {
     pub feature: String,
     pub version: String,
     pub expiration: f64,
     pub total: i64,
-    pub reserved: i64,
-    pub used: i64,
 }
 
-pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
+pub fn fetch(
+    lic: &config::Rlm,
+    rlmutil: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
     lazy_static! {
         static ref RE_RLM_FEATURE_VERSION: Regex =
             Regex::new(r"^\s+([\w\-.]+)\s([\w.]+).*$").unwrap();
         static ref RE_RLM_USAGE: Regex = Regex::new(
-            r"^\s+count:\s+(\d+),\s+# reservations:\s+(\d+),\s+inuse:\s+(\d+), exp:\s+([\w\-]+)"
+            r"^\s+count:\s+(\d+),\s+#\s*(?:reservations|res):\s+(\d+),\s+inuse:\s+(\d+),\s+exp:\s+([\w\-]+)"
         )
         .unwrap();
     }
@@ -77,18 +152,22 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
     let mut aggregated_expiration: HashMap<String, Vec<RlmLicenseData>> = HashMap::new();
     let mut expiration_dates = Vec::<f64>::new();
 
-    env::set_var("LANG", "C");
     debug!(
         "rlm.rs:fetch: Running {} rlmstat -c {} -l {}",
-        rlmutil, &lic.license, &lic.isv
+        rlmutil,
+        connect_string(lic),
+        &lic.isv
     );
-    let cmd = Command::new(rlmutil)
-        .arg("rlmstat")
-        .arg("-c")
-        .arg(&lic.license)
-        .arg("-l")
-        .arg(&lic.isv)
-        .output()?;
+    let cmd = runner.run(
+        Command::new(rlmutil)
+            .env("LANG", "C")
+            .arg("rlmstat")
+            .arg("-c")
+            .arg(connect_string(lic))
+            .arg("-l")
+            .arg(&lic.isv),
+        None,
+    )?;
 
     let rc = match cmd.status.code() {
         Some(v) => v,
@@ -133,7 +212,7 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
             feature = capt.get(1).map_or("", |m| m.as_str());
             version = capt.get(2).map_or("", |m| m.as_str());
 
-            if license::is_excluded(&lic.excluded_features, feature.to_string()) {
+            if lic.feature_filter.is_excluded(feature) {
                 debug!("rlm.rs:fetch: Skipping feature {} because it is in excluded_features list of {}", feature, lic.name);
                 feature = "";
                 continue;
@@ -163,15 +242,6 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
                 }
             };
 
-            let _reserved = capt.get(2).map_or("", |m| m.as_str());
-            let reserved: i64 = match _reserved.parse() {
-                Ok(v) => v,
-                Err(e) => {
-                    error!("Can't parse {} as integer: {}", _reserved, e);
-                    continue;
-                }
-            };
-
             let _used = capt.get(3).map_or("", |m| m.as_str());
             let used: i64 = match _used.parse() {
                 Ok(v) => v,
@@ -182,18 +252,11 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
             };
 
             let _expiration = capt.get(4).map_or("", |m| m.as_str());
-            let expiration: f64 = if _expiration == "permanent" {
-                f64::INFINITY
-            } else {
-                match NaiveDateTime::parse_from_str(
-                    &format!("{} 00:00:00", _expiration),
-                    "%d-%b-%Y %H:%M:%S",
-                ) {
-                    Ok(v) => v.timestamp() as f64,
-                    Err(e) => {
-                        error!("Can't parse {} as date and time: {}", _expiration, e);
-                        continue;
-                    }
+            let expiration: f64 = match dates::parse_expiration(_expiration) {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Can't parse {} as date and time: {}", _expiration, e);
+                    continue;
                 }
             };
 
@@ -203,29 +266,19 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
                 version: version.to_string(),
                 expiration,
                 total,
-                reserved,
-                used,
             });
 
             let expiration_str = expiration.to_string();
-            let aggregated = aggregated_expiration
-                .entry(expiration_str)
-                .or_insert_with(Vec::<RlmLicenseData>::new);
+            let aggregated = aggregated_expiration.entry(expiration_str).or_default();
             aggregated.push(RlmLicenseData {
                 feature: feature.to_string(),
                 version: version.to_string(),
                 expiration,
                 total,
-                reserved,
-                used,
             });
 
-            let feat = fv
-                .entry(feature.to_string())
-                .or_insert_with(HashMap::<String, HashMap<String, RlmLicenseData>>::new);
-            let ver = feat
-                .entry(feature.to_string())
-                .or_insert_with(HashMap::<String, RlmLicenseData>::new);
+            let feat = fv.entry(feature.to_string()).or_default();
+            let ver = feat.entry(feature.to_string()).or_default();
 
             ver.insert(
                 version.to_string(),
@@ -234,18 +287,28 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
                     version: version.to_string(),
                     expiration,
                     total,
-                    reserved,
-                    used,
                 },
             );
 
-            debug!(
-                "rlm.rs:fetch: Setting rlm_feature_issued {} {} {} -> {}",
-                lic.name, feature, version, total
-            );
-            RLM_FEATURES_TOTAL
-                .with_label_values(&[&lic.name, feature, version])
-                .set(total);
+            // NOTE: License pools of uncounted licenses are reported with a count of 0, the number
+            //       of issued licenses is meaningless for them.
+            if total == 0 {
+                debug!(
+                    "rlm.rs:fetch: Setting rlm_feature_uncounted {} {} {} -> 1",
+                    lic.name, feature, version
+                );
+                RLM_FEATURES_UNCOUNTED
+                    .with_label_values(&[&lic.name, feature, version])
+                    .set(1);
+            } else {
+                debug!(
+                    "rlm.rs:fetch: Setting rlm_feature_issued {} {} {} -> {}",
+                    lic.name, feature, version, total
+                );
+                RLM_FEATURES_TOTAL
+                    .with_label_values(&[&lic.name, feature, version])
+                    .set(total);
+            }
 
             debug!(
                 "rlm.rs:fetch: Setting rlm_feature_used {} {} {} -> {}",
@@ -259,18 +322,19 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
         }
     }
 
-    if let Some(report_users) = lic.export_user {
-        if report_users {
-            match fetch_checkouts(lic, rlmutil) {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Unable to fetch license checkouts: {}", e);
-                }
-            };
-        }
+    stats::features("rlm", &lic.name, fv.len());
+
+    // Checkouts are also needed for the top users if export_user isn't set
+    if lic.export_user.unwrap_or(false) || topusers::enabled() {
+        match fetch_checkouts(lic, rlmutil, runner) {
+            Ok(_) => {}
+            Err(e) => {
+                error!("Unable to fetch license checkouts: {}", e);
+            }
+        };
     }
 
-    match fetch_status(lic, rlmutil) {
+    match fetch_status(lic, rlmutil, runner) {
         Ok(_) => {}
         Err(e) => {
             error!("Unable to fetch server status: {}", e);
@@ -279,7 +343,7 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
 
     let mut index: i64 = 1;
     for entry in expiring {
-        if license::is_excluded(&lic.excluded_features, entry.feature.to_string()) {
+        if lic.feature_filter.is_excluded(&entry.feature) {
             debug!(
                 "rlm.rs:fetch: Skipping feature {} because it is in excluded_features list of {}",
                 entry.feature, lic.name
@@ -289,12 +353,7 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
 
         debug!(
             "rlm.rs:fetch: Setting rlm_feature_used_users {} {} {} {} {} -> {}",
-            lic.name,
-            index,
-            entry.total.to_string(),
-            entry.feature,
-            entry.version,
-            entry.expiration
+            lic.name, index, entry.total, entry.feature, entry.version, entry.expiration
         );
         RLM_FEATURE_EXPIRATION
             .with_label_values(&[
@@ -343,25 +402,33 @@ pub fn fetch(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-fn fetch_checkouts(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
+fn fetch_checkouts(
+    lic: &config::Rlm,
+    rlmutil: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
     lazy_static! {
         static ref RE_RLM_CHECKOUTS: Regex = Regex::new(r"^\s+([\w\-.]+)\s+([\w.]+):\s+([\w\-.@]+)\s+\d+/\d+\s+at\s+\d+/\d+\s+\d+:\d+\s+\(handle:\s+\w+\)$").unwrap();
     }
     // dict -> "feature" -> "user" -> "version" -> count
     let mut fuv: HashMap<String, HashMap<String, HashMap<String, i64>>> = HashMap::new();
 
-    env::set_var("LANG", "C");
     debug!(
         "rlm.rs:fetch_checkouts: Running {} rlmstat -c {} -i {}",
-        rlmutil, &lic.license, &lic.isv
+        rlmutil,
+        connect_string(lic),
+        &lic.isv
     );
-    let cmd = Command::new(rlmutil)
-        .arg("rlmstat")
-        .arg("-c")
-        .arg(&lic.license)
-        .arg("-i")
-        .arg(&lic.isv)
-        .output()?;
+    let cmd = runner.run(
+        Command::new(rlmutil)
+            .env("LANG", "C")
+            .arg("rlmstat")
+            .arg("-c")
+            .arg(connect_string(lic))
+            .arg("-i")
+            .arg(&lic.isv),
+        None,
+    )?;
 
     let rc = match cmd.status.code() {
         Some(v) => v,
@@ -409,22 +476,39 @@ fn fetch_checkouts(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error
             let _user: Vec<&str> = capt.get(3).map_or("", |m| m.as_str()).split('@').collect();
             let user = _user[0];
 
-            let feat = fuv
-                .entry(feature.to_string())
-                .or_insert_with(HashMap::<String, HashMap<String, i64>>::new);
-            let usr = feat
-                .entry(user.to_string())
-                .or_insert_with(HashMap::<String, i64>::new);
+            let feat = fuv.entry(feature.to_string()).or_default();
+            let usr = feat.entry(lic.anonymizer.user(user)).or_default();
             *usr.entry(version.to_string()).or_insert(0) += 1;
         } else {
             debug!("rlm.rs:fetch_checkouts: No regexp matches '{}'", line);
         }
     }
 
+    stats::users(
+        "rlm",
+        &lic.name,
+        stats::distinct_users(
+            fuv.iter()
+                .filter(|(f, _)| !lic.feature_filter.matches(f))
+                .flat_map(|(_, uv)| uv.keys()),
+        ),
+    );
+    topusers::export_fuv(&lic.name, &lic.feature_filter, &fuv);
+    topusers::export_user_count(
+        &lic.name,
+        &lic.feature_filter,
+        &RLM_FEATURES_USER_COUNT,
+        fuv.iter().map(|(f, u)| (f, u.len())),
+    );
+
+    if !lic.export_user.unwrap_or(false) {
+        return Ok(());
+    }
+
     for (feat, uv) in fuv.iter() {
         for (user, v) in uv.iter() {
             for (version, count) in v.iter() {
-                if license::is_excluded(&lic.excluded_features, feat.to_string()) {
+                if lic.feature_filter.is_excluded(feat) {
                     debug!("rlm.rs:fetch_checkouts: Skipping feature {} because it is in excluded_features list of {}", feat, lic.name);
                     continue;
                 }
@@ -442,120 +526,373 @@ fn fetch_checkouts(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error
     Ok(())
 }
 
-fn fetch_status(lic: &config::Rlm, rlmutil: &str) -> Result<(), Box<dyn Error>> {
-    lazy_static! {
-        static ref RE_RLM_STATUS: Regex =
-            Regex::new(r"^\s+[\w+\-.]+ ISV server status on [\w\-.]+ \(port (\d+)\), (\w+).*$")
-                .unwrap();
-        static ref RE_RLM_VERSION: Regex =
-            Regex::new(r"^\s+[\w+\-.]+ software version ([\w\s.:\-()]+)$").unwrap();
+// License servers to connect to, the failover server is tried last
+fn connect_string(lic: &config::Rlm) -> String {
+    match &lic.failover {
+        Some(failover) => format!("{}:{}", lic.license, failover),
+        None => lic.license.to_string(),
+    }
+}
+
+fn fetch_status(
+    lic: &config::Rlm,
+    rlmutil: &str,
+    runner: &dyn command::CommandRunner,
+) -> Result<(), Box<dyn Error>> {
+    let mut servers: Vec<(&str, &str)> = lic
+        .license
+        .split(':')
+        .map(|s| (s, RLM_ROLE_PRIMARY))
+        .collect();
+    if let Some(failover) = &lic.failover {
+        servers.push((failover, RLM_ROLE_FAILOVER));
     }
 
-    for server in lic.license.split(':') {
-        env::set_var("LANG", "C");
+    let mut serving: Option<usize> = None;
+    let mut states: Vec<RlmServerStatus> = Vec::new();
+    let mut parsed: usize = 0;
+
+    for (i, (server, _)) in servers.iter().enumerate() {
+        let state = match fetch_server_status(lic, rlmutil, runner, server) {
+            Ok(v) => {
+                parsed += 1;
+                v
+            }
+            Err(e) => {
+                error!(
+                    "rlm.rs:fetch_status: Unable to fetch status of server {} for {}: {}",
+                    server, lic.name, e
+                );
+                let mut v = RlmServerStatus::new();
+                // Use the port from the configuration if the server doesn't answer
+                if let Some((port, _)) = server.split_once('@') {
+                    v.port = port.to_string();
+                }
+                v
+            }
+        };
+
+        if let Some(major) = state.major_version {
+            debug!(
+                "rlm.rs:fetch_status: Setting rlm_server_major_version {} {} -> {}",
+                lic.name, server, major
+            );
+            RLM_SERVER_MAJOR_VERSION
+                .with_label_values(&[&lic.name, server])
+                .set(major);
+        }
+
+        // The first server with a running ISV server is serving the licenses
+        if serving.is_none() && state.status == 1 {
+            serving = Some(i);
+        }
+        states.push(state);
+    }
+
+    stats::servers("rlm", &lic.name, parsed);
+
+    // The server serving the licenses is reported as master
+    for (i, (server, _)) in servers.iter().enumerate() {
+        let state = &states[i];
+        let info = servers::ServerInfo {
+            fqdn: server.to_string(),
+            port: state.port.clone(),
+            master: serving == Some(i),
+            status: state.status,
+            version: state.version.clone(),
+        };
+        servers::export_status(&lic.name, &RLM_SERVER_STATUS, &info);
+    }
+
+    if lic.failover.is_none() {
+        return Ok(());
+    }
+
+    for (i, (server, role)) in servers.iter().enumerate() {
+        let state = &states[i];
+        debug!(
+            "rlm.rs:fetch_status: Setting rlm_failover_status {} {} {} {} -> {}",
+            lic.name, server, state.port, role, state.status
+        );
+        RLM_FAILOVER_STATUS
+            .with_label_values(&[&lic.name, server, &state.port, role])
+            .set(state.status);
+
+        let is_serving = i64::from(serving == Some(i));
         debug!(
-            "rlm.rs:fetch_status: Running {} rlmstat -c {} -l {}",
-            rlmutil, &lic.license, &lic.isv
+            "rlm.rs:fetch_status: Setting rlm_serving_server {} {} {} {} -> {}",
+            lic.name, server, state.port, role, is_serving
         );
-        let cmd = Command::new(rlmutil)
+        RLM_SERVING_SERVER
+            .with_label_values(&[&lic.name, server, &state.port, role])
+            .set(is_serving);
+    }
+
+    Ok(())
+}
+
+fn fetch_server_status(
+    lic: &config::Rlm,
+    rlmutil: &str,
+    runner: &dyn command::CommandRunner,
+    server: &str,
+) -> Result<RlmServerStatus, Box<dyn Error>> {
+    debug!(
+        "rlm.rs:fetch_server_status: Running {} rlmstat -c {} -l {}",
+        rlmutil, server, &lic.isv
+    );
+    let cmd = runner.run(
+        Command::new(rlmutil)
+            .env("LANG", "C")
             .arg("rlmstat")
             .arg("-c")
             .arg(server)
             .arg("-l")
-            .arg(&lic.isv)
-            .output()?;
+            .arg(&lic.isv),
+        None,
+    )?;
 
-        let rc = match cmd.status.code() {
-            Some(v) => v,
-            None => {
-                bail!("Can't get return code of {} command", rlmutil);
-            }
-        };
-        debug!(
-            "rlm.rs:fetch_status: external command finished with exit code {}",
-            rc
+    let rc = match cmd.status.code() {
+        Some(v) => v,
+        None => {
+            bail!("Can't get return code of {} command", rlmutil);
+        }
+    };
+    debug!(
+        "rlm.rs:fetch_server_status: external command finished with exit code {}",
+        rc
+    );
+
+    if !cmd.status.success() {
+        bail!(
+            "{} command exited with non-normal exit code {} for {}",
+            rlmutil,
+            rc,
+            lic.name
         );
+    }
 
-        if !cmd.status.success() {
-            bail!(
-                "{} command exited with non-normal exit code {} for {}",
-                rlmutil,
-                rc,
-                lic.name
-            );
+    let stdout = String::from_utf8(cmd.stdout)?;
+    Ok(parse_status(&stdout, &lic.isv))
+}
+
+fn parse_status(raw: &str, isv: &str) -> RlmServerStatus {
+    lazy_static! {
+        // The wording of the ISV status line changed between RLM versions, e.g.
+        //  - demo ISV server status on lic1 (port 40123), UP 12d 03:10:40
+        //  - ISV demo status on lic1 (port 40123), UP 3d 00:59:58
+        //  - ISV server demo status on lic1 (port: 40123), Up 0d 04:20:09
+        //  - demo ISV server status on lic1 (port 40123, ssl): UP 45d 11:02:01
+        static ref RE_RLM_STATUS: [Regex; 2] = [
+            Regex::new(r"^\s*([\w+\-.]+) ISV server status on [\w\-.]+ \(port:?\s*(\d+)[^)]*\)[,:]\s*(\w+).*$")
+                .unwrap(),
+            Regex::new(r"^\s*ISV (?:server )?([\w+\-.]+) status on [\w\-.]+ \(port:?\s*(\d+)[^)]*\)[,:]\s*(\w+).*$")
+                .unwrap(),
+        ];
+        static ref RE_RLM_VERSION: Regex =
+            Regex::new(r"^\s*([\w+\-.]+) software version:?\s+([\w\s.:\-()]+)$").unwrap();
+        static ref RE_RLM_MAJOR_VERSION: Regex = Regex::new(r"^v?(\d+)\.").unwrap();
+    }
+
+    let mut result = RlmServerStatus::new();
+    let mut rlm_version = String::new();
+
+    for line in raw.lines() {
+        if line.is_empty() {
+            continue;
         }
 
-        let stdout = String::from_utf8(cmd.stdout)?;
-        let mut port: &str = "";
-        let mut status: i64 = 0;
-        let mut version: &str = "";
-        for line in stdout.lines() {
-            if line.is_empty() {
+        if let Some(capt) = RE_RLM_STATUS.iter().find_map(|re| re.captures(line)) {
+            if capt.len() != 4 {
+                error!(
+                    "Regular expression returns {} capture groups instead of 4",
+                    capt.len(),
+                );
                 continue;
             }
 
-            if let Some(capt) = RE_RLM_STATUS.captures(line) {
-                if capt.len() != 3 {
-                    error!(
-                        "Regular expression returns {} capture groups instead of 3",
-                        capt.len(),
-                    );
-                    continue;
-                }
-
-                debug!("rlm.rs:fetch_status: RE_RLM_STATUS match on '{}'", line);
+            debug!("rlm.rs:parse_status: RE_RLM_STATUS match on '{}'", line);
 
-                port = capt.get(1).map_or("", |m| m.as_str());
-                let _status = capt.get(2).map_or("", |m| m.as_str());
-                if _status.to_lowercase() == "up" {
-                    status = 1;
-                }
-            } else if let Some(capt) = RE_RLM_VERSION.captures(line) {
-                if capt.len() != 3 {
-                    error!(
-                        "Regular expression returns {} capture groups instead of 3",
-                        capt.len(),
-                    );
-                    continue;
-                }
+            result.port = capt.get(2).map_or("", |m| m.as_str()).to_string();
+            let _status = capt.get(3).map_or("", |m| m.as_str()).to_lowercase();
+            if _status == "up" || _status == "running" {
+                result.status = 1;
+            }
+        } else if let Some(capt) = RE_RLM_VERSION.captures(line) {
+            if capt.len() != 3 {
+                error!(
+                    "Regular expression returns {} capture groups instead of 3",
+                    capt.len(),
+                );
+                continue;
+            }
 
-                debug!("rlm.rs:fetch_status: RE_RLM_VERSION match on '{}'", line);
+            debug!("rlm.rs:parse_status: RE_RLM_VERSION match on '{}'", line);
 
-                version = capt.get(1).map_or("", |m| m.as_str());
-            } else {
-                debug!("rlm.rs:fetch_status: No regexp matches '{}'", line);
+            let name = capt.get(1).map_or("", |m| m.as_str());
+            let version = capt.get(2).map_or("", |m| m.as_str()).trim().to_string();
+            if name == "rlm" {
+                rlm_version = version;
+            } else if isv.is_empty() || name == isv {
+                result.version = version;
             }
+        } else {
+            debug!("rlm.rs:parse_status: No regexp matches '{}'", line);
         }
+    }
 
-        debug!(
-            "rlm.rs:fetch_status: Setting rlm_server_status {} {} {} {} -> {}",
-            lic.name, server, port, version, status
-        );
-        RLM_SERVER_STATUS
-            .with_label_values(&[&lic.name, server, port, version])
-            .set(status);
+    // Older versions report the ISV server version only
+    if result.version.is_empty() {
+        result.version = rlm_version.clone();
+    }
+    if rlm_version.is_empty() {
+        rlm_version = result.version.clone();
     }
 
-    Ok(())
+    if let Some(capt) = RE_RLM_MAJOR_VERSION.captures(&rlm_version) {
+        result.major_version = capt.get(1).map_or("", |m| m.as_str()).parse().ok();
+    }
+
+    result
 }
 
-pub fn register() {
-    exporter::REGISTRY
-        .register(Box::new(RLM_FEATURES_TOTAL.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(RLM_FEATURES_USED.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(RLM_FEATURES_USER.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(RLM_FEATURE_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(RLM_FEATURE_AGGREGATED_EXPIRATION.clone()))
-        .unwrap();
-    exporter::REGISTRY
-        .register(Box::new(RLM_SERVER_STATUS.clone()))
-        .unwrap();
+pub fn register_into(registry: &Registry) {
+    for collector in collectors() {
+        registry.register(collector).unwrap();
+    }
+}
+
+// All metrics of the backend, e.g. to unregister them if all licenses of the backend are
+// removed by a configuration reload
+pub fn collectors() -> Vec<Box<dyn Collector>> {
+    vec![
+        Box::new(RLM_FEATURES_TOTAL.clone()),
+        Box::new(RLM_FEATURES_USED.clone()),
+        Box::new(RLM_FEATURES_UNCOUNTED.clone()),
+        Box::new(RLM_FEATURES_USER.clone()),
+        Box::new(RLM_FEATURES_USER_COUNT.clone()),
+        Box::new(RLM_FEATURE_EXPIRATION.clone()),
+        Box::new(RLM_FEATURE_AGGREGATED_EXPIRATION.clone()),
+        Box::new(RLM_SERVER_STATUS.clone()),
+        Box::new(RLM_SERVER_MAJOR_VERSION.clone()),
+        Box::new(RLM_FAILOVER_STATUS.clone()),
+        Box::new(RLM_SERVING_SERVER.clone()),
+        Box::new(RLM_LAST_UPDATED.clone()),
+    ]
+}
+
+// Metrics with series per license, replaced on every fetch of the license (see series.rs)
+pub fn vectors() -> Vec<series::Vector> {
+    vec![
+        series::Vector::IntGauge(&RLM_FEATURES_TOTAL),
+        series::Vector::IntGauge(&RLM_FEATURES_USED),
+        series::Vector::IntGauge(&RLM_FEATURES_UNCOUNTED),
+        series::Vector::IntGauge(&RLM_FEATURES_USER),
+        series::Vector::IntGauge(&RLM_FEATURES_USER_COUNT),
+        series::Vector::Gauge(&RLM_FEATURE_EXPIRATION),
+        series::Vector::Gauge(&RLM_FEATURE_AGGREGATED_EXPIRATION),
+        series::Vector::IntGauge(&RLM_SERVER_STATUS),
+        series::Vector::IntGauge(&RLM_SERVER_MAJOR_VERSION),
+        series::Vector::IntGauge(&RLM_FAILOVER_STATUS),
+        series::Vector::IntGauge(&RLM_SERVING_SERVER),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    fn check_status(raw: &str, port: &str, status: i64, version: &str, major: i64) {
+        let result = parse_status(raw, "demo");
+        assert_eq!(result.port, port);
+        assert_eq!(result.status, status);
+        assert_eq!(result.version, version);
+        assert_eq!(result.major_version, Some(major));
+    }
+
+    #[test]
+    fn parse_status_v11() {
+        check_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v11.txt"),
+            "40123",
+            1,
+            "v11.3 (build:2)",
+            11,
+        );
+    }
+
+    #[test]
+    fn parse_status_v12() {
+        check_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v12.txt"),
+            "40123",
+            1,
+            "v12.4 (build:2)",
+            12,
+        );
+    }
+
+    #[test]
+    fn parse_status_v13() {
+        check_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v13.txt"),
+            "40123",
+            1,
+            "v13.1 (build:1)",
+            13,
+        );
+    }
+
+    #[test]
+    fn parse_status_v14() {
+        check_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v14.txt"),
+            "40123",
+            1,
+            "v14.2 (build: 2)",
+            14,
+        );
+    }
+
+    #[test]
+    fn parse_status_v15_down() {
+        check_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v15_down.txt"),
+            "40123",
+            0,
+            "v15.0 (build:3)",
+            15,
+        );
+    }
+
+    #[test]
+    fn parse_status_ignores_other_isv() {
+        let result = parse_status(
+            include_str!("../tests/fixtures/rlm/rlmstat_v11.txt"),
+            "other",
+        );
+        assert_eq!(result.version, "v11.3 (build:2)");
+    }
+
+    #[test]
+    fn golden_metrics() {
+        let cfg = testutil::parse_config(
+            "rlm:\n  - name: rlm\n    license: 5053@lic1\n    isv: demo\n    export_user: true\n",
+        );
+        let registry = Registry::new();
+        register_into(&registry);
+
+        let runner = command::FixtureRunner::new()
+            .reply(
+                &["rlmstat", "-c", "5053@lic1", "-l", "demo"],
+                &testutil::fixture("rlm/rlmstat_l.txt"),
+            )
+            .reply(
+                &["rlmstat", "-c", "5053@lic1", "-i", "demo"],
+                &testutil::fixture("rlm/rlmstat_i.txt"),
+            );
+        fetch(&cfg.rlm.unwrap()[0], "rlmutil", &runner).unwrap();
+        testutil::assert_golden("rlm", &testutil::render(&registry));
+    }
 }
@@ -0,0 +1,98 @@
+use crate::config;
+
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, IntGauge};
+
+// State of the collection threads. Like the heartbeat, the metrics are rendered when a scrape
+// is answered instead of being part of the sample set, which is taken after the collection
+// has finished.
+lazy_static! {
+    pub static ref WORKERS_BUSY: IntGauge = IntGauge::new(
+        "license_exporter_workers_busy",
+        "Number of worker threads querying a license"
+    )
+    .unwrap();
+    pub static ref QUEUED_LICENSES: IntGauge = IntGauge::new(
+        "license_exporter_collection_queue_length",
+        "Number of licenses of running collections waiting for a worker thread"
+    )
+    .unwrap();
+    pub static ref DROPPED_CYCLES: IntCounter = IntCounter::new(
+        "license_exporter_collection_cycles_dropped_total",
+        "Number of background collection intervals passed while the previous collection was still running"
+    )
+    .unwrap();
+}
+
+// Worker thread querying a license, the license is taken from the queue on creation and the
+// worker is idle again when dropped
+pub struct Busy;
+
+impl Busy {
+    pub fn start() -> Self {
+        QUEUED_LICENSES.dec();
+        WORKERS_BUSY.inc();
+        Busy
+    }
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        WORKERS_BUSY.dec();
+    }
+}
+
+pub fn queue(count: usize) {
+    QUEUED_LICENSES.add(count as i64);
+}
+
+pub fn dropped_cycles(count: u64) {
+    DROPPED_CYCLES.inc_by(count);
+}
+
+// Current values, appended to the reply of a scrape
+pub fn families(cfg: &config::Configuration) -> Vec<MetricFamily> {
+    let mut families: Vec<MetricFamily> = Vec::new();
+    families.extend(WORKERS_BUSY.collect());
+    families.extend(QUEUED_LICENSES.collect());
+    if cfg.scrape_interval().is_some() {
+        families.extend(DROPPED_CYCLES.collect());
+    }
+    families
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn runtime_families() {
+        let names = |cfg: &config::Configuration| -> Vec<String> {
+            families(cfg)
+                .iter()
+                .map(|mf| mf.get_name().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            names(&testutil::parse_config("global:\n  scrape_interval: 60\n")),
+            vec![
+                "license_exporter_workers_busy",
+                "license_exporter_collection_queue_length",
+                "license_exporter_collection_cycles_dropped_total"
+            ]
+        );
+
+        // Cycles are only dropped by the background collection
+        assert_eq!(
+            names(&testutil::parse_config("global:\n  lmutil: lmutil\n")),
+            vec![
+                "license_exporter_workers_busy",
+                "license_exporter_collection_queue_length"
+            ]
+        );
+    }
+}
@@ -0,0 +1,415 @@
+use lazy_static::lazy_static;
+use log::{debug, error};
+use prometheus::core::Collector;
+use prometheus::{GaugeVec, IntGaugeVec};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Condvar, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+// Series of the backend metrics are replaced on every fetch of a license. The series of the
+// license are taken out of the metric vectors before the fetch, so series of released licenses
+// or removed features disappear instead of keeping their last value forever. If the fetch
+// fails, the previous series are put back.
+// Label names and values of a series with its value
+type Series = (HashMap<String, String>, f64);
+
+#[derive(Clone, Copy)]
+pub enum Vector {
+    Gauge(&'static GaugeVec),
+    IntGauge(&'static IntGaugeVec),
+}
+
+impl Vector {
+    fn collector(&self) -> &dyn Collector {
+        match self {
+            Vector::Gauge(v) => *v,
+            Vector::IntGauge(v) => *v,
+        }
+    }
+
+    // Label sets and values of all series with the app label
+    fn series(&self, app: &str) -> Vec<Series> {
+        let mut result = Vec::new();
+
+        for mf in self.collector().collect() {
+            for m in mf.get_metric() {
+                let labels: HashMap<String, String> = m
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                if labels.get("app").map(|a| a.as_str()) == Some(app) {
+                    result.push((labels, m.get_gauge().get_value()));
+                }
+            }
+        }
+
+        result
+    }
+
+    fn remove(&self, labels: &HashMap<String, String>) {
+        let labels = as_str_map(labels);
+        let _ = match self {
+            Vector::Gauge(v) => v.remove(&labels),
+            Vector::IntGauge(v) => v.remove(&labels),
+        };
+    }
+
+    fn set(&self, labels: &HashMap<String, String>, value: f64) {
+        let labels = as_str_map(labels);
+        match self {
+            Vector::Gauge(v) => v.with(&labels).set(value),
+            Vector::IntGauge(v) => v.with(&labels).set(value as i64),
+        };
+    }
+}
+
+fn as_str_map(labels: &HashMap<String, String>) -> HashMap<&str, &str> {
+    labels
+        .iter()
+        .map(|(n, v)| (n.as_str(), v.as_str()))
+        .collect()
+}
+
+// Series taken out of the metric vectors before a fetch
+#[derive(Clone)]
+pub struct Snapshot {
+    app: String,
+    series: Vec<(Vector, Vec<Series>)>,
+}
+
+// Remove all series of the license app from vectors
+pub fn take(vectors: &[Vector], app: &str) -> Snapshot {
+    let mut series = Vec::new();
+    let mut count = 0;
+
+    for vector in vectors {
+        let current = vector.series(app);
+        for (labels, _) in current.iter() {
+            vector.remove(labels);
+        }
+        count += current.len();
+        series.push((*vector, current));
+    }
+    debug!("series.rs:take: Removed {} series of {}", count, app);
+
+    Snapshot {
+        app: app.to_string(),
+        series,
+    }
+}
+
+lazy_static! {
+    // (backend, app) -> time of the last refresh and the series it set
+    static ref CACHE: Mutex<HashMap<(String, String), (Instant, Snapshot)>> =
+        Mutex::new(HashMap::new());
+    // (backend, app) of the licenses with series being replaced
+    static ref REPLACING: (Mutex<HashSet<(String, String)>>, Condvar) =
+        (Mutex::new(HashSet::new()), Condvar::new());
+    // Shared while series are replaced, exclusive while they are gathered
+    static ref GATHERING: RwLock<()> = RwLock::new(());
+}
+
+// Held while the series of a license are replaced. Concurrent scrapes without a scrape_interval
+// could take and restore the series of the same license in turns otherwise, and a scrape could
+// gather the series of a license while another scrape has them taken out.
+pub struct Replacing {
+    key: (String, String),
+    _shared: Option<RwLockReadGuard<'static, ()>>,
+}
+
+// Wait until no other fetch replaces the series of the license app
+pub fn replacing(backend: &str, app: &str) -> Replacing {
+    let key = (backend.to_string(), app.to_string());
+    let (busy, released) = &*REPLACING;
+
+    match busy.lock() {
+        Ok(mut v) => {
+            while v.contains(&key) {
+                v = match released.wait(v) {
+                    Ok(v) => v,
+                    Err(e) => e.into_inner(),
+                };
+            }
+            v.insert(key.clone());
+        }
+        Err(e) => error!("series.rs:replacing: Can't lock replaced licenses: {}", e),
+    };
+
+    let shared = match GATHERING.read() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!("series.rs:replacing: Can't lock series: {}", e);
+            None
+        }
+    };
+
+    Replacing {
+        key,
+        _shared: shared,
+    }
+}
+
+impl Drop for Replacing {
+    fn drop(&mut self) {
+        let (busy, released) = &*REPLACING;
+        match busy.lock() {
+            Ok(mut v) => {
+                v.remove(&self.key);
+            }
+            Err(e) => error!("series.rs:replacing: Can't lock replaced licenses: {}", e),
+        };
+        released.notify_all();
+    }
+}
+
+// Wait until no series are being replaced and block replacing them while the metrics are
+// gathered
+pub fn gathering() -> Option<RwLockWriteGuard<'static, ()>> {
+    match GATHERING.write() {
+        Ok(v) => Some(v),
+        Err(e) => {
+            error!("series.rs:gathering: Can't lock series: {}", e);
+            None
+        }
+    }
+}
+
+// Drop the cached series of a license removed by a configuration reload
+pub fn forget(backend: &str, app: &str) {
+    match CACHE.lock() {
+        Ok(mut v) => {
+            v.remove(&(backend.to_string(), app.to_string()));
+        }
+        Err(e) => error!("series.rs:forget: Can't lock series cache: {}", e),
+    };
+}
+
+// Refresh the series of vectors only if the last successful refresh of the license app is
+// older than interval, otherwise the series of the last refresh are set again. Without
+// interval, refresh runs on every fetch.
+pub fn cached<F>(
+    backend: &str,
+    app: &str,
+    interval: Option<Duration>,
+    vectors: &[Vector],
+    refresh: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce() -> Result<(), Box<dyn Error>>,
+{
+    let interval = match interval {
+        Some(v) => v,
+        None => return refresh(),
+    };
+    let key = (backend.to_string(), app.to_string());
+
+    let last = match CACHE.lock() {
+        Ok(v) => v.get(&key).cloned(),
+        Err(e) => {
+            error!("series.rs:cached: Can't lock series cache: {}", e);
+            None
+        }
+    };
+    if let Some((time, snapshot)) = last {
+        if time.elapsed() < interval {
+            debug!(
+                "series.rs:cached: Using series of {} {} from {:?} ago",
+                backend,
+                app,
+                time.elapsed()
+            );
+            snapshot.restore();
+            return Ok(());
+        }
+    }
+
+    refresh()?;
+
+    let mut series = Vec::new();
+    for vector in vectors {
+        series.push((*vector, vector.series(app)));
+    }
+    let snapshot = Snapshot {
+        app: app.to_string(),
+        series,
+    };
+    match CACHE.lock() {
+        Ok(mut v) => {
+            v.insert(key, (Instant::now(), snapshot));
+        }
+        Err(e) => error!("series.rs:cached: Can't lock series cache: {}", e),
+    };
+
+    Ok(())
+}
+
+impl Snapshot {
+    // Put the series back, e.g. if the fetch failed. Series set by the failed fetch are kept.
+    pub fn restore(self) {
+        self.put_back(false);
+    }
+
+    // Put the series back with the value 0, e.g. to show a failed license as unused
+    pub fn restore_zero(self) {
+        self.put_back(true);
+    }
+
+    fn put_back(self, zero: bool) {
+        for (vector, series) in self.series {
+            let current: Vec<HashMap<String, String>> = vector
+                .series(&self.app)
+                .into_iter()
+                .map(|(l, _)| l)
+                .collect();
+
+            for (labels, value) in series {
+                if !current.contains(&labels) {
+                    vector.set(&labels, if zero { 0.0 } else { value });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use prometheus::Opts;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    lazy_static! {
+        static ref TEST_USERS: IntGaugeVec = IntGaugeVec::new(
+            Opts::new("test_feature_used_users", "test"),
+            &["app", "name", "user"]
+        )
+        .unwrap();
+        static ref TEST_EXPIRATION: GaugeVec = GaugeVec::new(
+            Opts::new("test_feature_expiration_seconds", "test"),
+            &["app", "name"]
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn replace_series() {
+        let vectors = [Vector::IntGauge(&TEST_USERS)];
+        TEST_USERS
+            .with_label_values(&["lic1", "feat1", "alice"])
+            .set(2);
+        TEST_USERS
+            .with_label_values(&["lic1", "feat1", "bob"])
+            .set(1);
+        TEST_USERS
+            .with_label_values(&["lic2", "feat1", "carol"])
+            .set(1);
+
+        // Failed fetch, the series are restored
+        take(&vectors, "lic1").restore();
+        assert_eq!(vectors[0].series("lic1").len(), 2);
+
+        take(&vectors, "lic1").restore_zero();
+        let series = vectors[0].series("lic1");
+        assert_eq!(series.len(), 2);
+        assert!(series.iter().all(|(_, v)| *v == 0.0));
+        TEST_USERS
+            .with_label_values(&["lic1", "feat1", "alice"])
+            .set(2);
+        TEST_USERS
+            .with_label_values(&["lic1", "feat1", "bob"])
+            .set(1);
+
+        // bob released his license
+        let _snapshot = take(&vectors, "lic1");
+        TEST_USERS
+            .with_label_values(&["lic1", "feat1", "alice"])
+            .set(3);
+        let series = vectors[0].series("lic1");
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].1, 3.0);
+        assert_eq!(vectors[0].series("lic2").len(), 1);
+    }
+
+    #[test]
+    fn serialized_replacing() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    let _replacing = replacing("test", "serialized1");
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        assert_eq!(max_running.load(Ordering::SeqCst), 1);
+
+        // Other licenses aren't blocked
+        let _replacing = replacing("test", "serialized1");
+        drop(replacing("test", "serialized2"));
+    }
+
+    #[test]
+    fn cached_series() {
+        let vectors = [Vector::Gauge(&TEST_EXPIRATION)];
+        let refresh = |value: f64| {
+            move || {
+                TEST_EXPIRATION
+                    .with_label_values(&["lic1", "feat1"])
+                    .set(value);
+                Ok(())
+            }
+        };
+
+        cached(
+            "test",
+            "lic1",
+            Some(Duration::from_secs(3600)),
+            &vectors,
+            refresh(1.0),
+        )
+        .unwrap();
+        assert_eq!(vectors[0].series("lic1")[0].1, 1.0);
+
+        // The series are removed before every fetch and set again from the cache
+        take(&vectors, "lic1");
+        cached(
+            "test",
+            "lic1",
+            Some(Duration::from_secs(3600)),
+            &vectors,
+            refresh(2.0),
+        )
+        .unwrap();
+        let series = vectors[0].series("lic1");
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].1, 1.0);
+
+        take(&vectors, "lic1");
+        cached("test", "lic1", Some(Duration::ZERO), &vectors, refresh(3.0)).unwrap();
+        assert_eq!(vectors[0].series("lic1")[0].1, 3.0);
+
+        take(&vectors, "lic1");
+        cached("test", "lic1", None, &vectors, refresh(4.0)).unwrap();
+        assert_eq!(vectors[0].series("lic1")[0].1, 4.0);
+
+        // Failed refreshes aren't cached
+        take(&vectors, "lic2");
+        assert!(cached(
+            "test",
+            "lic2",
+            Some(Duration::from_secs(3600)),
+            &vectors,
+            || { Err(Box::new(simple_error::SimpleError::new("timeout"))) }
+        )
+        .is_err());
+        assert!(vectors[0].series("lic2").is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+use log::debug;
+use prometheus::core::Collector;
+use prometheus::IntGaugeVec;
+
+// A license server of a license entry as reported by the *_server_status metrics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerInfo {
+    pub fqdn: String,
+    pub port: String,
+    // The first server of a redundant setup is the master server
+    pub master: bool,
+    pub status: i64,
+    pub version: String,
+}
+
+impl ServerInfo {
+    pub fn new(fqdn: &str, port: &str) -> Self {
+        ServerInfo {
+            fqdn: fqdn.to_string(),
+            port: port.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+// License servers of a license entry in the order of the configuration
+#[derive(Clone, Debug, Default)]
+pub struct ServerTable {
+    servers: Vec<ServerInfo>,
+}
+
+impl ServerTable {
+    // Servers from the license setting, e.g. port@server1:port@server2:port@server3.
+    // Servers without port use default_port.
+    pub fn from_license(license: &str, default_port: &str) -> Self {
+        let mut result = ServerTable::default();
+
+        for (i, entry) in license.split(':').enumerate() {
+            // NOTE: Configuration validation checks for valid server lines
+            let (port, fqdn) = match entry.split_once('@') {
+                Some((p, s)) => (p, s),
+                None => (default_port, entry),
+            };
+
+            if result.get(fqdn).is_some() {
+                continue;
+            }
+
+            let mut info = ServerInfo::new(fqdn, port);
+            info.master = i == 0;
+            result.servers.push(info);
+        }
+
+        result
+    }
+
+    pub fn get(&self, fqdn: &str) -> Option<&ServerInfo> {
+        self.servers.iter().find(|s| s.fqdn == fqdn)
+    }
+
+    #[cfg(test)]
+    pub fn get_mut(&mut self, fqdn: &str) -> Option<&mut ServerInfo> {
+        self.servers.iter_mut().find(|s| s.fqdn == fqdn)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, ServerInfo> {
+        self.servers.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, ServerInfo> {
+        self.servers.iter_mut()
+    }
+
+    // Set *_server_status for all servers
+    pub fn export_status(&self, app: &str, metric: &IntGaugeVec) {
+        for server in self.servers.iter() {
+            export_status(app, metric, server);
+        }
+    }
+}
+
+// Set *_server_status of a server. The label values are taken from the label names of the
+// metric (app, fqdn, master, port, version), so all backends report a server the same way.
+pub fn export_status(app: &str, metric: &IntGaugeVec, server: &ServerInfo) {
+    let master = format!("{}", server.master);
+    let (name, label_names) = match metric.desc().first() {
+        Some(v) => (v.fq_name.clone(), v.variable_labels.clone()),
+        None => return,
+    };
+
+    let label_values: Vec<&str> = label_names
+        .iter()
+        .map(|l| match l.as_str() {
+            "app" => app,
+            "fqdn" => &server.fqdn,
+            "master" => &master,
+            "port" => &server.port,
+            "version" => &server.version,
+            _ => "",
+        })
+        .collect();
+
+    debug!(
+        "servers.rs:export_status: Setting {} {} -> {}",
+        name,
+        label_values.join(" "),
+        server.status
+    );
+    metric.with_label_values(&label_values).set(server.status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use prometheus::{Opts, Registry};
+
+    #[test]
+    fn from_license() {
+        let table = ServerTable::from_license("6200@lic1:lic2:6201@lic3:6200@lic1", "6200");
+        let servers: Vec<(&str, &str, bool)> = table
+            .iter()
+            .map(|s| (s.fqdn.as_str(), s.port.as_str(), s.master))
+            .collect();
+        assert_eq!(
+            servers,
+            vec![
+                ("lic1", "6200", true),
+                ("lic2", "6200", false),
+                ("lic3", "6201", false)
+            ]
+        );
+    }
+
+    #[test]
+    fn export_labels() {
+        let with_master = IntGaugeVec::new(
+            Opts::new("test_server_status", "Status of license server(s)"),
+            &["app", "fqdn", "master", "port", "version"],
+        )
+        .unwrap();
+        let without_master = IntGaugeVec::new(
+            Opts::new("test_other_server_status", "Status of license server"),
+            &["app", "fqdn", "port", "version"],
+        )
+        .unwrap();
+        let registry = Registry::new();
+        registry.register(Box::new(with_master.clone())).unwrap();
+        registry.register(Box::new(without_master.clone())).unwrap();
+
+        let mut table = ServerTable::from_license("1234@lic1:1234@lic2", "");
+        if let Some(s) = table.get_mut("lic1") {
+            s.status = 1;
+            s.version = "1.0".to_string();
+        }
+        table.export_status("app", &with_master);
+        table.export_status("app", &without_master);
+
+        assert_eq!(
+            testutil::render(&registry),
+            "# HELP test_other_server_status Status of license server
+# TYPE test_other_server_status gauge
+test_other_server_status{app=\"app\",fqdn=\"lic1\",port=\"1234\",version=\"1.0\"} 1
+test_other_server_status{app=\"app\",fqdn=\"lic2\",port=\"1234\",version=\"\"} 0
+# HELP test_server_status Status of license server(s)
+# TYPE test_server_status gauge
+test_server_status{app=\"app\",fqdn=\"lic1\",master=\"true\",port=\"1234\",version=\"1.0\"} 1
+test_server_status{app=\"app\",fqdn=\"lic2\",master=\"false\",port=\"1234\",version=\"\"} 0
+"
+        );
+    }
+}
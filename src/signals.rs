@@ -0,0 +1,49 @@
+use log::{debug, error};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// SIGHUP requests a reload of the configuration file. The signal handler only sets a flag,
+// the reload is done by the HTTP server loop.
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+#[cfg(unix)]
+pub fn init() {
+    let handler = handle_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+
+    // SAFETY: The handler only stores to an atomic, which is async-signal-safe
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        error!("signals.rs:init: Can't install handler for SIGHUP");
+        return;
+    }
+    debug!("signals.rs:init: Installed handler for SIGHUP");
+}
+
+#[cfg(not(unix))]
+pub fn init() {}
+
+// True once for every received SIGHUP (multiple signals before the check result in a
+// single reload)
+pub fn reload_requested() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sighup_requests_reload() {
+        init();
+        assert!(!reload_requested());
+
+        unsafe {
+            libc::raise(libc::SIGHUP);
+        }
+        assert!(reload_requested());
+        assert!(!reload_requested());
+    }
+}
@@ -0,0 +1,169 @@
+use base64::Engine;
+use libloading::Library;
+use log::debug;
+use simple_error::bail;
+use std::error::Error;
+use std::ffi::c_void;
+use std::ptr;
+
+// NOTE: The GSSAPI library is loaded at runtime, so the exporter can be built and run on
+//       systems without Kerberos libraries as long as SPNEGO authentication is not used.
+const GSSAPI_LIBRARIES: [&str; 3] = ["libgssapi_krb5.so.2", "libgssapi_krb5.so", "libgssapi.so.3"];
+
+// 1.2.840.113554.1.2.1.4 - GSS_C_NT_HOSTBASED_SERVICE
+static GSS_C_NT_HOSTBASED_SERVICE: [u8; 10] =
+    [0x2a, 0x86, 0x48, 0x86, 0xf7, 0x12, 0x01, 0x02, 0x01, 0x04];
+// 1.3.6.1.5.5.2 - SPNEGO
+static GSS_SPNEGO_MECHANISM: [u8; 6] = [0x2b, 0x06, 0x01, 0x05, 0x05, 0x02];
+
+// Calling and routine errors are reported in the upper 16 bits of the major status
+const GSS_S_ERROR_MASK: u32 = 0xffff_0000;
+
+#[repr(C)]
+struct GssBufferDesc {
+    length: usize,
+    value: *mut c_void,
+}
+
+#[repr(C)]
+struct GssOidDesc {
+    length: u32,
+    elements: *mut c_void,
+}
+
+type GssImportName =
+    unsafe extern "C" fn(*mut u32, *mut GssBufferDesc, *mut GssOidDesc, *mut *mut c_void) -> u32;
+type GssInitSecContext = unsafe extern "C" fn(
+    *mut u32,
+    *mut c_void,
+    *mut *mut c_void,
+    *mut c_void,
+    *mut GssOidDesc,
+    u32,
+    u32,
+    *mut c_void,
+    *mut GssBufferDesc,
+    *mut *mut GssOidDesc,
+    *mut GssBufferDesc,
+    *mut u32,
+    *mut u32,
+) -> u32;
+type GssReleaseBuffer = unsafe extern "C" fn(*mut u32, *mut GssBufferDesc) -> u32;
+type GssReleaseName = unsafe extern "C" fn(*mut u32, *mut *mut c_void) -> u32;
+type GssDeleteSecContext =
+    unsafe extern "C" fn(*mut u32, *mut *mut c_void, *mut GssBufferDesc) -> u32;
+
+fn load_library() -> Result<Library, Box<dyn Error>> {
+    for name in GSSAPI_LIBRARIES {
+        // Safety: Loading the system GSSAPI library doesn't run any initialisation code with side effects
+        match unsafe { Library::new(name) } {
+            Ok(v) => {
+                debug!("spnego.rs:load_library: Using GSSAPI library {}", name);
+                return Ok(v);
+            }
+            Err(e) => {
+                debug!("spnego.rs:load_library: Can't load {}: {}", name, e);
+            }
+        };
+    }
+    bail!(
+        "no GSSAPI library found, tried {}",
+        GSSAPI_LIBRARIES.join(", ")
+    );
+}
+
+/// Create the initial SPNEGO token for the HTTP service on `host`, encoded for the
+/// `Authorization: Negotiate` header. Credentials are taken from the credential cache or,
+/// if KRB5_CLIENT_KTNAME is set, from the client keytab.
+pub fn negotiate_token(host: &str) -> Result<String, Box<dyn Error>> {
+    let library = load_library()?;
+
+    // Safety: The function signatures match the GSSAPI C bindings (RFC 2744)
+    unsafe {
+        let import_name = library.get::<GssImportName>(b"gss_import_name\0")?;
+        let init_sec_context = library.get::<GssInitSecContext>(b"gss_init_sec_context\0")?;
+        let release_buffer = library.get::<GssReleaseBuffer>(b"gss_release_buffer\0")?;
+        let release_name = library.get::<GssReleaseName>(b"gss_release_name\0")?;
+        let delete_sec_context = library.get::<GssDeleteSecContext>(b"gss_delete_sec_context\0")?;
+
+        let service = format!("HTTP@{}", host);
+        let mut minor: u32 = 0;
+        let mut service_buffer = GssBufferDesc {
+            length: service.len(),
+            value: service.as_ptr() as *mut c_void,
+        };
+        let mut name_type = GssOidDesc {
+            length: GSS_C_NT_HOSTBASED_SERVICE.len() as u32,
+            elements: GSS_C_NT_HOSTBASED_SERVICE.as_ptr() as *mut c_void,
+        };
+        let mut target: *mut c_void = ptr::null_mut();
+
+        let major = import_name(&mut minor, &mut service_buffer, &mut name_type, &mut target);
+        if major & GSS_S_ERROR_MASK != 0 {
+            bail!(
+                "gss_import_name failed for {} with major status {:#x}, minor status {}",
+                service,
+                major,
+                minor
+            );
+        }
+
+        let mut mechanism = GssOidDesc {
+            length: GSS_SPNEGO_MECHANISM.len() as u32,
+            elements: GSS_SPNEGO_MECHANISM.as_ptr() as *mut c_void,
+        };
+        let mut context: *mut c_void = ptr::null_mut();
+        let mut input_token = GssBufferDesc {
+            length: 0,
+            value: ptr::null_mut(),
+        };
+        let mut output_token = GssBufferDesc {
+            length: 0,
+            value: ptr::null_mut(),
+        };
+
+        let major = init_sec_context(
+            &mut minor,
+            ptr::null_mut(),
+            &mut context,
+            target,
+            &mut mechanism,
+            0,
+            0,
+            ptr::null_mut(),
+            &mut input_token,
+            ptr::null_mut(),
+            &mut output_token,
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+
+        let result = if major & GSS_S_ERROR_MASK != 0 {
+            Err(format!(
+                "gss_init_sec_context failed for {} with major status {:#x}, minor status {}",
+                service, major, minor
+            ))
+        } else if output_token.length == 0 || output_token.value.is_null() {
+            Err(format!(
+                "gss_init_sec_context returned no token for {}",
+                service
+            ))
+        } else {
+            let token =
+                std::slice::from_raw_parts(output_token.value as *const u8, output_token.length);
+            Ok(base64::engine::general_purpose::STANDARD.encode(token))
+        };
+
+        let mut cleanup_minor: u32 = 0;
+        release_buffer(&mut cleanup_minor, &mut output_token);
+        if !context.is_null() {
+            delete_sec_context(&mut cleanup_minor, &mut context, ptr::null_mut());
+        }
+        release_name(&mut cleanup_minor, &mut target);
+
+        match result {
+            Ok(v) => Ok(v),
+            Err(e) => bail!(e),
+        }
+    }
+}
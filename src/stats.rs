@@ -0,0 +1,108 @@
+use lazy_static::lazy_static;
+use log::debug;
+use prometheus::core::Collector;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::collections::HashSet;
+
+// Parse statistics of the license data, a sudden drop of the parsed features or servers
+// usually means a changed output format of the license server tools
+lazy_static! {
+    pub static ref PARSED_FEATURES: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "license_exporter_parsed_features_total",
+            "Number of features parsed from the license data"
+        ),
+        &["app", "backend"],
+    )
+    .unwrap();
+    pub static ref PARSED_USERS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "license_exporter_parsed_users_total",
+            "Number of distinct users with license checkouts parsed from the license data"
+        ),
+        &["app", "backend"],
+    )
+    .unwrap();
+    pub static ref PARSED_SERVERS: IntCounterVec = IntCounterVec::new(
+        Opts::new(
+            "license_exporter_parsed_servers_total",
+            "Number of license servers with parsed status information"
+        ),
+        &["app", "backend"],
+    )
+    .unwrap();
+}
+
+pub fn features(backend: &str, app: &str, count: usize) {
+    add(&PARSED_FEATURES, backend, app, count);
+}
+
+pub fn users(backend: &str, app: &str, count: usize) {
+    add(&PARSED_USERS, backend, app, count);
+}
+
+pub fn servers(backend: &str, app: &str, count: usize) {
+    add(&PARSED_SERVERS, backend, app, count);
+}
+
+// Number of distinct users, e.g. of the user keys of all features
+pub fn distinct_users<'a, I>(users: I) -> usize
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    users.into_iter().collect::<HashSet<&String>>().len()
+}
+
+fn add(counter: &IntCounterVec, backend: &str, app: &str, count: usize) {
+    debug!(
+        "stats.rs:add: Adding {} to {} {} {}",
+        count,
+        counter.desc()[0].fq_name,
+        app,
+        backend
+    );
+    counter
+        .with_label_values(&[app, backend])
+        .inc_by(count as u64);
+}
+
+pub fn register_into(registry: &Registry) {
+    registry
+        .register(Box::new(PARSED_FEATURES.clone()))
+        .unwrap();
+    registry.register(Box::new(PARSED_USERS.clone())).unwrap();
+    registry.register(Box::new(PARSED_SERVERS.clone())).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn count_parsed_data() {
+        let mut fu: HashMap<String, HashMap<String, i64>> = HashMap::new();
+        fu.entry("feat1".to_string())
+            .or_default()
+            .insert("alice".to_string(), 1);
+        fu.entry("feat2".to_string())
+            .or_default()
+            .insert("alice".to_string(), 2);
+        fu.entry("feat2".to_string())
+            .or_default()
+            .insert("bob".to_string(), 1);
+
+        let users = distinct_users(fu.values().flat_map(|u| u.keys()));
+        assert_eq!(users, 2);
+
+        features("test", "count_parsed_data", 3);
+        features("test", "count_parsed_data", 0);
+        features("test", "count_parsed_data", 2);
+        assert_eq!(
+            PARSED_FEATURES
+                .with_label_values(&["count_parsed_data", "test"])
+                .get(),
+            5
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::config;
+use crate::constants;
+
+use chrono::{DateTime, Local};
+use lazy_static::lazy_static;
+use log::error;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+// Result of the last scrape of every license, shown on the landing page
+lazy_static! {
+    // (backend, name) -> last scrape
+    static ref LAST_SCRAPES: Mutex<HashMap<(String, String), Scrape>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Debug)]
+pub struct Scrape {
+    pub time: DateTime<Local>,
+    // None if the scrape was successful
+    pub error: Option<String>,
+}
+
+pub fn record(backend: &str, name: &str, result: &Result<(), Box<dyn Error>>) {
+    let scrape = Scrape {
+        time: Local::now(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+
+    match LAST_SCRAPES.lock() {
+        Ok(mut v) => {
+            v.insert((backend.to_string(), name.to_string()), scrape);
+        }
+        Err(e) => error!("status.rs:record: Can't lock scrape status: {}", e),
+    };
+}
+
+// Last scrape of a license, None if it wasn't scraped yet
+pub fn last_scrape(backend: &str, name: &str) -> Option<Scrape> {
+    match LAST_SCRAPES.lock() {
+        Ok(v) => v.get(&(backend.to_string(), name.to_string())).cloned(),
+        Err(e) => {
+            error!("status.rs:last_scrape: Can't lock scrape status: {}", e);
+            None
+        }
+    }
+}
+
+// Licenses of cfg whose last scrape failed
+pub fn failed(cfg: &config::Configuration) -> Vec<String> {
+    let mut result = Vec::new();
+
+    for backend in config::BACKENDS {
+        for name in cfg.license_names(backend) {
+            if let Some(Scrape { error: Some(_), .. }) = last_scrape(backend, &name) {
+                result.push(name);
+            }
+        }
+    }
+
+    result
+}
+
+// Landing page with the configured licenses and the result of their last scrape
+pub fn landing_page(cfg: &config::Configuration) -> String {
+    let mut rows = String::new();
+
+    for backend in config::BACKENDS {
+        for name in cfg.license_names(backend) {
+            let (status, time) = match last_scrape(backend, &name) {
+                Some(Scrape { time, error: None }) => (
+                    "OK".to_string(),
+                    time.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+                ),
+                Some(Scrape {
+                    time,
+                    error: Some(e),
+                }) => (
+                    format!("Failed: {}", e),
+                    time.format("%Y-%m-%d %H:%M:%S %z").to_string(),
+                ),
+                None => ("Not scraped yet".to_string(), "-".to_string()),
+            };
+            rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&name),
+                backend,
+                escape_html(&status),
+                time
+            ));
+        }
+    }
+
+    constants::ROOT_HTML
+        .replace("{metrics_path}", &escape_html(cfg.metrics_path()))
+        .replace("{licenses}", &rows)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+    use simple_error::SimpleError;
+
+    #[test]
+    fn landing_page_licenses() {
+        let cfg = testutil::parse_config(
+            "flexlm:\n  - name: status-ok\n    license: 27000@lic1\n  - name: status-failed\n    license: 27000@lic2\n  - name: status-new\n    license: 27000@lic3\n",
+        );
+        record("flexlm", "status-ok", &Ok(()));
+        record(
+            "flexlm",
+            "status-failed",
+            &Err(Box::new(SimpleError::new("lmstat returned <2>"))),
+        );
+
+        let page = landing_page(&cfg);
+        assert!(page.contains("<a href=\"/metrics\">"));
+        assert!(page.contains("<tr><td>status-ok</td><td>flexlm</td><td>OK</td>"));
+        assert!(page.contains(
+            "<tr><td>status-failed</td><td>flexlm</td><td>Failed: lmstat returned &lt;2&gt;</td>"
+        ));
+        assert!(page.contains(
+            "<tr><td>status-new</td><td>flexlm</td><td>Not scraped yet</td><td>-</td></tr>"
+        ));
+
+        assert_eq!(failed(&cfg), vec!["status-failed".to_string()]);
+    }
+}
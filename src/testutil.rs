@@ -0,0 +1,140 @@
+use crate::config;
+use crate::http;
+
+use prometheus::{Registry, TextEncoder};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+
+// Canned vendor outputs and fake vendor tools are located in tests/fixtures/<backend>/
+pub fn fixture_path(name: &str) -> String {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("fixtures")
+        .join(name)
+        .to_string_lossy()
+        .to_string()
+}
+
+pub fn fixture(name: &str) -> Vec<u8> {
+    match fs::read(fixture_path(name)) {
+        Ok(v) => v,
+        Err(e) => panic!("Can't read fixture {}: {}", name, e),
+    }
+}
+
+pub fn parse_config(raw: &str) -> config::Configuration {
+    match config::parse_config(raw) {
+        Ok(v) => v,
+        Err(e) => panic!("Can't parse test configuration: {}", e),
+    }
+}
+
+// Connect directly to the fixture server, regardless of the proxy settings of the environment
+pub fn no_proxy() -> http::ProxySettings {
+    http::ProxySettings {
+        http_proxy: Some(String::new()),
+        https_proxy: Some(String::new()),
+        no_proxy: None,
+    }
+}
+
+pub fn render(registry: &Registry) -> String {
+    let encoder = TextEncoder::new();
+    match encoder.encode_to_string(&registry.gather()) {
+        Ok(v) => v,
+        Err(e) => panic!("Can't encode metrics: {}", e),
+    }
+}
+
+// Compare rendered metrics with the golden file tests/golden/<name>.prom.
+// Setting UPDATE_GOLDEN=1 writes the rendered metrics to the golden file instead.
+pub fn assert_golden(name: &str, rendered: &str) {
+    let file = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("golden")
+        .join(format!("{}.prom", name));
+
+    if env::var("UPDATE_GOLDEN").map(|v| v == "1").unwrap_or(false) {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(&file, rendered).unwrap();
+        return;
+    }
+
+    let expected = match fs::read_to_string(&file) {
+        Ok(v) => v,
+        Err(e) => panic!(
+            "Can't read golden file {}: {} (run the tests with UPDATE_GOLDEN=1 to create it)",
+            file.display(),
+            e
+        ),
+    };
+
+    // Compare line by line for a readable diff of the mismatching series
+    assert_eq!(
+        rendered.lines().collect::<Vec<&str>>(),
+        expected.lines().collect::<Vec<&str>>(),
+        "rendered metrics don't match golden file {} (run the tests with UPDATE_GOLDEN=1 to update it)",
+        file.display()
+    );
+}
+
+// HTTP server on a random local port, serving fixture files for the requested paths
+pub struct FixtureServer {
+    pub port: u16,
+    server: Arc<tiny_http::Server>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl FixtureServer {
+    // routes maps the request path (without query string) to a fixture file
+    pub fn start(routes: &[(&str, &str)]) -> FixtureServer {
+        let server = Arc::new(tiny_http::Server::http("127.0.0.1:0").unwrap());
+        let port = match server.server_addr().to_ip() {
+            Some(v) => v.port(),
+            None => panic!("Fixture server doesn't listen on an IP address"),
+        };
+
+        let routes: Vec<(String, String)> = routes
+            .iter()
+            .map(|(p, f)| (p.to_string(), fixture_path(f)))
+            .collect();
+
+        let srv = server.clone();
+        let handle = thread::spawn(move || {
+            for request in srv.incoming_requests() {
+                let path = request.url().split('?').next().unwrap_or("").to_string();
+                let response = match routes.iter().find(|(p, _)| *p == path) {
+                    Some((_, file)) => tiny_http::Response::from_data(fs::read(file).unwrap())
+                        .with_status_code(200),
+                    None => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+                };
+                let _ = request.respond(response);
+            }
+        });
+
+        FixtureServer {
+            port,
+            server,
+            handle: Some(handle),
+        }
+    }
+
+    // Replace the random port in port labels for a stable golden file
+    pub fn mask_port(&self, rendered: &str) -> String {
+        rendered.replace(&format!("port=\"{}\"", self.port), "port=\"PORT\"")
+    }
+}
+
+impl Drop for FixtureServer {
+    fn drop(&mut self) {
+        self.server.unblock();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
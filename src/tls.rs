@@ -0,0 +1,153 @@
+use lazy_static::lazy_static;
+use log::debug;
+use prometheus::{GaugeVec, Opts, Registry};
+
+// Expiration of the server certificates of HTTPS license servers (HASP, OLicense), so expiring
+// certificates are noticed like expiring licenses
+lazy_static! {
+    pub static ref ENDPOINT_TLS_EXPIRY: GaugeVec = GaugeVec::new(
+        Opts::new(
+            "license_endpoint_tls_expiry_seconds",
+            "Expiration time of the TLS certificate of the license server as seconds since epoch"
+        ),
+        &["endpoint"],
+    )
+    .unwrap();
+}
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+
+// Set the expiration of the server certificate of the response, if the request used TLS
+pub fn observe(response: &reqwest::blocking::Response) {
+    let der = match response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|i| i.peer_certificate())
+    {
+        Some(v) => v,
+        None => return,
+    };
+
+    let url = response.url();
+    let endpoint = format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    );
+
+    match not_after(der) {
+        Some(v) => {
+            debug!(
+                "tls.rs:observe: Setting license_endpoint_tls_expiry_seconds {} -> {}",
+                endpoint, v
+            );
+            ENDPOINT_TLS_EXPIRY.with_label_values(&[&endpoint]).set(v);
+        }
+        None => debug!(
+            "tls.rs:observe: Can't get the expiration of the certificate of {}",
+            endpoint
+        ),
+    };
+}
+
+// notAfter of a DER encoded X.509 certificate as seconds since epoch:
+// Certificate ::= SEQUENCE { tbsCertificate SEQUENCE { [0] version OPTIONAL, serialNumber,
+// signature, issuer, validity SEQUENCE { notBefore, notAfter }, ... }, ... }
+pub fn not_after(der: &[u8]) -> Option<f64> {
+    let (tag, certificate, _) = read_tlv(der)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let (tag, tbs, _) = read_tlv(certificate)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+
+    let (tag, _, mut rest) = read_tlv(tbs)?;
+    if tag == TAG_VERSION {
+        // serial number
+        rest = read_tlv(rest)?.2;
+    }
+    // signature algorithm and issuer
+    rest = read_tlv(rest)?.2;
+    rest = read_tlv(rest)?.2;
+
+    let (tag, validity, _) = read_tlv(rest)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let (_, _, rest) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(rest)?;
+
+    parse_time(tag, std::str::from_utf8(time).ok()?)
+}
+
+// Tag, content and remaining data of a DER encoded value
+fn read_tlv(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+
+    let (length, start) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let bytes = data.get(2..2 + count)?;
+        (
+            bytes.iter().fold(0, |acc, b| (acc << 8) | *b as usize),
+            2 + count,
+        )
+    };
+
+    let content = data.get(start..start + length)?;
+    Some((tag, content, &data[start + length..]))
+}
+
+fn parse_time(tag: u8, time: &str) -> Option<f64> {
+    let time = time.strip_suffix('Z')?;
+    let full = match tag {
+        // YYMMDDHHMMSS, years 50 - 99 are 1950 - 1999 (RFC 5280)
+        TAG_UTC_TIME => {
+            let year: u32 = time.get(0..2)?.parse().ok()?;
+            let century = if year >= 50 { "19" } else { "20" };
+            format!("{}{}", century, time)
+        }
+        TAG_GENERALIZED_TIME => time.to_string(),
+        _ => return None,
+    };
+
+    let parsed = chrono::NaiveDateTime::parse_from_str(&full, "%Y%m%d%H%M%S").ok()?;
+    Some(parsed.and_utc().timestamp() as f64)
+}
+
+pub fn register_into(registry: &Registry) {
+    registry
+        .register(Box::new(ENDPOINT_TLS_EXPIRY.clone()))
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil;
+
+    #[test]
+    fn certificate_expiration() {
+        let der = testutil::fixture("tls/server.der");
+        assert_eq!(not_after(&der), Some(2107513793.0));
+        assert_eq!(not_after(&der[..100]), None);
+        assert_eq!(not_after(b"\x30\x84\xff\xff\xff\xff"), None);
+
+        assert_eq!(parse_time(TAG_UTC_TIME, "991231235959Z"), Some(946684799.0));
+        assert_eq!(
+            parse_time(TAG_GENERALIZED_TIME, "20500101000000Z"),
+            Some(2524608000.0)
+        );
+        assert_eq!(parse_time(TAG_UTC_TIME, "991231235959"), None);
+    }
+}
@@ -0,0 +1,466 @@
+use crate::config;
+use crate::constants;
+use crate::license;
+
+use lazy_static::lazy_static;
+use log::{debug, error};
+use prometheus::core::Collector;
+use prometheus::{HistogramOpts, HistogramVec, IntGaugeVec, Opts, Registry};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+
+// Users holding the most seats of a license, enabled by global.top_users. Unlike the
+// *_feature_used_users metrics the number of series is limited to top_users per license, so
+// it can be used if export_user is disabled for privacy or cardinality reasons.
+lazy_static! {
+    pub static ref TOP_USER_SEATS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new(
+            "license_top_user_seats",
+            "Number of seats held by the users with the most license checkouts"
+        ),
+        &["app", "rank", "user"],
+    )
+    .unwrap();
+    static ref TOP_USERS: RwLock<usize> = RwLock::new(0);
+    // label values (rank, user) of the last export per license, replaced on every export
+    static ref EXPORTED: Mutex<HashMap<String, Vec<(String, String)>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Features of a license with the seats of their users, observed by the histograms
+type Observed = Vec<(String, Vec<i64>)>;
+
+// Distribution of the seats held per user of a feature, enabled by
+// global.seats_per_user_histogram. The histogram of a feature only contains the checkouts of
+// the last fetch, so histogram_quantile can be used without rate.
+lazy_static! {
+    pub static ref SEATS_PER_USER: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "license_feature_seats_per_user",
+            "Distribution of the number of seats held by the users of a feature"
+        )
+        .buckets(constants::SEATS_PER_USER_BUCKETS.to_vec()),
+        &["app", "name"],
+    )
+    .unwrap();
+    static ref SEATS_HISTOGRAM: RwLock<bool> = RwLock::new(false);
+    // features of the last export per license with the observed seats, replaced on every export
+    static ref EXPORTED_FEATURES: Mutex<HashMap<String, Observed>> =
+        Mutex::new(HashMap::new());
+}
+
+// Series of disabled metrics are removed, they aren't replaced by exports anymore
+pub fn configure(cfg: &config::Configuration) {
+    match TOP_USERS.write() {
+        Ok(mut v) => *v = cfg.top_users(),
+        Err(e) => error!("topusers.rs:configure: Can't set top_users: {}", e),
+    };
+    if count() == 0 {
+        remove_top(None);
+    }
+    match SEATS_HISTOGRAM.write() {
+        Ok(mut v) => *v = cfg.seats_per_user_histogram(),
+        Err(e) => error!(
+            "topusers.rs:configure: Can't set seats_per_user_histogram: {}",
+            e
+        ),
+    };
+    if !histogram_enabled() {
+        take_histograms(None);
+    }
+}
+
+// Number of users to export, 0 if disabled
+pub fn count() -> usize {
+    TOP_USERS.read().map_or(0, |v| *v)
+}
+
+fn histogram_enabled() -> bool {
+    SEATS_HISTOGRAM.read().map_or(false, |v| *v)
+}
+
+// The checkouts are needed for the top users or the seats per user histogram
+pub fn enabled() -> bool {
+    count() > 0 || histogram_enabled()
+}
+
+// Sum up the seats of all (feature, user, seats) checkouts per user and per feature and user,
+// features matching the excluded_features of the license are skipped
+pub fn export<'a, I>(app: &str, filter: &license::FeatureFilter, checkouts: I)
+where
+    I: IntoIterator<Item = (&'a String, &'a String, i64)>,
+{
+    let n = count();
+    let histogram = histogram_enabled();
+    if n == 0 && !histogram {
+        return;
+    }
+
+    let mut seats: HashMap<&String, i64> = HashMap::new();
+    let mut feature_seats: HashMap<&String, HashMap<&String, i64>> = HashMap::new();
+    for (feature, user, count) in checkouts {
+        if filter.matches(feature) {
+            continue;
+        }
+        *seats.entry(user).or_insert(0) += count;
+        if histogram {
+            *feature_seats
+                .entry(feature)
+                .or_default()
+                .entry(user)
+                .or_insert(0) += count;
+        }
+    }
+
+    if histogram {
+        export_histogram(app, feature_seats);
+    }
+    if n > 0 {
+        export_top(app, seats, n);
+    }
+}
+
+fn export_top(app: &str, seats: HashMap<&String, i64>, n: usize) {
+    let top = rank(seats, n);
+
+    let mut exported = match EXPORTED.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("topusers.rs:export_top: Can't lock exported users: {}", e);
+            return;
+        }
+    };
+
+    // Users leaving the top list or changing their rank would be stuck at their last value
+    let labels: Vec<(String, String)> = top
+        .iter()
+        .enumerate()
+        .map(|(i, (user, _))| ((i + 1).to_string(), user.to_string()))
+        .collect();
+    for (rank, user) in exported.remove(app).unwrap_or_default() {
+        if !labels.contains(&(rank.clone(), user.clone())) {
+            let _ = TOP_USER_SEATS.remove_label_values(&[app, &rank, &user]);
+        }
+    }
+
+    for ((rank, user), (_, count)) in labels.iter().zip(top.iter()) {
+        debug!(
+            "topusers.rs:export_top: Setting license_top_user_seats {} {} {} -> {}",
+            app, rank, user, count
+        );
+        TOP_USER_SEATS
+            .with_label_values(&[app, rank, user])
+            .set(*count);
+    }
+    exported.insert(app.to_string(), labels);
+}
+
+// Remove the top users of the license app or, without app, of all licenses
+fn remove_top(app: Option<&str>) {
+    let mut exported = match EXPORTED.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("topusers.rs:remove_top: Can't lock exported users: {}", e);
+            return;
+        }
+    };
+
+    exported.retain(|a, labels| {
+        if app.map_or(false, |app| app != a) {
+            return true;
+        }
+        for (rank, user) in labels.iter() {
+            let _ = TOP_USER_SEATS.remove_label_values(&[a, rank, user]);
+        }
+        false
+    });
+}
+
+// Remove the series of a license removed by a configuration reload
+pub fn forget(app: &str) {
+    remove_top(Some(app));
+    take_histograms(Some(app));
+}
+
+// Histograms taken out before a fetch. Observations can't be set like gauge values, so they
+// are put back by observing the seats of the last export again.
+pub struct Histograms {
+    features: HashMap<String, Observed>,
+}
+
+// Remove the histograms of the license app or, without app, of all licenses
+pub fn take_histograms(app: Option<&str>) -> Histograms {
+    let mut features = HashMap::new();
+
+    let mut exported = match EXPORTED_FEATURES.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "topusers.rs:take_histograms: Can't lock exported features: {}",
+                e
+            );
+            return Histograms { features };
+        }
+    };
+
+    let apps: Vec<String> = exported
+        .keys()
+        .filter(|a| app.map_or(true, |app| app == a.as_str()))
+        .cloned()
+        .collect();
+    for a in apps {
+        if let Some(observed) = exported.remove(&a) {
+            for (feature, _) in observed.iter() {
+                let _ = SEATS_PER_USER.remove_label_values(&[&a, feature]);
+            }
+            features.insert(a, observed);
+        }
+    }
+
+    Histograms { features }
+}
+
+impl Histograms {
+    // Put the histograms back, e.g. if the fetch failed. Histograms exported by the failed
+    // fetch are kept.
+    pub fn restore(self) {
+        self.put_back(false);
+    }
+
+    // Put the histograms back without observations
+    pub fn restore_zero(self) {
+        self.put_back(true);
+    }
+
+    fn put_back(self, zero: bool) {
+        let mut exported = match EXPORTED_FEATURES.lock() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("topusers.rs:put_back: Can't lock exported features: {}", e);
+                return;
+            }
+        };
+
+        for (app, observed) in self.features {
+            if exported.contains_key(&app) {
+                continue;
+            }
+            let mut features = Vec::new();
+            for (feature, seats) in observed {
+                let histogram = SEATS_PER_USER.with_label_values(&[&app, &feature]);
+                let seats = if zero { Vec::new() } else { seats };
+                for s in seats.iter() {
+                    histogram.observe(*s as f64);
+                }
+                features.push((feature, seats));
+            }
+            exported.insert(app, features);
+        }
+    }
+}
+
+// Replace the histograms of the license by the seats of the current checkouts as
+// feature -> user -> seats
+fn export_histogram(app: &str, feature_seats: HashMap<&String, HashMap<&String, i64>>) {
+    let mut exported = match EXPORTED_FEATURES.lock() {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "topusers.rs:export_histogram: Can't lock exported features: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    // Observations accumulate, so the histograms are rebuilt on every export
+    for (feature, _) in exported.remove(app).unwrap_or_default() {
+        let _ = SEATS_PER_USER.remove_label_values(&[app, &feature]);
+    }
+
+    let mut features: Observed = Vec::new();
+    for (feature, users) in feature_seats {
+        debug!(
+            "topusers.rs:export_histogram: Setting license_feature_seats_per_user {} {} -> {:?}",
+            app, feature, users
+        );
+        let histogram = SEATS_PER_USER.with_label_values(&[app, feature]);
+        for seats in users.values() {
+            histogram.observe(*seats as f64);
+        }
+        features.push((feature.to_string(), users.into_values().collect()));
+    }
+    exported.insert(app.to_string(), features);
+}
+
+// Number of distinct users per feature as (feature, users), e.g. from the user maps of the
+// features. Like the top users, the counts are exported if export_user is disabled.
+pub fn export_user_count<'a, I>(
+    app: &str,
+    filter: &license::FeatureFilter,
+    metric: &IntGaugeVec,
+    users: I,
+) where
+    I: IntoIterator<Item = (&'a String, usize)>,
+{
+    for (feature, count) in users {
+        if filter.matches(feature) {
+            continue;
+        }
+        debug!(
+            "topusers.rs:export_user_count: Setting {} {} {} -> {}",
+            metric.desc()[0].fq_name,
+            app,
+            feature,
+            count
+        );
+        metric.with_label_values(&[app, feature]).set(count as i64);
+    }
+}
+
+// Checkouts as feature -> user -> count
+pub fn export_fu(
+    app: &str,
+    filter: &license::FeatureFilter,
+    fu: &HashMap<String, HashMap<String, i64>>,
+) {
+    export(
+        app,
+        filter,
+        fu.iter()
+            .flat_map(|(f, uc)| uc.iter().map(move |(u, c)| (f, u, *c))),
+    );
+}
+
+// Checkouts as feature -> user -> version -> count
+pub fn export_fuv(
+    app: &str,
+    filter: &license::FeatureFilter,
+    fuv: &HashMap<String, HashMap<String, HashMap<String, i64>>>,
+) {
+    export(
+        app,
+        filter,
+        fuv.iter().flat_map(|(f, uv)| {
+            uv.iter()
+                .flat_map(move |(u, vc)| vc.values().map(move |c| (f, u, *c)))
+        }),
+    );
+}
+
+// The n users with the most seats, ties are ordered by user name
+fn rank(seats: HashMap<&String, i64>, n: usize) -> Vec<(String, i64)> {
+    let mut result: Vec<(String, i64)> =
+        seats.into_iter().map(|(u, s)| (u.to_string(), s)).collect();
+    result.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    result.truncate(n);
+    result
+}
+
+pub fn register_into(registry: &Registry) {
+    registry.register(Box::new(TOP_USER_SEATS.clone())).unwrap();
+    registry.register(Box::new(SEATS_PER_USER.clone())).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rank_users() {
+        let names: Vec<String> = ["carol", "alice", "bob", "dave"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let seats: HashMap<&String, i64> = names.iter().zip([3, 5, 3, 1]).collect();
+
+        assert_eq!(
+            rank(seats, 3),
+            vec![
+                ("alice".to_string(), 5),
+                ("bob".to_string(), 3),
+                ("carol".to_string(), 3)
+            ]
+        );
+    }
+    #[test]
+    fn remove_top_users() {
+        let names: Vec<String> = ["alice", "bob"].iter().map(|s| s.to_string()).collect();
+        for app in ["top-removed", "top-kept"] {
+            export_top(
+                app,
+                [(&names[0], 2), (&names[1], 1)].into_iter().collect(),
+                2,
+            );
+        }
+        let series = |app: &str| {
+            TOP_USER_SEATS
+                .collect()
+                .iter()
+                .flat_map(|mf| mf.get_metric().iter())
+                .filter(|m| m.get_label().iter().any(|l| l.get_value() == app))
+                .count()
+        };
+        assert_eq!(series("top-removed"), 2);
+
+        forget("top-removed");
+        assert_eq!(series("top-removed"), 0);
+        assert_eq!(series("top-kept"), 2);
+
+        // Disabled by a reload, configure would remove the histograms of the other tests too
+        remove_top(None);
+        assert_eq!(series("top-kept"), 0);
+    }
+
+    #[test]
+    fn seats_per_user_histogram() {
+        let names: Vec<String> = ["feat_a", "feat_b", "alice", "bob", "carol"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let mut feature_seats: HashMap<&String, HashMap<&String, i64>> = HashMap::new();
+        feature_seats.insert(
+            &names[0],
+            [(&names[2], 1), (&names[3], 3), (&names[4], 20)]
+                .into_iter()
+                .collect(),
+        );
+        feature_seats.insert(&names[1], [(&names[2], 1)].into_iter().collect());
+        export_histogram("histogram", feature_seats);
+
+        let feat_a = SEATS_PER_USER.with_label_values(&["histogram", "feat_a"]);
+        assert_eq!(feat_a.get_sample_count(), 3);
+        assert_eq!(feat_a.get_sample_sum(), 24.0);
+
+        // Features without checkouts are removed, the others only contain the new checkouts
+        let mut feature_seats: HashMap<&String, HashMap<&String, i64>> = HashMap::new();
+        feature_seats.insert(&names[0], [(&names[3], 2)].into_iter().collect());
+        export_histogram("histogram", feature_seats);
+
+        let feat_a = SEATS_PER_USER.with_label_values(&["histogram", "feat_a"]);
+        assert_eq!(feat_a.get_sample_count(), 1);
+        assert_eq!(feat_a.get_sample_sum(), 2.0);
+        assert!(SEATS_PER_USER
+            .remove_label_values(&["histogram", "feat_b"])
+            .is_err());
+
+        // Put back after a failed fetch
+        let taken = take_histograms(Some("histogram"));
+        assert!(SEATS_PER_USER
+            .remove_label_values(&["histogram", "feat_a"])
+            .is_err());
+        taken.restore();
+        let feat_a = SEATS_PER_USER.with_label_values(&["histogram", "feat_a"]);
+        assert_eq!(feat_a.get_sample_count(), 1);
+        assert_eq!(feat_a.get_sample_sum(), 2.0);
+
+        take_histograms(Some("histogram")).restore_zero();
+        let feat_a = SEATS_PER_USER.with_label_values(&["histogram", "feat_a"]);
+        assert_eq!(feat_a.get_sample_count(), 0);
+
+        forget("histogram");
+        assert!(SEATS_PER_USER
+            .remove_label_values(&["histogram", "feat_a"])
+            .is_err());
+    }
+}
@@ -18,26 +18,80 @@ Public License Version 3. (http://www.gnu.org/copyleft/gpl.html)
 pub fn show_usage() {
     show_version();
     println!(
-        "Usage: {} [-D|--debug] [-V|--version] -c <config>|--config=<config> [-h|--help] [-l <address>|--listen=<address>]
+        "Usage: {} [-D|--debug] [-E|--config-from-env] [-F <format>|--log-format=<format>] [-V|--version] [-a <name>|--app=<name>] [-c <config>|--config=<config>] [-f <format>|--config-format=<format>] [-h|--help] [-l <address>|--listen=<address>] [-m <path>|--metrics-path=<path>] [-q|--quiet] [<command>]
 
     -D                  Enable debug mode
     --debug
 
+    -E                  Read the configuration from the CONFIG_YAML
+    --config-from-env   environment variable instead of a file.
+                        Used if CONFIG_YAML is set and -c is not given
+
+    -F <format>         Log format, plain or color. Colored output
+    --log-format=<format>
+                        is only used on a terminal and if NO_COLOR
+                        is not set. Default: plain
+
     -V                  Show version information
     --version
 
+    -a <name>           Name of the license to scrape
+    --app=<name>        (scrape command only)
+
     -c <config>         Configuration file
     --config=<config>
 
+    -f <format>         Format of the configuration file, json,
+    --config-format=<format>
+                        toml or yaml. Default: json/toml for files
+                        ending with .json/.toml, yaml otherwise
+
     -h                  Show this help text
     --help
 
     -l <address>        Listen on <address> for scrape requests
     --listen=<address>  Default: {}
 
+    -m <path>           Serve the metrics on <path> instead of the
+    --metrics-path=<path>
+                        metrics_path of the configuration. Default: {}
+
     -q                  Quiet operation. Only log warning
     --quiet             and error messages
+
+Commands:
+
+    {}                 Start the exporter (default)
+
+    {}        Check the configuration file including the
+                        referenced files and exit
+
+    {}      Print the configuration migrated to the current
+                        schema version to stdout
+
+    {}              Fetch the metrics of the license given by -a/--app
+                        and print them to stdout
+
+    {}       List the supported license backends and, if a
+                        configuration file is given, the configured licenses
+
+    {} <shell>
+                        Print shell completions for bash, fish or zsh
+
+    {}        Print the man page
+
+    {}             Show version information
 ",
-        constants::NAME, constants::DEFAULT_PROMETHEUS_ADDRESS
+        constants::NAME,
+        constants::DEFAULT_PROMETHEUS_ADDRESS,
+        constants::DEFAULT_METRICS_PATH,
+        constants::COMMAND_RUN,
+        constants::COMMAND_CHECK_CONFIG,
+        constants::COMMAND_MIGRATE_CONFIG,
+        constants::COMMAND_SCRAPE,
+        constants::COMMAND_LIST_BACKENDS,
+        constants::COMMAND_GENERATE_COMPLETIONS,
+        constants::COMMAND_GENERATE_MAN,
+        constants::COMMAND_VERSION,
     );
 }